@@ -0,0 +1,717 @@
+/*!
+ * Local API Server
+ *
+ * Opt-in OpenAI-compatible HTTP endpoint so scripts, editor plugins and CLI
+ * agents can reuse the models RAIN already has loaded instead of loading a
+ * second copy. Serves `/v1/models`, `/v1/chat/completions` (optionally as
+ * server-sent events) and `/v1/embeddings`.
+ */
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{Result, anyhow};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::embeddings;
+use super::generation::FinishReason;
+use super::grammar::OutputConstraint;
+use super::model_manager::{ConversationMessage, GenerationParams, ModelManager};
+use super::scheduler::{InferenceScheduler, Priority};
+use super::vision::ImageInput;
+use crate::config::{ApiServerConfig, SamplingPreset};
+
+/// A running server; dropping it shuts the server down like `stop`
+pub struct ApiServer {
+    address: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+}
+
+/// Reported to the UI by `get_api_server_status`
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiServerStatus {
+    pub running: bool,
+    pub address: Option<String>,
+    pub requires_api_key: bool,
+}
+
+struct ServerContext {
+    models: Arc<RwLock<ModelManager>>,
+    /// Generations queue with the app's own chat requests
+    scheduler: InferenceScheduler,
+    api_key: Option<String>,
+}
+
+impl ApiServer {
+    /// Bind to `config.bind_address` and serve in the background
+    pub fn start(models: Arc<RwLock<ModelManager>>, scheduler: InferenceScheduler, config: &ApiServerConfig) -> Result<Self> {
+        let address: SocketAddr = config.bind_address.parse()
+            .map_err(|e| anyhow!("Invalid bind address {}: {}", config.bind_address, e))?;
+        if !address.ip().is_loopback() && config.api_key.is_none() {
+            warn!("⚠️ API server listens on {} without an API key; anyone on the network can use the models", address);
+        }
+
+        let context = Arc::new(ServerContext {
+            models,
+            scheduler,
+            api_key: config.api_key.clone().filter(|key| !key.is_empty()),
+        });
+        let make_service = make_service_fn(move |connection: &AddrStream| {
+            let context = context.clone();
+            let remote = connection.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let context = context.clone();
+                    async move { Ok::<_, Infallible>(handle(context, remote, request).await) }
+                }))
+            }
+        });
+
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+        let server = Server::try_bind(&address)
+            .map_err(|e| anyhow!("Failed to bind {}: {}", address, e))?
+            .serve(make_service);
+        let address = server.local_addr();
+        let server = server.with_graceful_shutdown(async {
+            let _ = shutdown_signal.await;
+        });
+
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!("⚠️ API server stopped with an error: {}", e);
+            }
+        });
+
+        info!("🚀 OpenAI-compatible API listening on http://{}/v1", address);
+        Ok(Self { address, shutdown })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn stop(self) {
+        let _ = self.shutdown.send(());
+        info!("✅ API server on {} stopped", self.address);
+    }
+}
+
+/// Authenticate, route and log one request
+async fn handle(context: Arc<ServerContext>, remote: SocketAddr, request: Request<Body>) -> Response<Body> {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+
+    let response = if !authorized(&context, &request) {
+        error_response(StatusCode::UNAUTHORIZED, "invalid_api_key", "Missing or invalid bearer token")
+    } else {
+        match (&method, path.as_str()) {
+            (&Method::GET, "/v1/models") => list_models(&context).await,
+            (&Method::POST, "/v1/chat/completions") => chat_completions(&context, request).await,
+            (&Method::POST, "/v1/embeddings") => create_embeddings(&context, request).await,
+            (_, "/v1/models" | "/v1/chat/completions" | "/v1/embeddings") => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", &format!("{} is not supported on {}", method, path))
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "not_found", &format!("Unknown endpoint {}", path)),
+        }
+    };
+
+    info!("🌐 {} {} {} -> {} in {} ms", remote, method, path, response.status().as_u16(), started.elapsed().as_millis());
+    response
+}
+
+fn authorized(context: &ServerContext, request: &Request<Body>) -> bool {
+    let Some(api_key) = &context.api_key else {
+        return true;
+    };
+    request.headers().get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.trim(), api_key))
+}
+
+/// Compare digests so the time taken reveals neither a matching prefix nor the key's length
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (Sha256::digest(a.as_bytes()), Sha256::digest(b.as_bytes()));
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn json_response(status: StatusCode, body: &serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap_or_default()
+}
+
+/// Error body in the shape OpenAI clients expect
+fn error_response(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    json_response(status, &serde_json::json!({
+        "error": {
+            "message": message,
+            "type": if status.is_server_error() { "server_error" } else { "invalid_request_error" },
+            "code": code,
+        }
+    }))
+}
+
+/// Largest request body accepted; long chat histories and embedding batches fit well within it
+const MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+async fn read_json<T: for<'de> Deserialize<'de>>(request: Request<Body>) -> Result<T, Response<Body>> {
+    let too_large = || error_response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "request_too_large",
+        &format!("Request body exceeds {} bytes", MAX_BODY_BYTES),
+    );
+    let declared = request.headers().get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared.is_some_and(|length| length > MAX_BODY_BYTES as u64) {
+        return Err(too_large());
+    }
+
+    // Content-Length is optional (chunked bodies), so count while reading as well
+    let mut body = request.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| error_response(StatusCode::BAD_REQUEST, "invalid_body", &e.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    serde_json::from_slice(&bytes)
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, "invalid_json", &e.to_string()))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+async fn list_models(context: &ServerContext) -> Response<Body> {
+    let models = context.models.read().await;
+    let data: Vec<_> = models.get_available_models().into_iter()
+        .map(|model| serde_json::json!({
+            "id": model.id,
+            "object": "model",
+            "created": 0,
+            "owned_by": "local",
+            "loaded": model.loaded,
+        }))
+        .collect();
+    json_response(StatusCode::OK, &serde_json::json!({ "object": "list", "data": data }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionRequest {
+    /// Model id; the app's default model when absent
+    model: Option<String>,
+    messages: Vec<ChatCompletionMessage>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_tokens: Option<u32>,
+    stop: Option<StopSequences>,
+    seed: Option<u64>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    /// Token id (as a string key) to bias, as in OpenAI's API
+    logit_bias: Option<BTreeMap<u32, f32>>,
+    /// llama.cpp server extensions
+    top_k: Option<u32>,
+    min_p: Option<f32>,
+    typical_p: Option<f32>,
+    repeat_penalty: Option<f32>,
+    repeat_last_n: Option<usize>,
+    mirostat: Option<u8>,
+    mirostat_tau: Option<f32>,
+    mirostat_eta: Option<f32>,
+    response_format: Option<ResponseFormat>,
+    /// GBNF grammar, as accepted by llama.cpp's server
+    grammar: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonSchemaFormat {
+    schema: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionMessage {
+    role: String,
+    content: MessageContent,
+}
+
+/// A plain string, or text and image parts as sent to vision models
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageUrl {
+    /// Only `data:` URLs; the server does not fetch remote images
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ChatCompletionRequest {
+    fn params(&self, defaults: &GenerationParams) -> GenerationParams {
+        let mut params = defaults.with_preset(&SamplingPreset {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            max_tokens: self.max_tokens,
+            repeat_penalty: self.repeat_penalty,
+            repeat_last_n: self.repeat_last_n,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            mirostat: self.mirostat,
+            mirostat_tau: self.mirostat_tau,
+            mirostat_eta: self.mirostat_eta,
+            logit_bias: self.logit_bias.clone(),
+            seed: self.seed,
+        });
+        // Clients send whole conversations of their own; the saved prompt cache belongs to the app's chat
+        params.reuse_prompt_cache = false;
+        match &self.stop {
+            Some(StopSequences::One(stop)) => params.stop_sequences.push(stop.clone()),
+            Some(StopSequences::Many(stops)) => params.stop_sequences.extend(stops.iter().cloned()),
+            None => {}
+        }
+        params.constraint = match (&self.grammar, &self.response_format) {
+            (Some(grammar), _) => Some(OutputConstraint::Grammar(grammar.clone())),
+            (None, Some(ResponseFormat::JsonObject)) => Some(OutputConstraint::JsonSchema(serde_json::json!({ "type": "object" }))),
+            (None, Some(ResponseFormat::JsonSchema { json_schema })) => Some(OutputConstraint::JsonSchema(json_schema.schema.clone())),
+            (None, Some(ResponseFormat::Text) | None) => None,
+        };
+        params
+    }
+
+    fn conversation(&self) -> Result<Vec<ConversationMessage>> {
+        self.messages.iter()
+            .map(|message| {
+                let (content, images) = match &message.content {
+                    MessageContent::Text(text) => (text.clone(), Vec::new()),
+                    MessageContent::Parts(parts) => {
+                        let mut texts = Vec::new();
+                        let mut images = Vec::new();
+                        for part in parts {
+                            match part {
+                                ContentPart::Text { text } => texts.push(text.as_str()),
+                                ContentPart::ImageUrl { image_url } => images.push(ImageInput::from_data_url(&image_url.url)?),
+                            }
+                        }
+                        (texts.join("\n"), images)
+                    }
+                };
+                Ok(ConversationMessage {
+                    role: message.role.clone(),
+                    content,
+                    truncated: false,
+                    images,
+                })
+            })
+            .collect()
+    }
+}
+
+fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Length => "length",
+        FinishReason::Eos | FinishReason::StopSequence | FinishReason::Cancelled => "stop",
+    }
+}
+
+async fn chat_completions(context: &Arc<ServerContext>, request: Request<Body>) -> Response<Body> {
+    let request: ChatCompletionRequest = match read_json(request).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    if request.messages.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_messages", "messages must not be empty");
+    }
+    let conversation = match request.conversation() {
+        Ok(conversation) => conversation,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, "invalid_messages", &e.to_string()),
+    };
+    if request.stream {
+        // Every chunk names the model, so a default must be resolved before the first one is sent
+        let model_id = match request.model.clone() {
+            Some(model_id) => model_id,
+            None => match context.models.read().await.current_model_id() {
+                Some(model_id) => model_id.to_string(),
+                None => return error_response(StatusCode::BAD_REQUEST, "model_not_found", "No model loaded. Please load a model first."),
+            },
+        };
+        return stream_chat_completion(context.clone(), request, model_id, conversation);
+    }
+
+    let id = format!("chatcmpl-{}", Uuid::new_v4().simple());
+    let result = context.scheduler.submit(Priority::Chat, None, move |models| Box::pin(async move {
+        let params = request.params(models.generation_settings());
+        models.generate_chat_completion(request.model.as_deref(), &conversation, &params, &mut |_| true).await
+    })).await;
+
+    match result {
+        Ok((model_id, output)) => json_response(StatusCode::OK, &serde_json::json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": unix_time(),
+            "model": model_id,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": output.text },
+                "finish_reason": finish_reason(output.finish_reason),
+            }],
+            "usage": {
+                "prompt_tokens": output.prompt_tokens,
+                "completion_tokens": output.completion_tokens,
+                "total_tokens": output.prompt_tokens + output.completion_tokens,
+                "prompt_tokens_details": { "cached_tokens": output.cached_prompt_tokens },
+            },
+        })),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "generation_failed", &e.to_string()),
+    }
+}
+
+/// Stream deltas as `chat.completion.chunk` server-sent events, ending with `[DONE]`
+fn stream_chat_completion(context: Arc<ServerContext>, request: ChatCompletionRequest, model_id: String, conversation: Vec<ConversationMessage>) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let (tokens, mut token_receiver) = mpsc::unbounded_channel::<String>();
+    let id = format!("chatcmpl-{}", Uuid::new_v4().simple());
+    let created = unix_time();
+
+    tokio::spawn(async move {
+        let chunk = |model: &str, delta: serde_json::Value, finish: Option<&str>| {
+            Bytes::from(format!("data: {}\n\n", serde_json::json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": model,
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish }],
+            })))
+        };
+
+        // Generation blocks the scheduler's thread, so tokens are forwarded to the socket from here
+        let generation_model = model_id.clone();
+        let generation = context.scheduler.submit(Priority::Chat, None, move |models| Box::pin(async move {
+            let params = request.params(models.generation_settings());
+            // A closed receiver means the client went away: stop generating
+            models.generate_chat_completion(Some(&generation_model), &conversation, &params, &mut |delta| {
+                delta.is_empty() || tokens.send(delta.to_string()).is_ok()
+            }).await
+        }));
+
+        if sender.send_data(chunk(&model_id, serde_json::json!({ "role": "assistant" }), None)).await.is_err() {
+            return;
+        }
+        while let Some(delta) = token_receiver.recv().await {
+            if sender.send_data(chunk(&model_id, serde_json::json!({ "content": delta }), None)).await.is_err() {
+                // Dropping the receiver makes the next token send fail and cancels generation
+                return;
+            }
+        }
+
+        let final_chunk = match generation.await {
+            Ok((_, output)) => chunk(&model_id, serde_json::json!({}), Some(finish_reason(output.finish_reason))),
+            Err(e) => Bytes::from(format!("data: {}\n\n", serde_json::json!({
+                "error": { "message": e.to_string(), "type": "server_error", "code": "generation_failed" }
+            }))),
+        };
+        if sender.send_data(final_chunk).await.is_ok() {
+            let _ = sender.send_data(Bytes::from_static(b"data: [DONE]\n\n")).await;
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingsRequest {
+    input: EmbeddingInput,
+    /// Embedding model id; the resident (or first) embedding model when absent
+    model: Option<String>,
+    /// Matryoshka truncation, as in OpenAI's API
+    dimensions: Option<usize>,
+    /// Prompt to encode with (`query`, `document`, ...); RAIN extension, `document` by default
+    task_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+async fn create_embeddings(context: &ServerContext, request: Request<Body>) -> Response<Body> {
+    let request: EmbeddingsRequest = match read_json(request).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let inputs = match request.input {
+        EmbeddingInput::One(text) => vec![text],
+        EmbeddingInput::Many(texts) => texts,
+    };
+    let task_type = request.task_type.unwrap_or_else(|| "document".to_string());
+
+    let model = match embeddings::resident_model(&context.models, request.model.as_deref()).await {
+        Ok(model) => model,
+        Err(e) => return error_response(StatusCode::NOT_FOUND, "model_not_found", &e.to_string()),
+    };
+    let tokens = match model.count_tokens(&inputs, &task_type) {
+        Ok(tokens) => tokens,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, "invalid_task_type", &e.to_string()),
+    };
+    let vectors = match embeddings::encode(model.clone(), inputs, task_type, request.dimensions).await {
+        Ok(vectors) => vectors,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, "embedding_failed", &e.to_string()),
+    };
+
+    let data: Vec<_> = vectors.into_iter().enumerate()
+        .map(|(index, embedding)| serde_json::json!({
+            "object": "embedding",
+            "index": index,
+            "embedding": embedding,
+        }))
+        .collect();
+
+    json_response(StatusCode::OK, &serde_json::json!({
+        "object": "list",
+        "data": data,
+        "model": model.model_id(),
+        "usage": { "prompt_tokens": tokens, "total_tokens": tokens },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RemoteApi, RemoteModelConfig};
+
+    /// OpenAI-compatible upstream that streams "Hel" + "lo" for every chat request
+    fn upstream() -> SocketAddr {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let response = match request.uri().path() {
+                    "/chat/completions" => {
+                        let events = [
+                            r#"{"choices":[{"index":0,"delta":{"content":"Hel"}}]}"#,
+                            r#"{"choices":[{"index":0,"delta":{"content":"lo"},"finish_reason":"stop"}]}"#,
+                            "[DONE]",
+                        ];
+                        let body: String = events.iter().map(|event| format!("data: {}\n\n", event)).collect();
+                        Response::builder()
+                            .header(CONTENT_TYPE, "text/event-stream")
+                            .body(Body::from(body))
+                            .unwrap()
+                    }
+                    _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+                };
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    /// API server on an ephemeral loopback port, serving one remote model called `remote`
+    async fn start(api_key: Option<&str>, load_default: bool) -> (ApiServer, String) {
+        let mut models = ModelManager::new();
+        models.set_remote_models(vec![RemoteModelConfig {
+            id: "remote".to_string(),
+            base_url: format!("http://{}", upstream()),
+            api: RemoteApi::OpenAi,
+            model: None,
+            api_key: None,
+            timeout_secs: 5,
+            max_retries: 0,
+        }]);
+        if load_default {
+            models.load_model_by_id("remote").await.unwrap();
+        }
+        let config = ApiServerConfig {
+            enabled: true,
+            bind_address: "127.0.0.1:0".to_string(),
+            api_key: api_key.map(str::to_string),
+        };
+        let models = Arc::new(RwLock::new(models));
+        let scheduler = InferenceScheduler::new(models.clone());
+        let server = ApiServer::start(models, scheduler, &config).unwrap();
+        let base = format!("http://{}/v1", server.address());
+        (server, base)
+    }
+
+    /// POST a streaming request; returns the content type and the payload of every `data:` event
+    async fn stream_events(base: &str, body: serde_json::Value) -> (String, Vec<String>) {
+        let response = reqwest::Client::new().post(format!("{}/chat/completions", base)).json(&body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = response.headers().get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let text = response.text().await.unwrap();
+        assert!(text.ends_with("\n\n"), "{:?}", text);
+        let events = text.trim_end().split("\n\n")
+            .map(|event| event.strip_prefix("data: ").unwrap_or_else(|| panic!("not an SSE data line: {:?}", event)).to_string())
+            .collect();
+        (content_type, events)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requires_the_configured_bearer_token() {
+        let (_server, base) = start(Some("secret"), false).await;
+        let client = reqwest::Client::new();
+        let url = format!("{}/models", base);
+
+        let missing = client.get(&url).send().await.unwrap();
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = missing.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "invalid_api_key");
+
+        let wrong = client.get(&url).bearer_auth("secre").send().await.unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+
+        let listed: serde_json::Value = client.get(&url).bearer_auth("secret").send().await.unwrap().json().await.unwrap();
+        assert_eq!(listed["object"], "list");
+        assert!(listed["data"].as_array().unwrap().iter().any(|model| model["id"] == "remote"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn routes_unknown_paths_methods_and_bodies_to_errors() {
+        let (_server, base) = start(None, false).await;
+        let client = reqwest::Client::new();
+
+        let wrong_method = client.get(format!("{}/chat/completions", base)).send().await.unwrap();
+        assert_eq!(wrong_method.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let unknown = client.get(format!("{}/completions", base)).send().await.unwrap();
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+        let invalid = client.post(format!("{}/chat/completions", base)).body("{").send().await.unwrap();
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        let empty = client.post(format!("{}/chat/completions", base))
+            .json(&serde_json::json!({ "messages": [] }))
+            .send().await.unwrap();
+        assert_eq!(empty.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_chunks_as_server_sent_events() {
+        let (_server, base) = start(None, false).await;
+        let (content_type, events) = stream_events(&base, serde_json::json!({
+            "model": "remote",
+            "stream": true,
+            "messages": [{ "role": "user", "content": "Hi" }],
+        })).await;
+        assert_eq!(content_type, "text/event-stream");
+
+        assert_eq!(events.last().unwrap(), "[DONE]");
+        let chunks: Vec<serde_json::Value> = events[..events.len() - 1].iter()
+            .map(|event| serde_json::from_str(event).unwrap())
+            .collect();
+        assert!(chunks.iter().all(|chunk| chunk["object"] == "chat.completion.chunk" && chunk["model"] == "remote"));
+        assert!(chunks.iter().all(|chunk| chunk["id"] == chunks[0]["id"]));
+        assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+        let text: String = chunks.iter().filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str()).collect();
+        assert_eq!(text, "Hello");
+        assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streamed_chunks_name_the_default_model() {
+        let (_server, base) = start(None, true).await;
+        let (_, events) = stream_events(&base, serde_json::json!({
+            "stream": true,
+            "messages": [{ "role": "user", "content": "Hi" }],
+        })).await;
+        let first: serde_json::Value = serde_json::from_str(&events[0]).unwrap();
+        assert_eq!(first["model"], "remote");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streaming_without_any_model_fails_before_the_stream_starts() {
+        let (_server, base) = start(None, false).await;
+        let response = reqwest::Client::new().post(format!("{}/chat/completions", base))
+            .json(&serde_json::json!({ "stream": true, "messages": [{ "role": "user", "content": "Hi" }] }))
+            .send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "model_not_found");
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_over_the_size_cap() {
+        let status = |result: Result<serde_json::Value, Response<Body>>| result.err().map(|response| response.status());
+
+        let declared = Request::builder()
+            .header(CONTENT_LENGTH, MAX_BODY_BYTES + 1)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(read_json(declared).await), Some(StatusCode::PAYLOAD_TOO_LARGE));
+
+        // No Content-Length: the cap is enforced while reading
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let _ = sender.send_data(Bytes::from(vec![b' '; MAX_BODY_BYTES])).await;
+            let _ = sender.send_data(Bytes::from_static(b"{}")).await;
+        });
+        let streamed = Request::new(body);
+        assert_eq!(status(read_json(streamed).await), Some(StatusCode::PAYLOAD_TOO_LARGE));
+
+        let within = Request::new(Body::from(format!("{}{{}}", " ".repeat(MAX_BODY_BYTES - 2))));
+        assert_eq!(status(read_json(within).await), None);
+    }
+
+    #[test]
+    fn token_comparison_requires_an_exact_match() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+        assert!(!constant_time_eq("", "secret"));
+    }
+}
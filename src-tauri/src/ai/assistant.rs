@@ -11,6 +11,8 @@ use std::path::PathBuf;
 use anyhow::Result;
 use uuid::Uuid;
 
+use super::model_manager::ConversationMessage;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CodeAssistant {
     pub suggestions_cache: HashMap<String, Vec<CodeSuggestion>>,
@@ -155,6 +157,22 @@ pub enum IssueSeverity {
     Critical,
 }
 
+/// Issues as a model reports them, constrained by `CodeAssistant::analysis_schema`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelAnalysis {
+    pub issues: Vec<ModelIssue>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelIssue {
+    pub line: u32,
+    pub issue_type: IssueType,
+    pub severity: IssueSeverity,
+    pub message: String,
+    #[serde(default)]
+    pub fix_suggestion: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CodeMetrics {
     pub lines_of_code: u32,
@@ -436,8 +454,35 @@ impl CodeAssistant {
     pub async fn analyze_code(&self, file_path: &PathBuf, content: &str) -> Result<CodeAnalysis> {
         let language = self.detect_language(file_path);
         let issues = self.detect_issues(content, &language);
+        self.build_analysis(file_path, content, &language, issues).await
+    }
+
+    /// Analysis using the issues a model found instead of the built-in heuristics
+    pub async fn analyze_code_with_model(&self, file_path: &PathBuf, content: &str, analysis: ModelAnalysis) -> Result<CodeAnalysis> {
+        let language = self.detect_language(file_path);
+        let last_line = content.lines().count().max(1) as u32;
+        let issues = analysis.issues.into_iter()
+            .map(|issue| CodeIssue {
+                id: Uuid::new_v4().to_string(),
+                issue_type: issue.issue_type,
+                severity: issue.severity,
+                message: issue.message,
+                position: CodePosition {
+                    file_path: file_path.clone(),
+                    line: issue.line.clamp(1, last_line),
+                    column: 1,
+                    end_line: None,
+                    end_column: None,
+                },
+                fix_suggestion: issue.fix_suggestion.filter(|fix| !fix.trim().is_empty()),
+            })
+            .collect();
+        self.build_analysis(file_path, content, &language, issues).await
+    }
+
+    async fn build_analysis(&self, file_path: &PathBuf, content: &str, language: &str, issues: Vec<CodeIssue>) -> Result<CodeAnalysis> {
         let metrics = self.calculate_metrics(content);
-        let suggestions = self.generate_suggestions(content, &language).await?;
+        let suggestions = self.generate_suggestions(content, language).await?;
 
         Ok(CodeAnalysis {
             file_path: file_path.clone(),
@@ -447,6 +492,55 @@ impl CodeAssistant {
         })
     }
 
+    /// JSON Schema for `ModelAnalysis`, used to constrain the model's answer
+    pub fn analysis_schema(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "issues": {
+                    "type": "array",
+                    "maxItems": self.assistant_settings.max_suggestions,
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "line": { "type": "integer" },
+                            "issue_type": { "enum": ["Syntax", "Style", "Performance", "Security", "Maintainability", "Documentation", "Test"] },
+                            "severity": { "enum": ["Info", "Warning", "Error", "Critical"] },
+                            "message": { "type": "string", "maxLength": 200 },
+                            "fix_suggestion": { "type": ["string", "null"], "maxLength": 300 },
+                        },
+                        "required": ["line", "issue_type", "severity", "message"],
+                    },
+                },
+            },
+            "required": ["issues"],
+        })
+    }
+
+    /// Chat messages asking a model to review `content`, with line numbers it can refer to
+    pub fn analysis_messages(&self, file_path: &PathBuf, content: &str) -> Vec<ConversationMessage> {
+        let language = self.detect_language(file_path);
+        let mut instructions = format!(
+            "You review {} code. Report real problems only, each with the line it is on, as JSON.", language);
+        if let Some(rules) = self.assistant_settings.language_specific_rules.get(&language) {
+            instructions.push_str(&format!(" Follow {}; avoid: {}.", rules.style_guide, rules.anti_patterns.join("; ")));
+        }
+        let numbered: String = content.lines()
+            .enumerate()
+            .map(|(index, line)| format!("{:>4}| {}\n", index + 1, line))
+            .collect();
+
+        vec![
+            ConversationMessage { role: "system".to_string(), content: instructions, truncated: false, images: Vec::new() },
+            ConversationMessage {
+                role: "user".to_string(),
+                content: format!("File: {}\n\n{}", file_path.display(), numbered),
+                truncated: false,
+                images: Vec::new(),
+            },
+        ]
+    }
+
     fn detect_issues(&self, content: &str, language: &str) -> Vec<CodeIssue> {
         let mut issues = Vec::new();
         
@@ -0,0 +1,222 @@
+/*!
+ * Model Benchmarks
+ *
+ * Runs a fixed set of prompts against one model so models can be compared on
+ * this machine and regressions spotted after upgrades: cold load time, prompt
+ * evaluation and generation speed, time to first token and peak resident
+ * memory. Prompts are answered greedily with the prompt cache off, so every run
+ * does the same work.
+ */
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use super::generation::GenerationOutput;
+use super::model_pool;
+use crate::database::ModelBenchmarkRecord;
+
+/// Answer length for every benchmark prompt
+pub const BENCHMARK_MAX_TOKENS: u32 = 96;
+
+/// How often peak memory is sampled while a benchmark runs
+const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+pub struct BenchmarkPrompt {
+    pub name: &'static str,
+    pub text: &'static str,
+}
+
+/// The prompt set; changing it makes new results incomparable with stored ones
+pub const BENCHMARK_PROMPTS: &[BenchmarkPrompt] = &[
+    BenchmarkPrompt {
+        name: "short_question",
+        text: "What is the difference between a process and a thread?",
+    },
+    BenchmarkPrompt {
+        name: "code_generation",
+        text: "Write a Rust function that returns the n-th Fibonacci number iteratively, with a doc comment.",
+    },
+    BenchmarkPrompt {
+        name: "code_review",
+        text: r#"Review this function and suggest improvements:
+
+```rust
+fn find_duplicates(items: &Vec<String>) -> Vec<String> {
+    let mut result = Vec::new();
+    for i in 0..items.len() {
+        for j in 0..items.len() {
+            if i != j && items[i] == items[j] && !result.contains(&items[i]) {
+                result.push(items[i].clone());
+            }
+        }
+    }
+    result
+}
+
+fn main() {
+    let names = vec!["ada".to_string(), "grace".to_string(), "ada".to_string(), "linus".to_string(), "grace".to_string()];
+    for name in find_duplicates(&names) {
+        println!("duplicate: {}", name);
+    }
+}
+```"#,
+    },
+];
+
+/// Measurements for one prompt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptBenchmark {
+    pub name: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub time_to_first_token_ms: u64,
+    pub total_ms: u64,
+    /// Prompt tokens over the time to first token, which is dominated by evaluating the prompt
+    pub prompt_tokens_per_sec: f64,
+    /// Tokens after the first over the time after the first
+    pub generation_tokens_per_sec: f64,
+}
+
+impl PromptBenchmark {
+    pub fn measure(name: &str, output: &GenerationOutput, first_token_ms: Option<u64>, total: Duration) -> Self {
+        let total_ms = total.as_millis() as u64;
+        let first_token_ms = first_token_ms.unwrap_or(total_ms);
+        let evaluated = output.prompt_tokens.saturating_sub(output.cached_prompt_tokens);
+        let generation_ms = total_ms.saturating_sub(first_token_ms);
+        Self {
+            name: name.to_string(),
+            prompt_tokens: output.prompt_tokens,
+            completion_tokens: output.completion_tokens,
+            time_to_first_token_ms: first_token_ms,
+            total_ms,
+            prompt_tokens_per_sec: per_sec(evaluated, first_token_ms),
+            generation_tokens_per_sec: per_sec(output.completion_tokens.saturating_sub(1), generation_ms),
+        }
+    }
+}
+
+/// One benchmark run of a model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchmarkResult {
+    pub model_id: String,
+    /// Unix timestamp of the run
+    pub run_at: i64,
+    /// Version of this app, to line results up with upgrades
+    pub app_version: String,
+    pub format: String,
+    pub load_time_ms: u64,
+    /// Over all prompts together
+    pub prompt_tokens_per_sec: f64,
+    pub generation_tokens_per_sec: f64,
+    /// Mean over the prompts
+    pub time_to_first_token_ms: u64,
+    pub peak_rss_mb: u64,
+    pub prompts: Vec<PromptBenchmark>,
+}
+
+impl BenchmarkResult {
+    pub fn new(model_id: &str, format: &str, load_time_ms: u64, peak_rss_bytes: u64, prompts: Vec<PromptBenchmark>) -> Self {
+        // The prompt cache is off during benchmarks, so every prompt token was evaluated
+        let evaluated: usize = prompts.iter().map(|p| p.prompt_tokens).sum();
+        let first_token_ms: u64 = prompts.iter().map(|p| p.time_to_first_token_ms).sum();
+        let generated: usize = prompts.iter().map(|p| p.completion_tokens.saturating_sub(1)).sum();
+        let generation_ms: u64 = prompts.iter().map(|p| p.total_ms.saturating_sub(p.time_to_first_token_ms)).sum();
+        Self {
+            model_id: model_id.to_string(),
+            run_at: chrono::Utc::now().timestamp(),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            format: format.to_string(),
+            load_time_ms,
+            prompt_tokens_per_sec: per_sec(evaluated, first_token_ms),
+            generation_tokens_per_sec: per_sec(generated, generation_ms),
+            time_to_first_token_ms: first_token_ms / prompts.len().max(1) as u64,
+            peak_rss_mb: peak_rss_bytes / (1024 * 1024),
+            prompts,
+        }
+    }
+
+    pub fn to_record(&self, model_path: &str) -> ModelBenchmarkRecord {
+        ModelBenchmarkRecord {
+            model_id: self.model_id.clone(),
+            model_path: model_path.to_string(),
+            run_at: self.run_at,
+            app_version: self.app_version.clone(),
+            format: self.format.clone(),
+            load_time_ms: self.load_time_ms as i64,
+            prompt_tokens_per_sec: self.prompt_tokens_per_sec,
+            generation_tokens_per_sec: self.generation_tokens_per_sec,
+            time_to_first_token_ms: self.time_to_first_token_ms as i64,
+            peak_rss_mb: self.peak_rss_mb as i64,
+            prompts: serde_json::to_string(&self.prompts).unwrap_or_else(|_| "[]".to_string()),
+        }
+    }
+
+    pub fn from_record(record: ModelBenchmarkRecord) -> Self {
+        Self {
+            model_id: record.model_id,
+            run_at: record.run_at,
+            app_version: record.app_version,
+            format: record.format,
+            load_time_ms: record.load_time_ms.max(0) as u64,
+            prompt_tokens_per_sec: record.prompt_tokens_per_sec,
+            generation_tokens_per_sec: record.generation_tokens_per_sec,
+            time_to_first_token_ms: record.time_to_first_token_ms.max(0) as u64,
+            peak_rss_mb: record.peak_rss_mb.max(0) as u64,
+            prompts: serde_json::from_str(&record.prompts).unwrap_or_default(),
+        }
+    }
+}
+
+fn per_sec(tokens: usize, ms: u64) -> f64 {
+    if ms == 0 {
+        return 0.0;
+    }
+    tokens as f64 * 1000.0 / ms as f64
+}
+
+/// Tracks the process's peak resident memory on a sampling thread
+pub struct PeakMemory {
+    stop: Arc<AtomicBool>,
+    peak: Arc<AtomicU64>,
+    sampler: Option<JoinHandle<()>>,
+}
+
+impl PeakMemory {
+    pub fn start() -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let peak = Arc::new(AtomicU64::new(model_pool::process_memory_bytes()));
+        let sampler = {
+            let (stop, peak) = (stop.clone(), peak.clone());
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    peak.fetch_max(model_pool::process_memory_bytes(), Ordering::Relaxed);
+                    std::thread::sleep(MEMORY_SAMPLE_INTERVAL);
+                }
+            })
+        };
+        Self { stop, peak, sampler: Some(sampler) }
+    }
+
+    /// Stop sampling and return the peak in bytes
+    pub fn finish(mut self) -> u64 {
+        self.stop_sampler();
+        self.peak.fetch_max(model_pool::process_memory_bytes(), Ordering::Relaxed);
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    fn stop_sampler(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(sampler) = self.sampler.take() {
+            let _ = sampler.join();
+        }
+    }
+}
+
+impl Drop for PeakMemory {
+    fn drop(&mut self) {
+        self.stop_sampler();
+    }
+}
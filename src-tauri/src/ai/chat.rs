@@ -10,11 +10,15 @@ use uuid::Uuid;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 
+use super::token_counter::TokenCounter;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatEngine {
     pub sessions: HashMap<String, ChatSession>,
     pub active_session: Option<String>,
     pub chat_settings: ChatSettings,
+    #[serde(skip)]
+    token_counter: TokenCounter,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -87,6 +91,7 @@ impl Default for ChatEngine {
             sessions: HashMap::new(),
             active_session: None,
             chat_settings: ChatSettings::default(),
+            token_counter: TokenCounter::new(),
         }
     }
 }
@@ -109,6 +114,11 @@ impl ChatEngine {
         Self::default()
     }
 
+    /// Budget conversation context with the model manager's tokenizer
+    pub fn set_token_counter(&mut self, token_counter: TokenCounter) {
+        self.token_counter = token_counter;
+    }
+
     pub fn create_session(&mut self, title: String, context_type: ContextType) -> String {
         let id = Uuid::new_v4().to_string();
         let now = Utc::now();
@@ -259,7 +269,7 @@ impl ChatEngine {
             // Add system context if available
             if let Some(system_msg) = session.messages.iter().find(|m| matches!(m.role, MessageRole::System)) {
                 context.push_str(&format!("System: {}\n\n", system_msg.content));
-                token_count += estimate_tokens(&system_msg.content);
+                token_count += self.token_counter.count(&system_msg.content) as u32;
             }
             
             // Add recent messages
@@ -270,7 +280,7 @@ impl ChatEngine {
                     MessageRole::System => continue, // Already handled above
                 };
                 
-                let message_tokens = estimate_tokens(&message_text);
+                let message_tokens = self.token_counter.count(&message_text) as u32;
                 if token_count + message_tokens > max_tokens {
                     break;
                 }
@@ -315,8 +325,3 @@ impl ChatEngine {
         code_blocks
     }
 }
-
-fn estimate_tokens(text: &str) -> u32 {
-    // Simple token estimation: roughly 4 characters per token
-    (text.len() as f32 / 4.0).ceil() as u32
-}
\ No newline at end of file
@@ -0,0 +1,309 @@
+/*!
+ * Chat Templates
+ *
+ * Turns structured conversation messages into the prompt format a model was
+ * trained on. The template comes from a per-model override, the model's own
+ * `chat_template` (chat_template.jinja, tokenizer_config.json or GGUF
+ * metadata), or a built-in fallback for the model family.
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use tokio::fs;
+use tracing::{debug, warn};
+
+use super::jinja::{Template, Value};
+use super::model_manager::{ConversationMessage, GenerationParams};
+
+/// Built-in templates for model families whose checkpoints ship without one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuiltinTemplate {
+    ChatMl,
+    Llama3,
+    Gemma,
+    Lfm2,
+}
+
+impl BuiltinTemplate {
+    pub const ALL: [BuiltinTemplate; 4] = [Self::ChatMl, Self::Llama3, Self::Gemma, Self::Lfm2];
+
+    /// Name used in overrides and reported in model info
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ChatMl => "chatml",
+            Self::Llama3 => "llama3",
+            Self::Gemma => "gemma",
+            Self::Lfm2 => "lfm2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|b| b.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// Guess the family from a `model_type`, GGUF architecture or model name
+    pub fn detect(hint: &str) -> Self {
+        let hint = hint.to_lowercase();
+        if hint.contains("lfm2") {
+            Self::Lfm2
+        } else if hint.contains("gemma") {
+            Self::Gemma
+        } else if hint.contains("llama3") || hint.contains("llama-3") || hint.contains("llama_3") {
+            Self::Llama3
+        } else {
+            Self::ChatMl
+        }
+    }
+
+    fn source(self) -> &'static str {
+        match self {
+            Self::ChatMl => CHATML_TEMPLATE,
+            Self::Llama3 => LLAMA3_TEMPLATE,
+            Self::Gemma => GEMMA_TEMPLATE,
+            Self::Lfm2 => LFM2_TEMPLATE,
+        }
+    }
+
+    fn bos_token(self) -> Option<&'static str> {
+        match self {
+            Self::ChatMl => None,
+            Self::Llama3 => Some("<|begin_of_text|>"),
+            Self::Gemma => Some("<bos>"),
+            Self::Lfm2 => Some("<|startoftext|>"),
+        }
+    }
+}
+
+const CHATML_TEMPLATE: &str = r#"{%- for message in messages %}
+{{- '<|im_start|>' + message.role + '\n' + message.content + '<|im_end|>\n' }}
+{%- endfor %}
+{%- if add_generation_prompt %}
+{{- '<|im_start|>assistant\n' }}
+{%- endif %}"#;
+
+const LFM2_TEMPLATE: &str = r#"{{- bos_token }}
+{%- for message in messages %}
+{{- '<|im_start|>' + message.role + '\n' + message.content + '<|im_end|>\n' }}
+{%- endfor %}
+{%- if add_generation_prompt %}
+{{- '<|im_start|>assistant\n' }}
+{%- endif %}"#;
+
+const LLAMA3_TEMPLATE: &str = r#"{{- bos_token }}
+{%- for message in messages %}
+{{- '<|start_header_id|>' + message.role + '<|end_header_id|>\n\n' + message.content | trim + '<|eot_id|>' }}
+{%- endfor %}
+{%- if add_generation_prompt %}
+{{- '<|start_header_id|>assistant<|end_header_id|>\n\n' }}
+{%- endif %}"#;
+
+// Gemma has no system role: system text is folded into the next user turn
+const GEMMA_TEMPLATE: &str = r#"{{- bos_token }}
+{%- set ns = namespace(system='') %}
+{%- for message in messages %}
+{%- if message.role == 'system' %}
+{%- set ns.system = ns.system + message.content | trim + '\n\n' %}
+{%- else %}
+{%- set role = 'model' if message.role == 'assistant' else 'user' %}
+{{- '<start_of_turn>' + role + '\n' + (ns.system if role == 'user' else '') + message.content | trim + '<end_of_turn>\n' }}
+{%- if role == 'user' %}
+{%- set ns.system = '' %}
+{%- endif %}
+{%- endif %}
+{%- endfor %}
+{%- if add_generation_prompt %}
+{{- '<start_of_turn>model\n' }}
+{%- endif %}"#;
+
+/// End-of-turn markers that should stop generation when a template uses them
+const TURN_END_MARKERS: &[&str] = &["<|im_end|>", "<end_of_turn>", "<|eot_id|>", "<|end|>", "<|endoftext|>"];
+
+/// What a model ships with, kept so the template can be re-resolved when the override changes
+#[derive(Debug, Clone, Default)]
+pub struct ModelTemplateInfo {
+    /// Jinja source embedded in the model, if any
+    pub template: Option<String>,
+    /// `model_type`, GGUF architecture or model name, used to pick a built-in fallback
+    pub family_hint: String,
+    pub bos_token: Option<String>,
+    pub eos_token: Option<String>,
+}
+
+impl ModelTemplateInfo {
+    /// Read the template and special tokens of a HuggingFace-style model directory
+    pub async fn from_model_dir(model_dir: &Path, model_name: &str) -> Self {
+        let mut info = Self {
+            family_hint: model_name.to_string(),
+            ..Self::default()
+        };
+
+        if let Some(config) = read_json(&model_dir.join("config.json")).await {
+            if let Some(model_type) = config.get("model_type").and_then(|v| v.as_str()) {
+                info.family_hint = format!("{} {}", model_type, model_name);
+            }
+        }
+
+        if let Some(tokenizer_config) = read_json(&model_dir.join("tokenizer_config.json")).await {
+            info.template = match tokenizer_config.get("chat_template") {
+                Some(serde_json::Value::String(template)) => Some(template.clone()),
+                // Some repos ship several named templates; use the default one
+                Some(serde_json::Value::Array(templates)) => templates.iter()
+                    .find(|t| t.get("name").and_then(|n| n.as_str()) == Some("default"))
+                    .or_else(|| templates.first())
+                    .and_then(|t| t.get("template"))
+                    .and_then(|t| t.as_str())
+                    .map(str::to_string),
+                _ => None,
+            };
+            info.bos_token = special_token(tokenizer_config.get("bos_token"));
+            info.eos_token = special_token(tokenizer_config.get("eos_token"));
+        }
+
+        // Newer repos keep the template in its own file, which takes precedence
+        if let Ok(template) = fs::read_to_string(model_dir.join("chat_template.jinja")).await {
+            info.template = Some(template);
+        }
+
+        info
+    }
+}
+
+async fn read_json(path: &Path) -> Option<serde_json::Value> {
+    let content = fs::read_to_string(path).await.ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Special tokens are stored either as plain strings or as `{"content": "..."}` objects
+fn special_token(value: Option<&serde_json::Value>) -> Option<String> {
+    match value? {
+        serde_json::Value::String(token) => Some(token.clone()),
+        serde_json::Value::Object(token) => token.get("content").and_then(|c| c.as_str()).map(str::to_string),
+        _ => None,
+    }
+}
+
+/// A compiled chat template together with the special tokens it needs
+#[derive(Debug, Clone)]
+pub struct ChatTemplate {
+    name: String,
+    template: Template,
+    bos_token: Option<String>,
+    eos_token: Option<String>,
+    stop_sequences: Vec<String>,
+    info: ModelTemplateInfo,
+}
+
+impl ChatTemplate {
+    /// Pick the template for a model: override, then the model's own template, then a built-in
+    ///
+    /// `override_template` is either a built-in name (`chatml`, `llama3`, `gemma`, `lfm2`) or Jinja source.
+    /// Only an invalid override is an error; a broken embedded template falls back to a built-in.
+    pub fn resolve(info: ModelTemplateInfo, override_template: Option<&str>) -> Result<Self> {
+        if let Some(source) = override_template.filter(|s| !s.trim().is_empty()) {
+            if let Some(builtin) = BuiltinTemplate::from_name(source) {
+                return Ok(Self::builtin(builtin, info));
+            }
+            return Self::compile("override".to_string(), source, info)
+                .map_err(|e| anyhow!("Invalid chat template override: {}", e));
+        }
+
+        if let Some(source) = info.template.clone() {
+            match Self::compile("model".to_string(), &source, info.clone()) {
+                Ok(template) => return Ok(template),
+                Err(e) => warn!("⚠️ Model chat template could not be compiled, using a built-in one: {}", e),
+            }
+        }
+
+        Ok(Self::builtin(BuiltinTemplate::detect(&info.family_hint), info))
+    }
+
+    /// Resolve again with a different override, keeping the model's own template and tokens
+    pub fn with_override(&self, override_template: Option<&str>) -> Result<Self> {
+        Self::resolve(self.info.clone(), override_template)
+    }
+
+    fn builtin(builtin: BuiltinTemplate, info: ModelTemplateInfo) -> Self {
+        let bos_token = info.bos_token.clone().or_else(|| builtin.bos_token().map(str::to_string));
+        let template = Self::compile(builtin.name().to_string(), builtin.source(), info)
+            .expect("built-in chat templates compile");
+        Self { bos_token, ..template }
+    }
+
+    fn compile(name: String, source: &str, info: ModelTemplateInfo) -> Result<Self> {
+        let template = Template::compile(source)?;
+        let mut stop_sequences: Vec<String> = TURN_END_MARKERS.iter()
+            .filter(|marker| source.contains(*marker))
+            .map(|marker| marker.to_string())
+            .collect();
+        if let Some(eos) = info.eos_token.as_ref().filter(|eos| !stop_sequences.contains(eos)) {
+            stop_sequences.push(eos.clone());
+        }
+
+        debug!("🔧 Compiled {} chat template (stops: {:?})", name, stop_sequences);
+
+        Ok(Self {
+            name,
+            template,
+            bos_token: info.bos_token.clone(),
+            eos_token: info.eos_token.clone(),
+            stop_sequences,
+            info,
+        })
+    }
+
+    /// Where the template came from: `override`, `model`, or a built-in name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Render the conversation, optionally opening an assistant turn for the model to complete
+    pub fn render(&self, messages: &[ConversationMessage], add_generation_prompt: bool) -> Result<String> {
+        let messages = messages.iter()
+            .map(|message| {
+                let mut map = BTreeMap::new();
+                map.insert("role".to_string(), Value::from(message.role.as_str()));
+                map.insert("content".to_string(), Value::from(message.content.as_str()));
+                Value::Map(map)
+            })
+            .collect();
+
+        let mut variables = BTreeMap::new();
+        variables.insert("messages".to_string(), Value::List(messages));
+        variables.insert("add_generation_prompt".to_string(), Value::from(add_generation_prompt));
+        variables.insert("bos_token".to_string(), Value::from(self.bos_token.as_deref().unwrap_or_default()));
+        variables.insert("eos_token".to_string(), Value::from(self.eos_token.as_deref().unwrap_or_default()));
+
+        self.template.render(variables)
+            .map_err(|e| anyhow!("Failed to render {} chat template: {}", self.name, e))
+    }
+
+    /// Render only the leading system messages, which stay the same from turn to turn
+    ///
+    /// None if there are none or the template folds them into a later turn.
+    pub fn render_preamble(&self, messages: &[ConversationMessage]) -> Result<Option<String>> {
+        let system_messages = messages.iter().take_while(|message| message.role == "system").count();
+        if system_messages == 0 {
+            return Ok(None);
+        }
+        let preamble = self.render(&messages[..system_messages], false)?;
+        let bos_only = self.bos_token.as_deref().is_some_and(|bos| preamble.trim() == bos);
+        Ok(Some(preamble).filter(|preamble| !preamble.trim().is_empty() && !bos_only))
+    }
+
+    /// Whether the rendered prompt already begins with the BOS token, so tokenizers must not add another
+    pub fn starts_with_bos(&self, prompt: &str) -> bool {
+        self.bos_token.as_deref().is_some_and(|bos| !bos.is_empty() && prompt.starts_with(bos))
+    }
+
+    /// Generation parameters with this template's end-of-turn markers added as stop sequences
+    pub fn apply_stop_sequences(&self, params: &GenerationParams) -> GenerationParams {
+        let mut params = params.clone();
+        for stop in &self.stop_sequences {
+            if !params.stop_sequences.contains(stop) {
+                params.stop_sequences.push(stop.clone());
+            }
+        }
+        params
+    }
+}
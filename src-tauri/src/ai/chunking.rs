@@ -0,0 +1,532 @@
+/*!
+ * Code Chunking
+ *
+ * Splits source files into the chunks the semantic code index embeds. Cuts
+ * follow the structure of the code: a file is split between top-level items
+ * (a function together with its doc comment and attributes, a class, an
+ * `impl` block), small neighbouring items are packed into one chunk and items
+ * that are too long are split again one nesting level down, e.g. into the
+ * methods of a class. Nesting comes from brackets for C-like languages, from
+ * indentation for Python and YAML and from headings for Markdown and TOML.
+ */
+
+use std::ops::Range;
+use std::path::Path;
+use std::sync::OnceLock;
+use regex::Regex;
+
+/// Neighbouring items are packed into one chunk up to this many lines
+const TARGET_CHUNK_LINES: usize = 40;
+/// Longer items are split one nesting level down
+const MAX_CHUNK_LINES: usize = 80;
+/// Chunks stay well below the embedding model's input limit
+const MAX_CHUNK_CHARS: usize = 4000;
+/// Files with longer lines are minified or generated and not worth indexing
+const MAX_LINE_CHARS: usize = 1000;
+
+/// How a language shows its structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Blocks delimited by brackets: Rust, JavaScript, Go, C, ...
+    Brackets,
+    /// Blocks delimited by indentation: Python, YAML
+    Indentation,
+    /// Sections started by headings: Markdown, TOML
+    Headings,
+    /// Paragraphs separated by blank lines
+    Plain,
+}
+
+/// A piece of a source file
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// First line, 1-based
+    pub start_line: usize,
+    /// Last line, inclusive
+    pub end_line: usize,
+    /// Keyword of the first declaration (`fn`, `class`, ...), `section` or `code`
+    pub kind: String,
+    /// Name of the first declaration or heading
+    pub symbol: Option<String>,
+    pub content: String,
+}
+
+/// Language name and syntax of files the index reads, by extension
+pub fn language_for(path: &Path) -> Option<(&'static str, Syntax)> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    let language = match extension.as_str() {
+        "rs" => ("rust", Syntax::Brackets),
+        "js" | "jsx" | "mjs" | "cjs" => ("javascript", Syntax::Brackets),
+        "ts" | "tsx" | "mts" | "cts" => ("typescript", Syntax::Brackets),
+        "go" => ("go", Syntax::Brackets),
+        "java" => ("java", Syntax::Brackets),
+        "kt" | "kts" => ("kotlin", Syntax::Brackets),
+        "scala" => ("scala", Syntax::Brackets),
+        "swift" => ("swift", Syntax::Brackets),
+        "c" | "h" => ("c", Syntax::Brackets),
+        "cpp" | "cc" | "cxx" | "hpp" | "hh" | "hxx" => ("cpp", Syntax::Brackets),
+        "cs" => ("csharp", Syntax::Brackets),
+        "php" => ("php", Syntax::Brackets),
+        "dart" => ("dart", Syntax::Brackets),
+        "sh" | "bash" | "zsh" => ("shell", Syntax::Brackets),
+        "css" | "scss" | "less" => ("css", Syntax::Brackets),
+        "py" | "pyi" => ("python", Syntax::Indentation),
+        "rb" => ("ruby", Syntax::Indentation),
+        "yaml" | "yml" => ("yaml", Syntax::Indentation),
+        "md" | "markdown" | "mdx" => ("markdown", Syntax::Headings),
+        "toml" => ("toml", Syntax::Headings),
+        "html" | "htm" | "vue" | "svelte" => ("html", Syntax::Plain),
+        "sql" => ("sql", Syntax::Plain),
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// Split `content` into chunks; empty for blank and generated files
+pub fn chunk_file(content: &str, language: &str, syntax: Syntax) -> Vec<Chunk> {
+    let lines: Vec<&str> = content.lines().collect();
+    if lines.iter().any(|line| line.len() > MAX_LINE_CHARS) {
+        return Vec::new();
+    }
+    let analyzed = analyze(&lines, language, syntax);
+    let splitter = Splitter { lines: &lines, analyzed: &analyzed, syntax };
+
+    splitter.chunk(0..lines.len(), 0)
+        .into_iter()
+        .filter_map(|range| splitter.to_chunk(range))
+        .collect()
+}
+
+/// What chunking needs to know about a line
+struct LineInfo {
+    blank: bool,
+    /// Nesting depth (or indentation) at the start of the line
+    start_level: usize,
+    /// Nesting depth after the line
+    end_level: usize,
+    /// A Markdown heading or TOML table header
+    heading: bool,
+}
+
+struct Splitter<'a> {
+    lines: &'a [&'a str],
+    analyzed: &'a [LineInfo],
+    syntax: Syntax,
+}
+
+impl Splitter<'_> {
+    /// Chunks covering `range`, cutting between items at nesting level `base`
+    fn chunk(&self, range: Range<usize>, base: usize) -> Vec<Range<usize>> {
+        let mut pieces = Vec::new();
+        for unit in self.units(range, base) {
+            if !self.too_big(&unit) {
+                pieces.push(unit);
+                continue;
+            }
+            match self.inner_level(&unit, base) {
+                Some(inner) if self.units(unit.clone(), inner).len() > 1 => {
+                    pieces.extend(self.chunk(unit, inner));
+                }
+                _ => pieces.extend(self.windows(unit)),
+            }
+        }
+        self.pack(pieces)
+    }
+
+    /// Split `range` where a new item starts at level `base`
+    fn units(&self, range: Range<usize>, base: usize) -> Vec<Range<usize>> {
+        let mut units = Vec::new();
+        let mut start = range.start;
+        let mut previous: Option<usize> = None;
+        for index in range.clone() {
+            let line = &self.analyzed[index];
+            if line.blank {
+                continue;
+            }
+            if let Some(previous) = previous {
+                if index > start && self.starts_item(index, previous, base) {
+                    units.push(start..index);
+                    start = index;
+                }
+            }
+            previous = Some(index);
+        }
+        if start < range.end {
+            units.push(start..range.end);
+        }
+        units
+    }
+
+    /// Whether line `index` begins a new item, given the previous non-blank line
+    fn starts_item(&self, index: usize, previous: usize, base: usize) -> bool {
+        let line = &self.analyzed[index];
+        let text = self.lines[index].trim_start();
+        match self.syntax {
+            Syntax::Brackets => {
+                line.start_level <= base
+                    && self.analyzed[previous].end_level <= base
+                    && !is_continuation(text)
+                    && !is_leading(self.lines[previous].trim_start())
+            }
+            Syntax::Indentation => {
+                line.start_level <= base
+                    && !is_continuation(text)
+                    && !is_leading(self.lines[previous].trim_start())
+            }
+            Syntax::Headings => line.heading,
+            Syntax::Plain => self.analyzed[index - 1].blank,
+        }
+    }
+
+    /// The next nesting level inside an item, if it has one
+    fn inner_level(&self, range: &Range<usize>, base: usize) -> Option<usize> {
+        if matches!(self.syntax, Syntax::Headings | Syntax::Plain) {
+            return None;
+        }
+        self.analyzed[range.start + 1..range.end].iter()
+            .filter(|line| !line.blank && line.start_level > base)
+            .map(|line| line.start_level)
+            .min()
+    }
+
+    fn too_big(&self, range: &Range<usize>) -> bool {
+        range.len() > MAX_CHUNK_LINES || self.chars(range) > MAX_CHUNK_CHARS
+    }
+
+    fn chars(&self, range: &Range<usize>) -> usize {
+        self.lines[range.clone()].iter().map(|line| line.len() + 1).sum()
+    }
+
+    /// Cut a range without usable structure into pieces, preferring blank lines
+    fn windows(&self, range: Range<usize>) -> Vec<Range<usize>> {
+        let mut windows = Vec::new();
+        let mut start = range.start;
+        while start < range.end {
+            let mut end = start;
+            let mut chars = 0;
+            while end < range.end && end - start < MAX_CHUNK_LINES && chars + self.lines[end].len() < MAX_CHUNK_CHARS {
+                chars += self.lines[end].len() + 1;
+                end += 1;
+            }
+            let end = if end < range.end {
+                let halfway = start + (end - start) / 2;
+                (halfway..end).rev()
+                    .find(|&index| self.analyzed[index].blank)
+                    .map_or(end, |blank| blank + 1)
+                    .max(start + 1)
+            } else {
+                end
+            };
+            windows.push(start..end);
+            start = end;
+        }
+        windows
+    }
+
+    /// Merge neighbouring small pieces
+    fn pack(&self, pieces: Vec<Range<usize>>) -> Vec<Range<usize>> {
+        let mut packed: Vec<Range<usize>> = Vec::new();
+        for piece in pieces {
+            if let Some(last) = packed.last_mut() {
+                let merged = last.start..piece.end;
+                if merged.len() <= TARGET_CHUNK_LINES && self.chars(&merged) <= MAX_CHUNK_CHARS {
+                    *last = merged;
+                    continue;
+                }
+            }
+            packed.push(piece);
+        }
+        packed
+    }
+
+    /// The chunk for `range` without surrounding blank lines; None if it is all blank
+    fn to_chunk(&self, range: Range<usize>) -> Option<Chunk> {
+        let start = range.clone().find(|&index| !self.analyzed[index].blank)?;
+        let end = range.rev().find(|&index| !self.analyzed[index].blank)? + 1;
+        let content = self.lines[start..end].join("\n");
+
+        let (kind, symbol) = match self.syntax {
+            Syntax::Headings => {
+                let symbol = self.lines[start..end].iter()
+                    .zip(&self.analyzed[start..end])
+                    .find(|(_, line)| line.heading)
+                    .map(|(text, _)| text.trim_matches(|c: char| c == '#' || c == '[' || c == ']' || c.is_whitespace()).to_string());
+                ("section".to_string(), symbol)
+            }
+            _ => match declaration_pattern().captures(&content) {
+                Some(captures) => (captures[1].to_string(), Some(captures[2].to_string())),
+                None => ("code".to_string(), None),
+            },
+        };
+
+        Some(Chunk {
+            start_line: start + 1,
+            end_line: end,
+            kind,
+            symbol,
+            content,
+        })
+    }
+}
+
+/// Declarations whose keyword and name describe a chunk
+fn declaration_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r"(?m)^[ \t]*(?:(?:pub(?:\([^)]*\))?|export|default|async|static|public|private|protected|internal|abstract|final|override|unsafe|const|extern)\s+)*(fn|function|def|class|struct|enum|trait|impl|interface|type|mod|func)\b(?:<[^>]*>)?\s+([A-Za-z_]\w*)").unwrap()
+    })
+}
+
+/// Lines that continue the item above instead of starting a new one
+fn is_continuation(text: &str) -> bool {
+    text.starts_with(['}', ')', ']', '.'])
+        || ["else", "elif", "except", "finally", "catch", "where", "end"].iter().any(|keyword| {
+            text.strip_prefix(keyword).is_some_and(|rest| !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+        })
+}
+
+/// Comments, attributes and decorators belong to the item below them
+fn is_leading(text: &str) -> bool {
+    text.starts_with("//") || text.starts_with("/*") || text.starts_with('*')
+        || text.starts_with('#') || text.starts_with('@')
+}
+
+fn analyze(lines: &[&str], language: &str, syntax: Syntax) -> Vec<LineInfo> {
+    match syntax {
+        Syntax::Brackets => bracket_levels(lines, language != "rust"),
+        Syntax::Indentation => lines.iter()
+            .map(|line| {
+                let indent = indentation(line);
+                LineInfo { blank: line.trim().is_empty(), start_level: indent, end_level: indent, heading: false }
+            })
+            .collect(),
+        Syntax::Headings => {
+            let mut in_fence = false;
+            lines.iter()
+                .map(|line| {
+                    let text = line.trim_start();
+                    if text.starts_with("```") || text.starts_with("~~~") {
+                        in_fence = !in_fence;
+                    }
+                    let heading = !in_fence && match language {
+                        "toml" => text.starts_with('['),
+                        _ => text.starts_with('#') && text.trim_start_matches('#').starts_with(' '),
+                    };
+                    LineInfo { blank: text.is_empty(), start_level: 0, end_level: 0, heading }
+                })
+                .collect()
+        }
+        Syntax::Plain => lines.iter()
+            .map(|line| LineInfo { blank: line.trim().is_empty(), start_level: 0, end_level: 0, heading: false })
+            .collect(),
+    }
+}
+
+/// Leading whitespace in columns, counting a tab as four
+fn indentation(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum()
+}
+
+/// Bracket depth before and after every line, skipping strings and comments
+///
+/// Strings are assumed to end on the line they start; Rust's `'` is a char
+/// literal only when it closes right away, otherwise it starts a lifetime.
+fn bracket_levels(lines: &[&str], single_quote_strings: bool) -> Vec<LineInfo> {
+    let mut depth: usize = 0;
+    let mut in_block_comment = false;
+    let mut levels = Vec::with_capacity(lines.len());
+    for line in lines {
+        let start_level = depth;
+        let chars: Vec<char> = line.chars().collect();
+        let mut in_string: Option<char> = None;
+        let mut index = 0;
+        while index < chars.len() {
+            let c = chars[index];
+            let next = chars.get(index + 1).copied();
+            index += 1;
+            if in_block_comment {
+                if c == '*' && next == Some('/') {
+                    in_block_comment = false;
+                    index += 1;
+                }
+                continue;
+            }
+            if let Some(quote) = in_string {
+                if c == '\\' {
+                    index += 1;
+                } else if c == quote {
+                    in_string = None;
+                }
+                continue;
+            }
+            match c {
+                // `://` in URLs is not a comment
+                '/' if next == Some('/') && (index < 2 || chars[index - 2] != ':') => break,
+                '/' if next == Some('*') => {
+                    in_block_comment = true;
+                    index += 1;
+                }
+                '"' | '`' => in_string = Some(c),
+                '\'' if single_quote_strings => in_string = Some(c),
+                '\'' => {
+                    if next == Some('\\') {
+                        if let Some(close) = chars[index..].iter().take(10).skip(2).position(|&c| c == '\'') {
+                            index += close + 3;
+                        }
+                    } else if chars.get(index + 1) == Some(&'\'') {
+                        index += 2;
+                    }
+                }
+                '{' | '(' | '[' => depth += 1,
+                '}' | ')' | ']' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        levels.push(LineInfo {
+            blank: line.trim().is_empty(),
+            start_level,
+            end_level: depth,
+            heading: false,
+        });
+    }
+    levels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Rust function with a doc comment, an attribute and `body` lines
+    fn rust_function(name: &str, body: usize) -> String {
+        let mut text = format!("/// Computes {}\n#[inline]\npub fn {}() -> u32 {{\n", name, name);
+        for line in 0..body {
+            text.push_str(&format!("    let value_{} = {};\n", line, line));
+        }
+        text.push_str("    0\n}\n\n");
+        text
+    }
+
+    fn lines_of(chunks: &[Chunk]) -> Vec<(usize, usize)> {
+        chunks.iter().map(|chunk| (chunk.start_line, chunk.end_line)).collect()
+    }
+
+    fn symbols(chunks: &[Chunk]) -> Vec<Option<&str>> {
+        chunks.iter().map(|chunk| chunk.symbol.as_deref()).collect()
+    }
+
+    #[test]
+    fn detects_languages_by_extension() {
+        assert_eq!(language_for(Path::new("src/main.rs")), Some(("rust", Syntax::Brackets)));
+        assert_eq!(language_for(Path::new("app/view.tsx")), Some(("typescript", Syntax::Brackets)));
+        assert_eq!(language_for(Path::new("tools/build.py")), Some(("python", Syntax::Indentation)));
+        assert_eq!(language_for(Path::new("README.md")), Some(("markdown", Syntax::Headings)));
+        assert_eq!(language_for(Path::new("image.png")), None);
+        assert_eq!(language_for(Path::new("Makefile")), None);
+    }
+
+    #[test]
+    fn splits_between_items_keeping_leading_comments() {
+        let content = rust_function("first", 30) + &rust_function("second", 30);
+        let chunks = chunk_file(&content, "rust", Syntax::Brackets);
+
+        assert_eq!(lines_of(&chunks), [(1, 35), (37, 71)]);
+        assert!(chunks[1].content.starts_with("/// Computes second\n#[inline]\npub fn second()"));
+        assert_eq!(chunks[1].kind, "fn");
+        assert_eq!(symbols(&chunks), [Some("first"), Some("second")]);
+    }
+
+    #[test]
+    fn packs_small_items_together() {
+        let content = "use std::fmt;\n\nstruct Point {\n    x: i32,\n}\n\nfn origin() -> Point {\n    Point { x: 0 }\n}\n";
+        let chunks = chunk_file(content, "rust", Syntax::Brackets);
+
+        assert_eq!(lines_of(&chunks), [(1, 9)]);
+        assert_eq!((chunks[0].kind.as_str(), chunks[0].symbol.as_deref()), ("struct", Some("Point")));
+    }
+
+    #[test]
+    fn splits_long_items_one_level_down() {
+        let methods: String = ["start", "handle", "stop"].iter()
+            .map(|name| rust_function(name, 30).lines().map(|line| format!("    {}\n", line)).collect::<String>())
+            .collect();
+        let content = format!("impl Server {{\n{}}}\n", methods);
+        let chunks = chunk_file(&content, "rust", Syntax::Brackets);
+
+        assert_eq!(symbols(&chunks), [Some("Server"), Some("handle"), Some("stop")]);
+        assert_eq!(chunks[0].kind, "impl");
+        assert!(chunks[1].content.trim_start().starts_with("/// Computes handle"));
+        assert!(chunks[2].content.ends_with("\n}"));
+        assert_eq!(chunks[2].end_line, content.lines().count());
+    }
+
+    #[test]
+    fn follows_indentation_for_python() {
+        let function = |name: &str| {
+            let body: String = (0..30).map(|line| format!("    value_{} = {}\n", line, line)).collect();
+            format!("@cached\ndef {}(self):\n{}    return 0\n\n", name, body)
+        };
+        let content = format!("{}{}", function("load"), function("save"));
+        let chunks = chunk_file(&content, "python", Syntax::Indentation);
+
+        assert_eq!(lines_of(&chunks), [(1, 33), (35, 67)]);
+        assert!(chunks[1].content.starts_with("@cached\ndef save"));
+        assert_eq!(symbols(&chunks), [Some("load"), Some("save")]);
+    }
+
+    #[test]
+    fn cuts_markdown_at_headings_outside_code_fences() {
+        let paragraph: String = (0..30).map(|line| format!("Line {} of prose.\n", line)).collect();
+        let content = format!("# Intro\n{}```sh\n# not a heading\n```\n## Usage\n{}", paragraph, paragraph);
+        let chunks = chunk_file(&content, "markdown", Syntax::Headings);
+
+        assert_eq!(lines_of(&chunks), [(1, 34), (35, 65)]);
+        assert!(chunks[0].content.contains("# not a heading"));
+        assert!(chunks.iter().all(|chunk| chunk.kind == "section"));
+        assert_eq!(symbols(&chunks), [Some("Intro"), Some("Usage")]);
+    }
+
+    #[test]
+    fn windows_text_without_structure() {
+        let content: String = (0..200).map(|line| format!("SELECT {} FROM dual;\n", line)).collect();
+        let chunks = chunk_file(&content, "sql", Syntax::Plain);
+
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|chunk| chunk.end_line - chunk.start_line < MAX_CHUNK_LINES && chunk.kind == "code"));
+        assert_eq!(chunks[0].start_line, 1);
+        assert_eq!(chunks.last().unwrap().end_line, 200);
+        assert!(chunks.windows(2).all(|pair| pair[1].start_line == pair[0].end_line + 1));
+    }
+
+    #[test]
+    fn skips_minified_and_blank_files() {
+        let minified = format!("function a(){{{}}}\n", "x++;".repeat(300));
+        assert!(chunk_file(&minified, "javascript", Syntax::Brackets).is_empty());
+        assert!(chunk_file("\n\n   \n", "rust", Syntax::Brackets).is_empty());
+    }
+
+    #[test]
+    fn ignores_brackets_in_strings_and_comments() {
+        let end_levels = |lines: &[&str], single_quote_strings: bool| -> Vec<usize> {
+            bracket_levels(lines, single_quote_strings).iter().map(|line| line.end_level).collect()
+        };
+
+        let rust = [
+            "fn parse<'a>(input: &'a str) -> Token<'a> {",
+            "    let open = \"{ \\\" (\";",
+            "    let brace = '{';",
+            "    let escaped = '\\u{7b}';",
+            "    // closing } in a comment",
+            "    /* ( spans",
+            "       lines ] */",
+            "}",
+        ];
+        assert_eq!(end_levels(&rust, false), [1, 1, 1, 1, 1, 1, 1, 0]);
+
+        let shell = ["fetch() {", "    echo (see https://example.com/api)", "}"];
+        assert_eq!(end_levels(&shell, true), [1, 1, 0]);
+        assert_eq!(end_levels(&["const s = '{';", "const t = `(`;"], true), [0, 0]);
+    }
+}
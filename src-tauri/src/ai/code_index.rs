@@ -0,0 +1,738 @@
+/*!
+ * Semantic Code Index
+ *
+ * Per-project index of source chunks and their embeddings, stored in SQLite
+ * next to the `files` table. Indexing walks the project, skips files whose
+ * size and modification time are unchanged, hashes the rest and re-chunks
+ * only those whose `content_hash` changed or whose chunks were embedded with
+ * another model. It runs as a background job per project that reports
+ * progress and stops between files when cancelled. Without an embedding model
+ * the chunks are still stored, just without vectors.
+ *
+ * Queries embed the question and rank the project's chunks by cosine
+ * similarity. Chunks are read from the database once per project and kept in
+ * memory, together with a BM25 index over them, until the project is indexed
+ * again.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use anyhow::{Result, anyhow, bail};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use crate::database::{CodeChunkRecord, Database, FileRecord, IndexedFileRecord, ProjectRecord};
+use super::chunking::{self, Syntax};
+use super::embeddings::{self, EmbeddingModel};
+use super::generation::CancelToken;
+use super::model_manager::ModelManager;
+use super::retrieval::Bm25Index;
+
+/// Stored vectors are truncated to this Matryoshka dimension to keep the index small
+pub const INDEX_DIMENSION: usize = 256;
+
+/// Results of a query when the caller doesn't ask for a number
+pub const DEFAULT_TOP_K: usize = 10;
+
+/// Larger files are skipped
+const MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Directories that hold dependencies, build output or tool state
+const SKIPPED_DIRS: &[&str] = &["node_modules", "target", ".git", "dist", "build", "__pycache__", ".venv"];
+
+/// Progress events are throttled to this interval
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Embedding task types for stored chunks and for queries
+const DOCUMENT_TASK: &str = "document";
+const QUERY_TASK: &str = "code_retrieval";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexState {
+    Scanning,
+    Indexing,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+impl IndexState {
+    pub fn is_running(self) -> bool {
+        matches!(self, Self::Scanning | Self::Indexing)
+    }
+}
+
+/// Progress of an indexing job, reported while it runs and once it has ended
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexProgress {
+    pub project_path: String,
+    pub state: IndexState,
+    /// Model the chunks are embedded with; None stores them without vectors
+    pub embedding_model: Option<String>,
+    pub files_total: usize,
+    pub files_done: usize,
+    /// Files that were chunked again because their content or the embedding model changed
+    pub files_updated: usize,
+    pub files_removed: usize,
+    pub chunks_indexed: usize,
+    pub error: Option<String>,
+}
+
+impl IndexProgress {
+    fn new(project_path: &str) -> Self {
+        Self {
+            project_path: project_path.to_string(),
+            state: IndexState::Scanning,
+            embedding_model: None,
+            files_total: 0,
+            files_done: 0,
+            files_updated: 0,
+            files_removed: 0,
+            chunks_indexed: 0,
+            error: None,
+        }
+    }
+}
+
+/// A chunk returned by a query
+#[derive(Debug, Clone, Serialize)]
+pub struct CodeSearchResult {
+    /// Relative to the project root, with `/` separators
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub kind: String,
+    pub symbol: Option<String>,
+    pub content: String,
+    pub score: f32,
+}
+
+/// A stored chunk as kept in memory for queries
+#[derive(Debug, Clone)]
+pub struct IndexedChunk {
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub kind: String,
+    pub symbol: Option<String>,
+    pub content: String,
+    pub embedding: Option<Vec<f32>>,
+    pub embedding_model: Option<String>,
+}
+
+impl IndexedChunk {
+    pub fn to_result(&self, score: f32) -> CodeSearchResult {
+        CodeSearchResult {
+            path: self.path.clone(),
+            start_line: self.start_line,
+            end_line: self.end_line,
+            kind: self.kind.clone(),
+            symbol: self.symbol.clone(),
+            content: self.content.clone(),
+            score,
+        }
+    }
+}
+
+/// A project's stored chunks, loaded for queries
+#[derive(Debug)]
+pub struct ProjectIndex {
+    pub chunks: Vec<IndexedChunk>,
+    /// BM25 over each chunk's path and content, in the order of `chunks`
+    pub lexical: Bm25Index,
+}
+
+impl ProjectIndex {
+    /// Chunks embedded with `model_id`, most similar to `query_embedding` first
+    pub fn semantic_ranking(&self, model_id: &str, query_embedding: &[f32]) -> Vec<(usize, f32)> {
+        let mut ranked: Vec<(usize, f32)> = self.chunks.iter()
+            .enumerate()
+            .filter(|(_, chunk)| chunk.embedding_model.as_deref() == Some(model_id))
+            .filter_map(|(index, chunk)| Some((index, embeddings::cosine_similarity(query_embedding, chunk.embedding.as_ref()?))))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+    }
+
+    /// Chunks sharing terms with `query`, best BM25 score first
+    pub fn lexical_ranking(&self, query: &str) -> Vec<(usize, f32)> {
+        self.lexical.search(query)
+    }
+}
+
+/// A file the index reads
+struct SourceFile {
+    absolute: PathBuf,
+    /// Relative to the project root, with `/` separators
+    path: String,
+    language: &'static str,
+    syntax: Syntax,
+    size: i64,
+    last_modified: i64,
+}
+
+struct IndexJob {
+    cancel: CancelToken,
+    progress: IndexProgress,
+}
+
+/// Builds and queries the per-project indexes; clones share jobs and loaded chunks
+#[derive(Clone)]
+pub struct CodeIndex {
+    database: Arc<RwLock<Database>>,
+    models: Arc<RwLock<ModelManager>>,
+    /// Running and finished jobs by project path
+    jobs: Arc<Mutex<HashMap<String, IndexJob>>>,
+    /// Chunks read for queries by project path
+    loaded: Arc<Mutex<HashMap<String, Arc<ProjectIndex>>>>,
+}
+
+impl std::fmt::Debug for CodeIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CodeIndex")
+            .field("jobs", &lock(&self.jobs).len())
+            .field("loaded", &lock(&self.loaded).len())
+            .finish()
+    }
+}
+
+impl CodeIndex {
+    pub fn new(database: Arc<RwLock<Database>>, models: Arc<RwLock<ModelManager>>) -> Self {
+        Self {
+            database,
+            models,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            loaded: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Index `project_root` in the background, calling `on_progress` as it goes and once when it ends
+    pub fn start<F>(&self, project_root: PathBuf, on_progress: F) -> Result<()>
+    where
+        F: FnMut(&IndexProgress) + Send + 'static,
+    {
+        let key = project_key(&project_root);
+        let cancel = CancelToken::default();
+        {
+            let mut jobs = lock(&self.jobs);
+            if jobs.get(&key).is_some_and(|job| job.progress.state.is_running()) {
+                bail!("{} is already being indexed", key);
+            }
+            jobs.insert(key.clone(), IndexJob { cancel: cancel.clone(), progress: IndexProgress::new(&key) });
+        }
+
+        let index = self.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let mut reporter = ProgressReporter { jobs: index.jobs.clone(), on_progress, last_report: None };
+            let mut progress = IndexProgress::new(&key);
+            info!("🔍 Indexing {}", key);
+
+            if let Err(e) = index.run(&project_root, &cancel, &mut progress, &mut reporter).await {
+                warn!("⚠️ Indexing {} failed: {}", key, e);
+                progress.state = IndexState::Failed;
+                progress.error = Some(e.to_string());
+            }
+            match progress.state {
+                IndexState::Completed => info!("✅ Indexed {} in {:.1}s: {} files, {} updated, {} removed, {} chunks",
+                    key, started.elapsed().as_secs_f64(), progress.files_total, progress.files_updated,
+                    progress.files_removed, progress.chunks_indexed),
+                IndexState::Cancelled => info!("🔄 Indexing {} cancelled after {} of {} files",
+                    key, progress.files_done, progress.files_total),
+                _ => {}
+            }
+            lock(&index.loaded).remove(&key);
+            reporter.report(&progress, true);
+        });
+        Ok(())
+    }
+
+    /// Ask the job indexing `project_root` to stop after the current file. Returns false if none is running.
+    pub fn cancel(&self, project_root: &Path) -> bool {
+        match lock(&self.jobs).get(&project_key(project_root)) {
+            Some(job) if job.progress.state.is_running() => {
+                job.cancel.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Progress of the running or last job for `project_root`
+    pub fn progress(&self, project_root: &Path) -> Option<IndexProgress> {
+        lock(&self.jobs).get(&project_key(project_root)).map(|job| job.progress.clone())
+    }
+
+    /// The embedding model, if one is loaded; queries with it don't wait for a load
+    pub async fn resident_embedding_model(&self) -> Option<Arc<EmbeddingModel>> {
+        self.models.read().await.embedding_model()
+    }
+
+    /// The stored chunks of `project_root`; None if it has not been indexed
+    pub async fn load(&self, project_root: &Path) -> Result<Option<Arc<ProjectIndex>>> {
+        let key = project_key(project_root);
+        if let Some(index) = lock(&self.loaded).get(&key) {
+            return Ok(Some(index.clone()));
+        }
+
+        let database = self.database.read().await;
+        let Some(project_id) = database.find_project_id(&key).await? else {
+            return Ok(None);
+        };
+        let files = database.get_indexed_files(&project_id, None).await?;
+        if files.is_empty() {
+            return Ok(None);
+        }
+        let paths: HashMap<String, String> = files.into_iter().map(|file| (file.id, file.path)).collect();
+        let chunks: Vec<IndexedChunk> = database.get_code_chunks(&project_id).await?
+            .into_iter()
+            .filter_map(|record| Some(IndexedChunk {
+                path: paths.get(&record.file_id)?.clone(),
+                start_line: record.start_line as usize,
+                end_line: record.end_line as usize,
+                kind: record.kind,
+                symbol: record.symbol,
+                content: record.content,
+                embedding: record.embedding.as_deref().map(decode_vector),
+                embedding_model: record.embedding_model,
+            }))
+            .collect();
+        drop(database);
+
+        let index = tokio::task::spawn_blocking(move || {
+            let lexical = Bm25Index::new(chunks.iter().map(|chunk| format!("{}\n{}", chunk.path, chunk.content)));
+            ProjectIndex { chunks, lexical }
+        }).await
+            .map_err(|e| anyhow!("Building the keyword index failed: {}", e))?;
+
+        // Chunks read while a job is writing may be outdated by the time it ends
+        let index = Arc::new(index);
+        let indexing = lock(&self.jobs).get(&key).is_some_and(|job| job.progress.state.is_running());
+        if !indexing {
+            lock(&self.loaded).insert(key, index.clone());
+        }
+        Ok(Some(index))
+    }
+
+    /// The `top_k` chunks of `project_root` closest to `query`, loading the embedding model if needed
+    pub async fn search(&self, project_root: &Path, query: &str, top_k: usize) -> Result<Vec<CodeSearchResult>> {
+        let model = embeddings::resident_model(&self.models, None).await?;
+        self.search_with(&model, project_root, query, top_k).await
+    }
+
+    /// Like `search`, with a model the caller already has
+    pub async fn search_with(&self, model: &Arc<EmbeddingModel>, project_root: &Path, query: &str, top_k: usize) -> Result<Vec<CodeSearchResult>> {
+        let index = self.load(project_root).await?
+            .ok_or_else(|| anyhow!("{} has not been indexed yet", project_root.display()))?;
+        let query_embedding = embed_query(model, query).await?;
+
+        let ranked = index.semantic_ranking(model.model_id(), &query_embedding);
+        if ranked.is_empty() && !index.chunks.is_empty() {
+            bail!("{} has no chunks embedded with {}; index it again", project_root.display(), model.model_id());
+        }
+        Ok(ranked.into_iter()
+            .take(top_k)
+            .map(|(chunk, score)| index.chunks[chunk].to_result(score))
+            .collect())
+    }
+
+    async fn run<F: FnMut(&IndexProgress)>(
+        &self,
+        project_root: &Path,
+        cancel: &CancelToken,
+        progress: &mut IndexProgress,
+        reporter: &mut ProgressReporter<F>,
+    ) -> Result<()> {
+        if !project_root.is_dir() {
+            bail!("{} is not a directory", project_root.display());
+        }
+        reporter.report(progress, true);
+        let project_id = self.database.read().await
+            .get_or_insert_project(&project_record(project_root)).await?;
+
+        let model = match embeddings::resident_model(&self.models, None).await {
+            Ok(model) => Some(model),
+            Err(e) => {
+                warn!("⚠️ Indexing {} without embeddings: {}", project_root.display(), e);
+                None
+            }
+        };
+        progress.embedding_model = model.as_ref().map(|model| model.model_id().to_string());
+
+        let root = project_root.to_path_buf();
+        let files = tokio::task::spawn_blocking(move || source_files(&root)).await
+            .map_err(|e| anyhow!("Scanning the project failed: {}", e))?;
+        let indexed: HashMap<String, IndexedFileRecord> = self.database.read().await
+            .get_indexed_files(&project_id, progress.embedding_model.as_deref()).await?
+            .into_iter()
+            .map(|file| (file.path.clone(), file))
+            .collect();
+
+        progress.state = IndexState::Indexing;
+        progress.files_total = files.len();
+        reporter.report(progress, true);
+
+        for file in &files {
+            if cancel.is_cancelled() {
+                progress.state = IndexState::Cancelled;
+                return Ok(());
+            }
+            self.index_file(&project_id, file, indexed.get(&file.path), model.as_ref(), progress).await?;
+            progress.files_done += 1;
+            reporter.report(progress, false);
+        }
+
+        let present: HashSet<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        let removed: Vec<String> = indexed.values()
+            .filter(|file| !present.contains(file.path.as_str()))
+            .map(|file| file.id.clone())
+            .collect();
+        self.database.read().await.delete_files(&removed).await?;
+        progress.files_removed = removed.len();
+        progress.state = IndexState::Completed;
+        Ok(())
+    }
+
+    /// Chunk and embed `file` again unless its stored chunks are still current
+    async fn index_file(
+        &self,
+        project_id: &str,
+        file: &SourceFile,
+        indexed: Option<&IndexedFileRecord>,
+        model: Option<&Arc<EmbeddingModel>>,
+        progress: &mut IndexProgress,
+    ) -> Result<()> {
+        let current = |record: &IndexedFileRecord| chunks_current(record, model.is_some());
+        if let Some(record) = indexed {
+            if record.size == file.size && record.last_modified == file.last_modified && current(record) {
+                return Ok(());
+            }
+        }
+
+        let bytes = match tokio::fs::read(&file.absolute).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("⚠️ Skipping {}: {}", file.absolute.display(), e);
+                return Ok(());
+            }
+        };
+        let content_hash = hex::encode(Sha256::digest(&bytes));
+        if let Some(record) = indexed {
+            if record.content_hash == content_hash && current(record) {
+                return self.database.read().await
+                    .update_file_stat(&record.id, file.size, file.last_modified).await;
+            }
+        }
+
+        // Files that aren't UTF-8 are recorded without chunks
+        let text = String::from_utf8(bytes).unwrap_or_default();
+        let chunks = chunking::chunk_file(&text, file.language, file.syntax);
+        let vectors = match model {
+            Some(model) if !chunks.is_empty() => {
+                let texts = chunks.iter().map(|chunk| format!("{}\n{}", file.path, chunk.content)).collect();
+                let dimension = INDEX_DIMENSION.min(model.dimension());
+                Some(embeddings::encode(model.clone(), texts, DOCUMENT_TASK.to_string(), Some(dimension)).await?)
+            }
+            _ => None,
+        };
+
+        let file_id = indexed.map_or_else(|| Uuid::new_v4().to_string(), |record| record.id.clone());
+        let records: Vec<CodeChunkRecord> = chunks.into_iter()
+            .enumerate()
+            .map(|(index, chunk)| CodeChunkRecord {
+                file_id: file_id.clone(),
+                start_line: chunk.start_line as i64,
+                end_line: chunk.end_line as i64,
+                kind: chunk.kind,
+                symbol: chunk.symbol,
+                content: chunk.content,
+                embedding: vectors.as_ref().map(|vectors| encode_vector(&vectors[index])),
+                embedding_model: vectors.as_ref().and(model).map(|model| model.model_id().to_string()),
+            })
+            .collect();
+        let record = FileRecord {
+            id: file_id,
+            project_id: project_id.to_string(),
+            path: file.path.clone(),
+            language: file.language.to_string(),
+            size: file.size,
+            last_modified: file.last_modified,
+            content_hash,
+        };
+        self.database.read().await.replace_file_chunks(&record, &records).await?;
+
+        progress.files_updated += 1;
+        progress.chunks_indexed += records.len();
+        Ok(())
+    }
+}
+
+/// Stores progress on the job and passes it on, at most every `PROGRESS_INTERVAL` unless forced
+struct ProgressReporter<F> {
+    jobs: Arc<Mutex<HashMap<String, IndexJob>>>,
+    on_progress: F,
+    last_report: Option<Instant>,
+}
+
+impl<F: FnMut(&IndexProgress)> ProgressReporter<F> {
+    fn report(&mut self, progress: &IndexProgress, force: bool) {
+        if let Some(job) = lock(&self.jobs).get_mut(&progress.project_path) {
+            job.progress = progress.clone();
+        }
+        if force || self.last_report.is_none_or(|last| last.elapsed() >= PROGRESS_INTERVAL) {
+            (self.on_progress)(progress);
+            self.last_report = Some(Instant::now());
+        }
+    }
+}
+
+/// Embed a query the way stored chunks were embedded with `model`
+pub async fn embed_query(model: &Arc<EmbeddingModel>, query: &str) -> Result<Vec<f32>> {
+    let dimension = INDEX_DIMENSION.min(model.dimension());
+    embeddings::encode(model.clone(), vec![query.to_string()], QUERY_TASK.to_string(), Some(dimension)).await?
+        .pop()
+        .ok_or_else(|| anyhow!("The embedding model returned no vector"))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Jobs and loaded chunks stay usable even if a panic poisoned the lock
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Projects are identified by the path they were opened with
+fn project_key(project_root: &Path) -> String {
+    project_root.to_string_lossy().to_string()
+}
+
+/// Record for a project that is indexed before it was ever opened
+fn project_record(project_root: &Path) -> ProjectRecord {
+    let now = chrono::Utc::now().timestamp();
+    ProjectRecord {
+        id: Uuid::new_v4().to_string(),
+        name: project_root.file_name()
+            .map_or_else(|| "Untitled".to_string(), |name| name.to_string_lossy().to_string()),
+        path: project_key(project_root),
+        project_type: "General".to_string(),
+        language: "General".to_string(),
+        created_at: now,
+        last_opened: now,
+        settings: "{}".to_string(),
+    }
+}
+
+/// Source files under `root` in a language the chunker knows, sorted by path
+fn source_files(root: &Path) -> Vec<SourceFile> {
+    let mut files: Vec<SourceFile> = WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !entry.file_type().is_dir()
+                || entry.file_name().to_str().is_none_or(|name| !SKIPPED_DIRS.contains(&name))
+        })
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let (language, syntax) = chunking::language_for(entry.path())?;
+            let metadata = entry.metadata().ok()?;
+            if metadata.len() > MAX_FILE_BYTES {
+                return None;
+            }
+            let path = relative_path(root, entry.path())?;
+            let last_modified = metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+            Some(SourceFile {
+                absolute: entry.path().to_path_buf(),
+                path,
+                language,
+                syntax,
+                size: metadata.len() as i64,
+                last_modified,
+            })
+        })
+        .collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files
+}
+
+/// `path` relative to `root` with `/` separators, as stored in the index
+pub fn relative_path(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    Some(relative)
+}
+
+/// Whether the stored chunks of `record` need no new embeddings
+fn chunks_current(record: &IndexedFileRecord, has_model: bool) -> bool {
+    // Without a model, vectors from an earlier run are kept rather than dropped
+    !has_model || record.embedded_count == record.chunk_count
+}
+
+fn encode_vector(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+fn decode_vector(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(4)
+        .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Index over a fresh database, with an empty models directory so no embedding model is found
+    async fn code_index(dir: &Path) -> (CodeIndex, Arc<RwLock<Database>>) {
+        let mut database = Database::with_path(dir.join("index.db"));
+        database.initialize().await.unwrap();
+        let database = Arc::new(RwLock::new(database));
+        let mut models = ModelManager::new();
+        models.configure_directories(&dir.join("models"), Vec::new(), false);
+        (CodeIndex::new(database.clone(), Arc::new(RwLock::new(models))), database)
+    }
+
+    async fn index(code_index: &CodeIndex, root: &Path) -> IndexProgress {
+        let mut progress = IndexProgress::new(&project_key(root));
+        let mut reporter = ProgressReporter { jobs: code_index.jobs.clone(), on_progress: |_: &IndexProgress| {}, last_report: None };
+        code_index.run(root, &CancelToken::default(), &mut progress, &mut reporter).await.unwrap();
+        progress
+    }
+
+    async fn indexed_files(database: &RwLock<Database>, root: &Path) -> Vec<IndexedFileRecord> {
+        let database = database.read().await;
+        let project_id = database.find_project_id(&project_key(root)).await.unwrap().unwrap();
+        let mut files = database.get_indexed_files(&project_id, None).await.unwrap();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+
+    fn project(dir: &Path) -> PathBuf {
+        let root = dir.join("project");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("node_modules/dep")).unwrap();
+        fs::write(root.join("src/lib.rs"), "pub fn one() -> u32 {\n    1\n}\n").unwrap();
+        fs::write(root.join("src/util.py"), "def two():\n    return 2\n").unwrap();
+        fs::write(root.join("node_modules/dep/index.js"), "function dep() {}\n").unwrap();
+        fs::write(root.join("image.png"), [0u8, 1, 2]).unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn indexes_known_languages_outside_skipped_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = project(dir.path());
+        let (code_index, database) = code_index(dir.path()).await;
+
+        let progress = index(&code_index, &root).await;
+        assert_eq!(progress.state, IndexState::Completed);
+        assert_eq!(progress.embedding_model, None);
+        assert_eq!((progress.files_total, progress.files_updated), (2, 2));
+
+        let files = indexed_files(&database, &root).await;
+        let paths: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["src/lib.rs", "src/util.py"]);
+        assert!(files.iter().all(|file| file.chunk_count > 0));
+
+        let loaded = code_index.load(&root).await.unwrap().unwrap();
+        assert!(loaded.chunks.iter().any(|chunk| chunk.path == "src/lib.rs" && chunk.symbol.as_deref() == Some("one")));
+        assert!(loaded.chunks.iter().all(|chunk| chunk.embedding.is_none()));
+        let (best, _) = loaded.lexical_ranking("python two")[0];
+        assert_eq!(loaded.chunks[best].path, "src/util.py");
+    }
+
+    #[tokio::test]
+    async fn only_changed_files_are_chunked_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = project(dir.path());
+        let (code_index, database) = code_index(dir.path()).await;
+        index(&code_index, &root).await;
+
+        let progress = index(&code_index, &root).await;
+        assert_eq!((progress.files_done, progress.files_updated, progress.chunks_indexed), (2, 0, 0));
+
+        // A different stat with the same content only refreshes the stat
+        let lib = indexed_files(&database, &root).await.remove(0);
+        database.read().await.update_file_stat(&lib.id, lib.size, lib.last_modified - 60).await.unwrap();
+        let progress = index(&code_index, &root).await;
+        assert_eq!(progress.files_updated, 0);
+        assert_eq!(indexed_files(&database, &root).await[0].last_modified, lib.last_modified);
+
+        fs::write(root.join("src/lib.rs"), "pub fn one() -> u32 {\n    1\n}\n\npub fn three() -> u32 {\n    3\n}\n").unwrap();
+        let progress = index(&code_index, &root).await;
+        assert_eq!(progress.files_updated, 1);
+        let updated = indexed_files(&database, &root).await.remove(0);
+        assert_eq!(updated.id, lib.id);
+        assert_ne!(updated.content_hash, lib.content_hash);
+    }
+
+    #[tokio::test]
+    async fn deleted_files_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = project(dir.path());
+        let (code_index, database) = code_index(dir.path()).await;
+        index(&code_index, &root).await;
+
+        fs::remove_file(root.join("src/util.py")).unwrap();
+        let progress = index(&code_index, &root).await;
+        assert_eq!((progress.files_total, progress.files_removed), (1, 1));
+        let files = indexed_files(&database, &root).await;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "src/lib.rs");
+    }
+
+    #[tokio::test]
+    async fn counts_chunks_embedded_with_the_requested_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = project(dir.path());
+        let (code_index, database) = code_index(dir.path()).await;
+        index(&code_index, &root).await;
+
+        let lib = indexed_files(&database, &root).await.remove(0);
+        let chunk = |model: &str| CodeChunkRecord {
+            file_id: lib.id.clone(),
+            start_line: 1,
+            end_line: 3,
+            kind: "function".to_string(),
+            symbol: Some("one".to_string()),
+            content: "pub fn one() -> u32 { 1 }".to_string(),
+            embedding: Some(encode_vector(&[0.5, -1.0])),
+            embedding_model: Some(model.to_string()),
+        };
+        let file = FileRecord {
+            id: lib.id.clone(),
+            project_id: database.read().await.find_project_id(&project_key(&root)).await.unwrap().unwrap(),
+            path: lib.path.clone(),
+            language: "rust".to_string(),
+            size: lib.size,
+            last_modified: lib.last_modified,
+            content_hash: lib.content_hash.clone(),
+        };
+        database.read().await.replace_file_chunks(&file, &[chunk("old-model"), chunk("new-model")]).await.unwrap();
+
+        let database = database.read().await;
+        let record = database.get_indexed_files(&file.project_id, Some("new-model")).await.unwrap()
+            .into_iter().find(|record| record.id == lib.id).unwrap();
+        assert_eq!((record.chunk_count, record.embedded_count), (2, 1));
+        assert!(!chunks_current(&record, true));
+        assert!(chunks_current(&record, false));
+        assert!(chunks_current(&IndexedFileRecord { embedded_count: 2, ..record }, true));
+    }
+
+    #[test]
+    fn vectors_round_trip_through_bytes() {
+        let vector = [0.25, -1.5, f32::MAX, 0.0];
+        assert_eq!(encode_vector(&vector).len(), 16);
+        assert_eq!(decode_vector(&encode_vector(&vector)), vector);
+    }
+}
@@ -7,15 +7,26 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use anyhow::Result;
-use regex::Regex;
+use tracing::debug;
+
+use super::code_index::{self, CodeIndex, ProjectIndex};
+use super::retrieval::{self, Bm25Index};
+use super::token_counter::TokenCounter;
+
+/// Candidates each retriever contributes to the fused ranking
+const RANKING_DEPTH: usize = 50;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContextManager {
     pub context_cache: HashMap<String, ContextItem>,
     pub context_strategies: HashMap<String, ContextStrategy>,
     pub max_context_length: usize,
+    #[serde(skip)]
+    token_counter: TokenCounter,
+    #[serde(skip)]
+    code_index: Option<CodeIndex>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,6 +67,12 @@ pub struct ContextStrategy {
     pub include_dependencies: bool,
     pub include_tests: bool,
     pub include_documentation: bool,
+    /// Minimum fused rank score, where 1.0 means ranked first by every ranking
+    ///
+    /// When keywords and embeddings both rank, 0.5 keeps the chunks both found
+    /// (plus the top chunk of either) and 0.3 also keeps the top 41 of a single
+    /// ranking. With keywords alone every chunk within `RANKING_DEPTH` scores
+    /// above 0.5.
     pub relevance_threshold: f32,
 }
 
@@ -78,12 +95,29 @@ pub struct ContextResponse {
     pub relevance_scores: HashMap<String, f32>,
 }
 
+impl ContextResponse {
+    /// The retrieved items as a system message for the model, or None if nothing was retrieved
+    pub fn to_system_prompt(&self) -> Option<String> {
+        if self.context_items.is_empty() {
+            return None;
+        }
+        let mut prompt = String::from("Use the following excerpts from the user's project when they are relevant to the question.\n");
+        for item in &self.context_items {
+            let language = item.metadata.language.as_deref().unwrap_or("");
+            prompt.push_str(&format!("\n{}:\n```{}\n{}\n```\n", item.id, language, item.content.trim_end()));
+        }
+        Some(prompt)
+    }
+}
+
 impl Default for ContextManager {
     fn default() -> Self {
         Self {
             context_cache: HashMap::new(),
             context_strategies: Self::get_default_strategies(),
             max_context_length: 4096,
+            token_counter: TokenCounter::new(),
+            code_index: None,
         }
     }
 }
@@ -93,6 +127,16 @@ impl ContextManager {
         Self::default()
     }
 
+    /// Budget context with the model manager's tokenizer
+    pub fn set_token_counter(&mut self, token_counter: TokenCounter) {
+        self.token_counter = token_counter;
+    }
+
+    /// Pick project context from the semantic code index
+    pub fn set_code_index(&mut self, code_index: CodeIndex) {
+        self.code_index = Some(code_index);
+    }
+
     fn get_default_strategies() -> HashMap<String, ContextStrategy> {
         let mut strategies = HashMap::new();
 
@@ -139,7 +183,7 @@ impl ContextManager {
         strategies
     }
 
-    pub fn get_context(&mut self, request: ContextRequest) -> Result<ContextResponse> {
+    pub async fn get_context(&mut self, request: ContextRequest) -> Result<ContextResponse> {
         let strategy = self.context_strategies.get(&request.strategy)
             .ok_or_else(|| anyhow::anyhow!("Unknown context strategy: {}", request.strategy))?;
 
@@ -165,62 +209,82 @@ impl ContextManager {
                     access_count: 1,
                 };
                 context_items.push(selection_item);
-                _total_tokens += self.estimate_tokens(&selection);
+                _total_tokens += self.token_counter.count(&selection);
             }
         }
 
+        let current_content = match &request.current_file {
+            Some(current_file) => self.get_file_content(current_file)?,
+            None => None,
+        };
+
+        // Project context comes from the code index if the strategy wants more than one file
+        let project_root = request.project_root.as_deref().filter(|_| strategy.max_files > 1);
+        let project_index = match (project_root, &self.code_index) {
+            (Some(project_root), Some(code_index)) => {
+                let index = code_index.load(project_root).await?;
+                if index.is_none() {
+                    debug!("🔍 {} has no code index yet; no project context", project_root.display());
+                }
+                index
+            }
+            _ => None,
+        };
+
+        // Document 0 is the current file, if there is one; the project's chunks follow
+        let current = request.current_file.as_deref().zip(current_content.as_deref());
+        let project = project_root.zip(project_index.as_deref());
+        let fused = self.rank_documents(&request.query, current, project).await;
+        let offset = usize::from(current.is_some());
+
         // Add current file content
-        if let Some(current_file) = &request.current_file {
-            if let Some(file_content) = self.get_file_content(current_file)? {
-                let relevance = self.calculate_relevance(&file_content, &request.query);
-                relevance_scores.insert(current_file.to_string_lossy().to_string(), relevance);
-
-                if relevance >= strategy.relevance_threshold {
-                    let file_item = ContextItem {
-                        id: current_file.to_string_lossy().to_string(),
-                        content: file_content,
+        if let (Some(current_file), Some(file_content)) = (&request.current_file, current_content) {
+            let relevance = fused.iter().find(|(document, _)| *document == 0).map_or(0.0, |(_, score)| *score);
+            relevance_scores.insert(current_file.to_string_lossy().to_string(), relevance);
+
+            if relevance >= strategy.relevance_threshold {
+                let file_item = ContextItem {
+                    id: current_file.to_string_lossy().to_string(),
+                    content: file_content,
+                    metadata: ContextMetadata {
+                        source_type: ContextSourceType::File,
+                        file_path: Some(current_file.clone()),
+                        language: self.detect_language(&Some(current_file.clone())),
+                        size: 0, // Will be set below
+                        relevance_score: relevance,
+                        tags: vec!["current_file".to_string()],
+                    },
+                    last_accessed: chrono::Utc::now(),
+                    access_count: 1,
+                };
+                context_items.push(file_item);
+            }
+        }
+
+        // Add project context if strategy requires it
+        if let Some((project_root, index)) = project {
+            for &(document, relevance) in fused.iter().filter(|(document, _)| *document >= offset) {
+                let chunk = &index.chunks[document - offset];
+                let id = format!("{}:{}-{}", chunk.path, chunk.start_line, chunk.end_line);
+                relevance_scores.insert(id.clone(), relevance);
+
+                if relevance >= strategy.relevance_threshold && context_items.len() < strategy.max_files {
+                    let file_path = project_root.join(&chunk.path);
+                    let chunk_item = ContextItem {
+                        id,
+                        content: chunk.content.clone(),
                         metadata: ContextMetadata {
                             source_type: ContextSourceType::File,
-                            file_path: Some(current_file.clone()),
-                            language: self.detect_language(&Some(current_file.clone())),
-                            size: 0, // Will be set below
+                            language: self.detect_language(&Some(file_path.clone())),
+                            file_path: Some(file_path),
+                            size: chunk.content.len(),
                             relevance_score: relevance,
-                            tags: vec!["current_file".to_string()],
+                            tags: vec!["project_file".to_string()],
                         },
                         last_accessed: chrono::Utc::now(),
                         access_count: 1,
                     };
-                    context_items.push(file_item);
-                }
-            }
-        }
-
-        // Add project context if strategy requires it
-        if strategy.max_files > 1 {
-            if let Some(project_root) = &request.project_root {
-                let project_files = self.get_relevant_project_files(project_root, &request.query, strategy)?;
-                
-                for (file_path, content) in project_files {
-                    let relevance = self.calculate_relevance(&content, &request.query);
-                    relevance_scores.insert(file_path.to_string_lossy().to_string(), relevance);
-
-                    if relevance >= strategy.relevance_threshold && context_items.len() < strategy.max_files {
-                        let file_item = ContextItem {
-                            id: file_path.to_string_lossy().to_string(),
-                            content,
-                            metadata: ContextMetadata {
-                                source_type: ContextSourceType::File,
-                                file_path: Some(file_path.clone()),
-                                language: self.detect_language(&Some(file_path)),
-                                size: 0, // Will be set below
-                                relevance_score: relevance,
-                                tags: vec!["project_file".to_string()],
-                            },
-                            last_accessed: chrono::Utc::now(),
-                            access_count: 1,
-                        };
-                        context_items.push(file_item);
-                    }
+                    context_items.push(chunk_item);
                 }
             }
         }
@@ -232,7 +296,7 @@ impl ContextManager {
         let mut final_tokens = 0;
 
         for item in context_items {
-            let item_tokens = self.estimate_tokens(&item.content);
+            let item_tokens = self.token_counter.count(&item.content);
             if final_tokens + item_tokens <= request.max_tokens {
                 final_items.push(item);
                 final_tokens += item_tokens;
@@ -258,110 +322,51 @@ impl ContextManager {
         }
     }
 
-    fn get_relevant_project_files(&self, project_root: &PathBuf, query: &str, strategy: &ContextStrategy) -> Result<Vec<(PathBuf, String)>> {
-        let mut relevant_files = Vec::new();
-        
-        if !project_root.exists() {
-            return Ok(relevant_files);
-        }
-
-        let mut entries = Vec::new();
-        self.collect_files(project_root, &mut entries, strategy)?;
-
-        for file_path in entries {
-            if let Ok(content) = std::fs::read_to_string(&file_path) {
-                let relevance = self.calculate_relevance(&content, query);
-                if relevance >= strategy.relevance_threshold {
-                    relevant_files.push((file_path, content));
-                }
+    /// Fused ranking of the current file (document 0, if given) and the project's chunks (after it)
+    ///
+    /// BM25 always takes part; embedding similarity joins when an embedding
+    /// model is loaded. Scores are 1.0 for a document ranked first by every
+    /// retriever that took part.
+    async fn rank_documents(&self, query: &str, current: Option<(&Path, &str)>, project: Option<(&Path, &ProjectIndex)>) -> Vec<(usize, f32)> {
+        let offset = usize::from(current.is_some());
+
+        let mut lexical: Vec<(usize, f32)> = project
+            .map(|(_, index)| index.lexical_ranking(query).into_iter().map(|(chunk, score)| (chunk + offset, score)).collect())
+            .unwrap_or_default();
+        if let Some((_, content)) = current {
+            // Scored with the project's term statistics when there are any
+            let score = match project {
+                Some((_, index)) => index.lexical.score_text(query, content),
+                None => Bm25Index::new([content]).score_text(query, content),
+            };
+            if score > 0.0 {
+                lexical.push((0, score));
+                lexical.sort_by(|a, b| b.1.total_cmp(&a.1));
             }
         }
-
-        // Sort by relevance and limit
-        relevant_files.sort_by(|a, b| {
-            let relevance_a = self.calculate_relevance(&a.1, query);
-            let relevance_b = self.calculate_relevance(&b.1, query);
-            relevance_b.partial_cmp(&relevance_a).unwrap()
-        });
-
-        relevant_files.truncate(strategy.max_files);
-        Ok(relevant_files)
-    }
-
-    fn collect_files(&self, dir: &PathBuf, entries: &mut Vec<PathBuf>, strategy: &ContextStrategy) -> Result<()> {
-        if !dir.is_dir() {
-            return Ok(());
-        }
-
-        for entry in std::fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-
-            if path.is_file() {
-                // Skip certain file types
-                if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                    match ext {
-                        "log" | "tmp" | "cache" | "lock" => continue,
-                        _ => {}
-                    }
-                }
-
-                // Skip large files
-                if let Ok(metadata) = std::fs::metadata(&path) {
-                    if metadata.len() > 1024 * 1024 { // 1MB limit
-                        continue;
-                    }
-                }
-
-                entries.push(path);
-            } else if path.is_dir() {
-                // Skip certain directories
-                if let Some(dir_name) = path.file_name().and_then(|n| n.to_str()) {
-                    match dir_name {
-                        "node_modules" | "target" | ".git" | "dist" | "build" => continue,
-                        _ => {}
+        lexical.truncate(RANKING_DEPTH);
+
+        let mut semantic = Vec::new();
+        if let (Some((project_root, index)), Some(code_index)) = (project, &self.code_index) {
+            if let Some(model) = code_index.resident_embedding_model().await {
+                match code_index::embed_query(&model, query).await {
+                    Ok(query_embedding) => {
+                        let ranked = index.semantic_ranking(model.model_id(), &query_embedding);
+                        // The current file is as similar as its closest indexed chunk
+                        let current_path = current.and_then(|(path, _)| code_index::relative_path(project_root, path));
+                        if let Some(&(_, score)) = current_path.and_then(|path| ranked.iter().find(|(chunk, _)| index.chunks[*chunk].path == path)) {
+                            semantic.push((0, score));
+                        }
+                        semantic.extend(ranked.into_iter().map(|(chunk, score)| (chunk + offset, score)));
+                        semantic.sort_by(|a, b| b.1.total_cmp(&a.1));
                     }
+                    Err(e) => debug!("🔍 Ranking context by keywords only: {}", e),
                 }
-
-                self.collect_files(&path, entries, strategy)?;
             }
         }
+        semantic.truncate(RANKING_DEPTH);
 
-        Ok(())
-    }
-
-    fn calculate_relevance(&self, content: &str, query: &str) -> f32 {
-        let query_lower = query.to_lowercase();
-        let content_lower = content.to_lowercase();
-        
-        let mut score = 0.0;
-        
-        // Exact phrase match
-        if content_lower.contains(&query_lower) {
-            score += 1.0;
-        }
-        
-        // Word matches
-        let query_words: Vec<&str> = query_lower.split_whitespace().collect();
-        let content_words: Vec<&str> = content_lower.split_whitespace().collect();
-        
-        for query_word in &query_words {
-            let matches = content_words.iter().filter(|&&word| word.contains(query_word)).count();
-            score += matches as f32 * 0.1;
-        }
-        
-        // Function/class name matches
-        let function_pattern = Regex::new(r"\b(fn|function|def|class|struct|interface)\s+(\w+)").unwrap();
-        for cap in function_pattern.captures_iter(content) {
-            if let Some(name) = cap.get(2) {
-                if query_lower.contains(&name.as_str().to_lowercase()) {
-                    score += 0.5;
-                }
-            }
-        }
-        
-        // Normalize score
-        (score / 10.0).min(1.0)
+        retrieval::reciprocal_rank_fusion(&[lexical, semantic])
     }
 
     fn detect_language(&self, file_path: &Option<PathBuf>) -> Option<String> {
@@ -390,11 +395,6 @@ impl ContextManager {
         }
     }
 
-    fn estimate_tokens(&self, text: &str) -> usize {
-        // Simple token estimation: roughly 4 characters per token
-        (text.len() as f32 / 4.0).ceil() as usize
-    }
-
     pub fn cache_context(&mut self, id: String, content: String, metadata: ContextMetadata) {
         let item = ContextItem {
             id: id.clone(),
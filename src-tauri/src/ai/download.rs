@@ -0,0 +1,468 @@
+/*!
+ * Model Downloads
+ *
+ * Fetches model repositories over HTTP using the HuggingFace URL layout
+ * (`<base>/<repo>/resolve/<revision>/<file>`), so the same code works against
+ * huggingface.co, an internal mirror or a local file server. Files are written
+ * as `<file>.part`, resumed with Range requests, checked against the published
+ * SHA-256 and only then renamed into place.
+ */
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow, bail};
+use futures::StreamExt;
+use reqwest::header::{AUTHORIZATION, CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use super::integrity;
+
+pub const DEFAULT_BASE_URL: &str = "https://huggingface.co";
+
+/// Progress events are throttled to this interval per file
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A file of a remote repository
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteFile {
+    /// Path inside the repository, e.g. `onnx/model.onnx`
+    pub path: String,
+    pub size: Option<u64>,
+    /// Published SHA-256, known for LFS files
+    pub sha256: Option<String>,
+}
+
+/// Progress of a download, reported while bytes arrive and after every file
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadProgress {
+    pub repo_id: String,
+    pub file: String,
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    /// None if the server sent no length
+    pub bytes_total: Option<u64>,
+    /// Bytes that were already on disk from an earlier attempt
+    pub resumed_from: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepoInfo {
+    #[serde(default)]
+    siblings: Vec<RepoSibling>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepoSibling {
+    rfilename: String,
+    size: Option<u64>,
+    lfs: Option<LfsInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LfsInfo {
+    sha256: String,
+}
+
+/// HTTP client for one model host
+pub struct ModelDownloader {
+    client: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl ModelDownloader {
+    /// Downloader for `base_url`, or `HF_ENDPOINT` / huggingface.co when None
+    pub fn new(base_url: Option<&str>) -> Result<Self> {
+        let base_url = base_url
+            .map(str::to_string)
+            .or_else(|| std::env::var("HF_ENDPOINT").ok())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string())
+            .trim_end_matches('/')
+            .to_string();
+
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(30))
+            .user_agent(concat!("rain-chat/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+
+        // Gated repositories need the token saved by `huggingface-cli login`
+        let token = std::env::var("HF_TOKEN").ok()
+            .or_else(|| hf_hub::Cache::default().token());
+
+        Ok(Self { client, base_url, token })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        let request = self.client.get(url);
+        match &self.token {
+            Some(token) => request.header(AUTHORIZATION, format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    /// List a repository's files with sizes and LFS checksums
+    pub async fn list_files(&self, repo_id: &str, revision: &str) -> Result<Vec<RemoteFile>> {
+        let url = format!("{}/api/models/{}/revision/{}?blobs=true", self.base_url, repo_id, revision);
+        let response = self.get(&url).send().await?;
+        if !response.status().is_success() {
+            bail!("Failed to list {}@{}: HTTP {}", repo_id, revision, response.status());
+        }
+        let info: RepoInfo = response.json().await?;
+        Ok(info.siblings.into_iter()
+            .map(|sibling| RemoteFile {
+                path: sibling.rfilename,
+                size: sibling.size,
+                sha256: sibling.lfs.map(|lfs| lfs.sha256.to_lowercase()),
+            })
+            .collect())
+    }
+
+    /// Download `files` of `repo_id` into `target`, resuming anything left from an earlier attempt
+    ///
+    /// Returns the SHA-256 of every downloaded file. Files that already exist
+    /// with the published checksum are skipped.
+    pub async fn download(
+        &self,
+        repo_id: &str,
+        revision: &str,
+        files: &[RemoteFile],
+        target: &Path,
+        on_progress: &mut (dyn FnMut(&DownloadProgress) + Send),
+    ) -> Result<HashMap<String, String>> {
+        tokio::fs::create_dir_all(target).await?;
+        let mut hashes = HashMap::new();
+
+        for (index, file) in files.iter().enumerate() {
+            let destination = target.join(safe_relative_path(&file.path)?);
+            let mut progress = DownloadProgress {
+                repo_id: repo_id.to_string(),
+                file: file.path.clone(),
+                files_done: index,
+                files_total: files.len(),
+                bytes_done: 0,
+                bytes_total: file.size,
+                resumed_from: 0,
+            };
+
+            let hash = match existing_hash(&destination, file).await? {
+                Some(hash) => {
+                    info!("✅ {} is already downloaded", file.path);
+                    hash
+                }
+                None => self.download_file(repo_id, revision, file, &destination, &mut progress, on_progress).await?,
+            };
+            hashes.insert(file.path.clone(), hash);
+
+            progress.files_done = index + 1;
+            on_progress(&progress);
+        }
+        Ok(hashes)
+    }
+
+    async fn download_file(
+        &self,
+        repo_id: &str,
+        revision: &str,
+        file: &RemoteFile,
+        destination: &Path,
+        progress: &mut DownloadProgress,
+        on_progress: &mut (dyn FnMut(&DownloadProgress) + Send),
+    ) -> Result<String> {
+        if let Some(parent) = destination.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let partial = partial_path(destination);
+        let offset = tokio::fs::metadata(&partial).await.map(|m| m.len()).unwrap_or(0);
+
+        let url = format!("{}/{}/resolve/{}/{}", self.base_url, repo_id, revision, file.path);
+        let mut request = self.get(&url);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let response = request.send().await?;
+
+        // HuggingFace puts the LFS SHA-256 in the linked ETag
+        let expected = file.sha256.clone().or_else(|| linked_etag_sha256(&response));
+
+        let append = match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                info!("🔄 Resuming {} at {} bytes", file.path, offset);
+                true
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+                // The partial file already holds every byte
+                return self.finish_file(&partial, destination, file, expected).await;
+            }
+            status if status.is_success() => {
+                if offset > 0 {
+                    warn!("⚠️ {} ignored the range request; downloading {} from the start", self.base_url, file.path);
+                }
+                false
+            }
+            status => bail!("Failed to download {}: HTTP {}", url, status),
+        };
+
+        progress.resumed_from = if append { offset } else { 0 };
+        progress.bytes_done = progress.resumed_from;
+        progress.bytes_total = total_size(&response, progress.resumed_from).or(file.size);
+        on_progress(progress);
+
+        let mut output = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&partial)
+            .await?;
+
+        let mut stream = response.bytes_stream();
+        let mut last_report = Instant::now();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| anyhow!("Download of {} interrupted after {} bytes: {}", file.path, progress.bytes_done, e))?;
+            output.write_all(&chunk).await?;
+            progress.bytes_done += chunk.len() as u64;
+            if last_report.elapsed() >= PROGRESS_INTERVAL {
+                on_progress(progress);
+                last_report = Instant::now();
+            }
+        }
+        output.flush().await?;
+        output.sync_all().await?;
+        drop(output);
+        on_progress(progress);
+
+        self.finish_file(&partial, destination, file, expected).await
+    }
+
+    /// Check the finished `.part` file and move it into place
+    async fn finish_file(&self, partial: &Path, destination: &Path, file: &RemoteFile, expected: Option<String>) -> Result<String> {
+        let hash_path = partial.to_path_buf();
+        let hash = tokio::task::spawn_blocking(move || integrity::hash_file(&hash_path)).await??;
+
+        if let Some(expected) = expected.filter(|expected| *expected != hash) {
+            // A resumed file with bad bytes can't be repaired; start over next time
+            let _ = tokio::fs::remove_file(partial).await;
+            bail!("Checksum mismatch for {}: expected {}, got {}", file.path, expected, hash);
+        }
+
+        tokio::fs::rename(partial, destination).await?;
+        info!("✅ Downloaded {}", file.path);
+        Ok(hash)
+    }
+}
+
+/// Hash of an already downloaded file if it matches what the server publishes
+async fn existing_hash(destination: &Path, file: &RemoteFile) -> Result<Option<String>> {
+    let Some(expected) = &file.sha256 else {
+        return Ok(None);
+    };
+    if !destination.is_file() {
+        return Ok(None);
+    }
+    let path = destination.to_path_buf();
+    let hash = tokio::task::spawn_blocking(move || integrity::hash_file(&path)).await??;
+    Ok((hash == *expected).then_some(hash))
+}
+
+fn partial_path(destination: &Path) -> PathBuf {
+    let mut name = destination.as_os_str().to_os_string();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Reject repository paths that would escape the target directory
+fn safe_relative_path(path: &str) -> Result<PathBuf> {
+    let relative = PathBuf::from(path);
+    if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+        bail!("Refusing to download to unsafe path {:?}", path);
+    }
+    Ok(relative)
+}
+
+/// Reject model names that are not exactly one directory inside the models directory
+pub fn safe_directory_name(name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => Ok(()),
+        _ => bail!("Invalid model name: {:?}", name),
+    }
+}
+
+/// Full file size from `Content-Range: bytes a-b/total`, or offset plus `Content-Length`
+fn total_size(response: &reqwest::Response, offset: u64) -> Option<u64> {
+    response.headers().get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit('/').next())
+        .and_then(|total| total.parse().ok())
+        .or_else(|| response.content_length().map(|len| len + offset))
+}
+
+fn linked_etag_sha256(response: &reqwest::Response) -> Option<String> {
+    let etag = response.headers().get("x-linked-etag")?.to_str().ok()?;
+    let etag = etag.trim_start_matches("W/").trim_matches('"').to_lowercase();
+    (etag.len() == 64 && etag.chars().all(|c| c.is_ascii_hexdigit())).then_some(etag)
+}
+
+/// Record where the model came from and its checksums in `model_info.json`
+///
+/// Existing fields are kept; integrity verification reads the `sha256` map.
+pub async fn write_model_info(target: &Path, repo_id: &str, revision: &str, hashes: &HashMap<String, String>) -> Result<()> {
+    let path = target.join("model_info.json");
+    let mut info = match tokio::fs::read_to_string(&path).await {
+        Ok(content) => serde_json::from_str::<serde_json::Value>(&content).unwrap_or_else(|_| serde_json::json!({})),
+        Err(_) => serde_json::json!({}),
+    };
+    let object = info.as_object_mut()
+        .ok_or_else(|| anyhow!("{} is not a JSON object", path.display()))?;
+
+    object.entry("name").or_insert_with(|| repo_id.rsplit('/').next().unwrap_or(repo_id).into());
+    object.insert("repo_id".to_string(), repo_id.into());
+    object.insert("revision".to_string(), revision.into());
+    let sha256 = object.entry("sha256").or_insert_with(|| serde_json::json!({}));
+    if let Some(sha256) = sha256.as_object_mut() {
+        for (file, hash) in hashes {
+            sha256.insert(file.clone(), hash.clone().into());
+        }
+    }
+
+    // Write then rename so a crash never leaves a truncated model_info.json
+    let temporary = target.join("model_info.json.tmp");
+    tokio::fs::write(&temporary, serde_json::to_string_pretty(&info)?).await?;
+    tokio::fs::rename(&temporary, &path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use sha2::{Digest, Sha256};
+
+    const CONTENT: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    /// Serve CONTENT for every path, honouring `Range: bytes=N-`, and record the Range headers received
+    fn serve(ranges: Arc<Mutex<Vec<Option<String>>>>) -> SocketAddr {
+        let make_service = make_service_fn(move |_| {
+            let ranges = ranges.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let range = request.headers().get(RANGE).and_then(|v| v.to_str().ok()).map(str::to_string);
+                    ranges.lock().unwrap().push(range.clone());
+                    let start = range
+                        .and_then(|r| r.strip_prefix("bytes=")?.strip_suffix('-')?.parse::<usize>().ok());
+                    let response = match start {
+                        None => Response::new(Body::from(CONTENT)),
+                        Some(start) if start >= CONTENT.len() => Response::builder()
+                            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                            .header("Content-Range", format!("bytes */{}", CONTENT.len()))
+                            .body(Body::empty())
+                            .unwrap(),
+                        Some(start) => Response::builder()
+                            .status(StatusCode::PARTIAL_CONTENT)
+                            .header("Content-Range", format!("bytes {}-{}/{}", start, CONTENT.len() - 1, CONTENT.len()))
+                            .body(Body::from(&CONTENT[start..]))
+                            .unwrap(),
+                    };
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn sha256(bytes: &[u8]) -> String {
+        hex::encode(Sha256::digest(bytes))
+    }
+
+    fn remote_file(sha256: Option<String>) -> RemoteFile {
+        RemoteFile { path: "model.bin".to_string(), size: Some(CONTENT.len() as u64), sha256 }
+    }
+
+    async fn download(target: &Path, file: RemoteFile) -> (Result<HashMap<String, String>>, Vec<Option<String>>, Vec<DownloadProgress>) {
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let addr = serve(ranges.clone());
+        let downloader = ModelDownloader::new(Some(&format!("http://{}", addr))).unwrap();
+        let mut events = Vec::new();
+        let result = downloader
+            .download("org/model", "main", &[file], target, &mut |progress| events.push(progress.clone()))
+            .await;
+        let ranges = ranges.lock().unwrap().clone();
+        (result, ranges, events)
+    }
+
+    #[tokio::test]
+    async fn resumes_partial_file_with_range_request() {
+        let target = tempfile::tempdir().unwrap();
+        std::fs::write(target.path().join("model.bin.part"), &CONTENT[..10]).unwrap();
+
+        let (result, ranges, events) = download(target.path(), remote_file(Some(sha256(CONTENT)))).await;
+
+        assert_eq!(result.unwrap()["model.bin"], sha256(CONTENT));
+        assert_eq!(ranges, vec![Some("bytes=10-".to_string())]);
+        assert_eq!(events[0].resumed_from, 10);
+        assert_eq!(events.last().unwrap().bytes_done, CONTENT.len() as u64);
+        assert_eq!(std::fs::read(target.path().join("model.bin")).unwrap(), CONTENT);
+        assert!(!target.path().join("model.bin.part").exists());
+    }
+
+    #[tokio::test]
+    async fn finishes_complete_partial_file_on_416() {
+        let target = tempfile::tempdir().unwrap();
+        std::fs::write(target.path().join("model.bin.part"), CONTENT).unwrap();
+
+        let (result, ranges, _) = download(target.path(), remote_file(Some(sha256(CONTENT)))).await;
+
+        assert_eq!(result.unwrap()["model.bin"], sha256(CONTENT));
+        assert_eq!(ranges, vec![Some(format!("bytes={}-", CONTENT.len()))]);
+        assert_eq!(std::fs::read(target.path().join("model.bin")).unwrap(), CONTENT);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_discards_partial_file() {
+        let target = tempfile::tempdir().unwrap();
+        // Corrupt bytes from an earlier attempt can't be repaired by resuming
+        std::fs::write(target.path().join("model.bin.part"), b"XXXXX").unwrap();
+
+        let (result, _, _) = download(target.path(), remote_file(Some(sha256(CONTENT)))).await;
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("Checksum mismatch for model.bin"), "{}", error);
+        assert!(!target.path().join("model.bin").exists());
+        assert!(!target.path().join("model.bin.part").exists());
+    }
+
+    #[tokio::test]
+    async fn skips_file_that_is_already_downloaded() {
+        let target = tempfile::tempdir().unwrap();
+        std::fs::write(target.path().join("model.bin"), CONTENT).unwrap();
+
+        let (result, ranges, _) = download(target.path(), remote_file(Some(sha256(CONTENT)))).await;
+
+        assert_eq!(result.unwrap()["model.bin"], sha256(CONTENT));
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn directory_names_must_be_a_single_component() {
+        assert!(safe_directory_name("gemma-3-270m-it").is_ok());
+        for name in ["", ".", "..", "a/b", "../escape", "a\\b", "/abs", "name/"] {
+            assert!(safe_directory_name(name).is_err(), "{:?} should be rejected", name);
+        }
+    }
+}
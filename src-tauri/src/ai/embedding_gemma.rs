@@ -0,0 +1,359 @@
+/*!
+ * EmbeddingGemma Encoder
+ *
+ * Candle implementation of the Gemma 3 text encoder behind EmbeddingGemma:
+ * the Gemma 3 layer stack run with bidirectional attention, where
+ * sliding-window layers see a window on both sides of each token, mean
+ * pooled over the non-padding tokens. candle-transformers' Gemma 3 model only
+ * does causal attention.
+ */
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{embedding, linear_b, linear_no_bias, Embedding, Linear, VarBuilder};
+use candle_transformers::utils::repeat_kv;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub head_dim: usize,
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_norm_eps")]
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f64,
+    #[serde(default = "default_rope_local_base_freq")]
+    pub rope_local_base_freq: f64,
+    #[serde(default)]
+    pub query_pre_attn_scalar: Option<f64>,
+    #[serde(default = "default_sliding_window")]
+    pub sliding_window: usize,
+    #[serde(default = "default_sliding_window_pattern", alias = "_sliding_window_pattern")]
+    pub sliding_window_pattern: usize,
+    #[serde(default)]
+    pub layer_types: Option<Vec<String>>,
+}
+
+fn default_norm_eps() -> f64 {
+    1e-6
+}
+
+fn default_rope_theta() -> f64 {
+    1_000_000.0
+}
+
+fn default_rope_local_base_freq() -> f64 {
+    10_000.0
+}
+
+fn default_sliding_window() -> usize {
+    512
+}
+
+fn default_sliding_window_pattern() -> usize {
+    6
+}
+
+impl Config {
+    fn is_sliding_layer(&self, layer_idx: usize) -> bool {
+        if let Some(layer_types) = &self.layer_types {
+            return layer_types.get(layer_idx).is_some_and(|t| t == "sliding_attention");
+        }
+        !(layer_idx + 1).is_multiple_of(self.sliding_window_pattern.max(1))
+    }
+
+    /// Tokens on each side a sliding-window layer attends to; HuggingFace halves
+    /// the configured window when attention is bidirectional
+    fn bidirectional_window(&self) -> usize {
+        self.sliding_window / 2 + 1
+    }
+}
+
+/// Gemma's RMS norm scales by `1 + weight`
+#[derive(Debug, Clone)]
+struct RmsNorm {
+    weight: Tensor,
+    eps: f32,
+}
+
+impl RmsNorm {
+    fn new(dim: usize, eps: f64, vb: VarBuilder) -> Result<Self> {
+        let weight = (vb.get(dim, "weight")? + 1.0)?;
+        Ok(Self { weight, eps: eps as f32 })
+    }
+}
+
+impl Module for RmsNorm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        candle_nn::ops::rms_norm(&xs.contiguous()?, &self.weight, self.eps)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            gate_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("gate_proj"))?,
+            up_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("up_proj"))?,
+            down_proj: linear_no_bias(cfg.intermediate_size, cfg.hidden_size, vb.pp("down_proj"))?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        // candle's gelu is the tanh approximation (gelu_pytorch_tanh)
+        let gate = self.gate_proj.forward(xs)?.gelu()?;
+        self.down_proj.forward(&(gate * self.up_proj.forward(xs)?)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    q_norm: RmsNorm,
+    k_norm: RmsNorm,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    scale: f64,
+}
+
+impl Attention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let head_dim = cfg.head_dim;
+        let q_dim = cfg.num_attention_heads * head_dim;
+        let kv_dim = cfg.num_key_value_heads * head_dim;
+        Ok(Self {
+            q_proj: linear_no_bias(cfg.hidden_size, q_dim, vb.pp("q_proj"))?,
+            k_proj: linear_no_bias(cfg.hidden_size, kv_dim, vb.pp("k_proj"))?,
+            v_proj: linear_no_bias(cfg.hidden_size, kv_dim, vb.pp("v_proj"))?,
+            o_proj: linear_no_bias(q_dim, cfg.hidden_size, vb.pp("o_proj"))?,
+            q_norm: RmsNorm::new(head_dim, cfg.rms_norm_eps, vb.pp("q_norm"))?,
+            k_norm: RmsNorm::new(head_dim, cfg.rms_norm_eps, vb.pp("k_norm"))?,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim,
+            scale: 1.0 / cfg.query_pre_attn_scalar.unwrap_or(head_dim as f64).sqrt(),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, cos: &Tensor, sin: &Tensor, mask: &Tensor) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+
+        let q = self.q_proj.forward(xs)?
+            .reshape((b_sz, seq_len, self.num_heads, self.head_dim))?
+            .apply(&self.q_norm)?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = self.k_proj.forward(xs)?
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .apply(&self.k_norm)?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = self.v_proj.forward(xs)?
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let q = candle_nn::rotary_emb::rope(&q, cos, sin)?;
+        let k = candle_nn::rotary_emb::rope(&k, cos, sin)?;
+
+        let n_rep = self.num_heads / self.num_kv_heads;
+        let k = repeat_kv(k, n_rep)?.contiguous()?;
+        let v = repeat_kv(v, n_rep)?.contiguous()?;
+
+        let attn = (q.matmul(&k.t()?)? * self.scale)?.broadcast_add(mask)?;
+        let attn = candle_nn::ops::softmax_last_dim(&attn)?;
+
+        attn.matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, self.num_heads * self.head_dim))?
+            .apply(&self.o_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct EncoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+    pre_feedforward_layernorm: RmsNorm,
+    post_feedforward_layernorm: RmsNorm,
+    sliding: bool,
+}
+
+impl EncoderLayer {
+    fn new(cfg: &Config, layer_idx: usize, vb: VarBuilder) -> Result<Self> {
+        let norm = |name: &str| RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp(name));
+        Ok(Self {
+            self_attn: Attention::new(cfg, vb.pp("self_attn"))?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+            input_layernorm: norm("input_layernorm")?,
+            post_attention_layernorm: norm("post_attention_layernorm")?,
+            pre_feedforward_layernorm: norm("pre_feedforward_layernorm")?,
+            post_feedforward_layernorm: norm("post_feedforward_layernorm")?,
+            sliding: cfg.is_sliding_layer(layer_idx),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, cos: &Tensor, sin: &Tensor, mask: &Tensor) -> Result<Tensor> {
+        let attn = self.self_attn.forward(&self.input_layernorm.forward(xs)?, cos, sin, mask)?;
+        let xs = (self.post_attention_layernorm.forward(&attn)? + xs)?;
+        let ff = self.mlp.forward(&self.pre_feedforward_layernorm.forward(&xs)?)?;
+        self.post_feedforward_layernorm.forward(&ff)? + xs
+    }
+}
+
+/// Rotary tables for the global and the sliding-window layers
+#[derive(Debug, Clone)]
+struct RopeTables {
+    global: (Tensor, Tensor),
+    local: (Tensor, Tensor),
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: Embedding,
+    layers: Vec<EncoderLayer>,
+    norm: RmsNorm,
+    rope: RopeTables,
+    hidden_size: usize,
+    window: usize,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        // sentence-transformers checkpoints store the bare encoder, without the `model.` prefix
+        let vb = if vb.contains_tensor("model.embed_tokens.weight") { vb.pp("model") } else { vb };
+        let embed_tokens = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("embed_tokens"))?;
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| EncoderLayer::new(cfg, i, vb.pp(format!("layers.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+        let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("norm"))?;
+        let rope = RopeTables {
+            global: rope_tables(cfg, cfg.rope_theta, vb.dtype(), vb.device())?,
+            local: rope_tables(cfg, cfg.rope_local_base_freq, vb.dtype(), vb.device())?,
+        };
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            rope,
+            hidden_size: cfg.hidden_size,
+            window: cfg.bidirectional_window(),
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    pub fn max_positions(&self) -> usize {
+        self.rope.global.0.dim(0).unwrap_or_default()
+    }
+
+    /// Mean of the final hidden states over the tokens `attention_mask` marks with 1
+    ///
+    /// `input_ids` and `attention_mask` are (batch, seq) u32; returns (batch, hidden).
+    pub fn embed(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let (_b_sz, seq_len) = input_ids.dims2()?;
+        if seq_len > self.max_positions() {
+            candle_core::bail!("sequence length {} exceeds the supported {} positions", seq_len, self.max_positions());
+        }
+
+        let (global_cos, global_sin) = (self.rope.global.0.narrow(0, 0, seq_len)?, self.rope.global.1.narrow(0, 0, seq_len)?);
+        let (local_cos, local_sin) = (self.rope.local.0.narrow(0, 0, seq_len)?, self.rope.local.1.narrow(0, 0, seq_len)?);
+        let global_mask = self.attention_mask(attention_mask, None)?;
+        let sliding_mask = self.attention_mask(attention_mask, Some(self.window))?;
+
+        let mut xs = (self.embed_tokens.forward(input_ids)? * (self.hidden_size as f64).sqrt())?;
+        for layer in &self.layers {
+            xs = if layer.sliding {
+                layer.forward(&xs, &local_cos, &local_sin, &sliding_mask)?
+            } else {
+                layer.forward(&xs, &global_cos, &global_sin, &global_mask)?
+            };
+        }
+        let xs = self.norm.forward(&xs)?;
+
+        let mask = attention_mask.to_dtype(self.dtype)?.unsqueeze(D::Minus1)?;
+        let summed = xs.broadcast_mul(&mask)?.sum(1)?;
+        let counts = mask.sum(1)?.maximum(1.0)?;
+        summed.broadcast_div(&counts)
+    }
+
+    /// Additive (batch, 1, seq, seq) mask hiding padding and, for sliding layers, tokens outside the window
+    fn attention_mask(&self, attention_mask: &Tensor, window: Option<usize>) -> Result<Tensor> {
+        let (b_sz, seq_len) = attention_mask.dims2()?;
+        let keep = attention_mask.to_vec2::<u32>()?;
+        let mask: Vec<f32> = keep.iter()
+            .flat_map(|row| {
+                (0..seq_len).flat_map(move |i| {
+                    row.iter().enumerate().map(move |(j, &keep)| {
+                        // Every token sees itself, so padding rows never mask out completely and turn into NaN
+                        let in_window = window.is_none_or(|window| i.abs_diff(j) < window);
+                        if (keep == 1 && in_window) || i == j { 0.0 } else { f32::NEG_INFINITY }
+                    })
+                })
+            })
+            .collect();
+        Tensor::from_vec(mask, (b_sz, 1, seq_len, seq_len), &self.device)?
+            .to_dtype(self.dtype)
+    }
+}
+
+/// A sentence-transformers `Dense` module: a linear projection with an optional tanh
+#[derive(Debug, Clone)]
+pub struct Dense {
+    linear: Linear,
+    tanh: bool,
+}
+
+impl Dense {
+    pub fn new(in_features: usize, out_features: usize, bias: bool, tanh: bool, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            linear: linear_b(in_features, out_features, bias, vb.pp("linear"))?,
+            tanh,
+        })
+    }
+}
+
+impl Module for Dense {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.linear.forward(xs)?;
+        if self.tanh { xs.tanh() } else { Ok(xs) }
+    }
+}
+
+fn rope_tables(cfg: &Config, base: f64, dtype: DType, device: &Device) -> Result<(Tensor, Tensor)> {
+    let head_dim = cfg.head_dim;
+    let max_positions = cfg.max_position_embeddings;
+    let inv_freq: Vec<f32> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1.0 / base.powf(i as f64 / head_dim as f64) as f32)
+        .collect();
+    let inv_freq_len = inv_freq.len();
+    let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), device)?;
+    let positions = Tensor::arange(0u32, max_positions as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((max_positions, 1))?;
+    let freqs = positions.matmul(&inv_freq)?;
+    Ok((freqs.cos()?.to_dtype(dtype)?, freqs.sin()?.to_dtype(dtype)?))
+}
@@ -0,0 +1,386 @@
+/*!
+ * Embeddings
+ *
+ * Text embeddings shared by the Tauri commands, the local API server and
+ * semantic search, computed with an EmbeddingGemma model from
+ * `models/embedding`. Each text gets the model's prompt for its task type,
+ * texts are encoded in batches, mean pooled, passed through the
+ * sentence-transformers Dense layers and normalized. Matryoshka truncation to
+ * 512, 256 or 128 dimensions keeps the leading components and normalizes again.
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use anyhow::{Result, anyhow};
+use tokenizers::Tokenizer;
+use tokio::sync::RwLock;
+use tracing::info;
+
+use super::model_manager::{ModelInfo, ModelManager};
+
+#[cfg(feature = "ai_candle")]
+use std::path::Path;
+#[cfg(feature = "ai_candle")]
+use serde::Deserialize;
+#[cfg(feature = "ai_candle")]
+use candle_core::{DType, Device, Module, Tensor};
+#[cfg(feature = "ai_candle")]
+use candle_nn::VarBuilder;
+#[cfg(feature = "ai_candle")]
+use super::embedding_gemma;
+#[cfg(feature = "ai_candle")]
+use super::transformers;
+
+/// Dimensions EmbeddingGemma was trained to be truncated to
+pub const MATRYOSHKA_DIMENSIONS: &[usize] = &[768, 512, 256, 128];
+
+/// Texts encoded per forward pass
+const BATCH_SIZE: usize = 16;
+
+/// Input limit when the model doesn't state one in `sentence_bert_config.json`
+#[cfg(feature = "ai_candle")]
+const DEFAULT_MAX_TOKENS: usize = 2048;
+
+/// EmbeddingGemma's prompts by task type, for checkpoints without `config_sentence_transformers.json`
+#[cfg(feature = "ai_candle")]
+const DEFAULT_PROMPTS: &[(&str, &str)] = &[
+    ("query", "task: search result | query: "),
+    ("document", "title: none | text: "),
+    ("BitextMining", "task: search result | query: "),
+    ("Clustering", "task: clustering | query: "),
+    ("Classification", "task: classification | query: "),
+    ("InstructionRetrieval", "task: code retrieval | query: "),
+    ("MultilabelClassification", "task: classification | query: "),
+    ("PairClassification", "task: sentence similarity | query: "),
+    ("Reranking", "task: search result | query: "),
+    ("Retrieval", "task: search result | query: "),
+    ("Retrieval-query", "task: search result | query: "),
+    ("Retrieval-document", "title: none | text: "),
+    ("STS", "task: sentence similarity | query: "),
+    ("Summarization", "task: summarization | query: "),
+];
+
+/// Task type names used by the app, mapped to prompt names
+const TASK_ALIASES: &[(&str, &str)] = &[
+    ("search_query", "query"),
+    ("search_document", "document"),
+    ("similarity", "STS"),
+    ("sentence_similarity", "STS"),
+    ("code_retrieval", "InstructionRetrieval"),
+];
+
+#[cfg(feature = "ai_candle")]
+#[derive(Debug, Deserialize)]
+struct SentenceTransformersConfig {
+    #[serde(default)]
+    prompts: HashMap<String, String>,
+}
+
+#[cfg(feature = "ai_candle")]
+#[derive(Debug, Deserialize)]
+struct SentenceBertConfig {
+    max_seq_length: Option<usize>,
+}
+
+/// One entry of `modules.json`
+#[cfg(feature = "ai_candle")]
+#[derive(Debug, Deserialize)]
+struct ModuleEntry {
+    path: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[cfg(feature = "ai_candle")]
+#[derive(Debug, Deserialize)]
+struct DenseConfig {
+    in_features: usize,
+    out_features: usize,
+    #[serde(default)]
+    bias: bool,
+    #[serde(default)]
+    activation_function: String,
+}
+
+#[cfg(feature = "ai_candle")]
+#[derive(Debug, Deserialize)]
+struct PoolingConfig {
+    #[serde(default)]
+    pooling_mode_mean_tokens: bool,
+}
+
+/// A loaded embedding model
+pub struct EmbeddingModel {
+    model_id: String,
+    tokenizer: Tokenizer,
+    /// Prompt prefix by task type
+    prompts: HashMap<String, String>,
+    max_tokens: usize,
+    #[cfg(feature = "ai_candle")]
+    pad_token_id: u32,
+    /// Length of the vectors before Matryoshka truncation
+    dimension: usize,
+    #[cfg(feature = "ai_candle")]
+    encoder: embedding_gemma::Model,
+    #[cfg(feature = "ai_candle")]
+    dense: Vec<embedding_gemma::Dense>,
+    #[cfg(feature = "ai_candle")]
+    device: Device,
+}
+
+impl EmbeddingModel {
+    /// Load a sentence-transformers EmbeddingGemma directory
+    #[cfg(feature = "ai_candle")]
+    pub fn load(model_info: &ModelInfo) -> Result<Self> {
+        let model_dir = model_info.path.as_path();
+        let config_path = model_dir.join("config.json");
+        let config_json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&config_path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", config_path.display(), e))?)?;
+        let model_type = config_json.get("model_type").and_then(|v| v.as_str()).unwrap_or("unknown");
+        if model_type != "gemma3_text" {
+            return Err(anyhow!("Unsupported embedding model architecture in {} (model_type: {})", config_path.display(), model_type));
+        }
+        let config: embedding_gemma::Config = serde_json::from_value(config_json)?;
+
+        let tokenizer_path = model_dir.join("tokenizer.json");
+        let tokenizer = Tokenizer::from_file(&tokenizer_path)
+            .map_err(|e| anyhow!("Failed to load tokenizer {}: {}", tokenizer_path.display(), e))?;
+        let pad_token_id = tokenizer.get_padding().map(|padding| padding.pad_id)
+            .or_else(|| tokenizer.token_to_id("<pad>"))
+            .unwrap_or(0);
+
+        let device = Device::Cpu;
+        let dtype = DType::F32;
+        let encoder = embedding_gemma::Model::new(&config, load_weights(model_dir, dtype, &device)?)?;
+
+        let mut dense = Vec::new();
+        let mut dimension = config.hidden_size;
+        for module in read_modules(model_dir)? {
+            let module_dir = model_dir.join(&module.path);
+            if module.kind.ends_with("Pooling") {
+                let pooling: PoolingConfig = read_json(&module_dir.join("config.json"))?;
+                if !pooling.pooling_mode_mean_tokens {
+                    tracing::warn!("⚠️ {} asks for a pooling mode other than mean; mean pooling is used", module_dir.display());
+                }
+            } else if module.kind.ends_with("Dense") {
+                let dense_config: DenseConfig = read_json(&module_dir.join("config.json"))?;
+                let tanh = dense_config.activation_function.ends_with("Tanh");
+                if !tanh && !dense_config.activation_function.ends_with("Identity") {
+                    return Err(anyhow!("Unsupported activation {} in {}", dense_config.activation_function, module_dir.display()));
+                }
+                dense.push(embedding_gemma::Dense::new(
+                    dense_config.in_features,
+                    dense_config.out_features,
+                    dense_config.bias,
+                    tanh,
+                    load_weights(&module_dir, dtype, &device)?,
+                )?);
+                dimension = dense_config.out_features;
+            }
+        }
+
+        let prompts = match read_json::<SentenceTransformersConfig>(&model_dir.join("config_sentence_transformers.json")) {
+            Ok(st_config) if !st_config.prompts.is_empty() => st_config.prompts,
+            _ => DEFAULT_PROMPTS.iter().map(|(task, prompt)| (task.to_string(), prompt.to_string())).collect(),
+        };
+        let max_tokens = read_json::<SentenceBertConfig>(&model_dir.join("sentence_bert_config.json")).ok()
+            .and_then(|config| config.max_seq_length)
+            .unwrap_or(DEFAULT_MAX_TOKENS)
+            .min(encoder.max_positions());
+
+        Ok(Self {
+            model_id: model_info.id.clone(),
+            tokenizer,
+            prompts,
+            max_tokens,
+            pad_token_id,
+            dimension,
+            encoder,
+            dense,
+            device,
+        })
+    }
+
+    #[cfg(not(feature = "ai_candle"))]
+    pub fn load(model_info: &ModelInfo) -> Result<Self> {
+        Err(anyhow!("Cannot load {}: embedding models need Candle support. Rebuild with `--features ai_candle`.",
+            model_info.name))
+    }
+
+    pub fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    /// Length of the full vectors
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// Whether `name` refers to this model: its id, or a repo id ending in it (`google/embeddinggemma-300m`)
+    pub fn matches(&self, name: &str) -> bool {
+        name == self.model_id || name.rsplit('/').next() == Some(self.model_id.as_str())
+    }
+
+    /// The prompt prefix for `task_type`: a prompt name (`query`, `document`, `Clustering`, ...),
+    /// matched case-insensitively, or one of the app's aliases
+    pub fn prompt(&self, task_type: &str) -> Result<&str> {
+        let task_type = TASK_ALIASES.iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(task_type))
+            .map_or(task_type, |(_, name)| name);
+        self.prompts.get(task_type)
+            .or_else(|| self.prompts.iter().find(|(name, _)| name.eq_ignore_ascii_case(task_type)).map(|(_, prompt)| prompt))
+            .map(String::as_str)
+            .ok_or_else(|| {
+                let mut known: Vec<&str> = self.prompts.keys().map(String::as_str).collect();
+                known.sort();
+                anyhow!("Unknown embedding task type '{}'; expected one of {}", task_type, known.join(", "))
+            })
+    }
+
+    /// Number of tokens `texts` take with the prompt for `task_type`, after truncation
+    pub fn count_tokens(&self, texts: &[String], task_type: &str) -> Result<usize> {
+        Ok(self.tokenize(texts, task_type)?.iter().map(Vec::len).sum())
+    }
+
+    fn tokenize(&self, texts: &[String], task_type: &str) -> Result<Vec<Vec<u32>>> {
+        let prompt = self.prompt(task_type)?;
+        texts.iter()
+            .map(|text| {
+                let encoding = self.tokenizer.encode(format!("{}{}", prompt, text), true)
+                    .map_err(|e| anyhow!("Failed to tokenize text: {}", e))?;
+                let mut ids = encoding.get_ids().to_vec();
+                ids.truncate(self.max_tokens);
+                Ok(ids)
+            })
+            .collect()
+    }
+
+    /// Unit vectors for `texts`, truncated to `dimension` (the full dimension if None)
+    pub fn encode(&self, texts: &[String], task_type: &str, dimension: Option<usize>) -> Result<Vec<Vec<f32>>> {
+        let dimension = dimension.unwrap_or(self.dimension);
+        if dimension > self.dimension || (dimension != self.dimension && !MATRYOSHKA_DIMENSIONS.contains(&dimension)) {
+            return Err(anyhow!("{} can't produce {}-dimensional embeddings; use one of {:?} up to {}",
+                self.model_id, dimension, MATRYOSHKA_DIMENSIONS, self.dimension));
+        }
+        let token_ids = self.tokenize(texts, task_type)?;
+
+        // Batch texts of similar length together to keep padding down
+        let mut order: Vec<usize> = (0..token_ids.len()).collect();
+        order.sort_by_key(|&index| token_ids[index].len());
+        let mut embeddings = vec![Vec::new(); token_ids.len()];
+        for batch in order.chunks(BATCH_SIZE) {
+            let ids: Vec<&[u32]> = batch.iter().map(|&index| token_ids[index].as_slice()).collect();
+            for (&index, embedding) in batch.iter().zip(self.embed_batch(&ids)?) {
+                embeddings[index] = truncate_embedding(&embedding, dimension);
+            }
+        }
+        Ok(embeddings)
+    }
+
+    /// Pooled, projected vectors for one batch of token ids, not yet normalized
+    #[cfg(feature = "ai_candle")]
+    fn embed_batch(&self, token_ids: &[&[u32]]) -> Result<Vec<Vec<f32>>> {
+        let seq_len = token_ids.iter().map(|ids| ids.len()).max().unwrap_or(0).max(1);
+        let mut input_ids = Vec::with_capacity(token_ids.len() * seq_len);
+        let mut attention_mask = Vec::with_capacity(token_ids.len() * seq_len);
+        for ids in token_ids {
+            input_ids.extend_from_slice(ids);
+            input_ids.extend(std::iter::repeat_n(self.pad_token_id, seq_len - ids.len()));
+            attention_mask.extend(std::iter::repeat_n(1u32, ids.len()));
+            attention_mask.extend(std::iter::repeat_n(0u32, seq_len - ids.len()));
+        }
+        let input_ids = Tensor::from_vec(input_ids, (token_ids.len(), seq_len), &self.device)?;
+        let attention_mask = Tensor::from_vec(attention_mask, (token_ids.len(), seq_len), &self.device)?;
+
+        let mut pooled = self.encoder.embed(&input_ids, &attention_mask)?;
+        for dense in &self.dense {
+            pooled = dense.forward(&pooled)?;
+        }
+        Ok(pooled.to_vec2::<f32>()?)
+    }
+
+    #[cfg(not(feature = "ai_candle"))]
+    fn embed_batch(&self, _token_ids: &[&[u32]]) -> Result<Vec<Vec<f32>>> {
+        Err(anyhow!("Embedding support is not enabled in this build. Rebuild with `--features ai_candle`."))
+    }
+}
+
+/// The leading `dimension` components of `embedding`, scaled back to unit length
+pub fn truncate_embedding(embedding: &[f32], dimension: usize) -> Vec<f32> {
+    let mut truncated = embedding[..dimension.min(embedding.len())].to_vec();
+    let norm = truncated.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for value in &mut truncated {
+            *value /= norm;
+        }
+    }
+    truncated
+}
+
+/// Cosine similarity; 0.0 for vectors of different lengths or zero vectors
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|y| y * y).sum::<f32>().sqrt();
+    if norms > 0.0 { dot / norms } else { 0.0 }
+}
+
+/// The resident embedding model, loading `name` (or the first model in `models/embedding`) if needed
+///
+/// Loading happens outside the manager's lock so generations aren't held up.
+pub async fn resident_model(models: &RwLock<ModelManager>, name: Option<&str>) -> Result<Arc<EmbeddingModel>> {
+    if let Some(model) = models.read().await.embedding_model() {
+        if name.is_none_or(|name| model.matches(name)) {
+            return Ok(model);
+        }
+    }
+
+    let model_info = models.read().await.find_embedding_model(name).await?;
+    info!("📥 Loading embedding model: {}", model_info.name);
+    let started = Instant::now();
+    let model = tokio::task::spawn_blocking(move || EmbeddingModel::load(&model_info)).await
+        .map_err(|e| anyhow!("Embedding model loading failed: {}", e))??;
+    let model = Arc::new(model);
+    info!("✅ Embedding model {} loaded in {} ms ({} dimensions)",
+          model.model_id(), started.elapsed().as_millis(), model.dimension());
+    models.write().await.set_embedding_model(model.clone());
+    Ok(model)
+}
+
+/// Encode `texts` on a blocking thread
+pub async fn encode(model: Arc<EmbeddingModel>, texts: Vec<String>, task_type: String, dimension: Option<usize>) -> Result<Vec<Vec<f32>>> {
+    tokio::task::spawn_blocking(move || model.encode(&texts, &task_type, dimension)).await
+        .map_err(|e| anyhow!("Embedding task failed: {}", e))?
+}
+
+#[cfg(feature = "ai_candle")]
+fn load_weights(dir: &Path, dtype: DType, device: &Device) -> Result<VarBuilder<'static>> {
+    let mut tensors = HashMap::new();
+    for file in transformers::find_safetensors(dir)? {
+        for (name, tensor) in candle_core::safetensors::load(&file, device)? {
+            tensors.insert(name, tensor.to_dtype(dtype)?);
+        }
+    }
+    Ok(VarBuilder::from_tensors(tensors, dtype, device))
+}
+
+/// The modules of a sentence-transformers model; none for a bare encoder
+#[cfg(feature = "ai_candle")]
+fn read_modules(model_dir: &Path) -> Result<Vec<ModuleEntry>> {
+    let path = model_dir.join("modules.json");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    read_json(&path)
+}
+
+#[cfg(feature = "ai_candle")]
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&content)
+        .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))
+}
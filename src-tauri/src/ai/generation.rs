@@ -0,0 +1,415 @@
+/*!
+ * Generation Helpers
+ *
+ * Backend-independent pieces of the token sampling loop: accumulating decoded
+ * token bytes without splitting UTF-8 characters, honoring stop sequences,
+ * streaming text deltas to the caller as they become safe to show,
+ * cancelling requests by id and keeping evaluated prompt state between requests.
+ */
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Receives text deltas while a backend generates. Returning false stops generation.
+///
+/// Called once per sampled token; the delta is empty while text is being held back.
+pub type TokenCallback<'a> = dyn FnMut(&str) -> bool + Send + 'a;
+
+/// Why a generation finished
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model produced an end-of-generation token
+    Eos,
+    /// One of the configured stop sequences was produced
+    StopSequence,
+    /// `max_tokens` or the context window was exhausted
+    Length,
+    /// The token callback asked to stop
+    Cancelled,
+}
+
+/// Result of a finished generation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationOutput {
+    pub text: String,
+    pub prompt_tokens: usize,
+    /// Prompt tokens whose attention state was reused from an earlier request instead of evaluated
+    #[serde(default)]
+    pub cached_prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub finish_reason: FinishReason,
+}
+
+/// Running average of a latency, e.g. time to first token or queue wait
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct LatencyStats {
+    pub samples: u64,
+    pub average_ms: f64,
+    pub last_ms: u64,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, ms: u64) {
+        self.samples += 1;
+        self.average_ms += (ms as f64 - self.average_ms) / self.samples as f64;
+        self.last_ms = ms;
+    }
+}
+
+/// Time to first token, split by whether the prompt prefix came from the KV cache
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct FirstTokenLatency {
+    /// The whole prompt was evaluated
+    pub cold: LatencyStats,
+    /// Only the part after a cached prefix was evaluated
+    pub reused: LatencyStats,
+}
+
+impl FirstTokenLatency {
+    pub fn record(&mut self, ms: u64, cached_prompt_tokens: usize) {
+        if cached_prompt_tokens > 0 {
+            self.reused.record(ms);
+        } else {
+            self.cold.record(ms);
+        }
+    }
+}
+
+/// Evaluated prompt state that a later prompt sharing its leading tokens can resume from
+pub trait PromptState {
+    type Token: PartialEq;
+
+    /// Tokens whose state this holds
+    fn tokens(&self) -> &[Self::Token];
+
+    /// Context size the state was captured with
+    fn n_ctx(&self) -> u32;
+
+    /// Number of leading `tokens` whose state this holds, leaving at least one to evaluate
+    fn reusable(&self, tokens: &[Self::Token], n_ctx: u32) -> usize {
+        if self.n_ctx() < n_ctx {
+            return 0;
+        }
+        let common = self.tokens().iter().zip(tokens).take_while(|(a, b)| a == b).count();
+        common.min(tokens.len().saturating_sub(1))
+    }
+}
+
+/// The saved state sharing the most of `tokens` with how many it covers, if any shares some
+pub fn best_resume<'a, S: PromptState + 'a>(
+    saved: impl IntoIterator<Item = &'a S>,
+    tokens: &[S::Token],
+    n_ctx: u32,
+) -> Option<(&'a S, usize)> {
+    saved.into_iter()
+        .map(|state| (state, state.reusable(tokens, n_ctx)))
+        .filter(|(_, common)| *common > 0)
+        .max_by_key(|(_, common)| *common)
+}
+
+/// Prompt state kept between requests: the state after the last chat turn and after the shared preamble
+///
+/// Only requests with `reuse_prompt_cache` set read or replace it, so one-off
+/// prompts such as API requests leave the chat's cache in place.
+pub struct PromptCache<S> {
+    session: Mutex<Option<S>>,
+    prefix_snapshot: Mutex<Option<Arc<S>>>,
+}
+
+impl<S> Default for PromptCache<S> {
+    fn default() -> Self {
+        Self {
+            session: Mutex::new(None),
+            prefix_snapshot: Mutex::new(None),
+        }
+    }
+}
+
+impl<S> PromptCache<S> {
+    /// States a request may resume from; nothing unless it reuses the cache.
+    /// The session is taken so a failed request cannot leave stale state behind.
+    pub fn checkout(&self, reuse: bool) -> (Option<S>, Option<Arc<S>>) {
+        if !reuse {
+            return (None, None);
+        }
+        (self.session.lock().ok().and_then(|mut session| session.take()),
+         self.prefix_snapshot.lock().ok().and_then(|snapshot| snapshot.clone()))
+    }
+
+    /// Keep the state after a request for the next one, unless the request opted out of the cache.
+    /// `capture` only runs when the state is kept.
+    pub fn save_session(&self, reuse: bool, capture: impl FnOnce() -> S) {
+        if reuse {
+            if let Ok(mut saved) = self.session.lock() {
+                *saved = Some(capture());
+            }
+        }
+    }
+
+    pub fn save_snapshot(&self, snapshot: S) {
+        if let Ok(mut saved) = self.prefix_snapshot.lock() {
+            *saved = Some(Arc::new(snapshot));
+        }
+    }
+
+    /// Forget all saved state, e.g. once it no longer matches the model
+    pub fn clear(&mut self) {
+        if let Ok(session) = self.session.get_mut() {
+            *session = None;
+        }
+        if let Ok(snapshot) = self.prefix_snapshot.get_mut() {
+            *snapshot = None;
+        }
+    }
+}
+
+/// Cancellation flag shared between a running generation and `cancel_generation`
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Generations that are queued or running, keyed by request id
+///
+/// Lives outside the model manager so a request can be cancelled while the
+/// model lock is held by the generation itself.
+#[derive(Debug, Default)]
+pub struct GenerationRegistry {
+    active: HashMap<String, CancelToken>,
+}
+
+impl GenerationRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a request and return the token its sampling loop should poll
+    pub fn register(&mut self, request_id: &str) -> CancelToken {
+        let token = CancelToken::default();
+        self.active.insert(request_id.to_string(), token.clone());
+        token
+    }
+
+    /// Ask a request to stop at its next token. Returns false if the request is unknown.
+    pub fn cancel(&self, request_id: &str) -> bool {
+        match self.active.get(request_id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget a request once it has finished
+    pub fn finish(&mut self, request_id: &str) {
+        self.active.remove(request_id);
+    }
+}
+
+/// Collects the bytes of generated tokens, detects stop sequences and hands out deltas
+pub struct OutputBuffer {
+    text: String,
+    pending: Vec<u8>,
+    stop_sequences: Vec<String>,
+    stop_at: Option<usize>,
+    emitted: usize,
+}
+
+impl OutputBuffer {
+    pub fn new(stop_sequences: &[String]) -> Self {
+        Self {
+            text: String::new(),
+            pending: Vec::new(),
+            stop_sequences: stop_sequences.iter()
+                .filter(|s| !s.is_empty())
+                .cloned()
+                .collect(),
+            stop_at: None,
+            emitted: 0,
+        }
+    }
+
+    /// Append the bytes of one token. Returns true once a stop sequence has been produced.
+    pub fn push(&mut self, piece: &[u8]) -> bool {
+        if self.stop_at.is_some() {
+            return true;
+        }
+
+        self.pending.extend_from_slice(piece);
+        self.decode_pending(false);
+
+        self.stop_at = self.stop_sequences.iter()
+            .filter_map(|stop| self.text.find(stop.as_str()))
+            .min();
+
+        self.stop_at.is_some()
+    }
+
+    /// Pass any text that is safe to show to `on_token`. Returns false if the callback asked to stop.
+    ///
+    /// Incomplete UTF-8 characters and text that could be the start of a stop sequence are held back.
+    pub fn emit(&mut self, on_token: &mut TokenCallback) -> bool {
+        let end = match self.stop_at {
+            Some(stop_at) => stop_at,
+            None => self.holdback_start(),
+        };
+        self.emit_until(end, on_token)
+    }
+
+    /// Flush everything that was held back and return the final text
+    pub fn finish(mut self, on_token: &mut TokenCallback) -> String {
+        self.decode_pending(true);
+        let end = self.stop_at.unwrap_or(self.text.len());
+        self.emit_until(end, on_token);
+
+        self.text.truncate(end);
+        self.text
+    }
+
+    fn emit_until(&mut self, end: usize, on_token: &mut TokenCallback) -> bool {
+        let start = self.emitted.min(end);
+        self.emitted = self.emitted.max(end);
+        on_token(&self.text[start..end])
+    }
+
+    /// Start of the longest suffix that could still grow into a stop sequence
+    fn holdback_start(&self) -> usize {
+        self.text[self.emitted..].char_indices()
+            .map(|(i, _)| self.emitted + i)
+            .find(|&i| {
+                let tail = &self.text[i..];
+                self.stop_sequences.iter().any(|stop| stop.starts_with(tail))
+            })
+            .unwrap_or(self.text.len())
+    }
+
+    /// Move complete characters from `pending` into `text`, replacing invalid bytes
+    fn decode_pending(&mut self, flush: bool) {
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    self.text.push_str(valid);
+                    self.pending.clear();
+                    return;
+                }
+                Err(e) => {
+                    let valid_up_to = e.valid_up_to();
+                    // The prefix up to `valid_up_to` has just been validated
+                    self.text.push_str(std::str::from_utf8(&self.pending[..valid_up_to]).unwrap_or_default());
+                    match e.error_len() {
+                        Some(invalid) => {
+                            self.text.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid_up_to + invalid);
+                        }
+                        None if flush => {
+                            self.text.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.clear();
+                            return;
+                        }
+                        None => {
+                            // Incomplete character at the end; wait for the next token
+                            self.pending.drain(..valid_up_to);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestState {
+        tokens: Vec<u32>,
+        n_ctx: u32,
+    }
+
+    impl PromptState for TestState {
+        type Token = u32;
+
+        fn tokens(&self) -> &[u32] {
+            &self.tokens
+        }
+
+        fn n_ctx(&self) -> u32 {
+            self.n_ctx
+        }
+    }
+
+    /// One request the way a backend runs it; returns the number of prompt tokens taken from the cache
+    fn run(cache: &PromptCache<TestState>, prompt: &[u32], reuse: bool) -> usize {
+        let (session, snapshot) = cache.checkout(reuse);
+        let cached = best_resume([session.as_ref(), snapshot.as_deref()].into_iter().flatten(), prompt, 64)
+            .map_or(0, |(_, common)| common);
+        let mut evaluated = prompt.to_vec();
+        evaluated.push(99);
+        cache.save_session(reuse, || TestState { tokens: evaluated, n_ctx: 64 });
+        cached
+    }
+
+    #[test]
+    fn one_off_requests_leave_the_chat_session_in_place() {
+        let cache = PromptCache::default();
+        assert_eq!(run(&cache, &[1, 2, 3], true), 0);
+
+        // An API request with a different prompt neither uses nor replaces the chat's session
+        assert_eq!(run(&cache, &[7, 8, 9, 10], false), 0);
+
+        // The next chat turn extends the first one and resumes after its reply
+        assert_eq!(run(&cache, &[1, 2, 3, 99, 4, 5], true), 4);
+    }
+
+    #[test]
+    fn resumes_from_whichever_state_shares_more() {
+        let session = TestState { tokens: vec![1, 2, 3, 4], n_ctx: 64 };
+        let snapshot = TestState { tokens: vec![1, 2], n_ctx: 64 };
+        let (best, common) = best_resume([&session, &snapshot], &[1, 2, 3, 5], 64).unwrap();
+        assert_eq!((best.tokens.len(), common), (4, 3));
+        let (_, common) = best_resume([&session, &snapshot], &[1, 2, 6], 64).unwrap();
+        assert_eq!(common, 2);
+
+        assert!(best_resume([&session, &snapshot], &[5, 1, 2], 64).is_none());
+        // A context smaller than the request needs cannot be reused
+        assert!(best_resume([&session], &[1, 2, 3, 5], 128).is_none());
+    }
+
+    #[test]
+    fn always_leaves_a_token_to_evaluate() {
+        let state = TestState { tokens: vec![1, 2, 3], n_ctx: 64 };
+        assert_eq!(state.reusable(&[1, 2, 3], 64), 2);
+        assert_eq!(state.reusable(&[1], 64), 0);
+        assert_eq!(state.reusable(&[], 64), 0);
+    }
+
+    #[test]
+    fn snapshots_survive_a_failed_request() {
+        let mut cache = PromptCache::default();
+        cache.save_snapshot(TestState { tokens: vec![1, 2], n_ctx: 64 });
+        cache.save_session(true, || TestState { tokens: vec![1, 2, 3], n_ctx: 64 });
+
+        let (session, snapshot) = cache.checkout(true);
+        assert!(session.is_some() && snapshot.is_some());
+        // Nothing was saved back, so only the snapshot is left
+        let (session, snapshot) = cache.checkout(true);
+        assert!(session.is_none());
+        assert_eq!(snapshot.unwrap().tokens, [1, 2]);
+
+        cache.clear();
+        assert!(cache.checkout(true).1.is_none());
+    }
+}
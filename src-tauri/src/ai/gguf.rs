@@ -0,0 +1,690 @@
+/*!
+ * GGUF Metadata Reader
+ *
+ * Reads the header of a GGUF file (key/value metadata and tensor descriptors)
+ * without llama.cpp and without touching the tensor data, so discovery can
+ * report architecture, context window, quantization and parameter count for
+ * multi-GB models in a few milliseconds.
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+use anyhow::{Result, anyhow, bail};
+
+use super::chat_template::ModelTemplateInfo;
+use super::model_manager::ModelMetadata;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// Refuse absurd lengths from corrupt headers instead of allocating them
+const MAX_STRING_LEN: u64 = 64 * 1024 * 1024;
+const MAX_ARRAY_LEN: u64 = 1 << 26;
+const MAX_TENSOR_DIMS: u32 = 8;
+/// Arrays of arrays are read recursively; deeper nesting only comes from crafted files
+const MAX_ARRAY_DEPTH: u32 = 8;
+
+/// Arrays longer than this are skipped unless they are the vocabulary
+const MAX_KEPT_ARRAY_LEN: u64 = 1024;
+const TOKENS_KEY: &str = "tokenizer.ggml.tokens";
+
+/// Tensor data starts at the next multiple of this after the header unless `general.alignment` says otherwise
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+/// A metadata value from the GGUF key/value section
+#[derive(Debug, Clone)]
+pub enum GgufValue {
+    UInt(u64),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Array(Vec<GgufValue>),
+    /// Large arrays (scores, merges, ...) are skipped; only their length is kept
+    SkippedArray(u64),
+}
+
+impl GgufValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            GgufValue::UInt(v) => Some(*v),
+            GgufValue::Int(v) => u64::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn array_len(&self) -> Option<u64> {
+        match self {
+            GgufValue::Array(items) => Some(items.len() as u64),
+            GgufValue::SkippedArray(len) => Some(*len),
+            _ => None,
+        }
+    }
+}
+
+/// Shape and storage type of one tensor
+#[derive(Debug, Clone)]
+pub struct GgufTensorInfo {
+    pub dims: Vec<u64>,
+    pub ggml_type: u32,
+    /// Offset of the tensor's data relative to the start of the data section
+    pub offset: u64,
+}
+
+impl GgufTensorInfo {
+    /// Number of weights, or None if the dimensions overflow
+    pub fn element_count(&self) -> Option<u64> {
+        self.dims.iter().try_fold(1u64, |count, &dim| count.checked_mul(dim))
+    }
+
+    /// Bytes of tensor data, if the storage type is known and the size fits
+    pub fn byte_size(&self) -> Option<u64> {
+        let (block_elements, block_bytes) = ggml_type_block(self.ggml_type)?;
+        (self.element_count()? / block_elements).checked_mul(block_bytes)
+    }
+}
+
+/// Parsed GGUF header
+#[derive(Debug, Clone)]
+pub struct GgufHeader {
+    pub version: u32,
+    pub metadata: HashMap<String, GgufValue>,
+    pub tensors: Vec<GgufTensorInfo>,
+    /// Absolute file offset where tensor data begins
+    pub data_offset: u64,
+}
+
+impl GgufHeader {
+    /// Read the header of `path`. Blocking; call from `spawn_blocking` in async code.
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+        Self::read_from(&mut BufReader::new(file))
+            .map_err(|e| anyhow!("Invalid GGUF header in {}: {}", path.display(), e))
+    }
+
+    fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != GGUF_MAGIC {
+            bail!("not a GGUF file");
+        }
+
+        let version = read_u32(reader)?;
+        if !(1..=3).contains(&version) {
+            bail!("unsupported GGUF version {}", version);
+        }
+        let mut parser = Parser { reader, version };
+
+        let tensor_count = parser.read_count()?;
+        let kv_count = parser.read_count()?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..kv_count {
+            let key = parser.read_string()?;
+            let value_type = read_u32(parser.reader)?;
+            let keep_array = key == TOKENS_KEY;
+            let value = parser.read_value(value_type, keep_array, 0)?;
+            metadata.insert(key, value);
+        }
+
+        let mut tensors = Vec::with_capacity(tensor_count.min(4096) as usize);
+        for _ in 0..tensor_count {
+            let name = parser.read_string()?;
+            let n_dims = read_u32(parser.reader)?;
+            if n_dims > MAX_TENSOR_DIMS {
+                bail!("tensor {} has {} dimensions", name, n_dims);
+            }
+            let dims = (0..n_dims)
+                .map(|_| parser.read_count())
+                .collect::<Result<Vec<_>>>()?;
+            let ggml_type = read_u32(parser.reader)?;
+            let offset = read_u64(parser.reader)?;
+            let tensor = GgufTensorInfo { dims, ggml_type, offset };
+            if tensor.element_count().is_none() {
+                bail!("tensor {} has too many elements", name);
+            }
+            tensors.push(tensor);
+        }
+
+        let alignment = metadata.get("general.alignment")
+            .and_then(GgufValue::as_u64)
+            .filter(|&a| a > 0)
+            .unwrap_or(DEFAULT_ALIGNMENT);
+        let header_end = parser.reader.stream_position()?;
+        let data_offset = header_end.div_ceil(alignment).checked_mul(alignment)
+            .ok_or_else(|| anyhow!("alignment {} is too large", alignment))?;
+
+        // Sizes that overflow are corrupt, not unknown, so integrity checks don't pass them
+        for (index, tensor) in tensors.iter().enumerate() {
+            let end = tensor.byte_size()
+                .and_then(|size| data_offset.checked_add(tensor.offset)?.checked_add(size));
+            if ggml_type_block(tensor.ggml_type).is_some() && end.is_none() {
+                bail!("tensor #{} ends beyond any possible file size", index);
+            }
+        }
+
+        Ok(Self { version, metadata, tensors, data_offset })
+    }
+
+    /// Size the file must have to hold every tensor, or None if a storage type is unknown
+    /// or the size does not fit in 64 bits
+    pub fn expected_file_size(&self) -> Option<u64> {
+        self.tensors.iter()
+            .map(|tensor| tensor.offset.checked_add(tensor.byte_size()?))
+            .try_fold(0u64, |end, tensor_end| Some(end.max(tensor_end?)))
+            .and_then(|end| self.data_offset.checked_add(end))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(GgufValue::as_str)
+    }
+
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(GgufValue::as_u64)
+    }
+
+    /// `general.architecture`, e.g. `llama`, `qwen2`, `gemma3`, `lfm2`
+    pub fn architecture(&self) -> Option<&str> {
+        self.get_str("general.architecture")
+    }
+
+    /// Architecture-scoped key such as `llama.context_length`
+    fn arch_u64(&self, suffix: &str) -> Option<u64> {
+        self.get_u64(&format!("{}.{}", self.architecture()?, suffix))
+    }
+
+    /// Text of a special token, looked up by its id in the vocabulary
+    fn token_text(&self, id_key: &str) -> Option<String> {
+        let id = self.get_u64(id_key)?;
+        match self.get(TOKENS_KEY)? {
+            GgufValue::Array(tokens) => tokens.get(id as usize)?.as_str().map(str::to_string),
+            _ => None,
+        }
+    }
+
+    /// Total number of weights across all tensors
+    pub fn parameter_count(&self) -> u64 {
+        self.tensors.iter()
+            .filter_map(GgufTensorInfo::element_count)
+            .fold(0, u64::saturating_add)
+    }
+
+    /// Quantization from `general.file_type`, or the storage type holding most weights
+    pub fn quantization(&self) -> Option<String> {
+        if let Some(name) = self.get_u64("general.file_type").and_then(file_type_name) {
+            return Some(name.to_string());
+        }
+
+        let mut weights_by_type: HashMap<u32, u64> = HashMap::new();
+        for tensor in self.tensors.iter().filter(|t| t.dims.len() >= 2) {
+            let count = weights_by_type.entry(tensor.ggml_type).or_default();
+            *count = count.saturating_add(tensor.element_count().unwrap_or_default());
+        }
+        weights_by_type.into_iter()
+            .max_by_key(|(_, count)| *count)
+            .and_then(|(ggml_type, _)| ggml_type_name(ggml_type))
+            .map(str::to_string)
+    }
+
+    /// Summary shown in model discovery
+    pub fn model_metadata(&self) -> ModelMetadata {
+        let parameter_count = self.parameter_count();
+        ModelMetadata {
+            architecture: self.architecture().map(str::to_string),
+            context_length: self.arch_u64("context_length"),
+            embedding_length: self.arch_u64("embedding_length"),
+            layer_count: self.arch_u64("block_count"),
+            quantization: self.quantization(),
+            parameter_count: (parameter_count > 0).then_some(parameter_count),
+            tokenizer: self.get_str("tokenizer.ggml.model").map(str::to_string),
+            vocab_size: self.get(TOKENS_KEY)
+                .and_then(GgufValue::array_len)
+                .or_else(|| self.arch_u64("vocab_size")),
+            has_chat_template: self.get_str("tokenizer.chat_template").is_some(),
+        }
+    }
+
+    /// Embedded chat template and special tokens
+    pub fn template_info(&self, model_name: &str) -> ModelTemplateInfo {
+        ModelTemplateInfo {
+            template: self.get_str("tokenizer.chat_template").map(str::to_string),
+            family_hint: format!("{} {}", self.architecture().unwrap_or_default(), model_name),
+            bos_token: self.token_text("tokenizer.ggml.bos_token_id"),
+            eos_token: self.token_text("tokenizer.ggml.eos_token_id"),
+        }
+    }
+}
+
+struct Parser<'a, R> {
+    reader: &'a mut R,
+    version: u32,
+}
+
+impl<R: Read + Seek> Parser<'_, R> {
+    /// Counts and string lengths were 32-bit in GGUF v1
+    fn read_count(&mut self) -> Result<u64> {
+        if self.version == 1 {
+            Ok(read_u32(self.reader)? as u64)
+        } else {
+            read_u64(self.reader)
+        }
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_count()?;
+        if len > MAX_STRING_LEN {
+            bail!("string of {} bytes", len);
+        }
+        let mut bytes = vec![0u8; len as usize];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn skip_string(&mut self) -> Result<()> {
+        let len = self.read_count()?;
+        if len > MAX_STRING_LEN {
+            bail!("string of {} bytes", len);
+        }
+        self.reader.seek_relative(len as i64)?;
+        Ok(())
+    }
+
+    /// Read a value of `value_type`, `depth` arrays deep
+    fn read_value(&mut self, value_type: u32, keep_array: bool, depth: u32) -> Result<GgufValue> {
+        let reader = &mut *self.reader;
+        Ok(match value_type {
+            0 => GgufValue::UInt(read_array::<1, _>(reader)?[0] as u64),
+            1 => GgufValue::Int(read_array::<1, _>(reader)?[0] as i8 as i64),
+            2 => GgufValue::UInt(u16::from_le_bytes(read_array(reader)?) as u64),
+            3 => GgufValue::Int(i16::from_le_bytes(read_array(reader)?) as i64),
+            4 => GgufValue::UInt(read_u32(reader)? as u64),
+            5 => GgufValue::Int(i32::from_le_bytes(read_array(reader)?) as i64),
+            6 => GgufValue::Float(f32::from_le_bytes(read_array(reader)?) as f64),
+            7 => GgufValue::Bool(read_array::<1, _>(reader)?[0] != 0),
+            8 => GgufValue::Str(self.read_string()?),
+            9 => self.read_array_value(keep_array, depth)?,
+            10 => GgufValue::UInt(read_u64(reader)?),
+            11 => GgufValue::Int(i64::from_le_bytes(read_array(reader)?)),
+            12 => GgufValue::Float(f64::from_le_bytes(read_array(reader)?)),
+            other => bail!("unknown metadata value type {}", other),
+        })
+    }
+
+    fn read_array_value(&mut self, keep: bool, depth: u32) -> Result<GgufValue> {
+        if depth >= MAX_ARRAY_DEPTH {
+            bail!("arrays nested more than {} deep", MAX_ARRAY_DEPTH);
+        }
+        let item_type = read_u32(self.reader)?;
+        let len = self.read_count()?;
+        if len > MAX_ARRAY_LEN {
+            bail!("array of {} items", len);
+        }
+
+        if keep || len <= MAX_KEPT_ARRAY_LEN {
+            let items = (0..len)
+                .map(|_| self.read_value(item_type, false, depth + 1))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(GgufValue::Array(items));
+        }
+
+        match item_type {
+            8 => {
+                for _ in 0..len {
+                    self.skip_string()?;
+                }
+            }
+            9 => {
+                for _ in 0..len {
+                    self.read_array_value(false, depth + 1)?;
+                }
+            }
+            _ => {
+                let item_size = scalar_size(item_type)
+                    .ok_or_else(|| anyhow!("unknown array item type {}", item_type))?;
+                self.reader.seek_relative((item_size * len) as i64)?;
+            }
+        }
+        Ok(GgufValue::SkippedArray(len))
+    }
+}
+
+fn scalar_size(value_type: u32) -> Option<u64> {
+    match value_type {
+        0 | 1 | 7 => Some(1),
+        2 | 3 => Some(2),
+        4..=6 => Some(4),
+        10..=12 => Some(8),
+        _ => None,
+    }
+}
+
+fn read_array<const N: usize, R: Read>(reader: &mut R) -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_array(reader)?))
+}
+
+/// Names of llama.cpp's `llama_ftype` values
+fn file_type_name(file_type: u64) -> Option<&'static str> {
+    Some(match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        _ => return None,
+    })
+}
+
+/// Elements per block and bytes per block of ggml's tensor storage types
+fn ggml_type_block(ggml_type: u32) -> Option<(u64, u64)> {
+    Some(match ggml_type {
+        0 => (1, 4),
+        1 => (1, 2),
+        2 => (32, 18),
+        3 => (32, 20),
+        6 => (32, 22),
+        7 => (32, 24),
+        8 => (32, 34),
+        9 => (32, 36),
+        10 => (256, 84),
+        11 => (256, 110),
+        12 => (256, 144),
+        13 => (256, 176),
+        14 => (256, 210),
+        15 => (256, 292),
+        16 => (256, 66),
+        17 => (256, 74),
+        18 => (256, 98),
+        19 => (256, 50),
+        20 => (32, 18),
+        21 => (256, 110),
+        22 => (256, 82),
+        23 => (256, 136),
+        24 => (1, 1),
+        25 => (1, 2),
+        26 => (1, 4),
+        27 => (1, 8),
+        28 => (1, 8),
+        29 => (256, 56),
+        30 => (1, 2),
+        34 => (256, 54),
+        35 => (256, 66),
+        _ => return None,
+    })
+}
+
+/// Names of ggml's tensor storage types
+fn ggml_type_name(ggml_type: u32) -> Option<&'static str> {
+    Some(match ggml_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        6 => "Q5_0",
+        7 => "Q5_1",
+        8 => "Q8_0",
+        9 => "Q8_1",
+        10 => "Q2_K",
+        11 => "Q3_K",
+        12 => "Q4_K",
+        13 => "Q5_K",
+        14 => "Q6_K",
+        15 => "Q8_K",
+        16 => "IQ2_XXS",
+        17 => "IQ2_XS",
+        18 => "IQ3_XXS",
+        19 => "IQ1_S",
+        20 => "IQ4_NL",
+        21 => "IQ3_S",
+        22 => "IQ2_S",
+        23 => "IQ4_XS",
+        24 => "I8",
+        25 => "I16",
+        26 => "I32",
+        27 => "I64",
+        28 => "F64",
+        29 => "IQ1_M",
+        30 => "BF16",
+        34 => "TQ1_0",
+        35 => "TQ2_0",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Builds GGUF v3 headers in memory
+    #[derive(Default)]
+    struct HeaderBuilder {
+        metadata: Vec<u8>,
+        kv_count: u64,
+        tensors: Vec<u8>,
+        tensor_count: u64,
+    }
+
+    impl HeaderBuilder {
+        fn kv(mut self, key: &str, value_type: u32, value: &[u8]) -> Self {
+            self.metadata.extend(string(key));
+            self.metadata.extend(value_type.to_le_bytes());
+            self.metadata.extend(value);
+            self.kv_count += 1;
+            self
+        }
+
+        fn tensor(self, name: &str, dims: &[u64], ggml_type: u32) -> Self {
+            self.tensor_at(name, dims, ggml_type, 0)
+        }
+
+        fn tensor_at(mut self, name: &str, dims: &[u64], ggml_type: u32, offset: u64) -> Self {
+            self.tensors.extend(string(name));
+            self.tensors.extend((dims.len() as u32).to_le_bytes());
+            for dim in dims {
+                self.tensors.extend(dim.to_le_bytes());
+            }
+            self.tensors.extend(ggml_type.to_le_bytes());
+            self.tensors.extend(offset.to_le_bytes());
+            self.tensor_count += 1;
+            self
+        }
+
+        fn build(self) -> Vec<u8> {
+            let mut bytes = GGUF_MAGIC.to_vec();
+            bytes.extend(3u32.to_le_bytes());
+            bytes.extend(self.tensor_count.to_le_bytes());
+            bytes.extend(self.kv_count.to_le_bytes());
+            bytes.extend(self.metadata);
+            bytes.extend(self.tensors);
+            bytes
+        }
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut bytes = (s.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(s.as_bytes());
+        bytes
+    }
+
+    /// Array value: item type, length and the encoded items
+    fn array(item_type: u32, len: u64, items: &[u8]) -> Vec<u8> {
+        let mut bytes = item_type.to_le_bytes().to_vec();
+        bytes.extend(len.to_le_bytes());
+        bytes.extend(items);
+        bytes
+    }
+
+    /// An array holding one array, `depth` levels deep, with a u8 at the bottom
+    fn nested(depth: u32) -> Vec<u8> {
+        (1..depth).fold(array(0, 1, &[7]), |inner, _| array(9, 1, &inner))
+    }
+
+    fn parse(bytes: &[u8]) -> Result<GgufHeader> {
+        GgufHeader::read_from(&mut Cursor::new(bytes))
+    }
+
+    fn sample() -> Vec<u8> {
+        HeaderBuilder::default()
+            .kv("general.architecture", 8, &string("llama"))
+            .kv("llama.context_length", 4, &4096u32.to_le_bytes())
+            .kv("general.file_type", 4, &15u32.to_le_bytes())
+            .kv(TOKENS_KEY, 9, &array(8, 2, &[string("<s>"), string("</s>")].concat()))
+            .kv("tokenizer.ggml.bos_token_id", 4, &0u32.to_le_bytes())
+            .tensor("token_embd.weight", &[64, 32], 12)
+            .tensor("output_norm.weight", &[64], 0)
+            .build()
+    }
+
+    #[test]
+    fn reads_metadata_and_tensors() {
+        let header = parse(&sample()).unwrap();
+        assert_eq!(header.version, 3);
+        assert_eq!(header.architecture(), Some("llama"));
+        assert_eq!(header.arch_u64("context_length"), Some(4096));
+        assert_eq!(header.quantization().as_deref(), Some("Q4_K_M"));
+        assert_eq!(header.parameter_count(), 64 * 32 + 64);
+        assert_eq!(header.template_info("test").bos_token.as_deref(), Some("<s>"));
+
+        let metadata = header.model_metadata();
+        assert_eq!(metadata.vocab_size, Some(2));
+        assert!(!metadata.has_chat_template);
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let bytes = sample();
+        for len in 0..bytes.len() {
+            assert!(parse(&bytes[..len]).is_err(), "accepted a header cut at {} of {} bytes", len, bytes.len());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_versions() {
+        let mut bytes = sample();
+        bytes[0] = b'X';
+        assert!(parse(&bytes).unwrap_err().to_string().contains("not a GGUF file"));
+
+        let mut bytes = sample();
+        bytes[4..8].copy_from_slice(&9u32.to_le_bytes());
+        assert!(parse(&bytes).unwrap_err().to_string().contains("unsupported GGUF version"));
+    }
+
+    #[test]
+    fn rejects_oversized_counts() {
+        let mut long_string = (MAX_STRING_LEN + 1).to_le_bytes().to_vec();
+        long_string.extend(b"x");
+        let bytes = HeaderBuilder::default().kv("general.name", 8, &long_string).build();
+        assert!(parse(&bytes).unwrap_err().to_string().contains("string of"));
+
+        let bytes = HeaderBuilder::default().kv("general.tags", 9, &array(4, MAX_ARRAY_LEN + 1, &[])).build();
+        assert!(parse(&bytes).unwrap_err().to_string().contains("array of"));
+
+        let bytes = HeaderBuilder::default().tensor("blk.0.weight", &[1; 9], 0).build();
+        assert!(parse(&bytes).unwrap_err().to_string().contains("dimensions"));
+
+        let bytes = HeaderBuilder::default().tensor("blk.0.weight", &[u64::MAX, 2], 0).build();
+        assert!(parse(&bytes).unwrap_err().to_string().contains("too many elements"));
+    }
+
+    #[test]
+    fn skips_long_arrays_but_keeps_their_length() {
+        let items: Vec<u8> = (0..2000u32).flat_map(u32::to_le_bytes).collect();
+        let bytes = HeaderBuilder::default()
+            .kv("tokenizer.ggml.scores", 9, &array(6, 2000, &items))
+            .kv("general.architecture", 8, &string("qwen2"))
+            .build();
+        let header = parse(&bytes).unwrap();
+        assert!(matches!(header.get("tokenizer.ggml.scores"), Some(GgufValue::SkippedArray(2000))));
+        assert_eq!(header.architecture(), Some("qwen2"));
+    }
+
+    #[test]
+    fn limits_nested_arrays() {
+        let bytes = HeaderBuilder::default().kv("general.nested", 9, &nested(MAX_ARRAY_DEPTH)).build();
+        assert!(parse(&bytes).is_ok());
+
+        let bytes = HeaderBuilder::default().kv("general.nested", 9, &nested(MAX_ARRAY_DEPTH + 1)).build();
+        assert!(parse(&bytes).unwrap_err().to_string().contains("nested"));
+
+        // Long arrays of arrays are skipped item by item and hit the same limit
+        let skipped = array(9, MAX_KEPT_ARRAY_LEN + 1, &nested(MAX_ARRAY_DEPTH));
+        let bytes = HeaderBuilder::default().kv("general.nested", 9, &skipped).build();
+        assert!(parse(&bytes).unwrap_err().to_string().contains("nested"));
+    }
+
+    #[test]
+    fn expected_file_size_covers_the_last_tensor() {
+        let bytes = HeaderBuilder::default()
+            .kv("general.alignment", 4, &64u32.to_le_bytes())
+            .tensor_at("a", &[16], 0, 0)
+            .tensor_at("b", &[64], 8, 64)
+            .build();
+        let header = parse(&bytes).unwrap();
+        let data_offset = (bytes.len() as u64).div_ceil(64) * 64;
+        assert_eq!(header.data_offset, data_offset);
+        // Q8_0 stores 32 weights in 34 bytes
+        assert_eq!(header.expected_file_size(), Some(data_offset + 64 + 2 * 34));
+
+        let unknown = HeaderBuilder::default().tensor("a", &[16], 999).build();
+        assert_eq!(parse(&unknown).unwrap().expected_file_size(), None);
+    }
+
+    #[test]
+    fn rejects_tensors_past_any_file_size() {
+        let bytes = HeaderBuilder::default().tensor_at("a", &[16], 0, u64::MAX - 8).build();
+        assert!(parse(&bytes).unwrap_err().to_string().contains("ends beyond any possible file size"));
+
+        let bytes = HeaderBuilder::default().tensor("a", &[1 << 62, 2], 0).build();
+        assert!(parse(&bytes).unwrap_err().to_string().contains("ends beyond any possible file size"));
+    }
+}
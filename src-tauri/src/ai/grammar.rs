@@ -0,0 +1,768 @@
+/*!
+ * Constrained Generation
+ *
+ * Parses GBNF grammars (the llama.cpp grammar format) and tracks which
+ * characters a grammar allows next, so backends can mask out tokens that
+ * would break structured output. JSON Schemas are converted to GBNF first.
+ */
+
+use anyhow::{Result, anyhow};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use super::json_schema;
+
+/// Largest count allowed in `{m,n}`; every repetition becomes a rule, so huge counts would exhaust memory
+pub const MAX_REPETITIONS: usize = 1000;
+
+/// Restricts generated text to a formal language
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum OutputConstraint {
+    /// A GBNF grammar whose `root` rule must match the whole answer
+    Grammar(String),
+    /// A JSON Schema the answer must be an instance of
+    JsonSchema(serde_json::Value),
+}
+
+impl OutputConstraint {
+    /// The constraint as GBNF source
+    pub fn to_gbnf(&self) -> Result<String> {
+        match self {
+            OutputConstraint::Grammar(source) => Ok(source.clone()),
+            OutputConstraint::JsonSchema(schema) => json_schema::to_gbnf(schema),
+        }
+    }
+
+    /// Parse and check the constraint so mistakes surface before generation starts
+    pub fn compile(&self) -> Result<Grammar> {
+        Grammar::parse(&self.to_gbnf()?)
+    }
+}
+
+/// Deserialize constrained output, tolerating surrounding whitespace and a Markdown code fence
+pub fn parse_structured<T: DeserializeOwned>(text: &str) -> Result<T> {
+    let text = text.trim();
+    let text = text.strip_prefix("```json").or_else(|| text.strip_prefix("```"))
+        .and_then(|inner| inner.strip_suffix("```"))
+        .unwrap_or(text)
+        .trim();
+    serde_json::from_str(text)
+        .map_err(|e| anyhow!("Model output did not match the expected structure: {}", e))
+}
+
+/// One grammar symbol: a character set or a reference to another rule
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    Chars { ranges: Vec<(u32, u32)>, negated: bool },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: u32) -> bool {
+        match self {
+            Element::Chars { ranges, negated } => ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated,
+            Element::Rule(_) => false,
+        }
+    }
+
+    /// Whether any character in `lo..=hi` might match
+    fn may_match_range(&self, lo: u32, hi: u32) -> bool {
+        match self {
+            Element::Chars { ranges, negated: false } => ranges.iter().any(|&(a, b)| a <= hi && lo <= b),
+            Element::Chars { negated: true, .. } => true,
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+/// A compiled grammar: each rule is a list of alternatives, each a sequence of elements
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    rules: Vec<Vec<Vec<Element>>>,
+    root: usize,
+}
+
+impl Grammar {
+    /// Parse GBNF source; the start rule is `root`
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser { chars: source.chars().collect(), pos: 0, names: HashMap::new(), rules: Vec::new(), defined: Vec::new() };
+        parser.parse_rules()?;
+
+        if let Some(index) = parser.defined.iter().position(|defined| !defined) {
+            let name = parser.names.iter().find(|(_, &id)| id == index).map(|(name, _)| name.as_str()).unwrap_or("?");
+            return Err(anyhow!("Grammar references undefined rule '{}'", name));
+        }
+        let root = *parser.names.get("root")
+            .ok_or_else(|| anyhow!("Grammar has no 'root' rule"))?;
+
+        let grammar = Self { rules: parser.rules, root };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    /// Matching state before any text
+    pub fn start(&self) -> GrammarState {
+        let mut stacks = Vec::new();
+        let mut seen = HashSet::new();
+        for alt in 0..self.rules[self.root].len() {
+            self.expand(vec![Frame { rule: self.root, alt, pos: 0 }], &mut stacks, &mut seen);
+        }
+        GrammarState { stacks, partial: None }
+    }
+
+    /// Left recursion would make `expand` loop forever, so it is rejected up front
+    fn check_left_recursion(&self) -> Result<()> {
+        let nullable = self.nullable_rules();
+        // Rules that can appear first in each rule without consuming input
+        let first_refs: Vec<Vec<usize>> = self.rules.iter()
+            .map(|alts| {
+                let mut refs = Vec::new();
+                for alt in alts {
+                    for element in alt {
+                        match element {
+                            Element::Rule(rule) => {
+                                refs.push(*rule);
+                                if !nullable[*rule] {
+                                    break;
+                                }
+                            }
+                            Element::Chars { .. } => break,
+                        }
+                    }
+                }
+                refs
+            })
+            .collect();
+
+        // 0 = unvisited, 1 = on the current path, 2 = done
+        fn visit(rule: usize, first_refs: &[Vec<usize>], marks: &mut [u8]) -> bool {
+            match marks[rule] {
+                1 => return false,
+                2 => return true,
+                _ => {}
+            }
+            marks[rule] = 1;
+            let acyclic = first_refs[rule].iter().all(|&next| visit(next, first_refs, marks));
+            marks[rule] = 2;
+            acyclic
+        }
+        let mut marks = vec![0u8; self.rules.len()];
+        for rule in 0..self.rules.len() {
+            if !visit(rule, &first_refs, &mut marks) {
+                return Err(anyhow!("Grammar is left-recursive; rewrite the recursive rule to consume input first"));
+            }
+        }
+        Ok(())
+    }
+
+    fn nullable_rules(&self) -> Vec<bool> {
+        let mut nullable = vec![false; self.rules.len()];
+        loop {
+            let mut changed = false;
+            for (rule, alts) in self.rules.iter().enumerate() {
+                if !nullable[rule] && alts.iter().any(|alt| alt.iter().all(|e| matches!(e, Element::Rule(r) if nullable[*r]))) {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return nullable;
+            }
+        }
+    }
+
+    /// Resolve rule references until the top of `stack` is a character set, or the stack is empty
+    fn expand(&self, mut stack: Vec<Frame>, out: &mut Vec<Vec<Frame>>, seen: &mut HashSet<Vec<Frame>>) {
+        loop {
+            let Some(top) = stack.last().copied() else {
+                // The whole grammar has matched
+                if seen.insert(stack.clone()) {
+                    out.push(stack);
+                }
+                return;
+            };
+            let alt = &self.rules[top.rule][top.alt];
+            match alt.get(top.pos) {
+                None => {
+                    stack.pop();
+                }
+                Some(Element::Chars { .. }) => {
+                    if seen.insert(stack.clone()) {
+                        out.push(stack);
+                    }
+                    return;
+                }
+                Some(Element::Rule(rule)) => {
+                    // Advance past the reference first, dropping finished frames so right recursion stays flat
+                    let mut parent = stack;
+                    advance(&mut parent, self);
+                    for alt in 0..self.rules[*rule].len() {
+                        let mut child = parent.clone();
+                        child.push(Frame { rule: *rule, alt, pos: 0 });
+                        self.expand(child, out, seen);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn element(&self, frame: &Frame) -> &Element {
+        &self.rules[frame.rule][frame.alt][frame.pos]
+    }
+}
+
+/// Move the top frame past its current element, popping frames that become complete
+fn advance(stack: &mut Vec<Frame>, grammar: &Grammar) {
+    if let Some(top) = stack.last_mut() {
+        top.pos += 1;
+        if top.pos == grammar.rules[top.rule][top.alt].len() {
+            stack.pop();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Frame {
+    rule: usize,
+    alt: usize,
+    pos: usize,
+}
+
+/// Where matching stands: every way the text so far can continue
+#[derive(Debug, Clone)]
+pub struct GrammarState {
+    stacks: Vec<Vec<Frame>>,
+    /// Code point being assembled from a multi-byte UTF-8 sequence: value and bytes still missing
+    partial: Option<(u32, u8)>,
+}
+
+impl GrammarState {
+    /// The text so far is a complete match
+    pub fn is_accepting(&self) -> bool {
+        self.partial.is_none() && self.stacks.iter().any(Vec::is_empty)
+    }
+
+    /// Nothing more can be added, so generation should stop
+    pub fn is_complete(&self) -> bool {
+        self.partial.is_none() && self.stacks.iter().all(Vec::is_empty)
+    }
+
+    /// State after `bytes`, or None if the grammar does not allow them
+    pub fn accept_bytes(&self, grammar: &Grammar, bytes: &[u8]) -> Option<Self> {
+        let mut state = self.clone();
+        for &byte in bytes {
+            state = state.accept_byte(grammar, byte)?;
+        }
+        Some(state)
+    }
+
+    pub fn accept_byte(&self, grammar: &Grammar, byte: u8) -> Option<Self> {
+        match self.partial {
+            None if byte < 0x80 => self.accept_char(grammar, byte as u32),
+            None => {
+                let (value, missing, min) = match byte {
+                    0xC2..=0xDF => ((byte & 0x1F) as u32, 1, 0x80),
+                    0xE0..=0xEF => ((byte & 0x0F) as u32, 2, 0x800),
+                    0xF0..=0xF4 => ((byte & 0x07) as u32, 3, 0x10000),
+                    _ => return None,
+                };
+                // Only continue if some allowed character could start with this byte
+                let lo = (value << (6 * missing)).max(min);
+                let hi = (value << (6 * missing)) | ((1 << (6 * missing)) - 1);
+                let possible = self.stacks.iter()
+                    .filter_map(|stack| stack.last())
+                    .any(|frame| grammar.element(frame).may_match_range(lo, hi));
+                possible.then(|| Self { stacks: self.stacks.clone(), partial: Some((value, missing)) })
+            }
+            Some((value, missing)) => {
+                if byte & 0xC0 != 0x80 {
+                    return None;
+                }
+                let value = (value << 6) | (byte & 0x3F) as u32;
+                if missing > 1 {
+                    Some(Self { stacks: self.stacks.clone(), partial: Some((value, missing - 1)) })
+                } else if value < 0x80 {
+                    // Overlong encodings would smuggle in characters the grammar excludes
+                    None
+                } else {
+                    Self { stacks: self.stacks.clone(), partial: None }.accept_char(grammar, value)
+                }
+            }
+        }
+    }
+
+    fn accept_char(&self, grammar: &Grammar, c: u32) -> Option<Self> {
+        let mut stacks = Vec::new();
+        let mut seen = HashSet::new();
+        for stack in &self.stacks {
+            let Some(top) = stack.last() else { continue };
+            if grammar.element(top).matches(c) {
+                let mut next = stack.clone();
+                advance(&mut next, grammar);
+                grammar.expand(next, &mut stacks, &mut seen);
+            }
+        }
+        (!stacks.is_empty()).then_some(Self { stacks, partial: None })
+    }
+}
+
+/// Token byte strings arranged by shared prefix, so a grammar check covers many tokens at once
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+    token_bytes: Vec<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    tokens: Vec<u32>,
+}
+
+impl TokenTrie {
+    /// Build from each token id's bytes; tokens without bytes (special tokens) are left out
+    pub fn new(token_bytes: Vec<Vec<u8>>) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        for (id, bytes) in token_bytes.iter().enumerate() {
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for &byte in bytes {
+                node = match nodes[node].children.iter().find(|(b, _)| *b == byte) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(id as u32);
+        }
+        Self { nodes, token_bytes }
+    }
+
+    /// Bytes of one token, empty for special or unknown tokens
+    pub fn token_bytes(&self, token: u32) -> &[u8] {
+        self.token_bytes.get(token as usize).map_or(&[], Vec::as_slice)
+    }
+
+    /// Every token the grammar allows next
+    pub fn allowed_tokens(&self, grammar: &Grammar, state: &GrammarState) -> Vec<u32> {
+        let mut allowed = Vec::new();
+        self.collect(0, grammar, state, &mut allowed);
+        allowed
+    }
+
+    fn collect(&self, node: usize, grammar: &Grammar, state: &GrammarState, allowed: &mut Vec<u32>) {
+        for &(byte, child) in &self.nodes[node].children {
+            if let Some(next) = state.accept_byte(grammar, byte) {
+                allowed.extend_from_slice(&self.nodes[child].tokens);
+                self.collect(child, grammar, &next, allowed);
+            }
+        }
+    }
+}
+
+/// Recursive-descent parser for GBNF
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    names: HashMap<String, usize>,
+    rules: Vec<Vec<Vec<Element>>>,
+    defined: Vec<bool>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        let line = self.chars[..self.pos.min(self.chars.len())].iter().filter(|&&c| c == '\n').count() + 1;
+        anyhow!("Invalid grammar at line {}: {}", line, message)
+    }
+
+    /// Skip spaces and comments, and newlines too when `newlines` is set
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                '\n' | '\r' if !newlines => return,
+                c if c.is_whitespace() => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.names.get(name) {
+            return id;
+        }
+        self.rules.push(Vec::new());
+        self.defined.push(false);
+        self.names.insert(name.to_string(), self.rules.len() - 1);
+        self.rules.len() - 1
+    }
+
+    fn new_rule(&mut self, alts: Vec<Vec<Element>>) -> usize {
+        self.rules.push(alts);
+        self.defined.push(true);
+        self.rules.len() - 1
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a rule name"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn parse_rules(&mut self) -> Result<()> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            if self.chars.get(self.pos..self.pos + 3) != Some(&[':', ':', '=']) {
+                return Err(self.error(&format!("expected '::=' after '{}'", name)));
+            }
+            self.pos += 3;
+            self.skip_space(true);
+            let alts = self.parse_alternatives(false)?;
+            let id = self.rule_id(&name);
+            if self.defined[id] {
+                return Err(self.error(&format!("rule '{}' is defined twice", name)));
+            }
+            self.rules[id] = alts;
+            self.defined[id] = true;
+        }
+    }
+
+    fn parse_alternatives(&mut self, nested: bool) -> Result<Vec<Vec<Element>>> {
+        let mut alts = vec![self.parse_sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alts.push(self.parse_sequence(nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_sequence(&mut self, nested: bool) -> Result<Vec<Element>> {
+        let mut sequence: Vec<Element> = Vec::new();
+        loop {
+            self.skip_space(nested);
+            let start = sequence.len();
+            match self.peek() {
+                None | Some('|') | Some(')') | Some('\n') | Some('\r') => return Ok(sequence),
+                Some('"') => {
+                    self.pos += 1;
+                    loop {
+                        match self.peek() {
+                            None => return Err(self.error("unterminated string literal")),
+                            Some('"') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            Some(_) => {
+                                let c = self.parse_char()?;
+                                sequence.push(Element::Chars { ranges: vec![(c, c)], negated: false });
+                            }
+                        }
+                    }
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    loop {
+                        match self.peek() {
+                            None => return Err(self.error("unterminated character class")),
+                            Some(']') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            Some(_) => {
+                                let lo = self.parse_char()?;
+                                let hi = if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                                    self.pos += 1;
+                                    self.parse_char()?
+                                } else {
+                                    lo
+                                };
+                                ranges.push((lo, hi));
+                            }
+                        }
+                    }
+                    sequence.push(Element::Chars { ranges, negated });
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    sequence.push(Element::Chars { ranges: Vec::new(), negated: true });
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    let alts = self.parse_alternatives(true)?;
+                    self.skip_space(true);
+                    if self.peek() != Some(')') {
+                        return Err(self.error("expected ')'"));
+                    }
+                    self.pos += 1;
+                    sequence.push(Element::Rule(self.new_rule(alts)));
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.parse_name()?;
+                    sequence.push(Element::Rule(self.rule_id(&name)));
+                }
+                Some(c) => return Err(self.error(&format!("unexpected '{}'", c))),
+            }
+
+            // A postfix operator applies to the last item only; literals spanning several chars become a rule
+            let mut item = sequence.split_off(start);
+            let item = match (item.len(), self.peek()) {
+                (1, Some('*' | '+' | '?' | '{')) => item.remove(0),
+                (_, Some('*' | '+' | '?' | '{')) => Element::Rule(self.new_rule(vec![item])),
+                _ => {
+                    sequence.append(&mut item);
+                    continue;
+                }
+            };
+            match self.peek() {
+                Some('*') => {
+                    self.pos += 1;
+                    sequence.push(self.repeat(item, 0, None));
+                }
+                Some('+') => {
+                    self.pos += 1;
+                    sequence.push(self.repeat(item, 1, None));
+                }
+                Some('?') => {
+                    self.pos += 1;
+                    sequence.push(self.repeat(item, 0, Some(1)));
+                }
+                Some('{') => {
+                    self.pos += 1;
+                    let (min, max) = self.parse_bounds()?;
+                    sequence.push(self.repeat(item, min, max));
+                }
+                _ => sequence.push(item),
+            }
+        }
+    }
+
+    /// `{m}`, `{m,}` or `{m,n}`, after the opening brace
+    fn parse_bounds(&mut self) -> Result<(usize, Option<usize>)> {
+        let number = |parser: &mut Self| -> Option<usize> {
+            let start = parser.pos;
+            while parser.peek().is_some_and(|c| c.is_ascii_digit()) {
+                parser.pos += 1;
+            }
+            parser.chars[start..parser.pos].iter().collect::<String>().parse().ok()
+        };
+        self.skip_space(true);
+        let min = number(self).ok_or_else(|| self.error("expected a repetition count"))?;
+        self.skip_space(true);
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            self.skip_space(true);
+            number(self)
+        } else {
+            Some(min)
+        };
+        self.skip_space(true);
+        if self.peek() != Some('}') {
+            return Err(self.error("expected '}'"));
+        }
+        self.pos += 1;
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("repetition maximum is below the minimum"));
+        }
+        if max.unwrap_or(min) > MAX_REPETITIONS {
+            return Err(self.error(&format!("repetition counts above {} are not supported", MAX_REPETITIONS)));
+        }
+        Ok((min, max))
+    }
+
+    /// A rule matching `item` between `min` and `max` (unbounded if None) times
+    fn repeat(&mut self, item: Element, min: usize, max: Option<usize>) -> Element {
+        // The optional tail: `x*` is `R ::= x R |`, `x{0,n}` nests n optional copies
+        let tail = match max {
+            None => {
+                let id = self.new_rule(Vec::new());
+                self.rules[id] = vec![vec![item.clone(), Element::Rule(id)], Vec::new()];
+                Some(id)
+            }
+            Some(max) => (min..max).fold(None, |inner, _| {
+                let mut alt = vec![item.clone()];
+                alt.extend(inner.map(Element::Rule));
+                Some(self.new_rule(vec![alt, Vec::new()]))
+            }),
+        };
+        let mut sequence = vec![item; min];
+        sequence.extend(tail.map(Element::Rule));
+        Element::Rule(self.new_rule(vec![sequence]))
+    }
+
+    /// One character of a literal or class, with escapes
+    fn parse_char(&mut self) -> Result<u32> {
+        let c = self.peek().ok_or_else(|| self.error("unexpected end of grammar"))?;
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c as u32);
+        }
+        let escaped = self.peek().ok_or_else(|| self.error("unfinished escape"))?;
+        self.pos += 1;
+        let hex_digits = match escaped {
+            'n' => return Ok('\n' as u32),
+            'r' => return Ok('\r' as u32),
+            't' => return Ok('\t' as u32),
+            '\\' | '"' | '[' | ']' | '-' | '^' => return Ok(escaped as u32),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            other => return Err(self.error(&format!("unknown escape '\\{}'", other))),
+        };
+        let digits: String = self.chars.get(self.pos..self.pos + hex_digits)
+            .ok_or_else(|| self.error("unfinished escape"))?
+            .iter()
+            .collect();
+        self.pos += hex_digits;
+        u32::from_str_radix(&digits, 16).map_err(|_| self.error(&format!("invalid escape '\\{}{}'", escaped, digits)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(grammar: &Grammar, text: &str) -> bool {
+        grammar.start().accept_bytes(grammar, text.as_bytes()).is_some_and(|state| state.is_accepting())
+    }
+
+    fn parse_error(source: &str) -> String {
+        Grammar::parse(source).err().map(|e| e.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert!(parse_error("root ::= other").contains("undefined rule 'other'"));
+        assert!(parse_error("start ::= \"a\"").contains("no 'root' rule"));
+        assert!(parse_error("root \"a\"").contains("expected '::='"));
+        assert!(parse_error("root ::= \"a\"\nroot ::= \"b\"").contains("defined twice"));
+        assert!(parse_error("root ::= \"a").contains("unterminated string"));
+        assert!(parse_error("root ::= [ab").contains("unterminated character class"));
+        assert!(parse_error("root ::= \"\\q\"").contains("unknown escape"));
+        assert!(parse_error("root ::= (\"a\"").contains("expected ')'"));
+        assert!(parse_error("\n\nroot ::= \"a\"{3,2}").contains("line 3: repetition maximum is below the minimum"));
+        assert!(parse_error("root ::= \"a\"{1001}").contains("above 1000"));
+        assert!(parse_error("root ::= \"a\"{2,1001}").contains("above 1000"));
+    }
+
+    #[test]
+    fn repetition_operators() {
+        let grammar = Grammar::parse("root ::= \"a\"{2,3} \"b\"* \"c\"+ \"d\"? [0-9]{2} [x]{1,}").unwrap();
+        assert!(matches(&grammar, "aacd12x"));
+        assert!(matches(&grammar, "aaabbbccc07xxx"));
+        assert!(!matches(&grammar, "acd12x"));
+        assert!(!matches(&grammar, "aaaacd12x"));
+        assert!(!matches(&grammar, "aad12x"));
+        assert!(!matches(&grammar, "aacdd12x"));
+        assert!(!matches(&grammar, "aac1x"));
+        assert!(!matches(&grammar, "aac12"));
+    }
+
+    #[test]
+    fn tracks_whether_more_text_is_possible() {
+        let grammar = Grammar::parse("root ::= \"ab\" | \"abc\"").unwrap();
+        let ab = grammar.start().accept_bytes(&grammar, b"ab").unwrap();
+        assert!(ab.is_accepting() && !ab.is_complete());
+        let abc = ab.accept_byte(&grammar, b'c').unwrap();
+        assert!(abc.is_complete());
+        assert!(abc.accept_byte(&grammar, b'c').is_none());
+    }
+
+    #[test]
+    fn matches_multi_byte_characters_byte_by_byte() {
+        let grammar = Grammar::parse("root ::= \"日本\" [à-ü]+ [^a] \"\\U0001F600\"").unwrap();
+        assert!(matches(&grammar, "日本éü€😀"));
+        assert!(!matches(&grammar, "日本éa😀"));
+        assert!(!matches(&grammar, "日本e€😀"));
+
+        // A character split across tokens is only accepted once it is complete
+        let partial = grammar.start().accept_bytes(&grammar, &"日".as_bytes()[..2]).unwrap();
+        assert!(!partial.is_accepting());
+        assert!(partial.accept_byte(&grammar, b'x').is_none());
+
+        // Overlong and stray continuation bytes never match
+        let any = Grammar::parse("root ::= [^\"]*").unwrap();
+        assert!(any.start().accept_bytes(&any, &[0xC0, 0xA2]).is_none());
+        assert!(any.start().accept_bytes(&any, &[0xE0, 0x80, 0xA2]).is_none());
+        assert!(any.start().accept_bytes(&any, &[0x80]).is_none());
+    }
+
+    #[test]
+    fn rejects_left_recursion() {
+        assert!(parse_error("root ::= root \"a\" | \"a\"").contains("left-recursive"));
+        assert!(parse_error("root ::= item \"x\"\nitem ::= root | \"y\"").contains("left-recursive"));
+        // Recursion behind a rule that can match nothing is still left recursion
+        assert!(parse_error("root ::= opt root \"a\" | \"b\"\nopt ::= \"c\"?").contains("left-recursive"));
+        // Recursion after consuming input is fine
+        let grammar = Grammar::parse("root ::= \"(\" root \")\" | \"x\"").unwrap();
+        assert!(matches(&grammar, "((x))"));
+        assert!(!matches(&grammar, "((x)"));
+    }
+
+    #[test]
+    fn token_trie_allows_only_tokens_the_grammar_accepts() {
+        let grammar = Grammar::parse("root ::= \"true\" | \"false\"").unwrap();
+        let vocab = ["t", "tr", "true", "f", "fa", "x", "", "truex"];
+        let trie = TokenTrie::new(vocab.iter().map(|token| token.as_bytes().to_vec()).collect());
+
+        let mut allowed = trie.allowed_tokens(&grammar, &grammar.start());
+        allowed.sort_unstable();
+        assert_eq!(allowed, [0, 1, 2, 3, 4]);
+        assert_eq!(trie.token_bytes(2), b"true");
+        assert!(trie.token_bytes(6).is_empty() && trie.token_bytes(99).is_empty());
+
+        let state = grammar.start().accept_bytes(&grammar, b"tru").unwrap();
+        assert_eq!(trie.allowed_tokens(&grammar, &state), Vec::<u32>::new());
+        let state = grammar.start().accept_bytes(&grammar, b"fals").unwrap();
+        assert!(trie.allowed_tokens(&grammar, &state).is_empty());
+    }
+
+    #[test]
+    fn schema_constraints_compile_to_grammars() {
+        let constraint = OutputConstraint::JsonSchema(serde_json::json!({ "type": "array", "items": { "type": "boolean" } }));
+        let grammar = constraint.compile().unwrap();
+        assert!(matches(&grammar, "[true, false]"));
+        assert!(!matches(&grammar, "[1]"));
+        assert!(OutputConstraint::Grammar("root ::= root".to_string()).compile().is_err());
+    }
+
+    #[test]
+    fn structured_output_may_be_fenced() {
+        let value: Vec<u32> = parse_structured("```json\n[1, 2]\n```").unwrap();
+        assert_eq!(value, [1, 2]);
+        let value: Vec<u32> = parse_structured(" [3] ").unwrap();
+        assert_eq!(value, [3]);
+        assert!(parse_structured::<Vec<u32>>("[1,").is_err());
+    }
+}
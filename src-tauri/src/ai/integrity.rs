@@ -0,0 +1,440 @@
+/*!
+ * Model Integrity
+ *
+ * SHA-256 manifests of model files and the checks that catch partially
+ * copied or corrupted checkpoints before a backend tries to load them.
+ * Manifests are persisted in the `model_cache` table so discovery only has
+ * to look at files whose size or modification time changed.
+ */
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use anyhow::Result;
+use thiserror::Error;
+
+use super::gguf::GgufHeader;
+use super::safetensors_header::SafetensorsHeader;
+
+/// Suffixes left behind by interrupted copies and downloads
+const PARTIAL_SUFFIXES: &[&str] = &[".part", ".partial", ".incomplete", ".crdownload", ".tmp", ".download"];
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// One file of a model as it was last seen on disk
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileManifest {
+    pub name: String,
+    pub size_bytes: u64,
+    /// Modification time in milliseconds since the Unix epoch
+    pub modified_ms: i64,
+    /// Hex SHA-256, filled in by background verification
+    pub sha256: Option<String>,
+}
+
+impl FileManifest {
+    /// Stat a file without reading it
+    pub fn stat(dir: &Path, name: &str) -> Result<Self> {
+        let metadata = std::fs::metadata(dir.join(name))?;
+        let modified_ms = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+        Ok(Self {
+            name: name.to_string(),
+            size_bytes: metadata.len(),
+            modified_ms,
+            sha256: None,
+        })
+    }
+
+    /// Same size and modification time, i.e. the cached hash still applies
+    pub fn unchanged_since(&self, previous: &FileManifest) -> bool {
+        self.name == previous.name
+            && self.size_bytes == previous.size_bytes
+            && self.modified_ms == previous.modified_ms
+    }
+}
+
+/// Stat every file directly inside a model directory, sorted by name
+///
+/// Blocking; call from `spawn_blocking` in async code.
+pub fn scan_dir(dir: &Path) -> Result<Vec<FileManifest>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        // Follows symlinks, so imported and hub cache models are hashed through their links
+        if entry.path().is_file() {
+            files.push(FileManifest::stat(dir, &entry.file_name().to_string_lossy())?);
+        }
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+/// Copy cached hashes onto files that have not changed since `previous`
+pub fn carry_hashes(current: &mut [FileManifest], previous: &[FileManifest]) {
+    for file in current.iter_mut() {
+        if let Some(old) = previous.iter().find(|old| file.unchanged_since(old)) {
+            file.sha256 = old.sha256.clone();
+        }
+    }
+}
+
+/// True if every file matches the previous manifest by name, size and modification time
+pub fn manifest_unchanged(current: &[FileManifest], previous: &[FileManifest]) -> bool {
+    current.len() == previous.len()
+        && current.iter().all(|file| previous.iter().any(|old| file.unchanged_since(old)))
+}
+
+/// Verification state of a model
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum IntegrityStatus {
+    /// No hashes computed yet
+    #[default]
+    Unverified,
+    /// Hashing in the background
+    Verifying,
+    /// Headers are consistent and hashes match what is known about the files
+    Verified,
+    /// A file is shorter than its header says, or a download was interrupted
+    Partial { file: String, reason: String },
+    /// A header cannot be parsed or a hash does not match
+    Corrupt { file: String, reason: String },
+}
+
+impl IntegrityStatus {
+    pub fn is_loadable(&self) -> bool {
+        !matches!(self, IntegrityStatus::Partial { .. } | IntegrityStatus::Corrupt { .. })
+    }
+}
+
+/// Returned instead of attempting to load a model that failed verification
+#[derive(Debug, Error)]
+pub enum IntegrityError {
+    #[error("model {model_id} is incomplete: {file} {reason}; copy or download it again")]
+    Partial { model_id: String, file: String, reason: String },
+    #[error("model {model_id} is corrupted: {file} {reason}; copy or download it again")]
+    Corrupt { model_id: String, file: String, reason: String },
+}
+
+impl IntegrityError {
+    /// The error for a failed status, or None if the model may be loaded
+    pub fn from_status(model_id: &str, status: &IntegrityStatus) -> Option<Self> {
+        match status {
+            IntegrityStatus::Partial { file, reason } => Some(IntegrityError::Partial {
+                model_id: model_id.to_string(),
+                file: file.clone(),
+                reason: reason.clone(),
+            }),
+            IntegrityStatus::Corrupt { file, reason } => Some(IntegrityError::Corrupt {
+                model_id: model_id.to_string(),
+                file: file.clone(),
+                reason: reason.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Cheap checks that need no hashing: leftover download files and headers that promise more bytes than the file has
+///
+/// Blocking; call from `spawn_blocking` in async code.
+pub fn check_structure(dir: &Path, files: &[FileManifest]) -> IntegrityStatus {
+    for file in files {
+        let lower = file.name.to_lowercase();
+        if PARTIAL_SUFFIXES.iter().any(|suffix| lower.ends_with(suffix)) {
+            return IntegrityStatus::Partial {
+                file: file.name.clone(),
+                reason: "is an unfinished copy or download".to_string(),
+            };
+        }
+
+        let path = dir.join(&file.name);
+        let expected = if lower.ends_with(".gguf") {
+            GgufHeader::read(&path).map(|header| header.expected_file_size())
+        } else if lower.ends_with(".safetensors") {
+            SafetensorsHeader::read(&path).map(|header| Some(header.expected_size))
+        } else {
+            continue;
+        };
+
+        match expected {
+            Ok(Some(expected)) if file.size_bytes < expected => {
+                return IntegrityStatus::Partial {
+                    file: file.name.clone(),
+                    reason: format!("has {} of {} bytes", file.size_bytes, expected),
+                };
+            }
+            Ok(_) => {}
+            Err(e) => {
+                return IntegrityStatus::Corrupt {
+                    file: file.name.clone(),
+                    reason: format!("has an unreadable header ({})", e.root_cause()),
+                };
+            }
+        }
+    }
+    IntegrityStatus::Verified
+}
+
+/// Hex SHA-256 of a file, streamed so multi-GB weights never sit in memory
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Checksums published alongside the model: `sha256` in model_info.json or `<file>.sha256` sidecars
+pub fn expected_hashes(dir: &Path) -> HashMap<String, String> {
+    let mut expected = HashMap::new();
+
+    if let Ok(content) = std::fs::read_to_string(dir.join("model_info.json")) {
+        if let Ok(info) = serde_json::from_str::<serde_json::Value>(&content) {
+            if let Some(hashes) = info.get("sha256").and_then(|h| h.as_object()) {
+                for (name, hash) in hashes {
+                    if let Some(hash) = hash.as_str() {
+                        expected.insert(name.clone(), hash.to_lowercase());
+                    }
+                }
+            }
+        }
+    }
+
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(target) = name.strip_suffix(".sha256") {
+                if let Ok(content) = std::fs::read_to_string(entry.path()) {
+                    // `sha256sum` format: "<hash>  <file name>"
+                    if let Some(hash) = content.split_whitespace().next() {
+                        expected.insert(target.to_string(), hash.to_lowercase());
+                    }
+                }
+            }
+        }
+    }
+
+    expected
+}
+
+/// Hash files and compare them with the previous manifest and any published checksums
+///
+/// Files unchanged since `previous` keep their cached hash unless `full` is set.
+/// Blocking; call from `spawn_blocking` in async code.
+pub fn verify(dir: &Path, current: &[FileManifest], previous: &[FileManifest], full: bool) -> (Vec<FileManifest>, IntegrityStatus) {
+    let mut manifest = current.to_vec();
+
+    let structure = check_structure(dir, &manifest);
+    if !structure.is_loadable() {
+        return (manifest, structure);
+    }
+
+    let expected = expected_hashes(dir);
+    let mut status = IntegrityStatus::Verified;
+
+    for file in manifest.iter_mut() {
+        let cached = previous.iter().find(|old| file.unchanged_since(old));
+        if let (Some(cached), false) = (cached, full) {
+            file.sha256 = cached.sha256.clone();
+            if file.sha256.is_some() {
+                continue;
+            }
+        }
+
+        let hash = match hash_file(&dir.join(&file.name)) {
+            Ok(hash) => hash,
+            Err(e) => {
+                status = IntegrityStatus::Corrupt {
+                    file: file.name.clone(),
+                    reason: format!("cannot be read ({})", e),
+                };
+                break;
+            }
+        };
+
+        if let Some(expected_hash) = expected.get(&file.name).filter(|h| **h != hash) {
+            status = IntegrityStatus::Corrupt {
+                file: file.name.clone(),
+                reason: format!("has SHA-256 {} but {} was published", hash, expected_hash),
+            };
+        } else if let Some(old_hash) = cached.and_then(|old| old.sha256.as_ref()).filter(|h| **h != hash) {
+            // Same size and timestamp but different bytes: the file changed underneath us
+            status = IntegrityStatus::Corrupt {
+                file: file.name.clone(),
+                reason: format!("changed on disk without a new modification time (was {})", old_hash),
+            };
+        }
+        file.sha256 = Some(hash);
+
+        if !status.is_loadable() {
+            break;
+        }
+    }
+
+    (manifest, status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write(dir: &Path, name: &str, content: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn manifest(name: &str, size_bytes: u64, modified_ms: i64, sha256: Option<&str>) -> FileManifest {
+        FileManifest {
+            name: name.to_string(),
+            size_bytes,
+            modified_ms,
+            sha256: sha256.map(str::to_string),
+        }
+    }
+
+    /// GGUF v3 header for one F32 tensor of `elements` weights, without the tensor data
+    fn gguf_header(elements: u64) -> Vec<u8> {
+        let mut bytes = b"GGUF".to_vec();
+        bytes.extend(3u32.to_le_bytes());
+        bytes.extend(1u64.to_le_bytes());
+        bytes.extend(0u64.to_le_bytes());
+        bytes.extend(1u64.to_le_bytes());
+        bytes.extend(b"w");
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend(elements.to_le_bytes());
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend(0u64.to_le_bytes());
+        bytes.resize(bytes.len().div_ceil(32) * 32, 0);
+        bytes
+    }
+
+    #[test]
+    fn scan_dir_lists_files_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "model.gguf", b"12345");
+        write(dir.path(), "config.json", b"{}");
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+
+        let files = scan_dir(dir.path()).unwrap();
+        let names: Vec<&str> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["config.json", "model.gguf"]);
+        assert_eq!(files[1].size_bytes, 5);
+        assert!(files.iter().all(|file| file.sha256.is_none()));
+    }
+
+    #[test]
+    fn unchanged_manifests_match_by_name_size_and_time() {
+        let previous = vec![manifest("a.gguf", 10, 1000, Some("aa")), manifest("b.json", 2, 1000, None)];
+
+        assert!(manifest_unchanged(&[manifest("a.gguf", 10, 1000, None), manifest("b.json", 2, 1000, None)], &previous));
+        assert!(!manifest_unchanged(&[manifest("a.gguf", 11, 1000, None), manifest("b.json", 2, 1000, None)], &previous));
+        assert!(!manifest_unchanged(&[manifest("a.gguf", 10, 2000, None), manifest("b.json", 2, 1000, None)], &previous));
+        assert!(!manifest_unchanged(&[manifest("a.gguf", 10, 1000, None)], &previous));
+    }
+
+    #[test]
+    fn carry_hashes_only_onto_unchanged_files() {
+        let previous = vec![manifest("a.gguf", 10, 1000, Some("aa")), manifest("b.gguf", 10, 1000, Some("bb"))];
+        let mut current = vec![manifest("a.gguf", 10, 1000, None), manifest("b.gguf", 12, 1000, None)];
+        carry_hashes(&mut current, &previous);
+        assert_eq!(current[0].sha256.as_deref(), Some("aa"));
+        assert_eq!(current[1].sha256, None);
+    }
+
+    #[test]
+    fn structure_flags_partial_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "model.gguf.part", b"");
+        let files = scan_dir(dir.path()).unwrap();
+        assert!(matches!(check_structure(dir.path(), &files), IntegrityStatus::Partial { file, .. } if file == "model.gguf.part"));
+    }
+
+    #[test]
+    fn structure_compares_gguf_size_with_its_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = gguf_header(16);
+
+        write(dir.path(), "model.gguf", &header);
+        let files = scan_dir(dir.path()).unwrap();
+        let status = check_structure(dir.path(), &files);
+        assert!(matches!(&status, IntegrityStatus::Partial { reason, .. } if reason.contains(&format!("of {} bytes", header.len() + 64))));
+        assert!(!status.is_loadable());
+
+        let mut complete = header.clone();
+        complete.resize(header.len() + 64, 0);
+        write(dir.path(), "model.gguf", &complete);
+        let files = scan_dir(dir.path()).unwrap();
+        assert_eq!(check_structure(dir.path(), &files), IntegrityStatus::Verified);
+
+        write(dir.path(), "model.gguf", b"GGUF\x09\0\0\0");
+        let files = scan_dir(dir.path()).unwrap();
+        assert!(matches!(check_structure(dir.path(), &files), IntegrityStatus::Corrupt { .. }));
+    }
+
+    #[test]
+    fn structure_compares_safetensors_size_with_its_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = br#"{"w":{"dtype":"F32","shape":[4],"data_offsets":[0,16]}}"#;
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(header);
+
+        write(dir.path(), "model.safetensors", &bytes);
+        let files = scan_dir(dir.path()).unwrap();
+        assert!(matches!(check_structure(dir.path(), &files), IntegrityStatus::Partial { .. }));
+
+        bytes.extend([0u8; 16]);
+        write(dir.path(), "model.safetensors", &bytes);
+        let files = scan_dir(dir.path()).unwrap();
+        assert_eq!(check_structure(dir.path(), &files), IntegrityStatus::Verified);
+    }
+
+    #[test]
+    fn verify_checks_published_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(dir.path(), "weights.bin", b"weights");
+        let hash = hash_file(&path).unwrap();
+
+        write(dir.path(), "weights.bin.sha256", format!("{}  weights.bin\n", hash.to_uppercase()).as_bytes());
+        let files = scan_dir(dir.path()).unwrap();
+        let (manifest, status) = verify(dir.path(), &files, &[], false);
+        assert_eq!(status, IntegrityStatus::Verified);
+        assert_eq!(manifest.iter().find(|file| file.name == "weights.bin").unwrap().sha256.as_deref(), Some(hash.as_str()));
+
+        write(dir.path(), "weights.bin.sha256", format!("{}  weights.bin\n", "0".repeat(64)).as_bytes());
+        let (_, status) = verify(dir.path(), &files, &[], false);
+        assert!(matches!(status, IntegrityStatus::Corrupt { file, reason } if file == "weights.bin" && reason.contains("was published")));
+    }
+
+    #[test]
+    fn verify_reuses_cached_hashes_unless_full() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "weights.bin", b"weights");
+        let files = scan_dir(dir.path()).unwrap();
+        let mut previous = files.clone();
+        previous[0].sha256 = Some("cached".to_string());
+
+        let (manifest, status) = verify(dir.path(), &files, &previous, false);
+        assert_eq!(status, IntegrityStatus::Verified);
+        assert_eq!(manifest[0].sha256.as_deref(), Some("cached"));
+
+        // A full check rehashes and notices the bytes no longer match the cached hash
+        let (_, status) = verify(dir.path(), &files, &previous, true);
+        assert!(matches!(status, IntegrityStatus::Corrupt { reason, .. } if reason.contains("changed on disk")));
+    }
+}
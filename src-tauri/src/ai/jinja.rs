@@ -0,0 +1,1904 @@
+/*!
+ * Minimal Jinja Renderer
+ *
+ * Just enough of Jinja2 to render the `chat_template` shipped with HuggingFace
+ * and GGUF models: `{{ }}` output, `if`/`elif`/`else`, `for` loops with `loop.*`,
+ * `break`/`continue`, `set` (including namespaces), the usual operators, filters,
+ * tests and string methods, and the `trim_blocks`/`lstrip_blocks` whitespace
+ * handling that transformers enables for chat templates.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use anyhow::{Result, anyhow, bail};
+
+/// Longest list `range()` may build; chat templates only loop over messages
+const MAX_RANGE_LEN: usize = 100_000;
+/// Largest string `*` may build by repetition
+const MAX_REPEAT_LEN: usize = 1 << 20;
+
+/// A runtime value inside a template
+#[derive(Debug, Clone)]
+pub enum Value {
+    Undefined,
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<Value>),
+    /// Keys iterate in sorted order, unlike Python's insertion order
+    Map(BTreeMap<String, Value>),
+    /// Mutable attribute bag created by `namespace()`, the only way to carry state out of a loop
+    Namespace(Arc<Mutex<BTreeMap<String, Value>>>),
+}
+
+impl From<&serde_json::Value> for Value {
+    fn from(value: &serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Value::None,
+            serde_json::Value::Bool(b) => Value::Bool(*b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Value::Int(i),
+                None => Value::Float(n.as_f64().unwrap_or_default()),
+            },
+            serde_json::Value::String(s) => Value::Str(s.clone()),
+            serde_json::Value::Array(items) => Value::List(items.iter().map(Value::from).collect()),
+            serde_json::Value::Object(map) => Value::Map(
+                map.iter().map(|(k, v)| (k.clone(), Value::from(v))).collect()
+            ),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Str(value.to_string())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Undefined | Value::None => false,
+            Value::Bool(b) => *b,
+            Value::Int(i) => *i != 0,
+            Value::Float(f) => *f != 0.0,
+            Value::Str(s) => !s.is_empty(),
+            Value::List(items) => !items.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+            Value::Namespace(_) => true,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Undefined => "undefined",
+            Value::None => "none",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "mapping",
+            Value::Namespace(_) => "namespace",
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            Value::Bool(b) => Some(*b as i64 as f64),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            Value::Bool(b) => Some(*b as i64),
+            _ => None,
+        }
+    }
+
+    /// What `{{ value }}` prints, matching Python's `str()`
+    fn to_display(&self) -> String {
+        match self {
+            Value::Undefined => String::new(),
+            Value::Str(s) => s.clone(),
+            other => other.repr(),
+        }
+    }
+
+    /// Python's `repr()`, used for values nested inside lists and dicts
+    fn repr(&self) -> String {
+        match self {
+            Value::Undefined => String::new(),
+            Value::None => "None".to_string(),
+            Value::Bool(true) => "True".to_string(),
+            Value::Bool(false) => "False".to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) => format_float(*f),
+            Value::Str(s) => {
+                let quote = if s.contains('\'') && !s.contains('"') { '"' } else { '\'' };
+                let mut out = String::with_capacity(s.len() + 2);
+                out.push(quote);
+                for c in s.chars() {
+                    match c {
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\t' => out.push_str("\\t"),
+                        c if c == quote => {
+                            out.push('\\');
+                            out.push(c);
+                        }
+                        c => out.push(c),
+                    }
+                }
+                out.push(quote);
+                out
+            }
+            Value::List(items) => format!("[{}]", items.iter().map(Value::repr).collect::<Vec<_>>().join(", ")),
+            Value::Map(map) => format!("{{{}}}", map.iter()
+                .map(|(k, v)| format!("{}: {}", Value::Str(k.clone()).repr(), v.repr()))
+                .collect::<Vec<_>>()
+                .join(", ")),
+            Value::Namespace(_) => "<Namespace>".to_string(),
+        }
+    }
+
+    /// JSON as produced by Python's `json.dumps(..., ensure_ascii=False)`
+    fn to_json(&self, indent: Option<usize>, depth: usize) -> String {
+        let (open_pad, close_pad, separator) = match indent {
+            Some(width) => (
+                format!("\n{}", " ".repeat(width * (depth + 1))),
+                format!("\n{}", " ".repeat(width * depth)),
+                ",",
+            ),
+            None => (String::new(), String::new(), ", "),
+        };
+        let join = |parts: Vec<String>| -> String {
+            parts.join(&format!("{}{}", separator, if indent.is_some() { open_pad.as_str() } else { "" }))
+        };
+
+        match self {
+            Value::Undefined | Value::None => "null".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) => format_float(*f),
+            Value::Str(s) => serde_json::to_string(s).unwrap_or_default(),
+            Value::List(items) if items.is_empty() => "[]".to_string(),
+            Value::List(items) => format!("[{}{}{}]", open_pad,
+                join(items.iter().map(|v| v.to_json(indent, depth + 1)).collect()),
+                close_pad),
+            Value::Map(map) if map.is_empty() => "{}".to_string(),
+            Value::Map(map) => format!("{{{}{}{}}}", open_pad,
+                join(map.iter()
+                    .map(|(k, v)| format!("{}: {}", serde_json::to_string(k).unwrap_or_default(), v.to_json(indent, depth + 1)))
+                    .collect()),
+                close_pad),
+            Value::Namespace(ns) => Value::Map(ns.lock().map(|m| m.clone()).unwrap_or_default()).to_json(indent, depth),
+        }
+    }
+
+    fn length(&self) -> Result<usize> {
+        match self {
+            Value::Str(s) => Ok(s.chars().count()),
+            Value::List(items) => Ok(items.len()),
+            Value::Map(map) => Ok(map.len()),
+            other => bail!("object of type {} has no length", other.type_name()),
+        }
+    }
+
+    /// Items produced by iterating the value in a `for` loop
+    fn iterate(&self) -> Result<Vec<Value>> {
+        match self {
+            Value::List(items) => Ok(items.clone()),
+            Value::Map(map) => Ok(map.keys().map(|k| Value::Str(k.clone())).collect()),
+            Value::Str(s) => Ok(s.chars().map(|c| Value::Str(c.to_string())).collect()),
+            Value::Undefined | Value::None => Ok(Vec::new()),
+            other => bail!("{} is not iterable", other.type_name()),
+        }
+    }
+
+    fn get_attr(&self, name: &str) -> Value {
+        match self {
+            Value::Map(map) => map.get(name).cloned().unwrap_or(Value::Undefined),
+            Value::Namespace(ns) => ns.lock().ok()
+                .and_then(|m| m.get(name).cloned())
+                .unwrap_or(Value::Undefined),
+            _ => Value::Undefined,
+        }
+    }
+
+    fn get_item(&self, key: &Value) -> Value {
+        match (self, key) {
+            (Value::List(items), Value::Int(i)) => index(items.len(), *i)
+                .map(|i| items[i].clone())
+                .unwrap_or(Value::Undefined),
+            (Value::Str(s), Value::Int(i)) => {
+                let chars: Vec<char> = s.chars().collect();
+                index(chars.len(), *i)
+                    .map(|i| Value::Str(chars[i].to_string()))
+                    .unwrap_or(Value::Undefined)
+            }
+            (_, Value::Str(name)) => self.get_attr(name),
+            _ => Value::Undefined,
+        }
+    }
+
+    fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Undefined, Value::Undefined) | (Value::None, Value::None) => true,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.equals(y)),
+            (Value::Map(a), Value::Map(b)) => a.len() == b.len()
+                && a.iter().all(|(k, v)| b.get(k).is_some_and(|w| v.equals(w))),
+            (a, b) => match (a.as_f64(), b.as_f64()) {
+                (Some(x), Some(y)) => x == y,
+                _ => false,
+            },
+        }
+    }
+
+    fn compare(&self, other: &Value) -> Result<std::cmp::Ordering> {
+        match (self, other) {
+            (Value::Str(a), Value::Str(b)) => Ok(a.cmp(b)),
+            (a, b) => match (a.as_f64(), b.as_f64()) {
+                (Some(x), Some(y)) => x.partial_cmp(&y).ok_or_else(|| anyhow!("cannot compare NaN")),
+                _ => bail!("cannot compare {} with {}", a.type_name(), b.type_name()),
+            },
+        }
+    }
+
+    fn contains(&self, needle: &Value) -> Result<bool> {
+        match (self, needle) {
+            (Value::Str(haystack), Value::Str(needle)) => Ok(haystack.contains(needle.as_str())),
+            (Value::List(items), needle) => Ok(items.iter().any(|item| item.equals(needle))),
+            (Value::Map(map), Value::Str(key)) => Ok(map.contains_key(key)),
+            (Value::Map(_), _) => Ok(false),
+            (Value::Undefined | Value::None, _) => Ok(false),
+            (haystack, _) => bail!("argument of type {} is not iterable", haystack.type_name()),
+        }
+    }
+}
+
+/// Python-style index resolution with support for negative indices
+fn index(len: usize, i: i64) -> Option<usize> {
+    let i = if i < 0 { len as i64 + i } else { i };
+    (0..len as i64).contains(&i).then_some(i as usize)
+}
+
+fn format_float(f: f64) -> String {
+    if f.is_finite() && f.fract() == 0.0 && f.abs() < 1e16 {
+        format!("{:.1}", f)
+    } else {
+        f.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Op(&'static str),
+}
+
+const OPERATORS: &[&str] = &[
+    "//", "**", "==", "!=", "<=", ">=",
+    "+", "-", "*", "/", "%", "~", "|", ".", ",", ":", "(", ")", "[", "]", "{", "}", "<", ">", "=",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Name(chars[start..i].iter().collect()));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '_') {
+                i += 1;
+            }
+            let is_float = i + 1 < chars.len() && chars[i] == '.' && chars[i + 1].is_ascii_digit();
+            if is_float {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let text: String = chars[start..i].iter().filter(|c| **c != '_').collect();
+            tokens.push(if is_float {
+                Token::Float(text.parse()?)
+            } else {
+                Token::Int(text.parse()?)
+            });
+        } else if c == '\'' || c == '"' {
+            let quote = c;
+            let mut value = String::new();
+            i += 1;
+            loop {
+                let c = *chars.get(i).ok_or_else(|| anyhow!("unterminated string literal"))?;
+                i += 1;
+                if c == quote {
+                    break;
+                }
+                if c != '\\' {
+                    value.push(c);
+                    continue;
+                }
+                let escaped = *chars.get(i).ok_or_else(|| anyhow!("unterminated string literal"))?;
+                i += 1;
+                match escaped {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    '0' => value.push('\0'),
+                    '\\' | '\'' | '"' => value.push(escaped),
+                    other => {
+                        value.push('\\');
+                        value.push(other);
+                    }
+                }
+            }
+            tokens.push(Token::Str(value));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let op = OPERATORS.iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| anyhow!("unexpected character '{}'", c))?;
+            i += op.chars().count();
+            tokens.push(Token::Op(op));
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp {
+    Add, Sub, Mul, Div, FloorDiv, Mod, Pow, Concat,
+    Eq, Ne, Lt, Gt, Le, Ge, In, And, Or,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Var(String),
+    List(Vec<Expr>),
+    Dict(Vec<(Expr, Expr)>),
+    Attr(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>, Option<Box<Expr>>),
+    Call(Box<Expr>, Vec<Expr>, Vec<(String, Expr)>),
+    Filter(Box<Expr>, String, Vec<Expr>, Vec<(String, Expr)>),
+    Test(Box<Expr>, String, Vec<Expr>, bool),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+}
+
+/// Positional and keyword arguments of a call
+type CallArgs = (Vec<Expr>, Vec<(String, Expr)>);
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + offset)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn peek_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Some(Token::Op(o)) if *o == op)
+    }
+
+    fn peek_name(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(n)) if n == name)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if self.peek_op(op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_name(&mut self, name: &str) -> bool {
+        if self.peek_name(name) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if self.eat_op(op) {
+            Ok(())
+        } else {
+            bail!("expected '{}' but found {:?}", op, self.peek())
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            other => bail!("expected a name but found {:?}", other),
+        }
+    }
+
+    fn expect_end(&self) -> Result<()> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            bail!("unexpected {:?}", self.peek())
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr> {
+        self.parse_condexpr(true)
+    }
+
+    fn parse_condexpr(&mut self, allow_ternary: bool) -> Result<Expr> {
+        let expr = self.parse_or()?;
+        if allow_ternary && self.eat_name("if") {
+            let condition = self.parse_or()?;
+            let otherwise = if self.eat_name("else") {
+                Some(Box::new(self.parse_condexpr(true)?))
+            } else {
+                None
+            };
+            return Ok(Expr::Ternary(Box::new(condition), Box::new(expr), otherwise));
+        }
+        Ok(expr)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut left = self.parse_and()?;
+        while self.eat_name("or") {
+            left = Expr::Binary(BinOp::Or, Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut left = self.parse_not()?;
+        while self.eat_name("and") {
+            left = Expr::Binary(BinOp::And, Box::new(left), Box::new(self.parse_not()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.eat_name("not") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_compare()
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr> {
+        let mut left = self.parse_math1()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("==")) => BinOp::Eq,
+                Some(Token::Op("!=")) => BinOp::Ne,
+                Some(Token::Op("<")) => BinOp::Lt,
+                Some(Token::Op(">")) => BinOp::Gt,
+                Some(Token::Op("<=")) => BinOp::Le,
+                Some(Token::Op(">=")) => BinOp::Ge,
+                Some(Token::Name(n)) if n == "in" => BinOp::In,
+                Some(Token::Name(n)) if n == "not" && matches!(self.peek_at(1), Some(Token::Name(m)) if m == "in") => {
+                    self.pos += 2;
+                    let right = self.parse_math1()?;
+                    left = Expr::Not(Box::new(Expr::Binary(BinOp::In, Box::new(left), Box::new(right))));
+                    continue;
+                }
+                _ => break,
+            };
+            self.pos += 1;
+            left = Expr::Binary(op, Box::new(left), Box::new(self.parse_math1()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_math1(&mut self) -> Result<Expr> {
+        let mut left = self.parse_concat()?;
+        loop {
+            let op = if self.eat_op("+") {
+                BinOp::Add
+            } else if self.eat_op("-") {
+                BinOp::Sub
+            } else {
+                break;
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.parse_concat()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_concat(&mut self) -> Result<Expr> {
+        let mut left = self.parse_math2()?;
+        while self.eat_op("~") {
+            left = Expr::Binary(BinOp::Concat, Box::new(left), Box::new(self.parse_math2()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_math2(&mut self) -> Result<Expr> {
+        let mut left = self.parse_pow()?;
+        loop {
+            let op = if self.eat_op("*") {
+                BinOp::Mul
+            } else if self.eat_op("//") {
+                BinOp::FloorDiv
+            } else if self.eat_op("/") {
+                BinOp::Div
+            } else if self.eat_op("%") {
+                BinOp::Mod
+            } else {
+                break;
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.parse_pow()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_pow(&mut self) -> Result<Expr> {
+        let mut left = self.parse_unary()?;
+        while self.eat_op("**") {
+            left = Expr::Binary(BinOp::Pow, Box::new(left), Box::new(self.parse_unary()?));
+        }
+        Ok(left)
+    }
+
+    /// Unary sign binds tighter than filters: `-1|abs` is `abs(-1)`
+    fn parse_unary(&mut self) -> Result<Expr> {
+        let expr = self.parse_signed()?;
+        self.parse_filters(expr)
+    }
+
+    fn parse_signed(&mut self) -> Result<Expr> {
+        if self.eat_op("-") {
+            Ok(Expr::Neg(Box::new(self.parse_signed()?)))
+        } else if self.eat_op("+") {
+            self.parse_signed()
+        } else {
+            let primary = self.parse_primary()?;
+            self.parse_postfix(primary)
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(match name.as_str() {
+                "true" | "True" => Expr::Literal(Value::Bool(true)),
+                "false" | "False" => Expr::Literal(Value::Bool(false)),
+                "none" | "None" => Expr::Literal(Value::None),
+                _ => Expr::Var(name),
+            }),
+            Some(Token::Str(mut value)) => {
+                // Adjacent string literals are concatenated
+                while let Some(Token::Str(next)) = self.peek() {
+                    value.push_str(next);
+                    self.pos += 1;
+                }
+                Ok(Expr::Literal(Value::Str(value)))
+            }
+            Some(Token::Int(i)) => Ok(Expr::Literal(Value::Int(i))),
+            Some(Token::Float(f)) => Ok(Expr::Literal(Value::Float(f))),
+            Some(Token::Op("(")) => {
+                let first = self.parse_expr()?;
+                if self.peek_op(",") {
+                    let mut items = vec![first];
+                    while self.eat_op(",") && !self.peek_op(")") {
+                        items.push(self.parse_expr()?);
+                    }
+                    self.expect_op(")")?;
+                    return Ok(Expr::List(items));
+                }
+                self.expect_op(")")?;
+                Ok(first)
+            }
+            Some(Token::Op("[")) => {
+                let mut items = Vec::new();
+                while !self.peek_op("]") {
+                    items.push(self.parse_expr()?);
+                    if !self.eat_op(",") {
+                        break;
+                    }
+                }
+                self.expect_op("]")?;
+                Ok(Expr::List(items))
+            }
+            Some(Token::Op("{")) => {
+                let mut entries = Vec::new();
+                while !self.peek_op("}") {
+                    let key = self.parse_expr()?;
+                    self.expect_op(":")?;
+                    entries.push((key, self.parse_expr()?));
+                    if !self.eat_op(",") {
+                        break;
+                    }
+                }
+                self.expect_op("}")?;
+                Ok(Expr::Dict(entries))
+            }
+            other => bail!("unexpected {:?} in expression", other),
+        }
+    }
+
+    fn parse_postfix(&mut self, mut expr: Expr) -> Result<Expr> {
+        loop {
+            if self.eat_op(".") {
+                let name = match self.next() {
+                    Some(Token::Name(name)) => name,
+                    Some(Token::Int(i)) => {
+                        expr = Expr::Index(Box::new(expr), Box::new(Expr::Literal(Value::Int(i))));
+                        continue;
+                    }
+                    other => bail!("expected attribute name but found {:?}", other),
+                };
+                expr = Expr::Attr(Box::new(expr), name);
+            } else if self.eat_op("[") {
+                expr = self.parse_subscript(expr)?;
+            } else if self.eat_op("(") {
+                let (args, kwargs) = self.parse_call_args()?;
+                expr = Expr::Call(Box::new(expr), args, kwargs);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_subscript(&mut self, expr: Expr) -> Result<Expr> {
+        let start = if self.peek_op(":") { None } else { Some(Box::new(self.parse_expr()?)) };
+        if !self.eat_op(":") {
+            self.expect_op("]")?;
+            let index = start.ok_or_else(|| anyhow!("empty subscript"))?;
+            return Ok(Expr::Index(Box::new(expr), index));
+        }
+
+        let stop = if self.peek_op("]") || self.peek_op(":") { None } else { Some(Box::new(self.parse_expr()?)) };
+        let step = if self.eat_op(":") && !self.peek_op("]") { Some(Box::new(self.parse_expr()?)) } else { None };
+        self.expect_op("]")?;
+        Ok(Expr::Slice(Box::new(expr), start, stop, step))
+    }
+
+    /// Parse call arguments after the opening parenthesis
+    fn parse_call_args(&mut self) -> Result<CallArgs> {
+        let mut args = Vec::new();
+        let mut kwargs = Vec::new();
+        while !self.peek_op(")") {
+            let is_kwarg = matches!(self.peek(), Some(Token::Name(_))) && matches!(self.peek_at(1), Some(Token::Op("=")));
+            if is_kwarg {
+                let name = self.expect_name()?;
+                self.pos += 1;
+                kwargs.push((name, self.parse_expr()?));
+            } else {
+                args.push(self.parse_expr()?);
+            }
+            if !self.eat_op(",") {
+                break;
+            }
+        }
+        self.expect_op(")")?;
+        Ok((args, kwargs))
+    }
+
+    fn parse_filters(&mut self, mut expr: Expr) -> Result<Expr> {
+        loop {
+            if self.eat_op("|") {
+                let name = self.expect_name()?;
+                let (args, kwargs) = if self.eat_op("(") { self.parse_call_args()? } else { (Vec::new(), Vec::new()) };
+                expr = Expr::Filter(Box::new(expr), name, args, kwargs);
+            } else if self.eat_name("is") {
+                let negated = self.eat_name("not");
+                let name = match self.next() {
+                    Some(Token::Name(name)) => name,
+                    Some(Token::Op("==")) => "eq".to_string(),
+                    other => bail!("expected a test name but found {:?}", other),
+                };
+                let args = if self.eat_op("(") {
+                    self.parse_call_args()?.0
+                } else if matches!(self.peek(), Some(Token::Str(_) | Token::Int(_) | Token::Float(_))) {
+                    vec![self.parse_primary()?]
+                } else {
+                    Vec::new()
+                };
+                expr = Expr::Test(Box::new(expr), name, args, negated);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Output(Expr),
+    If(Vec<(Expr, Vec<Node>)>, Vec<Node>),
+    For {
+        targets: Vec<String>,
+        iterable: Expr,
+        condition: Option<Expr>,
+        body: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Set {
+        target: String,
+        attr: Option<String>,
+        value: Expr,
+    },
+    Break,
+    Continue,
+}
+
+enum Segment {
+    Text(String),
+    Output(String),
+    Statement(String),
+}
+
+/// Split template source into text, `{{ }}` and `{% %}` segments, applying whitespace control
+fn split_segments(source: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = source;
+    let mut at_template_start = true;
+    let mut strip_next_all = false;
+    let mut strip_next_newline = false;
+
+    loop {
+        let start = ["{{", "{%", "{#"].iter()
+            .filter_map(|open| rest.find(open))
+            .min();
+        let raw = &rest[..start.unwrap_or(rest.len())];
+        let mut end = raw.len();
+
+        let tag = match start {
+            Some(start) => {
+                let kind = &rest[start..start + 2];
+                let (left_trim, keep_left) = match rest[start + 2..].chars().next() {
+                    Some('-') => (true, false),
+                    Some('+') => (false, true),
+                    _ => (false, false),
+                };
+
+                if left_trim {
+                    end = raw.trim_end().len();
+                } else if kind != "{{" && !keep_left {
+                    // lstrip_blocks: drop indentation between the start of the line and a block tag
+                    let line_start = raw.rfind('\n').map(|i| i + 1)
+                        .or(if at_template_start { Some(0) } else { None });
+                    if let Some(line_start) = line_start {
+                        if raw[line_start..].chars().all(|c| c == ' ' || c == '\t') {
+                            end = line_start;
+                        }
+                    }
+                }
+                Some((start, kind, start + 2 + usize::from(left_trim || keep_left)))
+            }
+            None => None,
+        };
+
+        let mut text = &raw[..end];
+        if strip_next_all {
+            text = text.trim_start();
+        } else if strip_next_newline {
+            // trim_blocks: drop the first newline after a block tag
+            text = text.strip_prefix("\r\n").or_else(|| text.strip_prefix('\n')).unwrap_or(text);
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text.to_string()));
+        }
+
+        let Some((_, kind, body_start)) = tag else {
+            return Ok(segments);
+        };
+
+        let close = match kind {
+            "{#" => "#}",
+            "{{" => "}}",
+            _ => "%}",
+        };
+        let body_len = find_close(&rest[body_start..], close)
+            .ok_or_else(|| anyhow!("unclosed '{}' tag", kind))?;
+        let mut body = &rest[body_start..body_start + body_len];
+        let right_trim = body.ends_with('-');
+        if right_trim {
+            body = &body[..body.len() - 1];
+        }
+
+        match kind {
+            "{{" => segments.push(Segment::Output(body.trim().to_string())),
+            "{%" => segments.push(Segment::Statement(body.trim().to_string())),
+            _ => {}
+        }
+
+        at_template_start = false;
+        strip_next_all = right_trim;
+        strip_next_newline = kind != "{{";
+        rest = &rest[body_start + body_len + close.len()..];
+    }
+}
+
+/// Find the closing delimiter of a tag, ignoring delimiters inside string literals
+fn find_close(body: &str, close: &str) -> Option<usize> {
+    if close == "#}" {
+        return body.find(close);
+    }
+
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in body.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if body[i..].starts_with(close) {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Parsed nodes and the end tag that stopped them, with the rest of that tag
+type Block = (Vec<Node>, Option<(String, ExprParser)>);
+
+struct TemplateParser {
+    segments: Vec<Segment>,
+    pos: usize,
+}
+
+impl TemplateParser {
+    /// Parse nodes until one of `end_tags` (returned along with the rest of its tag)
+    fn parse_nodes(&mut self, end_tags: &[&str]) -> Result<Block> {
+        let mut nodes = Vec::new();
+
+        while self.pos < self.segments.len() {
+            let segment = &self.segments[self.pos];
+            self.pos += 1;
+
+            match segment {
+                Segment::Text(text) => nodes.push(Node::Text(text.clone())),
+                Segment::Output(source) => {
+                    let mut parser = ExprParser::new(tokenize(source)?);
+                    let expr = parser.parse_expr()?;
+                    parser.expect_end()?;
+                    nodes.push(Node::Output(expr));
+                }
+                Segment::Statement(source) => {
+                    let mut parser = ExprParser::new(tokenize(source)?);
+                    let keyword = parser.expect_name()?;
+                    if end_tags.contains(&keyword.as_str()) {
+                        return Ok((nodes, Some((keyword, parser))));
+                    }
+                    match keyword.as_str() {
+                        "if" => nodes.push(self.parse_if(parser)?),
+                        "for" => nodes.push(self.parse_for(parser)?),
+                        "set" => nodes.push(Self::parse_set(parser)?),
+                        "break" => nodes.push(Node::Break),
+                        "continue" => nodes.push(Node::Continue),
+                        // transformers' `{% generation %}` marker only tracks assistant spans
+                        "generation" => {
+                            let (body, _) = self.parse_nodes(&["endgeneration"])?;
+                            nodes.extend(body);
+                        }
+                        other => bail!("unsupported tag '{}'", other),
+                    }
+                }
+            }
+        }
+
+        if end_tags.is_empty() {
+            Ok((nodes, None))
+        } else {
+            bail!("missing {{% {} %}}", end_tags.last().unwrap_or(&"end"))
+        }
+    }
+
+    fn parse_if(&mut self, mut parser: ExprParser) -> Result<Node> {
+        let mut branches = Vec::new();
+        let mut condition = parser.parse_expr()?;
+        parser.expect_end()?;
+
+        loop {
+            let (body, end) = self.parse_nodes(&["elif", "else", "endif"])?;
+            branches.push((condition, body));
+            match end {
+                Some((tag, mut parser)) if tag == "elif" => {
+                    condition = parser.parse_expr()?;
+                    parser.expect_end()?;
+                }
+                Some((tag, _)) if tag == "else" => {
+                    let (otherwise, _) = self.parse_nodes(&["endif"])?;
+                    return Ok(Node::If(branches, otherwise));
+                }
+                _ => return Ok(Node::If(branches, Vec::new())),
+            }
+        }
+    }
+
+    fn parse_for(&mut self, mut parser: ExprParser) -> Result<Node> {
+        let mut targets = vec![parser.expect_name()?];
+        while parser.eat_op(",") {
+            targets.push(parser.expect_name()?);
+        }
+        if !parser.eat_name("in") {
+            bail!("expected 'in' in for loop");
+        }
+        let iterable = parser.parse_condexpr(false)?;
+        let condition = if parser.eat_name("if") { Some(parser.parse_or()?) } else { None };
+        parser.expect_end()?;
+
+        let (body, end) = self.parse_nodes(&["else", "endfor"])?;
+        let otherwise = match end {
+            Some((tag, _)) if tag == "else" => self.parse_nodes(&["endfor"])?.0,
+            _ => Vec::new(),
+        };
+
+        Ok(Node::For { targets, iterable, condition, body, otherwise })
+    }
+
+    fn parse_set(mut parser: ExprParser) -> Result<Node> {
+        let target = parser.expect_name()?;
+        let attr = if parser.eat_op(".") { Some(parser.expect_name()?) } else { None };
+        parser.expect_op("=")?;
+        let value = parser.parse_expr()?;
+        parser.expect_end()?;
+        Ok(Node::Set { target, attr, value })
+    }
+}
+
+/// A compiled template
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+}
+
+impl Template {
+    pub fn compile(source: &str) -> Result<Self> {
+        let mut parser = TemplateParser {
+            segments: split_segments(source)?,
+            pos: 0,
+        };
+        let (nodes, _) = parser.parse_nodes(&[])?;
+        Ok(Self { nodes })
+    }
+
+    /// Render with the given top-level variables
+    pub fn render(&self, variables: BTreeMap<String, Value>) -> Result<String> {
+        let mut renderer = Renderer {
+            scopes: vec![variables],
+            out: String::new(),
+        };
+        renderer.render_nodes(&self.nodes)?;
+        Ok(renderer.out)
+    }
+}
+
+struct Renderer {
+    scopes: Vec<BTreeMap<String, Value>>,
+    out: String,
+}
+
+impl Renderer {
+    fn lookup(&self, name: &str) -> Value {
+        self.scopes.iter().rev()
+            .find_map(|scope| scope.get(name).cloned())
+            .unwrap_or(Value::Undefined)
+    }
+
+    fn render_nodes(&mut self, nodes: &[Node]) -> Result<Flow> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => self.out.push_str(text),
+                Node::Output(expr) => {
+                    let value = self.eval(expr)?;
+                    self.out.push_str(&value.to_display());
+                }
+                Node::If(branches, otherwise) => {
+                    let mut body = otherwise;
+                    for (condition, branch) in branches {
+                        if self.eval(condition)?.truthy() {
+                            body = branch;
+                            break;
+                        }
+                    }
+                    match self.render_nodes(body)? {
+                        Flow::Normal => {}
+                        flow => return Ok(flow),
+                    }
+                }
+                Node::For { targets, iterable, condition, body, otherwise } => {
+                    self.render_for(targets, iterable, condition.as_ref(), body, otherwise)?;
+                }
+                Node::Set { target, attr, value } => {
+                    let value = self.eval(value)?;
+                    match attr {
+                        Some(attr) => match self.lookup(target) {
+                            Value::Namespace(ns) => {
+                                ns.lock()
+                                    .map_err(|_| anyhow!("namespace lock poisoned"))?
+                                    .insert(attr.clone(), value);
+                            }
+                            other => bail!("cannot set attribute on {}", other.type_name()),
+                        },
+                        None => {
+                            if let Some(scope) = self.scopes.last_mut() {
+                                scope.insert(target.clone(), value);
+                            }
+                        }
+                    }
+                }
+                Node::Break => return Ok(Flow::Break),
+                Node::Continue => return Ok(Flow::Continue),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn bind_targets(scope: &mut BTreeMap<String, Value>, targets: &[String], item: Value) -> Result<()> {
+        if let [target] = targets {
+            scope.insert(target.clone(), item);
+            return Ok(());
+        }
+        let values = item.iterate()?;
+        if values.len() != targets.len() {
+            bail!("cannot unpack {} values into {} names", values.len(), targets.len());
+        }
+        for (target, value) in targets.iter().zip(values) {
+            scope.insert(target.clone(), value);
+        }
+        Ok(())
+    }
+
+    fn render_for(
+        &mut self,
+        targets: &[String],
+        iterable: &Expr,
+        condition: Option<&Expr>,
+        body: &[Node],
+        otherwise: &[Node],
+    ) -> Result<()> {
+        let mut items = self.eval(iterable)?.iterate()?;
+        if let Some(condition) = condition {
+            let mut kept = Vec::new();
+            for item in items {
+                let mut scope = BTreeMap::new();
+                Self::bind_targets(&mut scope, targets, item.clone())?;
+                self.scopes.push(scope);
+                let keep = self.eval(condition);
+                self.scopes.pop();
+                if keep?.truthy() {
+                    kept.push(item);
+                }
+            }
+            items = kept;
+        }
+
+        if items.is_empty() {
+            self.render_nodes(otherwise)?;
+            return Ok(());
+        }
+
+        let length = items.len();
+        for (i, item) in items.iter().enumerate() {
+            let mut scope = BTreeMap::new();
+            Self::bind_targets(&mut scope, targets, item.clone())?;
+
+            let mut loop_info = BTreeMap::new();
+            loop_info.insert("index".to_string(), Value::Int(i as i64 + 1));
+            loop_info.insert("index0".to_string(), Value::Int(i as i64));
+            loop_info.insert("revindex".to_string(), Value::Int((length - i) as i64));
+            loop_info.insert("revindex0".to_string(), Value::Int((length - i - 1) as i64));
+            loop_info.insert("first".to_string(), Value::Bool(i == 0));
+            loop_info.insert("last".to_string(), Value::Bool(i + 1 == length));
+            loop_info.insert("length".to_string(), Value::Int(length as i64));
+            loop_info.insert("previtem".to_string(), if i > 0 { items[i - 1].clone() } else { Value::Undefined });
+            loop_info.insert("nextitem".to_string(), items.get(i + 1).cloned().unwrap_or(Value::Undefined));
+            scope.insert("loop".to_string(), Value::Map(loop_info));
+
+            self.scopes.push(scope);
+            let flow = self.render_nodes(body);
+            self.scopes.pop();
+            if let Flow::Break = flow? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Value> {
+        Ok(match expr {
+            Expr::Literal(value) => value.clone(),
+            Expr::Var(name) => self.lookup(name),
+            Expr::List(items) => Value::List(items.iter().map(|e| self.eval(e)).collect::<Result<_>>()?),
+            Expr::Dict(entries) => {
+                let mut map = BTreeMap::new();
+                for (key, value) in entries {
+                    map.insert(self.eval(key)?.to_display(), self.eval(value)?);
+                }
+                Value::Map(map)
+            }
+            Expr::Attr(target, name) => self.eval(target)?.get_attr(name),
+            Expr::Index(target, key) => self.eval(target)?.get_item(&self.eval(key)?),
+            Expr::Slice(target, start, stop, step) => {
+                let eval_int = |e: &Option<Box<Expr>>| -> Result<Option<i64>> {
+                    match e {
+                        Some(e) => match self.eval(e)? {
+                            Value::None => Ok(None),
+                            v => v.as_int().map(Some).ok_or_else(|| anyhow!("slice indices must be integers")),
+                        },
+                        None => Ok(None),
+                    }
+                };
+                slice(self.eval(target)?, eval_int(start)?, eval_int(stop)?, eval_int(step)?)?
+            }
+            Expr::Call(callee, args, kwargs) => {
+                let args = args.iter().map(|e| self.eval(e)).collect::<Result<Vec<_>>>()?;
+                let kwargs = kwargs.iter()
+                    .map(|(k, e)| Ok((k.clone(), self.eval(e)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                match callee.as_ref() {
+                    Expr::Attr(target, method) => call_method(self.eval(target)?, method, &args)?,
+                    Expr::Var(name) => call_global(name, &args, &kwargs)?,
+                    _ => bail!("value is not callable"),
+                }
+            }
+            Expr::Filter(target, name, args, kwargs) => {
+                let value = self.eval(target)?;
+                let args = args.iter().map(|e| self.eval(e)).collect::<Result<Vec<_>>>()?;
+                let kwargs = kwargs.iter()
+                    .map(|(k, e)| Ok((k.clone(), self.eval(e)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                apply_filter(value, name, &args, &kwargs)?
+            }
+            Expr::Test(target, name, args, negated) => {
+                let value = self.eval(target)?;
+                let args = args.iter().map(|e| self.eval(e)).collect::<Result<Vec<_>>>()?;
+                Value::Bool(apply_test(&value, name, &args)? != *negated)
+            }
+            Expr::Not(inner) => Value::Bool(!self.eval(inner)?.truthy()),
+            Expr::Neg(inner) => match self.eval(inner)? {
+                Value::Int(i) => checked_int(i.checked_neg(), "unary -")?,
+                Value::Float(f) => Value::Float(-f),
+                other => bail!("bad operand type for unary -: {}", other.type_name()),
+            },
+            Expr::Binary(BinOp::And, left, right) => {
+                let left = self.eval(left)?;
+                if left.truthy() { self.eval(right)? } else { left }
+            }
+            Expr::Binary(BinOp::Or, left, right) => {
+                let left = self.eval(left)?;
+                if left.truthy() { left } else { self.eval(right)? }
+            }
+            Expr::Binary(op, left, right) => binary(*op, self.eval(left)?, self.eval(right)?)?,
+            Expr::Ternary(condition, then, otherwise) => {
+                if self.eval(condition)?.truthy() {
+                    self.eval(then)?
+                } else {
+                    match otherwise {
+                        Some(otherwise) => self.eval(otherwise)?,
+                        None => Value::Undefined,
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn binary(op: BinOp, left: Value, right: Value) -> Result<Value> {
+    use std::cmp::Ordering;
+
+    Ok(match op {
+        BinOp::Eq => Value::Bool(left.equals(&right)),
+        BinOp::Ne => Value::Bool(!left.equals(&right)),
+        BinOp::Lt => Value::Bool(left.compare(&right)? == Ordering::Less),
+        BinOp::Gt => Value::Bool(left.compare(&right)? == Ordering::Greater),
+        BinOp::Le => Value::Bool(left.compare(&right)? != Ordering::Greater),
+        BinOp::Ge => Value::Bool(left.compare(&right)? != Ordering::Less),
+        BinOp::In => Value::Bool(right.contains(&left)?),
+        BinOp::Concat => Value::Str(left.to_display() + &right.to_display()),
+        BinOp::Add => match (left, right) {
+            (Value::Str(a), Value::Str(b)) => Value::Str(a + &b),
+            (Value::List(mut a), Value::List(b)) => {
+                a.extend(b);
+                Value::List(a)
+            }
+            (Value::Int(a), Value::Int(b)) => checked_int(a.checked_add(b), "+")?,
+            (a, b) => match (a.as_f64(), b.as_f64()) {
+                (Some(x), Some(y)) => Value::Float(x + y),
+                _ => bail!("unsupported operand types for +: {} and {}", a.type_name(), b.type_name()),
+            },
+        },
+        BinOp::Mul => match (left, right) {
+            (Value::Str(s), Value::Int(n)) | (Value::Int(n), Value::Str(s)) => {
+                let count = usize::try_from(n).unwrap_or(0);
+                if s.len().checked_mul(count).is_none_or(|len| len > MAX_REPEAT_LEN) {
+                    bail!("string repetition would exceed {} bytes", MAX_REPEAT_LEN);
+                }
+                Value::Str(s.repeat(count))
+            }
+            (Value::Int(a), Value::Int(b)) => checked_int(a.checked_mul(b), "*")?,
+            (a, b) => numeric(&a, &b, "*", |x, y| x * y)?,
+        },
+        BinOp::Sub => match (left, right) {
+            (Value::Int(a), Value::Int(b)) => checked_int(a.checked_sub(b), "-")?,
+            (a, b) => numeric(&a, &b, "-", |x, y| x - y)?,
+        },
+        BinOp::Div => numeric(&left, &right, "/", |x, y| x / y)?,
+        BinOp::FloorDiv => match (left, right) {
+            (Value::Int(_), Value::Int(0)) => bail!("integer division by zero"),
+            (Value::Int(a), Value::Int(b)) => checked_int(
+                a.checked_div_euclid(b).map(|q| q - i64::from(b < 0 && a.rem_euclid(b) != 0)),
+                "//",
+            )?,
+            (a, b) => numeric(&a, &b, "//", |x, y| (x / y).floor())?,
+        },
+        BinOp::Mod => match (left, right) {
+            (Value::Int(_), Value::Int(0)) => bail!("integer modulo by zero"),
+            // Python's modulo takes the sign of the divisor
+            (Value::Int(a), Value::Int(b)) => checked_int(
+                a.checked_rem(b).map(|r| if r != 0 && (r < 0) != (b < 0) { r + b } else { r }),
+                "%",
+            )?,
+            (a, b) => numeric(&a, &b, "%", |x, y| x - y * (x / y).floor())?,
+        },
+        BinOp::Pow => match (left, right) {
+            (Value::Int(a), Value::Int(b)) if b >= 0 => checked_int(
+                u32::try_from(b).ok().and_then(|b| a.checked_pow(b)),
+                "**",
+            )?,
+            (a, b) => numeric(&a, &b, "**", f64::powf)?,
+        },
+        BinOp::And | BinOp::Or => unreachable!("short-circuit operators are evaluated in Renderer::eval"),
+    })
+}
+
+/// Templates come from model files, so integer overflow is a render error rather than a panic
+fn checked_int(result: Option<i64>, symbol: &str) -> Result<Value> {
+    result.map(Value::Int).ok_or_else(|| anyhow!("integer overflow in {}", symbol))
+}
+
+fn numeric(left: &Value, right: &Value, symbol: &str, f: impl Fn(f64, f64) -> f64) -> Result<Value> {
+    match (left.as_f64(), right.as_f64()) {
+        (Some(x), Some(y)) => Ok(Value::Float(f(x, y))),
+        _ => bail!("unsupported operand types for {}: {} and {}", symbol, left.type_name(), right.type_name()),
+    }
+}
+
+/// Python slicing for lists and strings
+fn slice(value: Value, start: Option<i64>, stop: Option<i64>, step: Option<i64>) -> Result<Value> {
+    let step = step.unwrap_or(1);
+    if step == 0 {
+        bail!("slice step cannot be zero");
+    }
+
+    let pick = |len: usize| -> Vec<usize> {
+        let len = len as i64;
+        let clamp = |i: i64, low: i64, high: i64| {
+            let i = if i < 0 { i + len } else { i };
+            i.clamp(low, high)
+        };
+        let mut indices = Vec::new();
+        if step > 0 {
+            let mut i = start.map_or(0, |s| clamp(s, 0, len));
+            let end = stop.map_or(len, |s| clamp(s, 0, len));
+            while i < end {
+                indices.push(i as usize);
+                let Some(next) = i.checked_add(step) else { break };
+                i = next;
+            }
+        } else {
+            let mut i = start.map_or(len - 1, |s| clamp(s, -1, len - 1));
+            let end = stop.map_or(-1, |s| clamp(s, -1, len - 1));
+            while i > end {
+                indices.push(i as usize);
+                let Some(next) = i.checked_add(step) else { break };
+                i = next;
+            }
+        }
+        indices
+    };
+
+    Ok(match value {
+        Value::List(items) => Value::List(pick(items.len()).into_iter().map(|i| items[i].clone()).collect()),
+        Value::Str(s) => {
+            let chars: Vec<char> = s.chars().collect();
+            Value::Str(pick(chars.len()).into_iter().map(|i| chars[i]).collect())
+        }
+        Value::Undefined | Value::None => Value::Undefined,
+        other => bail!("{} is not subscriptable", other.type_name()),
+    })
+}
+
+fn str_arg(args: &[Value], i: usize) -> Option<&str> {
+    match args.get(i) {
+        Some(Value::Str(s)) => Some(s.as_str()),
+        _ => None,
+    }
+}
+
+fn call_method(target: Value, method: &str, args: &[Value]) -> Result<Value> {
+    let strip_chars = |args: &[Value]| -> Vec<char> {
+        str_arg(args, 0).map(|s| s.chars().collect()).unwrap_or_default()
+    };
+
+    Ok(match (&target, method) {
+        (Value::Str(s), "strip") => {
+            let chars = strip_chars(args);
+            Value::Str(if chars.is_empty() { s.trim().to_string() } else { s.trim_matches(chars.as_slice()).to_string() })
+        }
+        (Value::Str(s), "lstrip") => {
+            let chars = strip_chars(args);
+            Value::Str(if chars.is_empty() { s.trim_start().to_string() } else { s.trim_start_matches(chars.as_slice()).to_string() })
+        }
+        (Value::Str(s), "rstrip") => {
+            let chars = strip_chars(args);
+            Value::Str(if chars.is_empty() { s.trim_end().to_string() } else { s.trim_end_matches(chars.as_slice()).to_string() })
+        }
+        (Value::Str(s), "split") => {
+            let limit = args.get(1).and_then(Value::as_int).filter(|n| *n >= 0).map(|n| n as usize + 1);
+            let parts: Vec<Value> = match (str_arg(args, 0), limit) {
+                (Some(sep), Some(n)) => s.splitn(n, sep).map(Value::from).collect(),
+                (Some(sep), None) => s.split(sep).map(Value::from).collect(),
+                (None, _) => s.split_whitespace().map(Value::from).collect(),
+            };
+            Value::List(parts)
+        }
+        (Value::Str(s), "startswith" | "endswith") => {
+            let prefixes: Vec<String> = match args.first() {
+                Some(Value::Str(p)) => vec![p.clone()],
+                Some(Value::List(items)) => items.iter().map(Value::to_display).collect(),
+                _ => bail!("{}() expects a string", method),
+            };
+            Value::Bool(prefixes.iter().any(|p| if method == "startswith" { s.starts_with(p.as_str()) } else { s.ends_with(p.as_str()) }))
+        }
+        (Value::Str(s), "upper") => Value::Str(s.to_uppercase()),
+        (Value::Str(s), "lower") => Value::Str(s.to_lowercase()),
+        (Value::Str(s), "title") => Value::Str(title_case(s)),
+        (Value::Str(s), "capitalize") => Value::Str(capitalize(s)),
+        (Value::Str(s), "replace") => match (str_arg(args, 0), str_arg(args, 1)) {
+            (Some(from), Some(to)) => Value::Str(s.replace(from, to)),
+            _ => bail!("replace() expects two strings"),
+        },
+        (Value::Str(s), "find") => Value::Int(str_arg(args, 0)
+            .and_then(|needle| s.find(needle))
+            .map(|i| s[..i].chars().count() as i64)
+            .unwrap_or(-1)),
+        (Value::Map(map), "get") => str_arg(args, 0)
+            .and_then(|key| map.get(key).cloned())
+            .unwrap_or_else(|| args.get(1).cloned().unwrap_or(Value::None)),
+        (Value::Map(map), "items") => Value::List(map.iter()
+            .map(|(k, v)| Value::List(vec![Value::Str(k.clone()), v.clone()]))
+            .collect()),
+        (Value::Map(map), "keys") => Value::List(map.keys().map(|k| Value::Str(k.clone())).collect()),
+        (Value::Map(map), "values") => Value::List(map.values().cloned().collect()),
+        (_, method) => bail!("{} has no method '{}'", target.type_name(), method),
+    })
+}
+
+fn call_global(name: &str, args: &[Value], kwargs: &[(String, Value)]) -> Result<Value> {
+    Ok(match name {
+        "raise_exception" => bail!("{}", args.first().map(Value::to_display).unwrap_or_default()),
+        "namespace" => {
+            let mut attrs = match args.first() {
+                Some(Value::Map(map)) => map.clone(),
+                _ => BTreeMap::new(),
+            };
+            attrs.extend(kwargs.iter().cloned());
+            Value::Namespace(Arc::new(Mutex::new(attrs)))
+        }
+        "dict" => Value::Map(kwargs.iter().cloned().collect()),
+        "range" => {
+            let ints: Vec<i64> = args.iter().filter_map(Value::as_int).collect();
+            let (start, stop, step) = match ints.as_slice() {
+                [stop] => (0, *stop, 1),
+                [start, stop] => (*start, *stop, 1),
+                [start, stop, step] if *step != 0 => (*start, *stop, *step),
+                _ => bail!("range() expects 1 to 3 integers"),
+            };
+            let mut values = Vec::new();
+            let mut i = start;
+            while (step > 0 && i < stop) || (step < 0 && i > stop) {
+                if values.len() == MAX_RANGE_LEN {
+                    bail!("range() would produce more than {} items", MAX_RANGE_LEN);
+                }
+                values.push(Value::Int(i));
+                // Stepping past i64 means stepping past stop
+                let Some(next) = i.checked_add(step) else { break };
+                i = next;
+            }
+            Value::List(values)
+        }
+        "strftime_now" => {
+            let format = str_arg(args, 0).unwrap_or("%Y-%m-%d");
+            // `to_string()` panics on an invalid format; `write!` reports it instead
+            let mut formatted = String::new();
+            write!(formatted, "{}", chrono::Local::now().format(format))
+                .map_err(|_| anyhow!("strftime_now(): invalid format '{}'", format))?;
+            Value::Str(formatted)
+        }
+        other => bail!("'{}' is undefined", other),
+    })
+}
+
+fn kwarg<'a>(kwargs: &'a [(String, Value)], name: &str) -> Option<&'a Value> {
+    kwargs.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+}
+
+fn apply_filter(value: Value, name: &str, args: &[Value], kwargs: &[(String, Value)]) -> Result<Value> {
+    Ok(match name {
+        "tojson" => {
+            let indent = args.first().or_else(|| kwarg(kwargs, "indent"))
+                .and_then(Value::as_int)
+                .map(|i| i as usize);
+            Value::Str(value.to_json(indent, 0))
+        }
+        "trim" | "strip" => Value::Str(value.to_display().trim().to_string()),
+        "length" | "count" => Value::Int(value.length()? as i64),
+        "upper" => Value::Str(value.to_display().to_uppercase()),
+        "lower" => Value::Str(value.to_display().to_lowercase()),
+        "capitalize" => Value::Str(capitalize(&value.to_display())),
+        "title" => Value::Str(title_case(&value.to_display())),
+        "string" => Value::Str(value.to_display()),
+        "safe" | "e" | "escape" => value,
+        "int" => match &value {
+            Value::Int(_) => value,
+            Value::Float(f) => Value::Int(*f as i64),
+            other => Value::Int(other.to_display().trim().parse().unwrap_or(0)),
+        },
+        "float" => Value::Float(value.as_f64().unwrap_or_else(|| value.to_display().trim().parse().unwrap_or(0.0))),
+        "abs" => match value {
+            Value::Int(i) => Value::Int(i.abs()),
+            other => Value::Float(other.as_f64().unwrap_or_default().abs()),
+        },
+        "first" => value.iterate()?.into_iter().next().unwrap_or(Value::Undefined),
+        "last" => value.iterate()?.into_iter().last().unwrap_or(Value::Undefined),
+        "list" => Value::List(value.iterate()?),
+        "reverse" => match value {
+            Value::Str(s) => Value::Str(s.chars().rev().collect()),
+            other => Value::List(other.iterate()?.into_iter().rev().collect()),
+        },
+        "sort" => {
+            let mut items = value.iterate()?;
+            let attribute = kwarg(kwargs, "attribute").map(Value::to_display);
+            let key = |item: &Value| match &attribute {
+                Some(attr) => item.get_attr(attr),
+                None => item.clone(),
+            };
+            let mut error = None;
+            items.sort_by(|a, b| key(a).compare(&key(b)).unwrap_or_else(|e| {
+                error.get_or_insert(e);
+                std::cmp::Ordering::Equal
+            }));
+            if let Some(e) = error {
+                return Err(e);
+            }
+            if kwarg(kwargs, "reverse").is_some_and(Value::truthy) {
+                items.reverse();
+            }
+            Value::List(items)
+        }
+        "unique" => {
+            let mut unique: Vec<Value> = Vec::new();
+            for item in value.iterate()? {
+                if !unique.iter().any(|seen| seen.equals(&item)) {
+                    unique.push(item);
+                }
+            }
+            Value::List(unique)
+        }
+        "join" => {
+            let separator = str_arg(args, 0).unwrap_or_default();
+            Value::Str(value.iterate()?.iter().map(Value::to_display).collect::<Vec<_>>().join(separator))
+        }
+        "replace" => match (str_arg(args, 0), str_arg(args, 1)) {
+            (Some(from), Some(to)) => Value::Str(value.to_display().replace(from, to)),
+            _ => bail!("replace expects two strings"),
+        },
+        "default" | "d" => {
+            let boolean = args.get(1).map(Value::truthy).unwrap_or(false);
+            let missing = matches!(value, Value::Undefined) || (boolean && !value.truthy());
+            if missing { args.first().cloned().unwrap_or_else(|| Value::from("")) } else { value }
+        }
+        "items" => match value {
+            Value::Map(map) => Value::List(map.into_iter()
+                .map(|(k, v)| Value::List(vec![Value::Str(k), v]))
+                .collect()),
+            other => bail!("items filter expects a mapping, got {}", other.type_name()),
+        },
+        "map" => {
+            let items = value.iterate()?;
+            match kwarg(kwargs, "attribute") {
+                Some(Value::Str(attr)) => Value::List(items.iter().map(|item| item.get_attr(attr)).collect()),
+                _ => {
+                    let filter = str_arg(args, 0).ok_or_else(|| anyhow!("map expects a filter name or attribute="))?;
+                    Value::List(items.into_iter()
+                        .map(|item| apply_filter(item, filter, &args[1..], &[]))
+                        .collect::<Result<_>>()?)
+                }
+            }
+        }
+        "selectattr" | "rejectattr" => {
+            let attr = str_arg(args, 0).ok_or_else(|| anyhow!("{} expects an attribute name", name))?;
+            let select = name == "selectattr";
+            let mut kept = Vec::new();
+            for item in value.iterate()? {
+                let field = item.get_attr(attr);
+                let passed = match str_arg(args, 1) {
+                    Some(test) => apply_test(&field, test, &args[2..])?,
+                    None => field.truthy(),
+                };
+                if passed == select {
+                    kept.push(item);
+                }
+            }
+            Value::List(kept)
+        }
+        other => bail!("unknown filter '{}'", other),
+    })
+}
+
+fn apply_test(value: &Value, name: &str, args: &[Value]) -> Result<bool> {
+    Ok(match name {
+        "defined" => !matches!(value, Value::Undefined),
+        "undefined" => matches!(value, Value::Undefined),
+        "none" => matches!(value, Value::None),
+        "true" => matches!(value, Value::Bool(true)),
+        "false" => matches!(value, Value::Bool(false)),
+        "boolean" => matches!(value, Value::Bool(_)),
+        "string" => matches!(value, Value::Str(_)),
+        "number" => matches!(value, Value::Int(_) | Value::Float(_)),
+        "integer" => matches!(value, Value::Int(_)),
+        "float" => matches!(value, Value::Float(_)),
+        "mapping" => matches!(value, Value::Map(_) | Value::Namespace(_)),
+        "iterable" => matches!(value, Value::List(_) | Value::Map(_) | Value::Str(_)),
+        "sequence" => matches!(value, Value::List(_) | Value::Str(_)),
+        "even" => value.as_int().is_some_and(|i| i % 2 == 0),
+        "odd" => value.as_int().is_some_and(|i| i % 2 != 0),
+        "divisibleby" => match (value.as_int(), args.first().and_then(Value::as_int)) {
+            (Some(a), Some(b)) if b != 0 => a % b == 0,
+            _ => false,
+        },
+        "eq" | "equalto" | "sameas" => args.first().is_some_and(|other| value.equals(other)),
+        "ne" => !args.first().is_some_and(|other| value.equals(other)),
+        "in" => match args.first() {
+            Some(container) => container.contains(value)?,
+            None => false,
+        },
+        "lower" => matches!(value, Value::Str(s) if s.to_lowercase() == *s),
+        "upper" => matches!(value, Value::Str(s) if s.to_uppercase() == *s),
+        other => bail!("unknown test '{}'", other),
+    })
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+        None => String::new(),
+    }
+}
+
+fn title_case(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut at_word_start = true;
+    for c in s.chars() {
+        if c.is_alphanumeric() {
+            if at_word_start {
+                out.extend(c.to_uppercase());
+            } else {
+                out.extend(c.to_lowercase());
+            }
+            at_word_start = false;
+        } else {
+            out.push(c);
+            at_word_start = true;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// google/gemma-3-270m-it
+    const GEMMA3: &str = r#"{{ bos_token }}
+{%- if messages[0]['role'] == 'system' -%}
+    {%- if messages[0]['content'] is string -%}
+        {%- set first_user_prefix = messages[0]['content'] + '\n\n' -%}
+    {%- else -%}
+        {%- set first_user_prefix = messages[0]['content'][0]['text'] + '\n\n' -%}
+    {%- endif -%}
+    {%- set loop_messages = messages[1:] -%}
+{%- else -%}
+    {%- set first_user_prefix = "" -%}
+    {%- set loop_messages = messages -%}
+{%- endif -%}
+{%- for message in loop_messages -%}
+    {%- if (message['role'] == 'user') != (loop.index0 % 2 == 0) -%}
+        {{ raise_exception("Conversation roles must alternate user/assistant/user/assistant/...") }}
+    {%- endif -%}
+    {%- if (message['role'] == 'assistant') -%}
+        {%- set role = "model" -%}
+    {%- else -%}
+        {%- set role = message['role'] -%}
+    {%- endif -%}
+    {{ '<start_of_turn>' + role + '\n' + (first_user_prefix if loop.first else "") }}
+    {%- if message['content'] is string -%}
+        {{ message['content'] | trim }}
+    {%- elif message['content'] is iterable -%}
+        {%- for item in message['content'] -%}
+            {%- if item['type'] == 'image' -%}
+                {{ '<start_of_image>' }}
+            {%- elif item['type'] == 'text' -%}
+                {{ item['text'] | trim }}
+            {%- endif -%}
+        {%- endfor -%}
+    {%- else -%}
+        {{ raise_exception("Invalid content type") }}
+    {%- endif -%}
+    {{ '<end_of_turn>\n' }}
+{%- endfor -%}
+{%- if add_generation_prompt -%}
+    {{'<start_of_turn>model\n'}}
+{%- endif -%}
+"#;
+
+    /// meta-llama/Meta-Llama-3-8B-Instruct
+    const LLAMA3: &str = r#"{% set loop_messages = messages %}{% for message in loop_messages %}{% set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>
+
+'+ message['content'] | trim + '<|eot_id|>' %}{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}{{ content }}{% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>
+
+' }}{% endif %}"#;
+
+    /// Qwen/Qwen2.5-0.5B-Instruct, without the tool-calling branches
+    const QWEN25: &str = r#"{%- if messages[0]['role'] == 'system' %}
+    {{- '<|im_start|>system\n' + messages[0]['content'] + '<|im_end|>\n' }}
+{%- else %}
+    {{- '<|im_start|>system\nYou are Qwen, created by Alibaba Cloud. You are a helpful assistant.<|im_end|>\n' }}
+{%- endif %}
+{%- for message in messages %}
+    {%- if (message.role == "user") or (message.role == "system" and not loop.first) or (message.role == "assistant" and not message.tool_calls) %}
+        {{- '<|im_start|>' + message.role + '\n' + message.content + '<|im_end|>' + '\n' }}
+    {%- endif %}
+{%- endfor %}
+{%- if add_generation_prompt %}
+    {{- '<|im_start|>assistant\n' }}
+{%- endif %}
+"#;
+
+    /// LiquidAI/LFM2-350M
+    const LFM2: &str = r#"{{- bos_token -}}{%- set system_prompt = "" -%}{%- set ns = namespace(system_prompt="") -%}{%- if messages[0]["role"] == "system" -%} {%- set ns.system_prompt = messages[0]["content"] -%} {%- set messages = messages[1:] -%}{%- endif -%}{%- if tools -%} {%- set ns.system_prompt = ns.system_prompt + ("
+" if ns.system_prompt else "") + "List of tools: <|tool_list_start|>[" -%} {%- for tool in tools -%} {%- if tool is not string -%} {%- set tool = tool | tojson -%} {%- endif -%} {%- set ns.system_prompt = ns.system_prompt + tool -%} {%- if not loop.last -%} {%- set ns.system_prompt = ns.system_prompt + ", " -%} {%- endif -%} {%- endfor -%} {%- set ns.system_prompt = ns.system_prompt + "]<|tool_list_end|>" -%}{%- endif -%}{%- if ns.system_prompt -%} {{- "<|im_start|>system
+" + ns.system_prompt + "<|im_end|>
+" -}}{%- endif -%}{%- for message in messages -%} {{- "<|im_start|>" + message["role"] + "
+" -}} {%- set content = message["content"] -%} {%- if content is not string -%} {%- set content = content | tojson -%} {%- endif -%} {%- if message["role"] == "tool" -%} {%- set content = "<|tool_response_start|>" + content + "<|tool_response_end|>" -%} {%- endif -%} {{- content + "<|im_end|>
+" -}}{%- endfor -%}{%- if add_generation_prompt -%} {{- "<|im_start|>assistant
+" -}}{%- endif -%}"#;
+
+    fn render(source: &str, variables: serde_json::Value) -> Result<String> {
+        let variables = match Value::from(&variables) {
+            Value::Map(map) => map,
+            _ => unreachable!(),
+        };
+        Template::compile(source)?.render(variables)
+    }
+
+    fn chat(source: &str, bos: &str, messages: serde_json::Value, add_generation_prompt: bool) -> Result<String> {
+        render(source, json!({
+            "messages": messages,
+            "bos_token": bos,
+            "add_generation_prompt": add_generation_prompt,
+        }))
+    }
+
+    fn conversation() -> serde_json::Value {
+        json!([
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": "Hi"},
+            {"role": "assistant", "content": " Hello! "},
+            {"role": "user", "content": "Bye"},
+        ])
+    }
+
+    #[test]
+    fn gemma_folds_system_into_first_user_turn() {
+        let prompt = chat(GEMMA3, "<bos>", conversation(), true).unwrap();
+        assert_eq!(
+            prompt,
+            "<bos><start_of_turn>user\nBe brief.\n\nHi<end_of_turn>\n\
+             <start_of_turn>model\nHello!<end_of_turn>\n\
+             <start_of_turn>user\nBye<end_of_turn>\n\
+             <start_of_turn>model\n"
+        );
+    }
+
+    #[test]
+    fn gemma_rejects_non_alternating_roles() {
+        let messages = json!([
+            {"role": "user", "content": "one"},
+            {"role": "user", "content": "two"},
+        ]);
+        let error = chat(GEMMA3, "<bos>", messages, true).unwrap_err();
+        assert!(error.to_string().contains("Conversation roles must alternate"));
+    }
+
+    #[test]
+    fn gemma_renders_image_parts() {
+        let messages = json!([
+            {"role": "user", "content": [{"type": "image"}, {"type": "text", "text": " What is this? "}]},
+        ]);
+        let prompt = chat(GEMMA3, "<bos>", messages, false).unwrap();
+        assert_eq!(prompt, "<bos><start_of_turn>user\n<start_of_image>What is this?<end_of_turn>\n");
+    }
+
+    #[test]
+    fn llama3_adds_bos_once_and_trims_content() {
+        let prompt = chat(LLAMA3, "<|begin_of_text|>", conversation(), true).unwrap();
+        assert_eq!(
+            prompt,
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\nHello!<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nBye<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+
+    #[test]
+    fn qwen_uses_default_system_prompt() {
+        let messages = json!([{"role": "user", "content": "Hi"}]);
+        let prompt = chat(QWEN25, "", messages, false).unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nYou are Qwen, created by Alibaba Cloud. You are a helpful assistant.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn qwen_keeps_given_system_prompt() {
+        let prompt = chat(QWEN25, "", conversation(), true).unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\n Hello! <|im_end|>\n\
+             <|im_start|>user\nBye<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn lfm2_carries_system_prompt_through_namespace() {
+        let prompt = chat(LFM2, "<|startoftext|>", conversation(), true).unwrap();
+        assert_eq!(
+            prompt,
+            "<|startoftext|><|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\n Hello! <|im_end|>\n\
+             <|im_start|>user\nBye<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn lfm2_lists_tools_in_system_prompt() {
+        let prompt = render(LFM2, json!({
+            "messages": [{"role": "user", "content": "Hi"}],
+            "tools": [{"name": "search"}, "raw"],
+            "bos_token": "",
+            "add_generation_prompt": false,
+        })).unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nList of tools: <|tool_list_start|>[{\"name\": \"search\"}, raw]<|tool_list_end|><|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn loop_variables_and_control_flow() {
+        let source = "{% for x in items %}{% if x == 3 %}{% continue %}{% endif %}{% if x > 4 %}{% break %}{% endif %}\
+                      {{ loop.index }}:{{ x }}{{ ',' if not loop.last }}{% endfor %}";
+        let output = render(source, json!({"items": [1, 2, 3, 4, 5, 6]})).unwrap();
+        assert_eq!(output, "1:1,2:2,4:4,");
+    }
+
+    #[test]
+    fn set_inside_loop_only_escapes_through_namespace() {
+        let source = "{% set total = 0 %}{% set ns = namespace(total=0) %}\
+                      {% for x in range(1, 5) %}{% set total = total + x %}{% set ns.total = ns.total + x %}{% endfor %}\
+                      {{ total }} {{ ns.total }}";
+        assert_eq!(render(source, json!({})).unwrap(), "0 10");
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error() {
+        for source in [
+            "{{ 9223372036854775807 + 1 }}",
+            "{{ 9223372036854775807 * 2 }}",
+            "{{ -9223372036854775807 - 2 }}",
+            "{{ 2 ** 64 }}",
+            "{{ 2 ** 4294967296 }}",
+            "{{ 'ab' * 9223372036854775807 }}",
+        ] {
+            assert!(render(source, json!({})).is_err(), "{} should fail", source);
+        }
+        assert_eq!(render("{{ -7 // 2 }} {{ -7 % 3 }} {{ 7 % -3 }} {{ 2 ** 10 }}", json!({})).unwrap(), "-4 2 -2 1024");
+    }
+
+    #[test]
+    fn range_is_bounded() {
+        assert!(render("{{ range(1000000) | length }}", json!({})).is_err());
+        assert_eq!(render("{{ range(10, 0, -3) | join(',') }}", json!({})).unwrap(), "10,7,4,1");
+        assert_eq!(render("{{ range(9223372036854775806, 9223372036854775807, 5) | length }}", json!({})).unwrap(), "1");
+    }
+
+    #[test]
+    fn strftime_now_reports_invalid_format() {
+        assert_eq!(render("{{ strftime_now('%Y') | length }}", json!({})).unwrap(), "4");
+        assert!(render("{{ strftime_now('%Q') }}", json!({})).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+/*!
+ * JSON Schema to GBNF
+ *
+ * Converts the commonly used subset of JSON Schema (types, properties and
+ * required, items, enum/const, anyOf/oneOf, local $refs, string and array
+ * length bounds) into a GBNF grammar that only admits matching JSON.
+ * Object properties are emitted in key order, required ones first.
+ */
+
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use super::grammar::MAX_REPETITIONS;
+
+/// Shared rules for JSON primitives; whitespace is bounded so small models can't loop on it
+const PRIMITIVES: &str = r#"ws ::= | " " | "\n" [ \t]{0,20}
+boolean ::= "true" | "false"
+null ::= "null"
+integer ::= "-"? ("0" | [1-9] [0-9]{0,15})
+number ::= integer ("." [0-9]+)? ([eE] [-+]? [0-9]+)?
+char ::= [^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4})
+string ::= "\"" char* "\""
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ws ":" ws value ws ( "," ws string ws ":" ws value ws )* )? "}"
+array ::= "[" ws ( value ws ( "," ws value ws )* )? "]"
+"#;
+
+/// Grammar whose `root` matches JSON instances of `schema`
+pub fn to_gbnf(schema: &Value) -> Result<String> {
+    // Reserve the primitive rule names so schema-derived rules never shadow them
+    let names = PRIMITIVES.lines()
+        .filter_map(|line| line.split_once(" ::="))
+        .map(|(name, _)| (name.to_string(), 1))
+        .collect();
+    let mut converter = Converter { root_schema: schema, rules: Vec::new(), names, refs: HashMap::new() };
+    let root = converter.visit(schema, "root")?;
+    let mut grammar = if root == "root" { String::new() } else { format!("root ::= {}\n", root) };
+    for (name, body) in &converter.rules {
+        grammar.push_str(&format!("{} ::= {}\n", name, body));
+    }
+    grammar.push_str(PRIMITIVES);
+    Ok(grammar)
+}
+
+struct Converter<'a> {
+    root_schema: &'a Value,
+    rules: Vec<(String, String)>,
+    names: HashMap<String, usize>,
+    /// Rule names already assigned to `$ref` targets, so recursive schemas terminate
+    refs: HashMap<String, String>,
+}
+
+impl Converter<'_> {
+    /// Add a rule, making its name unique, and return the name
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let name = self.reserve(name);
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    fn reserve(&mut self, name: &str) -> String {
+        let base: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+            .collect();
+        let count = self.names.entry(base.clone()).or_insert(0);
+        *count += 1;
+        if *count == 1 { base } else { format!("{}-{}", base, count) }
+    }
+
+    /// The grammar expression for `schema`, adding rules for its parts under `name`
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Bool(false) => return Err(anyhow!("Schema '{}' admits no values", name)),
+            Value::Object(schema) => schema,
+            _ => return Err(anyhow!("Schema '{}' is not an object", name)),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return self.visit_ref(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(self.add_rule(name, literal(value)));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            let body = values.iter().map(literal).collect::<Vec<_>>().join(" | ");
+            return Ok(self.add_rule(name, body));
+        }
+        if let Some(options) = schema.get("anyOf").or_else(|| schema.get("oneOf")).and_then(Value::as_array) {
+            let body = options.iter().enumerate()
+                .map(|(index, option)| self.visit(option, &format!("{}-{}", name, index)))
+                .collect::<Result<Vec<_>>>()?
+                .join(" | ");
+            return Ok(self.add_rule(name, body));
+        }
+
+        match schema.get("type") {
+            Some(Value::Array(types)) => {
+                let body = types.iter()
+                    .map(|t| {
+                        let mut single = schema.clone();
+                        single.insert("type".to_string(), t.clone());
+                        self.visit_typed(&single, t.as_str().unwrap_or_default(), &format!("{}-{}", name, t.as_str().unwrap_or_default()))
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .join(" | ");
+                Ok(self.add_rule(name, body))
+            }
+            Some(Value::String(t)) => self.visit_typed(schema, t, name),
+            None if schema.contains_key("properties") => self.visit_typed(schema, "object", name),
+            None if schema.contains_key("items") => self.visit_typed(schema, "array", name),
+            _ => Ok("value".to_string()),
+        }
+    }
+
+    fn visit_typed(&mut self, schema: &serde_json::Map<String, Value>, kind: &str, name: &str) -> Result<String> {
+        match kind {
+            "string" => {
+                let min = schema.get("minLength").and_then(Value::as_u64).map(|m| m as usize);
+                let max = schema.get("maxLength").and_then(Value::as_u64).map(|m| m as usize);
+                if min.is_none() && max.is_none() {
+                    return Ok("string".to_string());
+                }
+                Ok(self.add_rule(name, format!("\"\\\"\" char{} \"\\\"\"", bounds(min.unwrap_or(0), max)?)))
+            }
+            "integer" => Ok("integer".to_string()),
+            "number" => Ok("number".to_string()),
+            "boolean" => Ok("boolean".to_string()),
+            "null" => Ok("null".to_string()),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.visit(items, &format!("{}-item", name))?,
+                    None => "value".to_string(),
+                };
+                let min = schema.get("minItems").and_then(Value::as_u64).unwrap_or(0) as usize;
+                let max = schema.get("maxItems").and_then(Value::as_u64).map(|m| m as usize);
+                let body = match (min, max) {
+                    (_, Some(0)) => "\"[\" ws \"]\"".to_string(),
+                    (0, max) => format!("\"[\" ws ( {item} ws ( \",\" ws {item} ws ){} )? \"]\"", bounds(0, max.map(|m| m - 1))?),
+                    (min, max) => format!("\"[\" ws {item} ws ( \",\" ws {item} ws ){} \"]\"", bounds(min - 1, max.map(|m| m - 1))?),
+                };
+                Ok(self.add_rule(name, body))
+            }
+            "object" => self.visit_object(schema, name),
+            other => Err(anyhow!("Unsupported schema type '{}' in '{}'", other, name)),
+        }
+    }
+
+    fn visit_object(&mut self, schema: &serde_json::Map<String, Value>, name: &str) -> Result<String> {
+        let empty = serde_json::Map::new();
+        let properties = schema.get("properties").and_then(Value::as_object).unwrap_or(&empty);
+        if properties.is_empty() {
+            return Ok("object".to_string());
+        }
+        let required: Vec<&str> = schema.get("required")
+            .and_then(Value::as_array)
+            .map(|names| names.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut required_pairs = Vec::new();
+        let mut optional_pairs = Vec::new();
+        for (key, property) in properties {
+            let value = self.visit(property, &format!("{}-{}", name, key))?;
+            let pair = format!("{} ws \":\" ws {}", literal(&Value::String(key.clone())), value);
+            if required.contains(&key.as_str()) {
+                required_pairs.push(pair);
+            } else {
+                optional_pairs.push(pair);
+            }
+        }
+
+        // Required properties come first in schema order, then each optional one may follow
+        let optional_tail = |pairs: &[String]| -> String {
+            pairs.iter().map(|pair| format!(" ( \",\" ws {} ws )?", pair)).collect()
+        };
+        let members = if required_pairs.is_empty() {
+            // Any optional property may come first, followed by the ones after it
+            let firsts = (0..optional_pairs.len())
+                .map(|index| format!("{} ws{}", optional_pairs[index], optional_tail(&optional_pairs[index + 1..])))
+                .collect::<Vec<_>>()
+                .join(" | ");
+            format!("( {} )?", firsts)
+        } else {
+            let required = required_pairs.join(" ws \",\" ws ");
+            format!("{} ws{}", required, optional_tail(&optional_pairs))
+        };
+        Ok(self.add_rule(name, format!("\"{{\" ws {} \"}}\"", members)))
+    }
+
+    fn visit_ref(&mut self, reference: &str) -> Result<String> {
+        if let Some(rule) = self.refs.get(reference) {
+            return Ok(rule.clone());
+        }
+        let path = reference.strip_prefix("#/")
+            .ok_or_else(|| anyhow!("Only local schema references are supported, not '{}'", reference))?;
+        let target = path.split('/')
+            .try_fold(self.root_schema, |node, part| node.get(part.replace("~1", "/").replace("~0", "~")))
+            .ok_or_else(|| anyhow!("Schema reference '{}' does not resolve", reference))?;
+
+        // Name the rule before visiting so recursive references point back at it
+        let name = self.reserve(path.rsplit('/').next().unwrap_or("ref"));
+        self.refs.insert(reference.to_string(), name.clone());
+        let body = self.visit(target, &format!("{}-body", name))?;
+        self.rules.push((name.clone(), body));
+        Ok(name)
+    }
+}
+
+/// `{min,max}` repetition bounds
+///
+/// Larger bounds are an error rather than dropped, since an answer beyond them would not match the schema.
+fn bounds(min: usize, max: Option<usize>) -> Result<String> {
+    if max.unwrap_or(min) > MAX_REPETITIONS {
+        return Err(anyhow!("Schema length bounds above {} are not supported", MAX_REPETITIONS));
+    }
+    Ok(match max {
+        Some(max) => format!("{{{},{}}}", min, max),
+        None => format!("{{{},}}", min),
+    })
+}
+
+/// A GBNF literal matching the JSON serialization of `value`
+fn literal(value: &Value) -> String {
+    let json = value.to_string();
+    let mut out = String::from("\"");
+    for c in json.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::grammar::Grammar;
+    use serde_json::json;
+
+    /// Compile `schema` and report which of `instances` its grammar accepts
+    fn accepts(schema: &Value, instances: &[&str]) -> Vec<bool> {
+        let grammar = Grammar::parse(&to_gbnf(schema).unwrap()).unwrap();
+        instances.iter()
+            .map(|text| grammar.start().accept_bytes(&grammar, text.as_bytes()).is_some_and(|state| state.is_accepting()))
+            .collect()
+    }
+
+    #[test]
+    fn objects_list_required_properties_first() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "tags": { "type": "array", "items": { "type": "string" } },
+            },
+            "required": ["name", "age"],
+        });
+        assert_eq!(accepts(&schema, &[
+            r#"{"age": 3, "name": "x"}"#,
+            r#"{"age":3,"name":"x","tags":["a", "b"]}"#,
+            r#"{"name": "x"}"#,
+            r#"{"age": "3", "name": "x"}"#,
+            r#"{"age": 3, "name": "x", "extra": 1}"#,
+        ]), [true, true, false, false, false]);
+    }
+
+    #[test]
+    fn optional_only_objects_may_be_empty_or_start_with_any_property() {
+        let schema = json!({
+            "properties": { "a": { "type": "boolean" }, "b": { "type": "null" } },
+        });
+        assert_eq!(accepts(&schema, &[r#"{}"#, r#"{"b": null}"#, r#"{"a": true, "b": null}"#, r#"{"b": null, "a": true}"#]),
+            [true, true, true, false]);
+    }
+
+    #[test]
+    fn enums_consts_and_unions() {
+        let schema = json!({
+            "anyOf": [
+                { "enum": ["red", 1, null] },
+                { "const": { "k": "v" } },
+                { "type": ["number", "boolean"] },
+            ],
+        });
+        assert_eq!(accepts(&schema, &[r#""red""#, "1", "null", r#"{"k":"v"}"#, "-2.5e3", "false", r#""blue""#]),
+            [true, true, true, true, true, true, false]);
+    }
+
+    #[test]
+    fn string_and_array_length_bounds() {
+        let schema = json!({ "type": "string", "minLength": 2, "maxLength": 3 });
+        assert_eq!(accepts(&schema, &[r#""a""#, r#""ab""#, r#""abc""#, r#""abcd""#]), [false, true, true, false]);
+
+        let schema = json!({ "type": "array", "items": { "type": "integer" }, "minItems": 1, "maxItems": 2 });
+        assert_eq!(accepts(&schema, &["[]", "[1]", "[1, 2]", "[1, 2, 3]"]), [false, true, true, false]);
+
+        let schema = json!({ "type": "array", "maxItems": 0 });
+        assert_eq!(accepts(&schema, &["[]", "[1]"]), [true, false]);
+    }
+
+    #[test]
+    fn bounds_beyond_the_grammar_limit_are_rejected() {
+        assert!(to_gbnf(&json!({ "type": "string", "maxLength": MAX_REPETITIONS })).is_ok());
+        let error = to_gbnf(&json!({ "type": "string", "maxLength": MAX_REPETITIONS + 1 })).unwrap_err();
+        assert!(error.to_string().contains("not supported"), "{}", error);
+        assert!(to_gbnf(&json!({ "type": "array", "minItems": MAX_REPETITIONS + 2 })).is_err());
+    }
+
+    #[test]
+    fn recursive_refs_terminate() {
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": { "children": { "type": "array", "items": { "$ref": "#/$defs/node" } } },
+                    "required": ["children"],
+                },
+            },
+        });
+        assert_eq!(accepts(&schema, &[r#"{"children": [{"children": []}]}"#, r#"{"children": [{}]}"#]), [true, false]);
+        assert!(to_gbnf(&json!({ "$ref": "#/$defs/missing" })).is_err());
+        assert!(to_gbnf(&json!({ "$ref": "other.json#/a" })).is_err());
+    }
+
+    #[test]
+    fn unsatisfiable_or_unknown_schemas_are_errors() {
+        assert!(to_gbnf(&json!(false)).is_err());
+        assert!(to_gbnf(&json!({ "type": "date" })).is_err());
+        assert_eq!(accepts(&json!(true), &["[1, {}]"]), [true]);
+    }
+}
@@ -0,0 +1,410 @@
+/*!
+ * LFM2 Model
+ *
+ * Candle implementation of Liquid AI's LFM2 hybrid architecture (gated short
+ * convolutions interleaved with grouped-query attention) for the HuggingFace
+ * safetensors checkpoints. candle-transformers only ships a GGUF variant.
+ */
+
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{
+    conv1d, conv1d_no_bias, embedding, linear_b, linear_no_bias, rms_norm, Conv1d, Conv1dConfig,
+    Embedding, Linear, RmsNorm, VarBuilder,
+};
+use candle_transformers::utils::repeat_kv;
+use serde::Deserialize;
+
+/// Rotary tables are only precomputed up to this many positions
+const MAX_ROPE_POSITIONS: usize = 32_768;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f64,
+    #[serde(default = "default_norm_eps")]
+    pub norm_eps: f64,
+    #[serde(rename = "conv_L_cache", default = "default_conv_l_cache")]
+    pub conv_l_cache: usize,
+    #[serde(default)]
+    pub conv_bias: bool,
+    #[serde(default, alias = "block_ff_dim")]
+    pub intermediate_size: Option<usize>,
+    #[serde(default = "default_true")]
+    pub block_auto_adjust_ff_dim: bool,
+    #[serde(default)]
+    pub block_ffn_dim_multiplier: Option<f64>,
+    #[serde(default = "default_multiple_of")]
+    pub block_multiple_of: usize,
+    #[serde(default)]
+    pub full_attn_idxs: Option<Vec<usize>>,
+    #[serde(default)]
+    pub layer_types: Option<Vec<String>>,
+}
+
+fn default_rope_theta() -> f64 {
+    1_000_000.0
+}
+
+fn default_norm_eps() -> f64 {
+    1e-5
+}
+
+fn default_conv_l_cache() -> usize {
+    3
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_multiple_of() -> usize {
+    256
+}
+
+impl Config {
+    /// Whether a layer is an attention block (otherwise a short convolution block)
+    fn is_attention_layer(&self, layer_idx: usize) -> bool {
+        if let Some(layer_types) = &self.layer_types {
+            return layer_types.get(layer_idx).map(|t| t == "full_attention").unwrap_or(false);
+        }
+        self.full_attn_idxs.as_ref()
+            .map(|idxs| idxs.contains(&layer_idx))
+            .unwrap_or(false)
+    }
+
+    /// Feed-forward width, following the HuggingFace auto-adjust rules
+    fn ff_dim(&self) -> usize {
+        let mut ff_dim = self.intermediate_size.unwrap_or(4 * self.hidden_size);
+        if self.block_auto_adjust_ff_dim {
+            ff_dim = 2 * ff_dim / 3;
+            if let Some(multiplier) = self.block_ffn_dim_multiplier {
+                ff_dim = (multiplier * ff_dim as f64) as usize;
+            }
+            let multiple = self.block_multiple_of.max(1);
+            ff_dim = multiple * ff_dim.div_ceil(multiple);
+        }
+        ff_dim
+    }
+
+    fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    w1: Linear,
+    w2: Linear,
+    w3: Linear,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let ff_dim = cfg.ff_dim();
+        Ok(Self {
+            w1: linear_no_bias(cfg.hidden_size, ff_dim, vb.pp("w1"))?,
+            w2: linear_no_bias(ff_dim, cfg.hidden_size, vb.pp("w2"))?,
+            w3: linear_no_bias(cfg.hidden_size, ff_dim, vb.pp("w3"))?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = candle_nn::ops::silu(&self.w1.forward(xs)?)?;
+        self.w2.forward(&(gate * self.w3.forward(xs)?)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    out_proj: Linear,
+    q_layernorm: RmsNorm,
+    k_layernorm: RmsNorm,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let head_dim = cfg.head_dim();
+        let kv_dim = cfg.num_key_value_heads * head_dim;
+        Ok(Self {
+            q_proj: linear_no_bias(cfg.hidden_size, cfg.hidden_size, vb.pp("q_proj"))?,
+            k_proj: linear_no_bias(cfg.hidden_size, kv_dim, vb.pp("k_proj"))?,
+            v_proj: linear_no_bias(cfg.hidden_size, kv_dim, vb.pp("v_proj"))?,
+            out_proj: linear_no_bias(cfg.hidden_size, cfg.hidden_size, vb.pp("out_proj"))?,
+            q_layernorm: rms_norm(head_dim, cfg.norm_eps, vb.pp("q_layernorm"))?,
+            k_layernorm: rms_norm(head_dim, cfg.norm_eps, vb.pp("k_layernorm"))?,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim,
+            kv_cache: None,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, cos: &Tensor, sin: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b_sz, seq_len, hidden) = xs.dims3()?;
+
+        let q = self.q_proj.forward(xs)?
+            .reshape((b_sz, seq_len, self.num_heads, self.head_dim))?
+            .apply(&self.q_layernorm)?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = self.k_proj.forward(xs)?
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .apply(&self.k_layernorm)?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = self.v_proj.forward(xs)?
+            .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let q = candle_nn::rotary_emb::rope(&q, cos, sin)?;
+        let k = candle_nn::rotary_emb::rope(&k, cos, sin)?;
+
+        let (k, v) = match &self.kv_cache {
+            Some((k_cache, v_cache)) => (
+                Tensor::cat(&[k_cache, &k], 2)?,
+                Tensor::cat(&[v_cache, &v], 2)?,
+            ),
+            None => (k, v),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let n_rep = self.num_heads / self.num_kv_heads;
+        let k = repeat_kv(k, n_rep)?.contiguous()?;
+        let v = repeat_kv(v, n_rep)?.contiguous()?;
+
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let attn = (q.matmul(&k.t()?)? * scale)?;
+        let attn = match mask {
+            Some(mask) => attn.broadcast_add(mask)?,
+            None => attn,
+        };
+        let attn = candle_nn::ops::softmax_last_dim(&attn)?;
+
+        attn.matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, hidden))?
+            .apply(&self.out_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ShortConv {
+    in_proj: Linear,
+    out_proj: Linear,
+    conv: Conv1d,
+    l_cache: usize,
+    /// Last `l_cache - 1` gated inputs, carried over between forward calls
+    state: Option<Tensor>,
+}
+
+impl ShortConv {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden = cfg.hidden_size;
+        let conv_cfg = Conv1dConfig {
+            groups: hidden,
+            ..Default::default()
+        };
+        let conv = if cfg.conv_bias {
+            conv1d(hidden, hidden, cfg.conv_l_cache, conv_cfg, vb.pp("conv"))?
+        } else {
+            conv1d_no_bias(hidden, hidden, cfg.conv_l_cache, conv_cfg, vb.pp("conv"))?
+        };
+        Ok(Self {
+            in_proj: linear_b(hidden, 3 * hidden, cfg.conv_bias, vb.pp("in_proj"))?,
+            out_proj: linear_b(hidden, hidden, cfg.conv_bias, vb.pp("out_proj"))?,
+            conv,
+            l_cache: cfg.conv_l_cache,
+            state: None,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
+        let (b_sz, _seq_len, hidden) = xs.dims3()?;
+
+        let bcx = self.in_proj.forward(xs)?.transpose(1, 2)?;
+        let b = bcx.narrow(1, 0, hidden)?;
+        let c = bcx.narrow(1, hidden, hidden)?;
+        let x = bcx.narrow(1, 2 * hidden, hidden)?;
+        let bx = (b * x)?.contiguous()?;
+
+        // Causal depthwise convolution: prepend the carried state (zeros on the first call)
+        let history = self.l_cache.saturating_sub(1);
+        let prefix = match &self.state {
+            Some(state) => state.clone(),
+            None => Tensor::zeros((b_sz, hidden, history), bx.dtype(), bx.device())?,
+        };
+        let padded = Tensor::cat(&[&prefix, &bx], 2)?.contiguous()?;
+        let padded_len = padded.dim(2)?;
+        self.state = Some(padded.narrow(2, padded_len - history, history)?.contiguous()?);
+
+        let conv_out = self.conv.forward(&padded)?;
+        (c * conv_out)?
+            .transpose(1, 2)?
+            .contiguous()?
+            .apply(&self.out_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Operator {
+    Attention(Attention),
+    ShortConv(ShortConv),
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    operator_norm: RmsNorm,
+    ffn_norm: RmsNorm,
+    operator: Operator,
+    feed_forward: Mlp,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &Config, layer_idx: usize, vb: VarBuilder) -> Result<Self> {
+        let operator = if cfg.is_attention_layer(layer_idx) {
+            Operator::Attention(Attention::new(cfg, vb.pp("self_attn"))?)
+        } else {
+            Operator::ShortConv(ShortConv::new(cfg, vb.pp("conv"))?)
+        };
+        Ok(Self {
+            operator_norm: rms_norm(cfg.hidden_size, cfg.norm_eps, vb.pp("operator_norm"))?,
+            ffn_norm: rms_norm(cfg.hidden_size, cfg.norm_eps, vb.pp("ffn_norm"))?,
+            operator,
+            feed_forward: Mlp::new(cfg, vb.pp("feed_forward"))?,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, cos: &Tensor, sin: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let normed = self.operator_norm.forward(xs)?;
+        let hidden = match &mut self.operator {
+            Operator::Attention(attn) => attn.forward(&normed, cos, sin, mask)?,
+            Operator::ShortConv(conv) => conv.forward(&normed)?,
+        };
+        let xs = (hidden + xs)?;
+        let ff = self.feed_forward.forward(&self.ffn_norm.forward(&xs)?)?;
+        ff + xs
+    }
+
+    fn clear_cache(&mut self) {
+        match &mut self.operator {
+            Operator::Attention(attn) => attn.kv_cache = None,
+            Operator::ShortConv(conv) => conv.state = None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    embedding_norm: RmsNorm,
+    lm_head: Linear,
+    cos: Tensor,
+    sin: Tensor,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens = embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| DecoderLayer::new(cfg, i, vb_m.pp(format!("layers.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+        let embedding_norm = rms_norm(cfg.hidden_size, cfg.norm_eps, vb_m.pp("embedding_norm"))?;
+        let lm_head = if vb.contains_tensor("lm_head.weight") {
+            linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
+        } else {
+            Linear::new(embed_tokens.embeddings().clone(), None)
+        };
+
+        let (cos, sin) = rope_tables(cfg, vb.dtype(), vb.device())?;
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            embedding_norm,
+            lm_head,
+            cos,
+            sin,
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    /// Run `input_ids` (batch, seq) starting at `seqlen_offset`; returns logits for the last position
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = input_ids.dims2()?;
+        if seqlen_offset + seq_len > self.cos.dim(0)? {
+            candle_core::bail!("sequence length {} exceeds the supported {} positions",
+                seqlen_offset + seq_len, self.cos.dim(0)?);
+        }
+
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let mask = if seq_len > 1 {
+            Some(self.causal_mask(seq_len, seqlen_offset)?)
+        } else {
+            None
+        };
+
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, &cos, &sin, mask.as_ref())?;
+        }
+
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.embedding_norm)?
+            .apply(&self.lm_head)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear_cache();
+        }
+    }
+
+    fn causal_mask(&self, seq_len: usize, seqlen_offset: usize) -> Result<Tensor> {
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| {
+                (0..seq_len + seqlen_offset)
+                    .map(move |j| if j > i + seqlen_offset { f32::NEG_INFINITY } else { 0.0 })
+            })
+            .collect();
+        Tensor::from_vec(mask, (seq_len, seq_len + seqlen_offset), &self.device)?
+            .to_dtype(self.dtype)
+    }
+}
+
+fn rope_tables(cfg: &Config, dtype: DType, device: &Device) -> Result<(Tensor, Tensor)> {
+    let head_dim = cfg.head_dim();
+    let max_positions = cfg.max_position_embeddings.min(MAX_ROPE_POSITIONS);
+    let inv_freq: Vec<f32> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1.0 / cfg.rope_theta.powf(i as f64 / head_dim as f64) as f32)
+        .collect();
+    let inv_freq_len = inv_freq.len();
+    let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), device)?;
+    let positions = Tensor::arange(0u32, max_positions as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((max_positions, 1))?;
+    let freqs = positions.matmul(&inv_freq)?;
+    Ok((freqs.cos()?.to_dtype(dtype)?, freqs.sin()?.to_dtype(dtype)?))
+}
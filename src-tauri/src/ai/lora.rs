@@ -0,0 +1,114 @@
+/*!
+ * LoRA Adapters
+ *
+ * Finds low-rank adapters stored next to a base model, as `.gguf` or
+ * `.safetensors` files in `<model>/adapters/`, and describes the set applied
+ * to a loaded model. GGUF adapters are attached to llama.cpp contexts and
+ * safetensors adapters (PEFT layout) are merged into the Candle weights; in
+ * both cases the base weights stay loaded while adapters come and go.
+ */
+
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Subdirectory of a model directory holding its adapters
+pub const ADAPTERS_DIR: &str = "adapters";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdapterFormat {
+    Gguf,
+    Safetensors,
+}
+
+/// An adapter file found in a model's `adapters` directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdapterInfo {
+    /// File name without its extension; unique per model
+    pub name: String,
+    pub path: PathBuf,
+    pub format: AdapterFormat,
+    pub size_mb: f64,
+}
+
+/// An adapter applied to a loaded model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActiveAdapter {
+    pub name: String,
+    pub path: PathBuf,
+    pub format: AdapterFormat,
+    /// Strength of the adapter: 1.0 as trained, 0.0 no effect
+    pub scale: f32,
+}
+
+impl ActiveAdapter {
+    pub fn new(adapter: &AdapterInfo, scale: f32) -> Self {
+        Self {
+            name: adapter.name.clone(),
+            path: adapter.path.clone(),
+            format: adapter.format,
+            scale,
+        }
+    }
+}
+
+/// Adapters in `<model_dir>/adapters`, sorted by name; a missing directory has none
+pub fn discover(model_dir: &Path) -> Vec<AdapterInfo> {
+    let dir = model_dir.join(ADAPTERS_DIR);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut adapters = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let format = match path.extension().unwrap_or_default().to_string_lossy().to_lowercase().as_str() {
+            "gguf" => AdapterFormat::Gguf,
+            "safetensors" => AdapterFormat::Safetensors,
+            _ => continue,
+        };
+        let size = match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("⚠️ Skipping adapter {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        adapters.push(AdapterInfo {
+            name: path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+            size_mb: (size as f64 / (1024.0 * 1024.0) * 100.0).round() / 100.0,
+            path,
+            format,
+        });
+    }
+    adapters.sort_by(|a, b| a.name.cmp(&b.name));
+    adapters
+}
+
+/// The rank and alpha a PEFT adapter was trained with
+#[derive(Debug, Deserialize)]
+struct PeftConfig {
+    r: f32,
+    lora_alpha: f32,
+}
+
+/// PEFT's `lora_alpha / r` for a safetensors adapter
+///
+/// Read from `<name>.json` next to the adapter, or a shared `adapter_config.json`
+/// in the same directory; 1.0 if neither exists.
+pub fn peft_scaling(adapter_path: &Path) -> f32 {
+    let dir = adapter_path.parent().unwrap_or(Path::new("."));
+    let candidates = [adapter_path.with_extension("json"), dir.join("adapter_config.json")];
+    for path in candidates {
+        let Ok(content) = std::fs::read_to_string(&path) else { continue };
+        match serde_json::from_str::<PeftConfig>(&content) {
+            Ok(config) if config.r > 0.0 => return config.lora_alpha / config.r,
+            Ok(_) => warn!("⚠️ Ignoring {}: rank must be positive", path.display()),
+            Err(e) => warn!("⚠️ Ignoring {}: {}", path.display(), e),
+        }
+    }
+    1.0
+}
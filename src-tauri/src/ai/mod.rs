@@ -9,6 +9,37 @@ pub mod model_manager;
 pub mod chat;
 pub mod context;
 pub mod assistant;
+pub mod generation;
+pub mod scheduler;
+pub mod jinja;
+pub mod chat_template;
+pub mod gguf;
+pub mod safetensors_header;
+pub mod integrity;
+pub mod model_import;
+pub mod download;
+pub mod model_pool;
+pub mod model_selection;
+pub mod lora;
+pub mod benchmark;
+pub mod vision;
+pub mod embeddings;
+pub mod chunking;
+pub mod code_index;
+pub mod retrieval;
+pub mod token_counter;
+pub mod grammar;
+pub mod json_schema;
+pub mod api_server;
+pub mod remote;
+#[cfg(feature = "ai_candle")]
+pub mod transformers;
+#[cfg(feature = "ai_candle")]
+mod lfm2;
+#[cfg(feature = "ai_candle")]
+mod embedding_gemma;
+#[cfg(feature = "ai_candle")]
+mod sampling;
 
 // Re-export main types for convenience
 // Note: Most re-exports removed as they were unused
@@ -0,0 +1,441 @@
+/*!
+ * Model Import
+ *
+ * Finds models outside the models directory (extra search roots and the
+ * HuggingFace hub cache with its `models--org--name/snapshots/<rev>` layout)
+ * and brings them into the models directory by symlink, hardlink or copy.
+ */
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+const COPY_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// Weight formats that can be imported as a single loose file
+const LOOSE_MODEL_EXTENSIONS: &[&str] = &["gguf", "ggml", "onnx", "safetensors"];
+
+/// How imported files reach the models directory
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// Link to the original; nothing is duplicated, but moving the source breaks the model
+    #[default]
+    Symlink,
+    /// Second name for the same data; needs source and target on one filesystem
+    Hardlink,
+    /// Independent copy
+    Copy,
+}
+
+/// One revision of a repository in the hub cache
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HubSnapshot {
+    /// `org/name`
+    pub repo_id: String,
+    pub revision: String,
+    pub path: PathBuf,
+}
+
+/// A directory holding one model, found under a search root
+#[derive(Debug, Clone)]
+pub struct ModelDirectory {
+    pub path: PathBuf,
+    /// Set when the directory is a hub cache snapshot
+    pub hub: Option<HubSnapshot>,
+}
+
+/// The hub cache location, honoring `HF_HOME` like the Python tooling does
+pub fn hf_cache_dir() -> PathBuf {
+    hf_hub::Cache::default().path().clone()
+}
+
+/// True if `root` uses the hub cache layout
+pub fn is_hub_cache(root: &Path) -> bool {
+    std::fs::read_dir(root)
+        .map(|entries| entries.flatten().any(|entry| {
+            entry.file_name().to_string_lossy().starts_with("models--") && entry.path().is_dir()
+        }))
+        .unwrap_or(false)
+}
+
+/// `models--org--name` → `org/name`
+fn repo_id_from_folder(folder: &str) -> Option<String> {
+    let rest = folder.strip_prefix("models--")?;
+    Some(rest.replacen("--", "/", 1))
+}
+
+/// The snapshot `refs/main` points to, or the newest one if there is no ref
+pub fn hub_snapshot(repo_dir: &Path) -> Option<HubSnapshot> {
+    let folder = repo_dir.file_name()?.to_string_lossy().to_string();
+    let repo_id = repo_id_from_folder(&folder)?;
+    let snapshots = repo_dir.join("snapshots");
+
+    let revision = std::fs::read_to_string(repo_dir.join("refs").join("main"))
+        .ok()
+        .map(|rev| rev.trim().to_string())
+        .filter(|rev| snapshots.join(rev).is_dir())
+        .or_else(|| {
+            std::fs::read_dir(&snapshots).ok()?
+                .flatten()
+                .filter(|entry| entry.path().is_dir())
+                .max_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+        })?;
+
+    Some(HubSnapshot {
+        repo_id,
+        path: snapshots.join(&revision),
+        revision,
+    })
+}
+
+/// Model directories under a search root: snapshots for a hub cache, subdirectories otherwise
+///
+/// Blocking; call from `spawn_blocking` in async code.
+pub fn model_directories(root: &Path) -> Result<Vec<ModelDirectory>> {
+    let hub_cache = is_hub_cache(root);
+    let mut directories = Vec::new();
+
+    for entry in std::fs::read_dir(root)? {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                warn!("⚠️ Skipping unreadable entry in {}: {}", root.display(), e);
+                continue;
+            }
+        };
+        if !path.is_dir() {
+            continue;
+        }
+        if hub_cache {
+            if let Some(snapshot) = hub_snapshot(&path) {
+                directories.push(ModelDirectory { path: snapshot.path.clone(), hub: Some(snapshot) });
+            }
+        } else {
+            directories.push(ModelDirectory { path, hub: None });
+        }
+    }
+
+    directories.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(directories)
+}
+
+/// What an import will create: `<models dir>/<name>/<file name>` for every source file
+#[derive(Debug, Clone)]
+pub struct ImportPlan {
+    pub name: String,
+    pub files: Vec<PathBuf>,
+}
+
+impl ImportPlan {
+    /// Plan the import of a loose weights file, a model directory, a hub repository or one of its snapshots
+    pub fn new(source: &Path, name: Option<&str>) -> Result<Self> {
+        if !source.exists() {
+            bail!("{} does not exist", source.display());
+        }
+
+        let (default_name, dir) = if source.is_file() {
+            let extension = source.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+            if !LOOSE_MODEL_EXTENSIONS.contains(&extension.as_str()) {
+                bail!("{} is not a model file (expected one of: {})", source.display(), LOOSE_MODEL_EXTENSIONS.join(", "));
+            }
+            let stem = source.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            return Ok(Self {
+                name: name.map(str::to_string).unwrap_or(stem),
+                files: vec![source.to_path_buf()],
+            });
+        } else if let Some(snapshot) = hub_snapshot(source) {
+            (repo_name(&snapshot.repo_id), snapshot.path)
+        } else if let Some(snapshot) = snapshot_repo(source).and_then(|repo_dir| hub_snapshot(&repo_dir)) {
+            // A specific revision rather than whatever refs/main points to
+            (repo_name(&snapshot.repo_id), source.to_path_buf())
+        } else {
+            let dir_name = source.file_name().unwrap_or_default().to_string_lossy().to_string();
+            (dir_name, source.to_path_buf())
+        };
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            // Follows symlinks, so hub snapshot entries count as files
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        if files.is_empty() {
+            bail!("{} contains no files", dir.display());
+        }
+        files.sort();
+
+        Ok(Self {
+            name: name.map(str::to_string).unwrap_or(default_name),
+            files,
+        })
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter()
+            .filter_map(|file| std::fs::metadata(file).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+}
+
+/// `org/name` → `name`
+fn repo_name(repo_id: &str) -> String {
+    repo_id.rsplit('/').next().unwrap_or(repo_id).to_string()
+}
+
+/// The `models--org--name` directory of a `snapshots/<rev>` path
+fn snapshot_repo(path: &Path) -> Option<PathBuf> {
+    let snapshots = path.parent()?;
+    if snapshots.file_name()? != "snapshots" {
+        return None;
+    }
+    let repo_dir = snapshots.parent()?;
+    repo_id_from_folder(&repo_dir.file_name()?.to_string_lossy())?;
+    Some(repo_dir.to_path_buf())
+}
+
+/// Progress of an import, reported after every file and every copied chunk
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportProgress {
+    pub name: String,
+    pub file: String,
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+/// Create `<models_dir>/<plan.name>` and link or copy the planned files into it
+///
+/// The directory is removed again if any file fails, so a failed import never
+/// leaves a half-populated model behind. Blocking; call from `spawn_blocking`.
+pub fn execute(plan: &ImportPlan, models_dir: &Path, mode: ImportMode, on_progress: &mut dyn FnMut(&ImportProgress)) -> Result<PathBuf> {
+    if plan.name.is_empty() || plan.name.contains(['/', '\\']) || plan.name == "." || plan.name == ".." {
+        bail!("Invalid model name: {:?}", plan.name);
+    }
+    let target = models_dir.join(&plan.name);
+    if target.exists() {
+        bail!("A model named {} already exists in {}", plan.name, models_dir.display());
+    }
+    std::fs::create_dir_all(&target)?;
+
+    let result = import_files(plan, &target, mode, on_progress);
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&target);
+    }
+    result.map(|_| target)
+}
+
+fn import_files(plan: &ImportPlan, target: &Path, mode: ImportMode, on_progress: &mut dyn FnMut(&ImportProgress)) -> Result<()> {
+    let mut progress = ImportProgress {
+        name: plan.name.clone(),
+        file: String::new(),
+        files_done: 0,
+        files_total: plan.files.len(),
+        bytes_done: 0,
+        bytes_total: plan.total_bytes(),
+    };
+
+    for source in &plan.files {
+        let file_name = source.file_name()
+            .ok_or_else(|| anyhow!("Invalid file name: {}", source.display()))?;
+        // Hub snapshot entries are relative symlinks into blobs/; link to the blob itself
+        let source = std::fs::canonicalize(source)?;
+        let destination = target.join(file_name);
+        progress.file = file_name.to_string_lossy().to_string();
+        on_progress(&progress);
+
+        match mode {
+            ImportMode::Symlink => {
+                symlink_file(&source, &destination)
+                    .map_err(|e| anyhow!("Failed to link {}: {} (try a hardlink or a copy instead)", source.display(), e))?;
+                progress.bytes_done += std::fs::metadata(&source)?.len();
+            }
+            ImportMode::Hardlink => {
+                std::fs::hard_link(&source, &destination)
+                    .map_err(|e| anyhow!("Failed to hardlink {}: {} (source and models directory must be on the same drive)", source.display(), e))?;
+                progress.bytes_done += std::fs::metadata(&source)?.len();
+            }
+            ImportMode::Copy => copy_file(&source, &destination, &mut progress, on_progress)?,
+        }
+
+        progress.files_done += 1;
+        on_progress(&progress);
+    }
+    Ok(())
+}
+
+/// Copy through a `.part` file so an interrupted copy is recognizable as partial
+fn copy_file(source: &Path, destination: &Path, progress: &mut ImportProgress, on_progress: &mut dyn FnMut(&ImportProgress)) -> Result<()> {
+    let mut partial_name = destination.as_os_str().to_os_string();
+    partial_name.push(".part");
+    let partial = PathBuf::from(partial_name);
+
+    let mut reader = File::open(source)?;
+    let mut writer = File::create(&partial)?;
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..read])?;
+        progress.bytes_done += read as u64;
+        on_progress(progress);
+    }
+    writer.sync_all()?;
+    drop(writer);
+
+    std::fs::rename(&partial, destination)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink_file(source: &Path, destination: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(source, destination)
+}
+
+#[cfg(windows)]
+fn symlink_file(source: &Path, destination: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(source, destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `models--<org>--<name>` with one snapshot per revision, each holding `config.json`
+    fn hub_repo(root: &Path, repo_id: &str, revisions: &[&str], main: Option<&str>) -> PathBuf {
+        let repo_dir = root.join(format!("models--{}", repo_id.replace('/', "--")));
+        for revision in revisions {
+            let snapshot = repo_dir.join("snapshots").join(revision);
+            std::fs::create_dir_all(&snapshot).unwrap();
+            std::fs::write(snapshot.join("config.json"), revision).unwrap();
+        }
+        if let Some(main) = main {
+            std::fs::create_dir_all(repo_dir.join("refs")).unwrap();
+            std::fs::write(repo_dir.join("refs").join("main"), format!("{}\n", main)).unwrap();
+        }
+        repo_dir
+    }
+
+    #[test]
+    fn repo_ids_come_from_folder_names() {
+        assert_eq!(repo_id_from_folder("models--Qwen--Qwen2.5-0.5B").as_deref(), Some("Qwen/Qwen2.5-0.5B"));
+        assert_eq!(repo_id_from_folder("models--org--name--with--dashes").as_deref(), Some("org/name--with--dashes"));
+        assert_eq!(repo_id_from_folder("datasets--org--name"), None);
+    }
+
+    #[test]
+    fn hub_snapshot_follows_refs_main() {
+        let root = tempfile::tempdir().unwrap();
+        let repo_dir = hub_repo(root.path(), "org/model", &["aaa", "bbb"], Some("aaa"));
+
+        let snapshot = hub_snapshot(&repo_dir).unwrap();
+        assert_eq!(snapshot.repo_id, "org/model");
+        assert_eq!(snapshot.revision, "aaa");
+        assert_eq!(snapshot.path, repo_dir.join("snapshots").join("aaa"));
+    }
+
+    #[test]
+    fn hub_snapshot_falls_back_to_an_existing_revision() {
+        let root = tempfile::tempdir().unwrap();
+        let repo_dir = hub_repo(root.path(), "org/model", &["ccc"], Some("missing"));
+        assert_eq!(hub_snapshot(&repo_dir).unwrap().revision, "ccc");
+
+        let empty = hub_repo(root.path(), "org/empty", &[], None);
+        std::fs::create_dir_all(&empty).unwrap();
+        assert!(hub_snapshot(&empty).is_none());
+    }
+
+    #[test]
+    fn model_directories_resolve_hub_snapshots() {
+        let root = tempfile::tempdir().unwrap();
+        hub_repo(root.path(), "org/b-model", &["rev2"], Some("rev2"));
+        hub_repo(root.path(), "org/a-model", &["rev1"], Some("rev1"));
+        std::fs::write(root.path().join("version.txt"), "1").unwrap();
+
+        assert!(is_hub_cache(root.path()));
+        let directories = model_directories(root.path()).unwrap();
+        let repos: Vec<(&str, &str)> = directories.iter()
+            .map(|directory| {
+                let hub = directory.hub.as_ref().unwrap();
+                (hub.repo_id.as_str(), hub.revision.as_str())
+            })
+            .collect();
+        assert_eq!(repos, [("org/a-model", "rev1"), ("org/b-model", "rev2")]);
+        assert!(directories.iter().all(|directory| directory.path.ends_with(&directory.hub.as_ref().unwrap().revision)));
+    }
+
+    #[test]
+    fn model_directories_list_plain_subdirectories() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("llama")).unwrap();
+        std::fs::create_dir(root.path().join("gemma")).unwrap();
+        std::fs::write(root.path().join("notes.txt"), "").unwrap();
+
+        assert!(!is_hub_cache(root.path()));
+        let directories = model_directories(root.path()).unwrap();
+        let names: Vec<_> = directories.iter().map(|directory| directory.path.file_name().unwrap().to_owned()).collect();
+        assert_eq!(names, ["gemma", "llama"]);
+        assert!(directories.iter().all(|directory| directory.hub.is_none()));
+    }
+
+    #[test]
+    fn plans_name_hub_imports_after_the_repository() {
+        let root = tempfile::tempdir().unwrap();
+        let repo_dir = hub_repo(root.path(), "org/model", &["old", "new"], Some("new"));
+
+        let plan = ImportPlan::new(&repo_dir, None).unwrap();
+        assert_eq!(plan.name, "model");
+        assert_eq!(plan.files, [repo_dir.join("snapshots").join("new").join("config.json")]);
+
+        // A specific snapshot imports that revision, not refs/main
+        let old = repo_dir.join("snapshots").join("old");
+        let plan = ImportPlan::new(&old, Some("model-old")).unwrap();
+        assert_eq!(plan.name, "model-old");
+        assert_eq!(plan.files, [old.join("config.json")]);
+    }
+
+    #[test]
+    fn plans_accept_only_model_files() {
+        let root = tempfile::tempdir().unwrap();
+        let weights = root.path().join("tiny.Q4_K_M.gguf");
+        std::fs::write(&weights, b"GGUF").unwrap();
+        let plan = ImportPlan::new(&weights, None).unwrap();
+        assert_eq!(plan.name, "tiny.Q4_K_M");
+        assert_eq!(plan.total_bytes(), 4);
+
+        let notes = root.path().join("notes.txt");
+        std::fs::write(&notes, b"").unwrap();
+        assert!(ImportPlan::new(&notes, None).is_err());
+        assert!(ImportPlan::new(&root.path().join("missing"), None).is_err());
+    }
+
+    #[test]
+    fn execute_copies_and_refuses_to_overwrite() {
+        let source = tempfile::tempdir().unwrap();
+        let models = tempfile::tempdir().unwrap();
+        let weights = source.path().join("tiny.gguf");
+        std::fs::write(&weights, b"weights").unwrap();
+        let plan = ImportPlan::new(&weights, None).unwrap();
+
+        let mut reports = 0;
+        let target = execute(&plan, models.path(), ImportMode::Copy, &mut |_| reports += 1).unwrap();
+        assert_eq!(std::fs::read(target.join("tiny.gguf")).unwrap(), b"weights");
+        assert!(!target.join("tiny.gguf.part").exists());
+        assert!(reports >= 2);
+
+        assert!(execute(&plan, models.path(), ImportMode::Copy, &mut |_| {}).is_err());
+
+        let escaping = ImportPlan { name: "..".to_string(), files: plan.files.clone() };
+        assert!(execute(&escaping, models.path(), ImportMode::Copy, &mut |_| {}).is_err());
+    }
+}
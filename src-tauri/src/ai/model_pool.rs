@@ -0,0 +1,297 @@
+/*!
+ * Model Pool
+ *
+ * Keeps several loaded backends resident so switching between, say, a small
+ * completion model and a larger chat model does not reload weights each time.
+ * Residents are kept under a RAM budget and evicted least recently used first.
+ */
+
+use std::collections::HashMap;
+use std::time::Instant;
+use anyhow::Result;
+use serde::Serialize;
+use sysinfo::{Pid, System};
+use tracing::info;
+
+use super::model_manager::ModelBackend;
+
+/// Share of total RAM used as the budget when none is configured
+const DEFAULT_BUDGET_FRACTION: f64 = 0.6;
+
+/// Headroom on top of the weights for KV cache, scratch buffers and tokenizer
+const LOAD_OVERHEAD: f64 = 1.2;
+
+struct Resident {
+    backend: Box<dyn ModelBackend>,
+    footprint_bytes: u64,
+    loaded_at: Instant,
+    last_used: Instant,
+}
+
+/// A resident model as reported by `get_model_info`
+#[derive(Debug, Clone, Serialize)]
+pub struct ResidentModel {
+    pub model_id: String,
+    pub footprint_mb: u64,
+    pub loaded_secs_ago: u64,
+    pub idle_secs: u64,
+}
+
+pub struct ModelPool {
+    residents: HashMap<String, Resident>,
+    budget_bytes: u64,
+}
+
+impl ModelPool {
+    pub fn new() -> Self {
+        Self {
+            residents: HashMap::new(),
+            budget_bytes: default_budget_bytes(),
+        }
+    }
+
+    /// Set the budget in MB, or None for a share of physical RAM
+    pub fn set_budget_mb(&mut self, budget_mb: Option<u64>) {
+        self.budget_bytes = budget_mb
+            .map(|mb| mb * 1024 * 1024)
+            .unwrap_or_else(default_budget_bytes);
+        info!("🔧 Model memory budget: {} MB", self.budget_bytes / (1024 * 1024));
+    }
+
+    pub fn budget_bytes(&self) -> u64 {
+        self.budget_bytes
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.residents.values().map(|r| r.footprint_bytes).sum()
+    }
+
+    pub fn contains(&self, model_id: &str) -> bool {
+        self.residents.contains_key(model_id)
+    }
+
+    pub fn get(&self, model_id: &str) -> Option<&dyn ModelBackend> {
+        self.residents.get(model_id).map(|r| r.backend.as_ref())
+    }
+
+    pub fn get_mut(&mut self, model_id: &str) -> Option<&mut Box<dyn ModelBackend>> {
+        self.residents.get_mut(model_id).map(|r| &mut r.backend)
+    }
+
+    /// Mark a model as just used so it is evicted last
+    pub fn touch(&mut self, model_id: &str) {
+        if let Some(resident) = self.residents.get_mut(model_id) {
+            resident.last_used = Instant::now();
+        }
+    }
+
+    pub fn insert(&mut self, model_id: &str, backend: Box<dyn ModelBackend>, footprint_bytes: u64) {
+        let now = Instant::now();
+        self.residents.insert(model_id.to_string(), Resident {
+            backend,
+            footprint_bytes,
+            loaded_at: now,
+            last_used: now,
+        });
+    }
+
+    /// Unload and drop one model
+    pub fn remove(&mut self, model_id: &str) -> Result<bool> {
+        match self.residents.remove(model_id) {
+            Some(mut resident) => {
+                resident.backend.unload()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Unload least recently used models until `needed_bytes` more fit in the budget
+    ///
+    /// Returns the evicted model ids. A model larger than the whole budget
+    /// empties the pool but is still allowed to load.
+    pub fn make_room(&mut self, needed_bytes: u64) -> Result<Vec<String>> {
+        let mut evicted = Vec::new();
+        while self.used_bytes() + needed_bytes > self.budget_bytes {
+            let Some(lru) = self.residents.iter()
+                .min_by_key(|(_, r)| r.last_used)
+                .map(|(id, _)| id.clone()) else {
+                break;
+            };
+            info!("🔄 Evicting {} to stay within the {} MB model budget", lru, self.budget_bytes / (1024 * 1024));
+            self.remove(&lru)?;
+            evicted.push(lru);
+        }
+        Ok(evicted)
+    }
+
+    /// Resident models, most recently used first
+    pub fn residents(&self) -> Vec<ResidentModel> {
+        let mut residents: Vec<_> = self.residents.iter().collect();
+        residents.sort_by_key(|(_, r)| std::cmp::Reverse(r.last_used));
+        residents.into_iter()
+            .map(|(id, r)| ResidentModel {
+                model_id: id.clone(),
+                footprint_mb: r.footprint_bytes / (1024 * 1024),
+                loaded_secs_ago: r.loaded_at.elapsed().as_secs(),
+                idle_secs: r.last_used.elapsed().as_secs(),
+            })
+            .collect()
+    }
+}
+
+impl Default for ModelPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Expected RAM for a model of `size_mb` on disk
+pub fn estimate_load_bytes(size_mb: u64) -> u64 {
+    (size_mb as f64 * 1024.0 * 1024.0 * LOAD_OVERHEAD) as u64
+}
+
+/// Resident set size of this process in bytes
+pub fn process_memory_bytes() -> u64 {
+    let mut system = System::new();
+    let pid = Pid::from_u32(std::process::id());
+    system.refresh_process(pid);
+    system.process(pid).map(|p| p.memory()).unwrap_or(0)
+}
+
+fn default_budget_bytes() -> u64 {
+    let mut system = System::new();
+    system.refresh_memory();
+    (system.total_memory() as f64 * DEFAULT_BUDGET_FRACTION) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::ai::chat_template::{ChatTemplate, ModelTemplateInfo};
+    use crate::ai::generation::{GenerationOutput, TokenCallback};
+    use crate::ai::model_manager::{GenerationParams, ModelFormat, ModelInfo};
+
+    const MB: u64 = 1024 * 1024;
+
+    /// Backend that only counts how often it was unloaded
+    struct IdleBackend {
+        info: ModelInfo,
+        template: ChatTemplate,
+        unloads: Arc<AtomicUsize>,
+    }
+
+    impl ModelBackend for IdleBackend {
+        fn generate_stream(&self, _prompt: &str, _params: &GenerationParams, _on_token: &mut TokenCallback) -> Result<GenerationOutput> {
+            anyhow::bail!("not a real model")
+        }
+
+        fn chat_template(&self) -> &ChatTemplate {
+            &self.template
+        }
+
+        fn set_chat_template(&mut self, template: ChatTemplate) {
+            self.template = template;
+        }
+
+        fn get_model_info(&self) -> &ModelInfo {
+            &self.info
+        }
+
+        fn unload(&mut self) -> Result<()> {
+            self.unloads.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn backend(id: &str, unloads: &Arc<AtomicUsize>) -> Box<dyn ModelBackend> {
+        let info = ModelInfo {
+            id: id.to_string(),
+            name: id.to_string(),
+            description: String::new(),
+            format: ModelFormat::Gguf,
+            path: PathBuf::from(id),
+            size_mb: 0,
+            loaded: true,
+            capabilities: BTreeSet::new(),
+            config: None,
+            files: Vec::new(),
+            metadata: None,
+            adapters: Vec::new(),
+        };
+        Box::new(IdleBackend {
+            info,
+            template: ChatTemplate::resolve(ModelTemplateInfo::default(), Some("chatml")).unwrap(),
+            unloads: unloads.clone(),
+        })
+    }
+
+    /// Pool with a 100 MB budget holding `models` (id, MB), inserted and used in that order
+    fn pool(models: &[(&str, u64)], unloads: &Arc<AtomicUsize>) -> ModelPool {
+        let mut pool = ModelPool::new();
+        pool.set_budget_mb(Some(100));
+        for (id, mb) in models {
+            pool.insert(id, backend(id, unloads), mb * MB);
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        pool
+    }
+
+    #[test]
+    fn make_room_keeps_models_that_fit() {
+        let unloads = Arc::new(AtomicUsize::new(0));
+        let mut pool = pool(&[("a", 30), ("b", 30)], &unloads);
+        assert!(pool.make_room(40 * MB).unwrap().is_empty());
+        assert_eq!(pool.used_bytes(), 60 * MB);
+        assert_eq!(unloads.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn make_room_evicts_least_recently_used_first() {
+        let unloads = Arc::new(AtomicUsize::new(0));
+        let mut pool = pool(&[("a", 30), ("b", 30), ("c", 30)], &unloads);
+        pool.touch("a");
+
+        assert_eq!(pool.make_room(20 * MB).unwrap(), ["b"]);
+        assert_eq!(pool.make_room(80 * MB).unwrap(), ["c", "a"]);
+        assert_eq!(unloads.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.used_bytes(), 0);
+    }
+
+    #[test]
+    fn oversized_models_empty_the_pool_but_may_load() {
+        let unloads = Arc::new(AtomicUsize::new(0));
+        let mut pool = pool(&[("a", 10), ("b", 10)], &unloads);
+        assert_eq!(pool.make_room(500 * MB).unwrap(), ["a", "b"]);
+        assert!(!pool.contains("a") && !pool.contains("b"));
+    }
+
+    #[test]
+    fn residents_are_listed_most_recent_first() {
+        let unloads = Arc::new(AtomicUsize::new(0));
+        let mut pool = pool(&[("a", 10), ("b", 20)], &unloads);
+        pool.touch("a");
+
+        let residents = pool.residents();
+        let ids: Vec<&str> = residents.iter().map(|resident| resident.model_id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert_eq!(residents[1].footprint_mb, 20);
+
+        assert!(pool.remove("b").unwrap());
+        assert!(!pool.remove("b").unwrap());
+        assert_eq!(unloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn footprint_estimate_adds_load_overhead() {
+        assert_eq!(estimate_load_bytes(0), 0);
+        assert_eq!(estimate_load_bytes(1000), (1000.0 * MB as f64 * LOAD_OVERHEAD) as u64);
+        assert!(estimate_load_bytes(1000) > 1000 * MB);
+    }
+}
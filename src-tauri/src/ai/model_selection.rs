@@ -0,0 +1,283 @@
+/*!
+ * Model Selection
+ *
+ * Ranks discovered models for `load_best_model`: the user's preferred list
+ * first, then whether the model fits in free RAM, then how well this build
+ * supports its format, then whether it can do the requested task. Every
+ * candidate gets a human-readable explanation so the UI can show why a model
+ * was picked or skipped.
+ */
+
+use serde::Serialize;
+
+use super::integrity::{IntegrityError, IntegrityStatus};
+use super::model_manager::{ModelFormat, ModelInfo};
+use super::model_pool;
+
+/// Why a model was ranked where it was
+#[derive(Debug, Clone, Serialize)]
+pub struct CandidateRanking {
+    pub model_id: String,
+    pub name: String,
+    /// Position in the load order, None if the model was skipped
+    pub rank: Option<usize>,
+    pub estimated_ram_mb: u64,
+    /// Set when the model cannot be loaded at all
+    pub skipped: Option<String>,
+    pub reasons: Vec<String>,
+}
+
+/// Outcome of `load_best_model`
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelSelection {
+    pub task: String,
+    pub free_memory_mb: u64,
+    /// The model that was loaded, if any
+    pub selected: Option<String>,
+    pub candidates: Vec<CandidateRanking>,
+}
+
+/// What the ranking needs to know about the machine and the user's wishes
+pub struct SelectionContext<'a> {
+    pub task: &'a str,
+    pub preferred_models: &'a [String],
+    /// RAM the model may use: free memory plus what evicting resident models would release
+    pub available_bytes: u64,
+}
+
+/// Sort key, compared in order; lower is better
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct RankKey {
+    preference: usize,
+    ram_tier: u8,
+    format_tier: u8,
+    capability_tier: u8,
+    /// Larger models first among otherwise equal candidates
+    size_desc: std::cmp::Reverse<u64>,
+}
+
+/// How well this build handles a format: 0 best, or why it can't load it
+fn format_tier(format: ModelFormat) -> Result<u8, &'static str> {
+    match format {
+        ModelFormat::Gguf if cfg!(feature = "gguf") => Ok(0),
+        ModelFormat::HuggingFace if cfg!(feature = "ai_candle") => Ok(1),
+        ModelFormat::Ggml if cfg!(feature = "gguf") => Ok(2),
+        // Needs no local RAM, but depends on the network and another machine
+        ModelFormat::Remote => Ok(3),
+        // Placeholder backend: it loads, but only echoes
+        ModelFormat::Onnx => Ok(4),
+        ModelFormat::Gguf | ModelFormat::Ggml => Err("GGUF support is not enabled in this build (`--features gguf`)"),
+        ModelFormat::HuggingFace => Err("Transformers support is not enabled in this build (`--features ai_candle`)"),
+    }
+}
+
+fn preference_index(model: &ModelInfo, preferred: &[String]) -> Option<usize> {
+    preferred.iter().position(|wanted| {
+        wanted.eq_ignore_ascii_case(&model.id) || wanted.eq_ignore_ascii_case(&model.name)
+    })
+}
+
+/// Rank `models` for `context`, best first, with skipped models at the end
+pub fn rank_models<'a>(
+    models: impl IntoIterator<Item = &'a ModelInfo>,
+    integrity: impl Fn(&str) -> IntegrityStatus,
+    context: &SelectionContext,
+) -> Vec<CandidateRanking> {
+    let mut ranked = Vec::new();
+    let mut skipped = Vec::new();
+
+    for model in models {
+        let estimated = model_pool::estimate_load_bytes(model.size_mb);
+        let mut candidate = CandidateRanking {
+            model_id: model.id.clone(),
+            name: model.name.clone(),
+            rank: None,
+            estimated_ram_mb: estimated / (1024 * 1024),
+            skipped: None,
+            reasons: Vec::new(),
+        };
+
+        let format = match format_tier(model.format) {
+            Ok(tier) => tier,
+            Err(reason) => {
+                candidate.skipped = Some(reason.to_string());
+                skipped.push(candidate);
+                continue;
+            }
+        };
+        if let Some(e) = IntegrityError::from_status(&model.id, &integrity(&model.id)) {
+            candidate.skipped = Some(e.to_string());
+            skipped.push(candidate);
+            continue;
+        }
+        if estimated > context.available_bytes {
+            candidate.skipped = Some(format!("needs about {} MB but only {} MB can be freed",
+                                             estimated / (1024 * 1024), context.available_bytes / (1024 * 1024)));
+            skipped.push(candidate);
+            continue;
+        }
+
+        let preference = preference_index(model, context.preferred_models);
+        match preference {
+            Some(index) => candidate.reasons.push(format!("#{} in preferred models", index + 1)),
+            None => candidate.reasons.push("not in preferred models".to_string()),
+        }
+
+        // Tight fits load, but leave little for the rest of the IDE
+        let ram_tier = if estimated * 2 <= context.available_bytes { 0 } else { 1 };
+        candidate.reasons.push(format!("needs about {} MB of {} MB available{}",
+                                       estimated / (1024 * 1024), context.available_bytes / (1024 * 1024),
+                                       if ram_tier == 0 { "" } else { " (tight)" }));
+
+        candidate.reasons.push(match format {
+            3 => format!("served remotely from {}", model.path.display()),
+            4 => format!("{:?} runs on a placeholder backend", model.format),
+            _ => format!("{:?} is supported by this build", model.format),
+        });
+
+        let capable = model.capabilities.contains(context.task);
+        candidate.reasons.push(if capable {
+            format!("supports {}", context.task)
+        } else {
+            format!("does not advertise {}", context.task)
+        });
+
+        let key = RankKey {
+            preference: preference.unwrap_or(usize::MAX),
+            ram_tier,
+            format_tier: format,
+            capability_tier: if capable { 0 } else { 1 },
+            size_desc: std::cmp::Reverse(model.size_mb),
+        };
+        ranked.push((key, candidate));
+    }
+
+    ranked.sort_by_key(|(key, _)| *key);
+    let mut candidates: Vec<CandidateRanking> = ranked.into_iter()
+        .enumerate()
+        .map(|(rank, (_, mut candidate))| {
+            candidate.rank = Some(rank + 1);
+            candidate
+        })
+        .collect();
+    skipped.sort_by(|a, b| a.model_id.cmp(&b.model_id));
+    candidates.extend(skipped);
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn model(id: &str, format: ModelFormat, size_mb: u64, capabilities: &[&str]) -> ModelInfo {
+        ModelInfo {
+            id: id.to_string(),
+            name: format!("{} name", id),
+            description: String::new(),
+            format,
+            path: PathBuf::from(id),
+            size_mb,
+            loaded: false,
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect::<BTreeSet<_>>(),
+            config: None,
+            files: Vec::new(),
+            metadata: None,
+            adapters: Vec::new(),
+        }
+    }
+
+    fn rank(models: &[ModelInfo], preferred: &[&str], available_mb: u64) -> Vec<CandidateRanking> {
+        let preferred: Vec<String> = preferred.iter().map(|p| p.to_string()).collect();
+        let context = SelectionContext { task: "chat", preferred_models: &preferred, available_bytes: available_mb * MB };
+        rank_models(models, |_| IntegrityStatus::Verified, &context)
+    }
+
+    fn order(candidates: &[CandidateRanking]) -> Vec<&str> {
+        candidates.iter().filter(|c| c.rank.is_some()).map(|c| c.model_id.as_str()).collect()
+    }
+
+    #[test]
+    fn preferred_models_come_first_in_list_order() {
+        let models = [
+            model("a", ModelFormat::Onnx, 100, &["chat"]),
+            model("b", ModelFormat::Onnx, 100, &["chat"]),
+            model("c", ModelFormat::Onnx, 100, &["chat"]),
+        ];
+        let candidates = rank(&models, &["C NAME", "b"], 10_000);
+        assert_eq!(order(&candidates), ["c", "b", "a"]);
+        assert_eq!(candidates[0].rank, Some(1));
+        assert!(candidates[0].reasons.contains(&"#1 in preferred models".to_string()));
+        assert!(candidates[2].reasons.contains(&"not in preferred models".to_string()));
+    }
+
+    #[test]
+    fn roomy_models_beat_tight_fits_and_big_ones_are_skipped() {
+        let models = [
+            model("tight", ModelFormat::Onnx, 600, &["chat"]),
+            model("roomy", ModelFormat::Onnx, 100, &["chat"]),
+            model("huge", ModelFormat::Onnx, 5000, &["chat"]),
+        ];
+        let candidates = rank(&models, &[], 1000);
+        assert_eq!(order(&candidates), ["roomy", "tight"]);
+        assert!(candidates[1].reasons.iter().any(|reason| reason.ends_with("(tight)")));
+
+        let huge = candidates.iter().find(|c| c.model_id == "huge").unwrap();
+        assert_eq!(huge.rank, None);
+        assert!(huge.skipped.as_deref().unwrap().starts_with("needs about 6000 MB"));
+        assert_eq!(huge.estimated_ram_mb, 6000);
+    }
+
+    #[test]
+    fn capable_models_rank_before_larger_ones() {
+        let models = [
+            model("big", ModelFormat::Onnx, 300, &["completion"]),
+            model("small", ModelFormat::Onnx, 100, &["chat"]),
+            model("medium", ModelFormat::Onnx, 200, &["chat"]),
+        ];
+        let candidates = rank(&models, &[], 10_000);
+        assert_eq!(order(&candidates), ["medium", "small", "big"]);
+        assert!(candidates[2].reasons.contains(&"does not advertise chat".to_string()));
+        assert!(candidates[0].reasons.contains(&"supports chat".to_string()));
+    }
+
+    #[test]
+    fn formats_without_a_backend_are_skipped() {
+        let models = [
+            model("gguf", ModelFormat::Gguf, 100, &["chat"]),
+            model("hf", ModelFormat::HuggingFace, 100, &["chat"]),
+            model("onnx", ModelFormat::Onnx, 100, &["chat"]),
+        ];
+        let candidates = rank(&models, &[], 10_000);
+        let skipped = |id: &str| candidates.iter().find(|c| c.model_id == id).unwrap().skipped.clone();
+        assert_eq!(skipped("gguf").is_some(), !cfg!(feature = "gguf"));
+        assert_eq!(skipped("hf").is_some(), !cfg!(feature = "ai_candle"));
+        assert_eq!(skipped("onnx"), None);
+        // Any supported format outranks the placeholder backend
+        assert_eq!(order(&candidates).last(), Some(&"onnx"));
+    }
+
+    #[test]
+    fn failed_integrity_skips_the_model() {
+        let models = [
+            model("ok", ModelFormat::Onnx, 100, &["chat"]),
+            model("broken", ModelFormat::Onnx, 100, &["chat"]),
+            model("cut", ModelFormat::Onnx, 100, &["chat"]),
+        ];
+        let preferred = vec!["broken".to_string()];
+        let context = SelectionContext { task: "chat", preferred_models: &preferred, available_bytes: 10_000 * MB };
+        let candidates = rank_models(&models, |id| match id {
+            "broken" => IntegrityStatus::Corrupt { file: "model.onnx".to_string(), reason: "has an unreadable header".to_string() },
+            "cut" => IntegrityStatus::Partial { file: "model.onnx".to_string(), reason: "has 1 of 2 bytes".to_string() },
+            _ => IntegrityStatus::Verified,
+        }, &context);
+
+        let ids: Vec<&str> = candidates.iter().map(|c| c.model_id.as_str()).collect();
+        assert_eq!(ids, ["ok", "broken", "cut"]);
+        assert!(candidates[1].skipped.as_deref().unwrap().contains("is corrupted"));
+        assert!(candidates[2].skipped.as_deref().unwrap().contains("is incomplete"));
+    }
+}
@@ -0,0 +1,701 @@
+/*!
+ * Remote Inference Backend
+ *
+ * Runs models on another machine through an OpenAI-compatible
+ * (`/chat/completions`, `/completions`, server-sent events) or Ollama
+ * (`/api/chat`, `/api/generate`, newline-delimited JSON) HTTP API. Replies are
+ * streamed; requests that fail before any text has arrived are retried with
+ * exponential backoff.
+ */
+
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use futures::StreamExt;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::config::{RemoteApi, RemoteModelConfig};
+use super::chat_template::ChatTemplate;
+use super::generation::{FinishReason, GenerationOutput, TokenCallback};
+use super::grammar::OutputConstraint;
+use super::model_manager::{ConversationMessage, GenerationParams, ModelBackend, ModelFormat, ModelInfo};
+
+/// Wait before the first retry; doubled for every further attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest wait between attempts, however many retries are configured
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Connection attempts give up sooner than responses, which may wait for a busy GPU
+const MAX_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Name of the model on the server
+fn remote_model_name(config: &RemoteModelConfig) -> &str {
+    config.model.as_deref().unwrap_or(&config.id)
+}
+
+/// Model list entry for a configured remote model
+pub fn model_info(config: &RemoteModelConfig) -> ModelInfo {
+    let api = match config.api {
+        RemoteApi::OpenAi => "OpenAI-compatible",
+        RemoteApi::Ollama => "Ollama",
+    };
+    ModelInfo {
+        id: config.id.clone(),
+        name: config.id.clone(),
+        description: format!("Remote: {} on {} ({})", remote_model_name(config), config.base_url, api),
+        format: ModelFormat::Remote,
+        path: PathBuf::from(&config.base_url),
+        size_mb: 0,
+        loaded: false,
+        capabilities: ["chat", "text_generation"].into_iter().map(str::to_string).collect(),
+        // Everything but the API key, for display
+        config: Some(json!({
+            "base_url": config.base_url,
+            "api": config.api,
+            "model": remote_model_name(config),
+            "timeout_secs": config.timeout_secs,
+            "max_retries": config.max_retries,
+        })),
+        files: Vec::new(),
+        metadata: None,
+        adapters: Vec::new(),
+    }
+}
+
+/// A request attempt that failed, and whether trying again could help
+struct AttemptError {
+    error: anyhow::Error,
+    retryable: bool,
+    /// Set when the server answered with an error status
+    status: Option<StatusCode>,
+}
+
+impl AttemptError {
+    fn fatal(error: anyhow::Error) -> Self {
+        Self { error, retryable: false, status: None }
+    }
+
+    fn transient(error: anyhow::Error) -> Self {
+        Self { error, retryable: true, status: None }
+    }
+}
+
+/// What has arrived of a streamed reply so far
+#[derive(Default)]
+struct StreamState {
+    text: String,
+    chunks: usize,
+    prompt_tokens: Option<usize>,
+    cached_prompt_tokens: Option<usize>,
+    completion_tokens: Option<usize>,
+    finish_reason: Option<FinishReason>,
+    done: bool,
+}
+
+impl StreamState {
+    fn into_output(self) -> GenerationOutput {
+        GenerationOutput {
+            prompt_tokens: self.prompt_tokens.unwrap_or(0),
+            cached_prompt_tokens: self.cached_prompt_tokens.unwrap_or(0),
+            // Servers that don't report usage send roughly one token per chunk
+            completion_tokens: self.completion_tokens.unwrap_or(self.chunks),
+            finish_reason: self.finish_reason.unwrap_or(FinishReason::Eos),
+            text: self.text,
+        }
+    }
+}
+
+/// Model backend that forwards generation to an HTTP inference server
+///
+/// Chat requests go to the server's chat endpoint, so the server applies the
+/// model's own chat template; `chat_template` is only reported, not rendered.
+pub struct RemoteBackend {
+    model_info: ModelInfo,
+    chat_template: ChatTemplate,
+    config: RemoteModelConfig,
+    client: reqwest::Client,
+}
+
+impl RemoteBackend {
+    pub fn new(model_info: ModelInfo, config: RemoteModelConfig, chat_template: ChatTemplate) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(MAX_CONNECT_TIMEOUT.min(Duration::from_secs(config.timeout_secs.max(1))))
+            .user_agent(concat!("rain-chat/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Self { model_info, chat_template, config, client })
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_secs.max(1))
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/{}", self.config.base_url.trim_end_matches('/'), path)
+    }
+
+    fn request(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self.config.api_key.as_deref().filter(|key| !key.is_empty()) {
+            Some(key) => builder.header(AUTHORIZATION, format!("Bearer {}", key)),
+            None => builder,
+        }
+    }
+
+    /// Check that the server answers and, if it lists its models, that it serves this one
+    pub async fn probe(&self) -> Result<()> {
+        let url = match self.config.api {
+            RemoteApi::OpenAi => self.endpoint("models"),
+            RemoteApi::Ollama => self.endpoint("api/tags"),
+        };
+        let mut retries = 0;
+        let listing = loop {
+            let attempt = match self.send(self.request(self.client.get(&url)), &url).await {
+                Ok(response) => response.json::<Value>().await
+                    .map_err(|e| AttemptError::fatal(anyhow!("{} sent an invalid model list: {}", url, e))),
+                Err(e) => Err(e),
+            };
+            match attempt {
+                Ok(listing) => break listing,
+                // Not every OpenAI-compatible server implements the model list
+                Err(e) if self.config.api == RemoteApi::OpenAi && e.status == Some(StatusCode::NOT_FOUND) => {
+                    warn!("⚠️ {} has no model list; skipping the model check", self.config.base_url);
+                    return Ok(());
+                }
+                Err(e) => self.retry_or_fail(e, &url, &mut retries).await?,
+            }
+        };
+
+        let name = remote_model_name(&self.config);
+        let (entries, key) = match self.config.api {
+            RemoteApi::OpenAi => (&listing["data"], "id"),
+            RemoteApi::Ollama => (&listing["models"], "name"),
+        };
+        let served: Vec<&str> = entries.as_array()
+            .map(|entries| entries.iter().filter_map(|entry| entry[key].as_str()).collect())
+            .unwrap_or_default();
+        // Ollama lists `llama3:latest` for a model requested as `llama3`
+        let listed = served.iter().any(|served| *served == name || served.strip_suffix(":latest") == Some(name));
+
+        match (self.config.api, listed) {
+            (_, true) => Ok(()),
+            (RemoteApi::Ollama, false) => Err(anyhow!("{} does not have {} (run `ollama pull {}` there)", self.config.base_url, name, name)),
+            // Single-model servers such as llama.cpp answer under any model name
+            (RemoteApi::OpenAi, false) => {
+                warn!("⚠️ {} does not list {}; sending requests anyway", self.config.base_url, name);
+                Ok(())
+            }
+        }
+    }
+
+    /// Send a request, waiting at most `timeout` for the response headers
+    async fn send(&self, request: reqwest::RequestBuilder, url: &str) -> Result<reqwest::Response, AttemptError> {
+        let response = match tokio::time::timeout(self.timeout(), request.send()).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => return Err(AttemptError::transient(anyhow!("Request to {} failed: {}", url, e))),
+            Err(_) => return Err(AttemptError::transient(anyhow!("{} did not respond within {} s", url, self.config.timeout_secs))),
+        };
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let detail = response.text().await.unwrap_or_default();
+        let error = anyhow!("{} returned HTTP {}: {}", url, status, error_message(&detail));
+        Err(AttemptError {
+            error,
+            retryable: status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+            status: Some(status),
+        })
+    }
+
+    /// Wait before the next attempt, or give up with the error if it is fatal or retries are used up
+    async fn retry_or_fail(&self, attempt: AttemptError, url: &str, retries: &mut u32) -> Result<()> {
+        if !attempt.retryable || *retries >= self.config.max_retries {
+            return Err(attempt.error);
+        }
+        let delay = retry_delay(*retries);
+        *retries += 1;
+        warn!("⚠️ {}; retrying {} in {} ms ({}/{})", attempt.error, url, delay.as_millis(), retries, self.config.max_retries);
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+
+    /// Blocking entry point for the synchronous `ModelBackend` methods
+    fn run(&self, url: &str, body: Value, chat: bool, on_token: &mut TokenCallback) -> Result<GenerationOutput> {
+        // Backends are called from `block_in_place`, so blocking on the runtime here is allowed
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| anyhow!("Remote models can only be used from within the async runtime"))?;
+        debug!("🔍 Remote generation: {} {}", url, body);
+        runtime.block_on(async {
+            let mut retries = 0;
+            loop {
+                let mut state = StreamState::default();
+                match self.stream(url, &body, chat, on_token, &mut state).await {
+                    Ok(()) => return Ok(state.into_output()),
+                    // Text already shown to the user can't be taken back, so only retry clean failures
+                    Err(e) if !state.text.is_empty() => return Err(e.error),
+                    Err(e) => self.retry_or_fail(e, url, &mut retries).await?,
+                }
+            }
+        })
+    }
+
+    async fn stream(&self, url: &str, body: &Value, chat: bool, on_token: &mut TokenCallback<'_>, state: &mut StreamState) -> Result<(), AttemptError> {
+        let response = self.send(self.request(self.client.post(url).json(body)), url).await?;
+
+        // Some OpenAI-compatible servers ignore `stream` and answer with one JSON document
+        let whole_document = self.config.api == RemoteApi::OpenAi && response.headers().get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+        if whole_document {
+            let document = response.json::<Value>().await
+                .map_err(|e| AttemptError::transient(anyhow!("Invalid response from {}: {}", url, e)))?;
+            return self.handle_openai(&document, chat, on_token, state);
+        }
+
+        let mut stream = response.bytes_stream();
+        let mut buffer = Vec::new();
+        while !state.done {
+            let chunk = match tokio::time::timeout(self.timeout(), stream.next()).await {
+                Ok(Some(Ok(chunk))) => chunk,
+                Ok(Some(Err(e))) => return Err(AttemptError::transient(anyhow!("Stream from {} broke off: {}", url, e))),
+                Ok(None) => break,
+                Err(_) => return Err(AttemptError::transient(anyhow!("{} sent nothing for {} s", url, self.config.timeout_secs))),
+            };
+            buffer.extend_from_slice(&chunk);
+
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                self.handle_line(line.trim(), chat, on_token, state)?;
+                if state.done {
+                    break;
+                }
+            }
+        }
+        if !state.done && !buffer.is_empty() {
+            let line = String::from_utf8_lossy(&buffer).to_string();
+            self.handle_line(line.trim(), chat, on_token, state)?;
+        }
+        Ok(())
+    }
+
+    fn handle_line(&self, line: &str, chat: bool, on_token: &mut TokenCallback, state: &mut StreamState) -> Result<(), AttemptError> {
+        if line.is_empty() {
+            return Ok(());
+        }
+        let payload = match self.config.api {
+            RemoteApi::OpenAi => match line.strip_prefix("data:") {
+                Some(data) if data.trim() == "[DONE]" => {
+                    state.done = true;
+                    return Ok(());
+                }
+                Some(data) => data.trim(),
+                // SSE comments, `event:` and `id:` lines
+                None => return Ok(()),
+            },
+            RemoteApi::Ollama => line,
+        };
+        let event: Value = serde_json::from_str(payload)
+            .map_err(|e| AttemptError::fatal(anyhow!("Invalid event from {}: {}", self.config.base_url, e)))?;
+
+        match self.config.api {
+            RemoteApi::OpenAi => self.handle_openai(&event, chat, on_token, state),
+            RemoteApi::Ollama => self.handle_ollama(&event, chat, on_token, state),
+        }
+    }
+
+    /// A streamed `chat.completion.chunk` / `text_completion` event, or a whole non-streamed reply
+    fn handle_openai(&self, event: &Value, chat: bool, on_token: &mut TokenCallback, state: &mut StreamState) -> Result<(), AttemptError> {
+        if let Some(error) = event.get("error") {
+            return Err(AttemptError::fatal(anyhow!("{} reported an error: {}", self.config.base_url, error_message(&error.to_string()))));
+        }
+        if let Some(usage) = event.get("usage").filter(|usage| !usage.is_null()) {
+            state.prompt_tokens = usage["prompt_tokens"].as_u64().map(|n| n as usize);
+            state.cached_prompt_tokens = usage["prompt_tokens_details"]["cached_tokens"].as_u64().map(|n| n as usize);
+            state.completion_tokens = usage["completion_tokens"].as_u64().map(|n| n as usize);
+        }
+
+        let Some(choice) = event["choices"].get(0) else {
+            return Ok(());
+        };
+        let text = if chat {
+            choice["delta"]["content"].as_str().or_else(|| choice["message"]["content"].as_str())
+        } else {
+            choice["text"].as_str()
+        };
+        if let Some(text) = text {
+            emit(text, on_token, state);
+        }
+        if let Some(reason) = choice["finish_reason"].as_str() {
+            state.finish_reason.get_or_insert(finish_reason(reason));
+        }
+        Ok(())
+    }
+
+    /// One line of an Ollama `/api/chat` or `/api/generate` stream
+    fn handle_ollama(&self, event: &Value, chat: bool, on_token: &mut TokenCallback, state: &mut StreamState) -> Result<(), AttemptError> {
+        if let Some(error) = event["error"].as_str() {
+            return Err(AttemptError::fatal(anyhow!("{} reported an error: {}", self.config.base_url, error)));
+        }
+        let text = if chat { event["message"]["content"].as_str() } else { event["response"].as_str() };
+        if let Some(text) = text {
+            emit(text, on_token, state);
+        }
+        if event["done"].as_bool() == Some(true) {
+            state.done = true;
+            state.prompt_tokens = event["prompt_eval_count"].as_u64().map(|n| n as usize);
+            state.completion_tokens = event["eval_count"].as_u64().map(|n| n as usize);
+            let reason = event["done_reason"].as_str().unwrap_or("stop");
+            state.finish_reason.get_or_insert(finish_reason(reason));
+        }
+        Ok(())
+    }
+
+    /// Sampling settings in the shape the server expects
+    fn request_body(&self, params: &GenerationParams, input: (&str, Value)) -> Result<Value> {
+        let model = remote_model_name(&self.config);
+        let mut body = match self.config.api {
+            RemoteApi::OpenAi => {
+                let mut body = json!({
+                    "model": model,
+                    "stream": true,
+                    "stream_options": { "include_usage": true },
+                    "temperature": params.temperature,
+                    "top_p": params.top_p,
+                    "max_tokens": params.max_tokens,
+                });
+                if !params.stop_sequences.is_empty() {
+                    body["stop"] = json!(params.stop_sequences);
+                }
+                if let Some(seed) = params.seed {
+                    body["seed"] = json!(seed);
+                }
+                if params.frequency_penalty != 0.0 {
+                    body["frequency_penalty"] = json!(params.frequency_penalty);
+                }
+                if params.presence_penalty != 0.0 {
+                    body["presence_penalty"] = json!(params.presence_penalty);
+                }
+                if !params.logit_bias.is_empty() {
+                    body["logit_bias"] = json!(params.logit_bias);
+                }
+                // Not part of OpenAI's API, so only sent when used; llama.cpp's server and vLLM accept them
+                for (key, value, default) in [
+                    ("min_p", params.min_p, 0.0),
+                    ("typical_p", params.typical_p, 1.0),
+                    ("repeat_penalty", params.repeat_penalty, 1.0),
+                ] {
+                    if value != default {
+                        body[key] = json!(value);
+                    }
+                }
+                if params.repeat_penalty != 1.0 {
+                    body["repeat_last_n"] = json!(params.repeat_last_n);
+                }
+                if params.mirostat != 0 {
+                    body["mirostat"] = json!(params.mirostat);
+                    body["mirostat_tau"] = json!(params.mirostat_tau);
+                    body["mirostat_eta"] = json!(params.mirostat_eta);
+                }
+                body
+            }
+            RemoteApi::Ollama => {
+                if !params.logit_bias.is_empty() {
+                    return Err(anyhow!("Ollama does not accept logit bias"));
+                }
+                let mut body = json!({
+                    "model": model,
+                    "stream": true,
+                    "options": {
+                        "temperature": params.temperature,
+                        "top_p": params.top_p,
+                        "top_k": params.top_k,
+                        "num_predict": params.max_tokens,
+                        "stop": params.stop_sequences,
+                        "repeat_penalty": params.repeat_penalty,
+                        "repeat_last_n": params.repeat_last_n,
+                        "frequency_penalty": params.frequency_penalty,
+                        "presence_penalty": params.presence_penalty,
+                        "min_p": params.min_p,
+                        "typical_p": params.typical_p,
+                        "mirostat": params.mirostat,
+                        "mirostat_tau": params.mirostat_tau,
+                        "mirostat_eta": params.mirostat_eta,
+                    },
+                });
+                if let Some(seed) = params.seed {
+                    body["options"]["seed"] = json!(seed);
+                }
+                body
+            }
+        };
+        let (key, value) = input;
+        body[key] = value;
+
+        // Servers enforce constraints themselves; GBNF goes in llama.cpp server's `grammar` field
+        match (&params.constraint, &self.config.api) {
+            (None, _) => {}
+            (Some(OutputConstraint::JsonSchema(schema)), RemoteApi::OpenAi) => {
+                body["response_format"] = json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": "output", "schema": schema, "strict": true },
+                });
+            }
+            (Some(OutputConstraint::JsonSchema(schema)), RemoteApi::Ollama) => body["format"] = schema.clone(),
+            (Some(OutputConstraint::Grammar(grammar)), RemoteApi::OpenAi) => body["grammar"] = json!(grammar),
+            (Some(OutputConstraint::Grammar(_)), RemoteApi::Ollama) => {
+                return Err(anyhow!("Ollama does not accept GBNF grammars; use a JSON Schema constraint instead"));
+            }
+        }
+        Ok(body)
+    }
+}
+
+/// Exponential backoff for attempt `retries + 1`, capped at `MAX_RETRY_BACKOFF`
+fn retry_delay(retries: u32) -> Duration {
+    RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(retries)).min(MAX_RETRY_BACKOFF)
+}
+
+/// Pass a delta to the caller, stopping the stream if the caller cancelled
+fn emit(text: &str, on_token: &mut TokenCallback, state: &mut StreamState) {
+    if text.is_empty() {
+        return;
+    }
+    state.text.push_str(text);
+    state.chunks += 1;
+    if !on_token(text) {
+        state.finish_reason = Some(FinishReason::Cancelled);
+        state.done = true;
+    }
+}
+
+fn finish_reason(reason: &str) -> FinishReason {
+    match reason {
+        "length" => FinishReason::Length,
+        _ => FinishReason::Eos,
+    }
+}
+
+/// The `message` of an OpenAI/Ollama error body, or the body itself
+fn error_message(body: &str) -> String {
+    let parsed: Option<Value> = serde_json::from_str(body).ok();
+    parsed.as_ref()
+        .and_then(|value| value["error"]["message"].as_str()
+            .or_else(|| value["error"].as_str())
+            .or_else(|| value["message"].as_str()))
+        .map(str::to_string)
+        .unwrap_or_else(|| body.trim().chars().take(500).collect())
+}
+
+impl ModelBackend for RemoteBackend {
+    /// Raw prompt completion: `/completions` or `/api/generate` with `raw`
+    fn generate_stream(&self, prompt: &str, params: &GenerationParams, on_token: &mut TokenCallback) -> Result<GenerationOutput> {
+        let (url, mut body) = match self.config.api {
+            RemoteApi::OpenAi => (self.endpoint("completions"), self.request_body(params, ("prompt", json!(prompt)))?),
+            RemoteApi::Ollama => (self.endpoint("api/generate"), self.request_body(params, ("prompt", json!(prompt)))?),
+        };
+        if self.config.api == RemoteApi::Ollama {
+            // The prompt is already templated
+            body["raw"] = json!(true);
+        }
+        self.run(&url, body, false, on_token)
+    }
+
+    fn generate_chat(&self, messages: &[ConversationMessage], params: &GenerationParams, on_token: &mut TokenCallback) -> Result<GenerationOutput> {
+        let messages: Vec<Value> = messages.iter()
+            .map(|message| json!({ "role": message.role, "content": message.content }))
+            .collect();
+        let url = match self.config.api {
+            RemoteApi::OpenAi => self.endpoint("chat/completions"),
+            RemoteApi::Ollama => self.endpoint("api/chat"),
+        };
+        self.run(&url, self.request_body(params, ("messages", json!(messages)))?, true, on_token)
+    }
+
+    fn chat_template(&self) -> &ChatTemplate {
+        &self.chat_template
+    }
+
+    fn set_chat_template(&mut self, template: ChatTemplate) {
+        self.chat_template = template;
+    }
+
+    fn get_model_info(&self) -> &ModelInfo {
+        &self.model_info
+    }
+
+    fn unload(&mut self) -> Result<()> {
+        info!("🔄 Disconnected from remote model {}", self.model_info.id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use hyper::body::Bytes;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use crate::ai::chat_template::ModelTemplateInfo;
+
+    type Reply = fn(usize) -> Response<Body>;
+
+    /// Answer the n-th request (from 0) with `reply(n)`; returns the address and the request counter
+    fn serve(reply: Reply) -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let counter = counter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                    let n = counter.fetch_add(1, Ordering::SeqCst);
+                    async move { Ok::<_, Infallible>(reply(n)) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (address, requests)
+    }
+
+    /// Body delivered in the given pieces, which need not end at line boundaries
+    fn chunked(pieces: &'static [&'static str]) -> Body {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for piece in pieces {
+                if sender.send_data(Bytes::from_static(piece.as_bytes())).await.is_err() {
+                    return;
+                }
+            }
+        });
+        body
+    }
+
+    fn backend(address: SocketAddr, api: RemoteApi, max_retries: u32) -> RemoteBackend {
+        let config = RemoteModelConfig {
+            id: "remote".to_string(),
+            base_url: format!("http://{}", address),
+            api,
+            model: None,
+            api_key: None,
+            timeout_secs: 5,
+            max_retries,
+        };
+        let chat_template = ChatTemplate::resolve(ModelTemplateInfo::default(), None).unwrap();
+        RemoteBackend::new(model_info(&config), config, chat_template).unwrap()
+    }
+
+    fn chat(backend: &RemoteBackend) -> (Result<GenerationOutput>, Vec<String>) {
+        let messages = [ConversationMessage {
+            role: "user".to_string(),
+            content: "Hi".to_string(),
+            truncated: false,
+            images: Vec::new(),
+        }];
+        let mut deltas = Vec::new();
+        let output = tokio::task::block_in_place(|| {
+            backend.generate_chat(&messages, &GenerationParams::default(), &mut |delta| {
+                deltas.push(delta.to_string());
+                true
+            })
+        });
+        (output, deltas)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parses_openai_server_sent_events() {
+        let (address, _) = serve(|_| Response::new(chunked(&[
+            ": keep-alive\n\ndata: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"con",
+            "tent\":\"lo\"},\"finish_reason\":\"length\"}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":7,\"completion_tokens\":2,\"prompt_tokens_details\":{\"cached_tokens\":4}}}\n\n",
+            "data: [DONE]\n\n",
+        ])));
+        let (output, deltas) = chat(&backend(address, RemoteApi::OpenAi, 0));
+        let output = output.unwrap();
+
+        assert_eq!(deltas, ["Hel", "lo"]);
+        assert_eq!(output.text, "Hello");
+        assert_eq!(output.finish_reason, FinishReason::Length);
+        assert_eq!((output.prompt_tokens, output.cached_prompt_tokens, output.completion_tokens), (7, 4, 2));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parses_ollama_newline_delimited_json() {
+        let (address, _) = serve(|_| Response::new(chunked(&[
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n{\"message\":{\"con",
+            "tent\":\"lo\"},\"done\":false}\n",
+            "{\"message\":{\"content\":\"\"},\"done\":true,\"done_reason\":\"stop\",\"prompt_eval_count\":9,\"eval_count\":2}",
+        ])));
+        let (output, deltas) = chat(&backend(address, RemoteApi::Ollama, 0));
+        let output = output.unwrap();
+
+        assert_eq!(deltas, ["Hel", "lo"]);
+        assert_eq!(output.text, "Hello");
+        assert_eq!(output.finish_reason, FinishReason::Eos);
+        assert_eq!((output.prompt_tokens, output.completion_tokens), (9, 2));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retries_failures_before_the_first_token() {
+        let (address, requests) = serve(|n| match n {
+            0 => Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::from("busy")).unwrap(),
+            _ => Response::new(Body::from("data: {\"choices\":[{\"delta\":{\"content\":\"ok\"}}]}\n\ndata: [DONE]\n\n")),
+        });
+        let (output, _) = chat(&backend(address, RemoteApi::OpenAi, 1));
+
+        assert_eq!(output.unwrap().text, "ok");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn does_not_retry_client_errors_or_exhausted_retries() {
+        let (address, requests) = serve(|_| {
+            Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from(r#"{"error":{"message":"bad model"}}"#)).unwrap()
+        });
+        let (output, _) = chat(&backend(address, RemoteApi::OpenAi, 3));
+        let error = output.unwrap_err().to_string();
+        assert!(error.contains("bad model"), "{}", error);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let (address, requests) = serve(|_| Response::builder().status(StatusCode::BAD_GATEWAY).body(Body::empty()).unwrap());
+        let (output, _) = chat(&backend(address, RemoteApi::OpenAi, 0));
+        assert!(output.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn does_not_retry_after_text_has_streamed() {
+        // The connection breaks once the first delta has been flushed
+        let (address, requests) = serve(|_| {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                let _ = sender.send_data(Bytes::from_static(b"data: {\"choices\":[{\"delta\":{\"content\":\"par\"}}]}\n\n")).await;
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                sender.abort();
+            });
+            Response::new(body)
+        });
+        let (output, deltas) = chat(&backend(address, RemoteApi::OpenAi, 2));
+
+        assert!(output.is_err());
+        assert_eq!(deltas, ["par"]);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(retry_delay(0), RETRY_BACKOFF);
+        assert_eq!(retry_delay(1), RETRY_BACKOFF * 2);
+        assert_eq!(retry_delay(6), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_BACKOFF);
+    }
+}
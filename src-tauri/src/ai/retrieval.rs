@@ -0,0 +1,279 @@
+/*!
+ * Lexical Retrieval
+ *
+ * BM25 over source code and the fusion of rankings. Text is split into words
+ * and identifiers, and identifiers are split again at snake_case and
+ * camelCase boundaries, so `getContext`, `get_context` and "get the context"
+ * share terms while the whole identifier still matches exactly. An inverted
+ * index maps every term to the documents containing it. Rankings from BM25
+ * and from embedding similarity are merged by reciprocal rank fusion.
+ */
+
+use std::collections::{HashMap, HashSet};
+
+/// Term frequency saturation
+const K1: f32 = 1.2;
+/// Document length normalization
+const B: f32 = 0.75;
+/// Reciprocal rank fusion constant; larger values flatten the difference between ranks
+const RRF_K: f32 = 60.0;
+
+/// Inverted index over a fixed set of documents
+#[derive(Debug, Default)]
+pub struct Bm25Index {
+    /// Documents containing each term, with the term's frequency in them
+    postings: HashMap<String, Vec<(usize, u32)>>,
+    /// Length of every document in terms
+    document_lengths: Vec<usize>,
+    average_length: f32,
+}
+
+impl Bm25Index {
+    pub fn new<I, S>(documents: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut postings: HashMap<String, Vec<(usize, u32)>> = HashMap::new();
+        let mut document_lengths = Vec::new();
+        for (document, text) in documents.into_iter().enumerate() {
+            let terms = tokenize(text.as_ref());
+            document_lengths.push(terms.len());
+            for (term, frequency) in term_frequencies(terms) {
+                postings.entry(term).or_default().push((document, frequency));
+            }
+        }
+        let average_length = if document_lengths.is_empty() {
+            0.0
+        } else {
+            document_lengths.iter().sum::<usize>() as f32 / document_lengths.len() as f32
+        };
+        Self { postings, document_lengths, average_length }
+    }
+
+    pub fn document_count(&self) -> usize {
+        self.document_lengths.len()
+    }
+
+    /// Documents matching any term of `query`, best first
+    pub fn search(&self, query: &str) -> Vec<(usize, f32)> {
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        for term in query_terms(query) {
+            let Some(postings) = self.postings.get(&term) else {
+                continue;
+            };
+            let idf = self.idf(postings.len());
+            for &(document, frequency) in postings {
+                *scores.entry(document).or_default() += idf * self.saturate(frequency, self.document_lengths[document]);
+            }
+        }
+        let mut ranked: Vec<(usize, f32)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+    }
+
+    /// Score a text outside the index against `query`, using the index's term statistics
+    pub fn score_text(&self, query: &str, text: &str) -> f32 {
+        let terms = tokenize(text);
+        let length = terms.len();
+        let frequencies = term_frequencies(terms);
+        query_terms(query).into_iter()
+            .filter_map(|term| {
+                let frequency = *frequencies.get(&term)?;
+                let documents = self.postings.get(&term).map_or(0, Vec::len);
+                Some(self.idf(documents) * self.saturate(frequency, length))
+            })
+            .sum()
+    }
+
+    /// Inverse document frequency, never negative
+    fn idf(&self, documents: usize) -> f32 {
+        let total = self.document_count() as f32;
+        let documents = documents as f32;
+        (1.0 + (total - documents + 0.5) / (documents + 0.5)).ln()
+    }
+
+    fn saturate(&self, frequency: u32, length: usize) -> f32 {
+        let frequency = frequency as f32;
+        let average_length = if self.average_length > 0.0 { self.average_length } else { length.max(1) as f32 };
+        frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length as f32 / average_length))
+    }
+}
+
+/// Lowercased terms of `text`: every identifier or word, plus the parts of compound identifiers
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    for word in text.split(|c: char| !(c.is_alphanumeric() || c == '_')) {
+        let word = word.trim_matches('_');
+        if word.chars().count() < 2 {
+            continue;
+        }
+        terms.push(word.to_lowercase());
+        let parts = identifier_parts(word);
+        if parts.len() > 1 {
+            terms.extend(parts.into_iter().filter(|part| part.chars().count() >= 2));
+        }
+    }
+    terms
+}
+
+/// Split at underscores and camelCase boundaries: `parseHTTPResponse_v2` -> parse, http, response, v2
+fn identifier_parts(word: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    for piece in word.split('_').filter(|piece| !piece.is_empty()) {
+        let chars: Vec<char> = piece.chars().collect();
+        let mut start = 0;
+        for index in 1..chars.len() {
+            let (previous, current) = (chars[index - 1], chars[index]);
+            let next_is_lower = chars.get(index + 1).is_some_and(|c| c.is_lowercase());
+            // fooBar | HTTPServer (before "Server")
+            if current.is_uppercase() && (previous.is_lowercase() || previous.is_numeric() || (previous.is_uppercase() && next_is_lower)) {
+                parts.push(chars[start..index].iter().collect::<String>().to_lowercase());
+                start = index;
+            }
+        }
+        parts.push(chars[start..].iter().collect::<String>().to_lowercase());
+    }
+    parts
+}
+
+fn query_terms(query: &str) -> HashSet<String> {
+    tokenize(query).into_iter().collect()
+}
+
+fn term_frequencies(terms: Vec<String>) -> HashMap<String, u32> {
+    let mut frequencies = HashMap::new();
+    for term in terms {
+        *frequencies.entry(term).or_default() += 1;
+    }
+    frequencies
+}
+
+/// Merge rankings of document ids (best first) by reciprocal rank fusion
+///
+/// Scores are scaled so that a document ranked first in every non-empty
+/// ranking gets 1.0, which keeps thresholds meaningful whether one or two
+/// rankings took part.
+pub fn reciprocal_rank_fusion(rankings: &[Vec<(usize, f32)>]) -> Vec<(usize, f32)> {
+    let used = rankings.iter().filter(|ranking| !ranking.is_empty()).count();
+    if used == 0 {
+        return Vec::new();
+    }
+    let best = used as f32 / (RRF_K + 1.0);
+
+    let mut fused: HashMap<usize, f32> = HashMap::new();
+    for ranking in rankings {
+        for (rank, &(document, _)) in ranking.iter().enumerate() {
+            *fused.entry(document).or_default() += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    let mut fused: Vec<(usize, f32)> = fused.into_iter()
+        .map(|(document, score)| (document, score / best))
+        .collect();
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Normalized fused score of `document`
+    fn fused_score(rankings: &[Vec<(usize, f32)>], document: usize) -> f32 {
+        reciprocal_rank_fusion(rankings).into_iter()
+            .find(|&(fused, _)| fused == document)
+            .map_or(0.0, |(_, score)| score)
+    }
+
+    /// A ranking of `count` documents starting at id `first`
+    fn ranking(first: usize, count: usize) -> Vec<(usize, f32)> {
+        (first..first + count).map(|document| (document, 1.0)).collect()
+    }
+
+    #[test]
+    fn splits_identifiers_at_case_and_underscores() {
+        assert_eq!(identifier_parts("parseHTTPResponse_v2"), ["parse", "http", "response", "v2"]);
+        assert_eq!(identifier_parts("get_context"), ["get", "context"]);
+        assert_eq!(identifier_parts("HTTPServer"), ["http", "server"]);
+        assert_eq!(identifier_parts("utf8Decode"), ["utf8", "decode"]);
+        assert_eq!(identifier_parts("plain"), ["plain"]);
+    }
+
+    #[test]
+    fn tokenizes_whole_identifiers_and_their_parts() {
+        assert_eq!(tokenize("let parseHTTPResponse_v2 = a + b;"), ["let", "parsehttpresponse_v2", "parse", "http", "response", "v2"]);
+        assert_eq!(tokenize("__init__(self, x)"), ["init", "self"]);
+        // Both spellings share their parts but keep the whole identifier apart
+        assert_eq!(tokenize("getContext"), ["getcontext", "get", "context"]);
+        assert_eq!(tokenize("get_context"), ["get_context", "get", "context"]);
+    }
+
+    #[test]
+    fn rare_terms_weigh_more() {
+        let index = Bm25Index::new(["fn load", "fn save", "fn parse", "fn render"]);
+        assert!(index.idf(1) > index.idf(4));
+        assert!(index.idf(4) > 0.0);
+        // A term missing from the index still gets the highest weight
+        assert!(index.idf(0) > index.idf(1));
+    }
+
+    #[test]
+    fn ranks_documents_by_bm25() {
+        let index = Bm25Index::new([
+            "fn render(frame: Frame) { draw(frame) }",
+            "fn parse_config(path: &Path) -> Config { read(path) }",
+            "fn load_config() -> Config { parse_config(default_path()) }",
+            "struct Config { name: String }",
+        ]);
+        assert_eq!(index.document_count(), 4);
+
+        let ranked: Vec<usize> = index.search("parseConfig").into_iter().map(|(document, _)| document).collect();
+        assert_eq!(ranked, [1, 2, 3]);
+        assert!(index.search("unrelated words").is_empty());
+
+        let score = index.score_text("parse config", "fn parse_config() {}");
+        assert!(score > 0.0);
+        assert_eq!(index.score_text("parse config", "fn render() {}"), 0.0);
+    }
+
+    #[test]
+    fn shorter_documents_win_on_equal_frequency() {
+        let index = Bm25Index::new(["cache hit", "cache hit with a lot of other words around it", "miss"]);
+        let ranked = index.search("cache");
+        assert_eq!(ranked.iter().map(|(document, _)| *document).collect::<Vec<_>>(), [0, 1]);
+        assert!(ranked[0].1 > ranked[1].1);
+        assert_eq!(Bm25Index::default().search("cache"), []);
+    }
+
+    #[test]
+    fn fusion_scores_the_top_of_every_ranking_as_one() {
+        assert!(reciprocal_rank_fusion(&[Vec::new(), Vec::new()]).is_empty());
+
+        let single = [ranking(0, 3), Vec::new()];
+        assert_eq!(fused_score(&single, 0), 1.0);
+
+        let both = [ranking(0, 3), ranking(0, 3)];
+        assert_eq!(fused_score(&both, 0), 1.0);
+        let fused: Vec<usize> = reciprocal_rank_fusion(&[ranking(0, 3), vec![(2, 0.9), (1, 0.8)]]).into_iter()
+            .map(|(document, _)| document)
+            .collect();
+        assert_eq!(fused, [2, 1, 0]);
+    }
+
+    #[test]
+    fn context_thresholds_fall_where_documented() {
+        // Keywords and embeddings rank disjoint chunks 0..50 and 100..150
+        let disjoint = [ranking(0, 50), ranking(100, 50)];
+        assert_eq!(fused_score(&disjoint, 0), 0.5);
+        assert!(fused_score(&disjoint, 1) < 0.5);
+        assert!(fused_score(&disjoint, 40) >= 0.3);
+        assert!(fused_score(&disjoint, 41) < 0.3);
+
+        // Found by both, even at the bottom of each ranking
+        let overlapping = [ranking(0, 50), ranking(0, 50).into_iter().rev().collect()];
+        assert!(reciprocal_rank_fusion(&overlapping).iter().all(|&(_, score)| score > 0.5));
+
+        // With keywords alone everything within the ranking depth clears 0.5
+        assert!(fused_score(&[ranking(0, 50), Vec::new()], 49) > 0.5);
+    }
+}
@@ -0,0 +1,97 @@
+/*!
+ * Safetensors Header Reader
+ *
+ * Reads the JSON header at the start of `.safetensors` files to get tensor
+ * dtypes and shapes without mapping the weights. Sharded checkpoints are
+ * read shard by shard and summed.
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow, bail};
+use serde::Deserialize;
+
+/// The format caps headers at 100 MB
+const MAX_HEADER_LEN: u64 = 100 * 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SafetensorsTensor {
+    pub dtype: String,
+    pub shape: Vec<u64>,
+    /// Start and end of the tensor's bytes within the data section
+    pub data_offsets: [u64; 2],
+}
+
+impl SafetensorsTensor {
+    pub fn element_count(&self) -> u64 {
+        self.shape.iter().product()
+    }
+}
+
+/// Tensor descriptors of one checkpoint, possibly spread over several shards
+#[derive(Debug, Clone, Default)]
+pub struct SafetensorsHeader {
+    pub tensors: HashMap<String, SafetensorsTensor>,
+    /// Bytes the file(s) must occupy according to the header(s)
+    pub expected_size: u64,
+}
+
+impl SafetensorsHeader {
+    /// Read the header of a single file. Blocking; call from `spawn_blocking` in async code.
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::read_file(path)
+            .map_err(|e| anyhow!("Invalid safetensors header in {}: {}", path.display(), e))
+    }
+
+    /// Read and merge the headers of every shard
+    pub fn read_shards(paths: &[PathBuf]) -> Result<Self> {
+        let mut merged = Self::default();
+        for path in paths {
+            let shard = Self::read(path)?;
+            merged.tensors.extend(shard.tensors);
+            merged.expected_size += shard.expected_size;
+        }
+        Ok(merged)
+    }
+
+    fn read_file(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut len_bytes = [0u8; 8];
+        file.read_exact(&mut len_bytes)?;
+        let header_len = u64::from_le_bytes(len_bytes);
+        if header_len > MAX_HEADER_LEN {
+            bail!("header of {} bytes", header_len);
+        }
+
+        let mut header = vec![0u8; header_len as usize];
+        file.read_exact(&mut header)?;
+
+        let mut entries: HashMap<String, serde_json::Value> = serde_json::from_slice(&header)?;
+        // Free-form string metadata, not a tensor
+        entries.remove("__metadata__");
+
+        let tensors: HashMap<String, SafetensorsTensor> = entries.into_iter()
+            .map(|(name, entry)| Ok((name, serde_json::from_value(entry)?)))
+            .collect::<Result<_>>()?;
+        let data_len = tensors.values().map(|t| t.data_offsets[1]).max().unwrap_or(0);
+        Ok(Self { tensors, expected_size: 8 + header_len + data_len })
+    }
+
+    /// Total number of weights across all tensors
+    pub fn parameter_count(&self) -> u64 {
+        self.tensors.values().map(SafetensorsTensor::element_count).sum()
+    }
+
+    /// Storage dtype holding most of the weights, e.g. `BF16` or `F32`
+    pub fn dominant_dtype(&self) -> Option<String> {
+        let mut weights_by_dtype: HashMap<&str, u64> = HashMap::new();
+        for tensor in self.tensors.values() {
+            *weights_by_dtype.entry(tensor.dtype.as_str()).or_default() += tensor.element_count();
+        }
+        weights_by_dtype.into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(dtype, _)| dtype.to_string())
+    }
+}
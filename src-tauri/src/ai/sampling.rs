@@ -0,0 +1,397 @@
+/*!
+ * Token Sampling
+ *
+ * Picks the next token from raw logits for backends that don't bring their own
+ * sampler (Candle). Applies logit bias and repetition/frequency/presence
+ * penalties, then either greedy decoding, Mirostat, or top-k, typical-p, top-p
+ * and min-p followed by temperature, in llama.cpp's order. Draws come from a
+ * small seedable RNG, so the same seed, model and prompt give the same tokens
+ * on every platform and release.
+ */
+
+use std::collections::HashMap;
+use anyhow::{Result, anyhow};
+
+use super::model_manager::GenerationParams;
+
+/// Candidates Mirostat 1.0 looks at when estimating the distribution's shape
+const MIROSTAT_M: usize = 100;
+
+/// Sampling state for one generation
+pub struct Sampler {
+    params: GenerationParams,
+    rng: SplitMix64,
+    /// Tokens accepted so far, for the penalties
+    history: Vec<u32>,
+    /// Mirostat's target-surprise controller
+    mirostat_mu: f32,
+    /// Surprise of the last sample, applied to `mirostat_mu` once it is accepted
+    last_surprise: Option<(u32, f32)>,
+}
+
+impl Sampler {
+    pub fn new(params: &GenerationParams) -> Self {
+        Self {
+            params: params.clone(),
+            rng: SplitMix64(params.seed.unwrap_or_else(random_seed)),
+            history: Vec::new(),
+            mirostat_mu: 2.0 * params.mirostat_tau,
+            last_surprise: None,
+        }
+    }
+
+    /// Pick a token; tokens whose logit is -inf (e.g. masked by a grammar) are never picked
+    pub fn sample(&mut self, logits: &[f32]) -> Result<u32> {
+        let mut logits = logits.to_vec();
+        self.apply_bias_and_penalties(&mut logits);
+
+        let mut candidates: Vec<Candidate> = logits.iter().enumerate()
+            .filter(|(_, logit)| logit.is_finite())
+            .map(|(token, &logit)| Candidate { token: token as u32, logit, p: 0.0 })
+            .collect();
+        if candidates.is_empty() {
+            return Err(anyhow!("No token can be sampled: every logit is masked"));
+        }
+
+        if self.params.temperature <= 0.0 {
+            let best = candidates.iter()
+                .max_by(|a, b| a.logit.total_cmp(&b.logit))
+                .map(|candidate| candidate.token)
+                .unwrap_or_default();
+            return Ok(best);
+        }
+
+        match self.params.mirostat {
+            1 => self.mirostat_v1(candidates, logits.len()),
+            2 => self.mirostat_v2(candidates),
+            _ => {
+                let p = &self.params;
+                if p.top_k > 0 && (p.top_k as usize) < candidates.len() {
+                    let k = p.top_k as usize;
+                    candidates.select_nth_unstable_by(k - 1, |a, b| b.logit.total_cmp(&a.logit));
+                    candidates.truncate(k);
+                }
+                candidates.sort_unstable_by(|a, b| b.logit.total_cmp(&a.logit));
+                softmax(&mut candidates, 1.0);
+                if p.typical_p < 1.0 {
+                    typical(&mut candidates, p.typical_p);
+                }
+                if p.top_p < 1.0 {
+                    top_p(&mut candidates, p.top_p);
+                }
+                if p.min_p > 0.0 {
+                    let threshold = candidates[0].p * p.min_p;
+                    candidates.retain(|candidate| candidate.p >= threshold);
+                }
+                softmax(&mut candidates, p.temperature);
+                let index = self.draw(&candidates);
+                Ok(candidates[index].token)
+            }
+        }
+    }
+
+    /// Record that `token` was emitted, so later penalties and Mirostat take it into account
+    pub fn accept(&mut self, token: u32) {
+        if let Some((sampled, surprise)) = self.last_surprise.take() {
+            if sampled == token {
+                self.mirostat_mu -= self.params.mirostat_eta * (surprise - self.params.mirostat_tau);
+            }
+        }
+        self.history.push(token);
+    }
+
+    fn apply_bias_and_penalties(&self, logits: &mut [f32]) {
+        for (&token, &bias) in &self.params.logit_bias {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit += bias;
+            }
+        }
+
+        let p = &self.params;
+        let penalize = p.repeat_penalty != 1.0 || p.frequency_penalty != 0.0 || p.presence_penalty != 0.0;
+        if !penalize || p.repeat_last_n == 0 {
+            return;
+        }
+        let window = &self.history[self.history.len().saturating_sub(p.repeat_last_n)..];
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for &token in window {
+            *counts.entry(token).or_default() += 1;
+        }
+        for (token, count) in counts {
+            let Some(logit) = logits.get_mut(token as usize) else { continue };
+            // Dividing a negative logit would make the token more likely, so those are multiplied
+            if *logit > 0.0 {
+                *logit /= p.repeat_penalty;
+            } else {
+                *logit *= p.repeat_penalty;
+            }
+            *logit -= count as f32 * p.frequency_penalty + p.presence_penalty;
+        }
+    }
+
+    fn mirostat_v1(&mut self, mut candidates: Vec<Candidate>, n_vocab: usize) -> Result<u32> {
+        candidates.sort_unstable_by(|a, b| b.logit.total_cmp(&a.logit));
+        softmax(&mut candidates, self.params.temperature);
+
+        // Estimate the Zipf exponent from the top probabilities
+        let m = MIROSTAT_M.min(candidates.len());
+        let (mut sum_ti_bi, mut sum_ti_sq) = (0.0f32, 0.0f32);
+        for i in 0..m.saturating_sub(1) {
+            let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+            let b_i = (candidates[i].p / candidates[i + 1].p).ln();
+            if b_i.is_finite() {
+                sum_ti_bi += t_i * b_i;
+                sum_ti_sq += t_i * t_i;
+            }
+        }
+        let s_hat = if sum_ti_sq > 0.0 { sum_ti_bi / sum_ti_sq } else { 1.0 };
+        let epsilon_hat = s_hat - 1.0;
+        let k = ((epsilon_hat * 2f32.powf(self.mirostat_mu)) / (1.0 - (n_vocab as f32).powf(-epsilon_hat))).powf(1.0 / s_hat);
+        let k = if k.is_finite() { (k as usize).clamp(1, candidates.len()) } else { candidates.len() };
+
+        candidates.truncate(k);
+        softmax(&mut candidates, 1.0);
+        Ok(self.draw_mirostat(&candidates))
+    }
+
+    fn mirostat_v2(&mut self, mut candidates: Vec<Candidate>) -> Result<u32> {
+        candidates.sort_unstable_by(|a, b| b.logit.total_cmp(&a.logit));
+        softmax(&mut candidates, self.params.temperature);
+
+        // Drop tokens more surprising than the current target, keeping at least one
+        let keep = candidates.iter()
+            .position(|candidate| -candidate.p.log2() > self.mirostat_mu)
+            .unwrap_or(candidates.len())
+            .max(1);
+        candidates.truncate(keep);
+        softmax(&mut candidates, 1.0);
+        Ok(self.draw_mirostat(&candidates))
+    }
+
+    fn draw_mirostat(&mut self, candidates: &[Candidate]) -> u32 {
+        let index = self.draw(candidates);
+        let chosen = &candidates[index];
+        self.last_surprise = Some((chosen.token, -chosen.p.log2()));
+        chosen.token
+    }
+
+    /// Index of a candidate drawn by probability
+    fn draw(&mut self, candidates: &[Candidate]) -> usize {
+        let total: f32 = candidates.iter().map(|candidate| candidate.p).sum();
+        let mut target = self.rng.next_f32() * total;
+        for (index, candidate) in candidates.iter().enumerate() {
+            target -= candidate.p;
+            if target < 0.0 {
+                return index;
+            }
+        }
+        candidates.len() - 1
+    }
+}
+
+struct Candidate {
+    token: u32,
+    logit: f32,
+    p: f32,
+}
+
+/// Set `p` from the logits scaled by `1 / temperature`
+fn softmax(candidates: &mut [Candidate], temperature: f32) {
+    let max = candidates.iter().map(|candidate| candidate.logit).fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for candidate in candidates.iter_mut() {
+        candidate.p = ((candidate.logit - max) / temperature).exp();
+        sum += candidate.p;
+    }
+    for candidate in candidates.iter_mut() {
+        candidate.p /= sum;
+    }
+}
+
+/// Keep the most likely candidates covering probability `p`; expects them sorted by probability
+fn top_p(candidates: &mut Vec<Candidate>, p: f32) {
+    let mut cumulative = 0.0;
+    let keep = candidates.iter()
+        .position(|candidate| {
+            cumulative += candidate.p;
+            cumulative >= p
+        })
+        .map_or(candidates.len(), |index| index + 1);
+    candidates.truncate(keep);
+}
+
+/// Locally typical sampling: keep the candidates whose surprise is closest to the entropy
+fn typical(candidates: &mut Vec<Candidate>, p: f32) {
+    let entropy: f32 = candidates.iter()
+        .filter(|candidate| candidate.p > 0.0)
+        .map(|candidate| -candidate.p * candidate.p.ln())
+        .sum();
+    let distance = |candidate: &Candidate| (-candidate.p.ln() - entropy).abs();
+    candidates.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+    top_p(candidates, p);
+    // Later filters expect descending probability again
+    candidates.sort_by(|a, b| b.p.total_cmp(&a.p));
+}
+
+/// SplitMix64: tiny, fast and identical everywhere, which a fixed seed needs
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+fn random_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(299792458)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plain temperature-1 sampling with every filter off
+    fn params() -> GenerationParams {
+        GenerationParams { temperature: 1.0, top_k: 0, top_p: 1.0, seed: Some(7), ..GenerationParams::default() }
+    }
+
+    /// Logits whose softmax is `probabilities`
+    fn logits(probabilities: &[f32]) -> Vec<f32> {
+        probabilities.iter().map(|p| p.ln()).collect()
+    }
+
+    /// Tokens drawn from the same logits `n` times, accepting each one
+    fn draws(params: &GenerationParams, logits: &[f32], n: usize) -> Vec<u32> {
+        let mut sampler = Sampler::new(params);
+        (0..n).map(|_| {
+            let token = sampler.sample(logits).unwrap();
+            sampler.accept(token);
+            token
+        }).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_tokens() {
+        let logits = logits(&[0.2, 0.2, 0.2, 0.2, 0.2]);
+        let first = draws(&params(), &logits, 32);
+        assert_eq!(draws(&params(), &logits, 32), first);
+        assert_ne!(draws(&GenerationParams { seed: Some(8), ..params() }, &logits, 32), first);
+        assert!((0..5).all(|token| first.contains(&token)));
+    }
+
+    #[test]
+    fn greedy_picks_the_largest_unmasked_logit() {
+        let greedy = GenerationParams { temperature: 0.0, ..params() };
+        let mut sampler = Sampler::new(&greedy);
+        assert_eq!(sampler.sample(&[1.0, 3.0, f32::NEG_INFINITY, 2.0]).unwrap(), 1);
+        assert_eq!(sampler.sample(&[f32::NEG_INFINITY, 3.0, f32::NAN, 2.0]).unwrap(), 1);
+        assert!(sampler.sample(&[f32::NEG_INFINITY; 3]).is_err());
+
+        // Masked tokens are never drawn either
+        assert!(draws(&params(), &[0.0, f32::NEG_INFINITY, 0.0], 64).iter().all(|&token| token != 1));
+    }
+
+    #[test]
+    fn logit_bias_shifts_the_choice() {
+        let params = GenerationParams { temperature: 0.0, logit_bias: [(0, -100.0), (2, 1.5)].into(), ..params() };
+        assert_eq!(Sampler::new(&params).sample(&[5.0, 4.0, 3.0]).unwrap(), 2);
+    }
+
+    #[test]
+    fn penalties_apply_to_recent_tokens() {
+        let greedy = |params: GenerationParams, history: &[u32], logits: &[f32]| {
+            let mut sampler = Sampler::new(&GenerationParams { temperature: 0.0, ..params });
+            history.iter().for_each(|&token| sampler.accept(token));
+            sampler.sample(logits).unwrap()
+        };
+
+        // Positive logits are divided, negative ones multiplied, so both become less likely
+        let repeat = GenerationParams { repeat_penalty: 2.0, ..params() };
+        assert_eq!(greedy(repeat.clone(), &[0], &[3.0, 2.0]), 1);
+        assert_eq!(greedy(repeat.clone(), &[0], &[-1.0, -1.5]), 1);
+        assert_eq!(greedy(repeat.clone(), &[], &[3.0, 2.0]), 0);
+
+        // Frequency scales with the count, presence does not
+        let frequency = GenerationParams { frequency_penalty: 0.4, ..params() };
+        assert_eq!(greedy(frequency.clone(), &[0], &[3.0, 2.5]), 0);
+        assert_eq!(greedy(frequency, &[0, 0], &[3.0, 2.5]), 1);
+        let presence = GenerationParams { presence_penalty: 0.6, ..params() };
+        assert_eq!(greedy(presence, &[0], &[3.0, 2.5]), 1);
+
+        // Only the last `repeat_last_n` tokens count
+        let window = GenerationParams { repeat_last_n: 1, ..repeat };
+        assert_eq!(greedy(window.clone(), &[0, 1], &[3.0, 2.0]), 0);
+        assert_eq!(greedy(GenerationParams { repeat_last_n: 0, ..window }, &[0], &[3.0, 2.0]), 0);
+    }
+
+    #[test]
+    fn top_k_and_min_p_cut_unlikely_tokens() {
+        let logits = logits(&[0.5, 0.3, 0.15, 0.05]);
+        let top_k = draws(&GenerationParams { top_k: 2, ..params() }, &logits, 200);
+        assert!(top_k.iter().all(|&token| token < 2) && top_k.contains(&1));
+
+        // 0.5 * 0.25 keeps everything down to 0.125
+        let min_p = draws(&GenerationParams { min_p: 0.25, ..params() }, &logits, 200);
+        assert!(min_p.iter().all(|&token| token < 3) && min_p.contains(&2));
+    }
+
+    #[test]
+    fn top_p_keeps_the_smallest_set_covering_p() {
+        let mut candidates: Vec<Candidate> = [0.5, 0.3, 0.2].iter().enumerate()
+            .map(|(token, &p)| Candidate { token: token as u32, logit: 0.0, p })
+            .collect();
+        top_p(&mut candidates, 0.8);
+        assert_eq!(candidates.iter().map(|c| c.token).collect::<Vec<_>>(), [0, 1]);
+        top_p(&mut candidates, 0.1);
+        assert_eq!(candidates.iter().map(|c| c.token).collect::<Vec<_>>(), [0]);
+    }
+
+    #[test]
+    fn typical_keeps_tokens_whose_surprise_is_near_the_entropy() {
+        let candidates = || -> Vec<Candidate> {
+            [0.5, 0.3, 0.2].iter().enumerate()
+                .map(|(token, &p)| Candidate { token: token as u32, logit: 0.0, p })
+                .collect()
+        };
+        // Entropy is 1.03 nats; surprises are 0.69, 1.20 and 1.61
+        let mut kept = candidates();
+        typical(&mut kept, 0.25);
+        assert_eq!(kept.iter().map(|c| c.token).collect::<Vec<_>>(), [1]);
+        let mut kept = candidates();
+        typical(&mut kept, 0.5);
+        assert_eq!(kept.iter().map(|c| c.token).collect::<Vec<_>>(), [0, 1]);
+    }
+
+    #[test]
+    fn mirostat_narrows_to_the_target_surprise() {
+        let logits = logits(&[0.6, 0.3, 0.1]);
+        for mirostat in [1, 2] {
+            // A tiny target leaves only the most likely token
+            let strict = GenerationParams { mirostat, mirostat_tau: 0.1, mirostat_eta: 0.0, ..params() };
+            assert!(draws(&strict, &logits, 50).iter().all(|&token| token == 0), "mirostat {}", mirostat);
+
+            // A generous one samples from the whole distribution
+            let loose = GenerationParams { mirostat, mirostat_tau: 5.0, mirostat_eta: 0.0, ..params() };
+            let tokens = draws(&loose, &logits, 200);
+            assert!((0..3).all(|token| tokens.contains(&token)), "mirostat {}", mirostat);
+        }
+
+        // Sampling the only allowed token has zero surprise, so the target loosens towards tau
+        let mut sampler = Sampler::new(&GenerationParams { mirostat: 2, mirostat_tau: 0.5, mirostat_eta: 0.5, ..params() });
+        let token = sampler.sample(&logits).unwrap();
+        sampler.accept(token);
+        assert!(sampler.mirostat_mu > 1.0);
+    }
+}
@@ -0,0 +1,397 @@
+/*!
+ * Inference Scheduler
+ *
+ * A single worker in front of the model manager. Inline completions, chat and
+ * background analysis submit jobs and await a ticket instead of taking the
+ * model lock themselves; the worker runs one job at a time, highest priority
+ * first, and drops completions that waited past their deadline because the
+ * user has typed on since. A job that is already running is not preempted.
+ */
+
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, Notify, RwLock};
+use tracing::{debug, info, warn};
+
+use super::generation::LatencyStats;
+use super::model_manager::ModelManager;
+
+/// Future returned by a job, borrowing the model manager while it runs
+pub type JobFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Request classes, highest priority first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Inline completions while the user types
+    Completion,
+    /// Chat answers and API requests
+    Chat,
+    /// Code analysis and other work nobody is waiting on
+    Background,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Completion, Priority::Chat, Priority::Background];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SchedulerError {
+    #[error("{priority:?} request dropped after waiting {waited_ms} ms, past its deadline")]
+    Expired { priority: Priority, waited_ms: u64 },
+    /// The job panicked or the worker went away
+    #[error("inference request ended without a result")]
+    Stopped,
+}
+
+/// Queue figures for one priority class
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PriorityMetrics {
+    /// Jobs waiting right now
+    pub queue_depth: usize,
+    pub peak_queue_depth: usize,
+    pub submitted: u64,
+    pub completed: u64,
+    pub failed: u64,
+    /// Dropped because their deadline passed while queued
+    pub expired: u64,
+    /// Dropped because the caller stopped waiting
+    pub abandoned: u64,
+    /// Time from submission until the job started
+    pub wait: LatencyStats,
+    /// Time the job held the model
+    pub run: LatencyStats,
+}
+
+/// Reported by `get_scheduler_metrics`
+#[derive(Debug, Clone, Serialize)]
+pub struct SchedulerMetrics {
+    pub classes: BTreeMap<Priority, PriorityMetrics>,
+    /// Class of the job holding the model, if any
+    pub running: Option<Priority>,
+    pub running_for_ms: Option<u64>,
+}
+
+/// Result of a submitted job; dropping it before the job starts takes the job off the queue
+pub struct InferenceTicket<T> {
+    receiver: oneshot::Receiver<Result<T>>,
+}
+
+impl<T> Future for InferenceTicket<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver).poll(cx)
+            .map(|result| result.unwrap_or_else(|_| Err(SchedulerError::Stopped.into())))
+    }
+}
+
+/// Handle for submitting jobs; clones share the queue and worker
+#[derive(Clone)]
+pub struct InferenceScheduler {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    queue: Mutex<QueueState>,
+    wake: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    queues: [VecDeque<QueuedJob>; 3],
+    metrics: [PriorityMetrics; 3],
+    running: Option<(Priority, Instant)>,
+}
+
+struct QueuedJob {
+    priority: Priority,
+    submitted: Instant,
+    deadline: Option<Instant>,
+    job: Box<dyn ErasedJob>,
+}
+
+/// A job with its result type hidden so jobs of any type share one queue
+trait ErasedJob: Send {
+    /// Run the job and deliver its result; resolves to whether it succeeded
+    fn run<'a>(self: Box<Self>, models: &'a mut ModelManager) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+    fn reject(self: Box<Self>, error: SchedulerError);
+    /// The caller dropped its ticket
+    fn abandoned(&self) -> bool;
+}
+
+struct TypedJob<T, F> {
+    job: F,
+    reply: oneshot::Sender<Result<T>>,
+}
+
+impl<T, F> ErasedJob for TypedJob<T, F>
+where
+    T: Send + 'static,
+    F: for<'a> FnOnce(&'a mut ModelManager) -> JobFuture<'a, T> + Send + 'static,
+{
+    fn run<'a>(self: Box<Self>, models: &'a mut ModelManager) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        let TypedJob { job, reply } = *self;
+        Box::pin(async move {
+            let result = job(models).await;
+            let succeeded = result.is_ok();
+            let _ = reply.send(result);
+            succeeded
+        })
+    }
+
+    fn reject(self: Box<Self>, error: SchedulerError) {
+        let _ = self.reply.send(Err(error.into()));
+    }
+
+    fn abandoned(&self) -> bool {
+        self.reply.is_closed()
+    }
+}
+
+impl InferenceScheduler {
+    /// Start the worker; it runs for as long as the runtime does
+    pub fn new(models: Arc<RwLock<ModelManager>>) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(QueueState::default()),
+            wake: Notify::new(),
+        });
+        tokio::spawn(run_worker(models, shared.clone()));
+        Self { shared }
+    }
+
+    /// Queue `job` to run with exclusive access to the model manager
+    ///
+    /// Jobs still queued after `deadline` fail with `SchedulerError::Expired` without running.
+    pub fn submit<T, F>(&self, priority: Priority, deadline: Option<Duration>, job: F) -> InferenceTicket<T>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut ModelManager) -> JobFuture<'a, T> + Send + 'static,
+    {
+        let (reply, receiver) = oneshot::channel();
+        let submitted = Instant::now();
+        {
+            let mut state = self.shared.lock();
+            let QueueState { queues, metrics, .. } = &mut *state;
+            let queue = &mut queues[priority.index()];
+            queue.push_back(QueuedJob {
+                priority,
+                submitted,
+                deadline: deadline.map(|deadline| submitted + deadline),
+                job: Box::new(TypedJob { job, reply }),
+            });
+            let metrics = &mut metrics[priority.index()];
+            metrics.submitted += 1;
+            metrics.queue_depth = queue.len();
+            metrics.peak_queue_depth = metrics.peak_queue_depth.max(queue.len());
+        }
+        self.shared.wake.notify_one();
+        InferenceTicket { receiver }
+    }
+
+    pub fn metrics(&self) -> SchedulerMetrics {
+        let state = self.shared.lock();
+        SchedulerMetrics {
+            classes: Priority::ALL.iter()
+                .map(|priority| (*priority, state.metrics[priority.index()].clone()))
+                .collect(),
+            running: state.running.map(|(priority, _)| priority),
+            running_for_ms: state.running.map(|(_, started)| started.elapsed().as_millis() as u64),
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        // The queue stays consistent even if a panic poisoned the lock
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn has_work(&self) -> bool {
+        self.lock().queues.iter().any(|queue| !queue.is_empty())
+    }
+
+    /// Take the oldest job of the highest waiting class, dropping expired and abandoned ones
+    fn next_job(&self) -> Option<QueuedJob> {
+        let mut state = self.lock();
+        let now = Instant::now();
+        let QueueState { queues, metrics, running } = &mut *state;
+        for priority in Priority::ALL {
+            let queue = &mut queues[priority.index()];
+            let metrics = &mut metrics[priority.index()];
+            while let Some(queued) = queue.pop_front() {
+                metrics.queue_depth = queue.len();
+                if queued.job.abandoned() {
+                    metrics.abandoned += 1;
+                    continue;
+                }
+                let waited_ms = now.duration_since(queued.submitted).as_millis() as u64;
+                if queued.deadline.is_some_and(|deadline| now > deadline) {
+                    debug!("🔄 Dropping stale {:?} request after {} ms", priority, waited_ms);
+                    metrics.expired += 1;
+                    queued.job.reject(SchedulerError::Expired { priority, waited_ms });
+                    continue;
+                }
+                metrics.wait.record(waited_ms);
+                *running = Some((priority, now));
+                return Some(queued);
+            }
+        }
+        None
+    }
+
+    fn finish(&self, priority: Priority, started: Instant, succeeded: bool) {
+        let mut state = self.lock();
+        state.running = None;
+        let metrics = &mut state.metrics[priority.index()];
+        metrics.run.record(started.elapsed().as_millis() as u64);
+        if succeeded {
+            metrics.completed += 1;
+        } else {
+            metrics.failed += 1;
+        }
+    }
+}
+
+async fn run_worker(models: Arc<RwLock<ModelManager>>, shared: Arc<Shared>) {
+    info!("🚀 Inference scheduler started");
+    loop {
+        if !shared.has_work() {
+            shared.wake.notified().await;
+            continue;
+        }
+
+        // Pick the job only once the model is ours, so anything submitted while
+        // waiting for the lock (e.g. behind a model load) is ranked too
+        let mut models = models.clone().write_owned().await;
+        let Some(QueuedJob { priority, job, .. }) = shared.next_job() else { continue };
+        let started = Instant::now();
+        // Run on its own task so a panicking job fails alone instead of stopping the worker
+        let succeeded = tokio::spawn(async move { job.run(&mut models).await }).await
+            .unwrap_or_else(|e| {
+                warn!("⚠️ {:?} request panicked: {}", priority, e);
+                false
+            });
+        debug!("🔍 {:?} request {} after {} ms", priority,
+               if succeeded { "finished" } else { "failed" }, started.elapsed().as_millis());
+        shared.finish(priority, started, succeeded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    type Log = Arc<Mutex<Vec<&'static str>>>;
+
+    /// Scheduler over an empty model manager, plus the manager so tests can hold its lock
+    fn scheduler() -> (InferenceScheduler, Arc<RwLock<ModelManager>>) {
+        let models = Arc::new(RwLock::new(ModelManager::new()));
+        (InferenceScheduler::new(models.clone()), models)
+    }
+
+    /// Queue a job that appends `name` to `log` when it runs
+    fn logged(scheduler: &InferenceScheduler, priority: Priority, deadline: Option<Duration>, log: &Log, name: &'static str) -> InferenceTicket<()> {
+        let log = log.clone();
+        scheduler.submit(priority, deadline, move |_| Box::pin(async move {
+            log.lock().unwrap().push(name);
+            Ok(())
+        }))
+    }
+
+    #[tokio::test]
+    async fn runs_queued_jobs_by_priority_then_age() {
+        let (scheduler, models) = scheduler();
+        let log = Log::default();
+
+        // Everything queues up while the model is busy elsewhere
+        let held = models.write().await;
+        let tickets = [
+            logged(&scheduler, Priority::Background, None, &log, "background"),
+            logged(&scheduler, Priority::Chat, None, &log, "chat 1"),
+            logged(&scheduler, Priority::Completion, None, &log, "completion"),
+            logged(&scheduler, Priority::Chat, None, &log, "chat 2"),
+        ];
+        tokio::task::yield_now().await;
+        drop(held);
+
+        for ticket in tickets {
+            ticket.await.unwrap();
+        }
+        assert_eq!(*log.lock().unwrap(), ["completion", "chat 1", "chat 2", "background"]);
+
+        let metrics = scheduler.metrics();
+        let chat = &metrics.classes[&Priority::Chat];
+        assert_eq!((chat.submitted, chat.completed, chat.queue_depth, chat.peak_queue_depth), (2, 2, 0, 2));
+        assert_eq!(chat.wait.samples, 2);
+        assert_eq!(metrics.classes[&Priority::Background].completed, 1);
+        assert!(metrics.running.is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_jobs_past_their_deadline_without_running_them() {
+        let (scheduler, models) = scheduler();
+        let log = Log::default();
+
+        let held = models.write().await;
+        let stale = logged(&scheduler, Priority::Completion, Some(Duration::from_millis(1)), &log, "stale");
+        let patient = logged(&scheduler, Priority::Chat, Some(Duration::from_secs(60)), &log, "patient");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(held);
+
+        let error = stale.await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<SchedulerError>(),
+            Some(SchedulerError::Expired { priority: Priority::Completion, waited_ms }) if *waited_ms >= 1));
+        patient.await.unwrap();
+        assert_eq!(*log.lock().unwrap(), ["patient"]);
+
+        let completion = &scheduler.metrics().classes[&Priority::Completion];
+        assert_eq!((completion.expired, completion.completed, completion.queue_depth), (1, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn skips_jobs_whose_caller_stopped_waiting() {
+        let (scheduler, models) = scheduler();
+        let log = Log::default();
+
+        let held = models.write().await;
+        drop(logged(&scheduler, Priority::Chat, None, &log, "abandoned"));
+        let kept = logged(&scheduler, Priority::Chat, None, &log, "kept");
+        assert_eq!(scheduler.metrics().classes[&Priority::Chat].queue_depth, 2);
+        drop(held);
+
+        kept.await.unwrap();
+        assert_eq!(*log.lock().unwrap(), ["kept"]);
+        let chat = &scheduler.metrics().classes[&Priority::Chat];
+        assert_eq!((chat.abandoned, chat.completed, chat.queue_depth), (1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn failing_and_panicking_jobs_do_not_stop_the_worker() {
+        let (scheduler, _models) = scheduler();
+
+        let failed = scheduler.submit(Priority::Chat, None, |_| Box::pin(async { Err::<(), _>(anyhow!("no model")) }));
+        assert_eq!(failed.await.unwrap_err().to_string(), "no model");
+
+        let panicked = scheduler.submit(Priority::Chat, None, |_| Box::pin(async { panic!("job bug") }));
+        let error: Result<()> = panicked.await;
+        assert!(matches!(error.unwrap_err().downcast_ref::<SchedulerError>(), Some(SchedulerError::Stopped)));
+
+        let answer = scheduler.submit(Priority::Background, None, |_| Box::pin(async { Ok(42) }));
+        assert_eq!(answer.await.unwrap(), 42);
+
+        let chat = &scheduler.metrics().classes[&Priority::Chat];
+        assert_eq!((chat.submitted, chat.completed, chat.failed), (2, 0, 2));
+    }
+}
@@ -0,0 +1,140 @@
+/*!
+ * Token Counter
+ *
+ * Counts tokens with the default model's own tokenizer (tokenizer.json or the
+ * GGUF vocab) so chat history and context budgets match what the model will
+ * actually see. Counts are cached per content hash; the 4-characters-per-token
+ * estimate is only used while no model with a local vocabulary is loaded.
+ */
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use anyhow::{Result, anyhow};
+use tracing::{debug, warn};
+
+/// Cached counts kept before the cache is cleared and starts over
+const MAX_CACHED_COUNTS: usize = 16_384;
+
+/// A model vocabulary that can tokenize text
+pub trait TextTokenizer: Send + Sync {
+    /// Number of tokens in `text`, without BOS/EOS or other special tokens
+    fn count_tokens(&self, text: &str) -> Result<usize>;
+}
+
+impl TextTokenizer for tokenizers::Tokenizer {
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        let encoding = self.encode(text, false)
+            .map_err(|e| anyhow!("Failed to tokenize text: {}", e))?;
+        Ok(encoding.get_ids().len())
+    }
+}
+
+/// Rough token count for when no tokenizer is available
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(4)
+}
+
+/// Shared token counting service; clones count with the same tokenizer and cache
+#[derive(Clone, Default)]
+pub struct TokenCounter {
+    state: Arc<Mutex<CounterState>>,
+}
+
+#[derive(Default)]
+struct CounterState {
+    model_id: Option<String>,
+    tokenizer: Option<Arc<dyn TextTokenizer>>,
+    /// Bumped on every tokenizer change so counts computed for the old one are not cached
+    generation: u64,
+    counts: HashMap<u64, usize>,
+}
+
+impl std::fmt::Debug for TokenCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenCounter")
+            .field("model_id", &self.model_id())
+            .finish()
+    }
+}
+
+impl TokenCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count with `tokenizer` from now on, or estimate if it is `None`
+    ///
+    /// Setting the same model again keeps the cached counts.
+    pub fn set_model(&self, model_id: Option<&str>, tokenizer: Option<Arc<dyn TextTokenizer>>) {
+        let mut state = self.lock();
+        if state.model_id.as_deref() == model_id && state.tokenizer.is_some() == tokenizer.is_some() {
+            return;
+        }
+        match (model_id, tokenizer.is_some()) {
+            (Some(id), true) => debug!("🔧 Counting tokens with the {} tokenizer", id),
+            (Some(id), false) => debug!("🔧 {} has no local tokenizer; estimating token counts", id),
+            (None, _) => debug!("🔧 No model loaded; estimating token counts"),
+        }
+        state.model_id = model_id.map(str::to_string);
+        state.tokenizer = tokenizer;
+        state.generation += 1;
+        state.counts.clear();
+    }
+
+    /// Model whose tokenizer is counting, if any
+    pub fn model_id(&self) -> Option<String> {
+        let state = self.lock();
+        state.tokenizer.as_ref().and(state.model_id.clone())
+    }
+
+    /// Whether counts come from a real tokenizer rather than the estimate
+    pub fn is_exact(&self) -> bool {
+        self.lock().tokenizer.is_some()
+    }
+
+    /// Number of tokens `text` takes with the current model
+    pub fn count(&self, text: &str) -> usize {
+        let key = content_hash(text);
+        let (tokenizer, generation) = {
+            let state = self.lock();
+            let Some(tokenizer) = state.tokenizer.clone() else {
+                return estimate_tokens(text);
+            };
+            if let Some(&count) = state.counts.get(&key) {
+                return count;
+            }
+            (tokenizer, state.generation)
+        };
+
+        // Tokenize without holding the lock; long files take a while
+        let count = match tokenizer.count_tokens(text) {
+            Ok(count) => count,
+            Err(e) => {
+                warn!("⚠️ {}; estimating instead", e);
+                return estimate_tokens(text);
+            }
+        };
+
+        let mut state = self.lock();
+        if state.generation == generation {
+            if state.counts.len() >= MAX_CACHED_COUNTS {
+                state.counts.clear();
+            }
+            state.counts.insert(key, count);
+        }
+        count
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CounterState> {
+        // Counts stay valid even if a panic poisoned the lock mid-insert
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn content_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
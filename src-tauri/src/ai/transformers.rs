@@ -0,0 +1,594 @@
+/*!
+ * Transformers Runtime
+ *
+ * Loads HuggingFace-style model directories (config.json + *.safetensors +
+ * tokenizer.json) with candle-transformers and runs text generation on CPU.
+ * The attention state of the last sequence is kept between requests, so a
+ * chat turn only evaluates what was appended since the previous one.
+ */
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use anyhow::{Result, anyhow};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{gemma, gemma2, gemma3, llama, qwen2};
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::Tokenizer;
+use tracing::{debug, info};
+
+use super::generation::{FinishReason, GenerationOutput, OutputBuffer, TokenCallback};
+use super::grammar::{Grammar, GrammarState, TokenTrie};
+use super::lfm2;
+use super::lora::{self, ActiveAdapter, AdapterFormat};
+use super::model_manager::GenerationParams;
+use super::sampling::Sampler;
+
+/// Model architectures supported by the Candle runtime
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Architecture {
+    Llama,
+    Qwen2,
+    Gemma,
+    Gemma2,
+    Gemma3,
+    Lfm2,
+}
+
+impl Architecture {
+    /// Detect the architecture from `model_type` or `architectures` in config.json
+    pub fn detect(config: &serde_json::Value) -> Option<Self> {
+        let model_type = config.get("model_type").and_then(|v| v.as_str()).unwrap_or_default();
+        let architecture = config.get("architectures")
+            .and_then(|v| v.as_array())
+            .and_then(|a| a.first())
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+
+        match (model_type, architecture) {
+            ("llama", _) | (_, "LlamaForCausalLM") => Some(Self::Llama),
+            ("qwen2", _) | (_, "Qwen2ForCausalLM") => Some(Self::Qwen2),
+            ("gemma", _) | (_, "GemmaForCausalLM") => Some(Self::Gemma),
+            ("gemma2", _) | (_, "Gemma2ForCausalLM") => Some(Self::Gemma2),
+            ("gemma3_text", _) | ("gemma3", _) | (_, "Gemma3ForCausalLM") => Some(Self::Gemma3),
+            ("lfm2", _) | (_, "Lfm2ForCausalLM") => Some(Self::Lfm2),
+            _ => None,
+        }
+    }
+}
+
+/// Model weights together with their KV caches; cloning shares the weight tensors
+#[derive(Clone)]
+enum Weights {
+    Llama { model: llama::Llama, config: llama::Config },
+    Qwen2(qwen2::ModelForCausalLM),
+    Gemma(gemma::Model),
+    Gemma2(gemma2::Model),
+    Gemma3(gemma3::Model),
+    Lfm2(lfm2::Model),
+}
+
+/// Model state right after a prompt preamble, restored to start later conversations from
+struct PrefixSnapshot {
+    tokens: Vec<u32>,
+    weights: Weights,
+    llama_cache: Option<llama::Cache>,
+}
+
+/// A causal language model loaded with Candle, plus its tokenizer
+pub struct CandleCausalLm {
+    architecture: Architecture,
+    config: serde_json::Value,
+    weights: Weights,
+    /// Weights as loaded, without adapters
+    base_tensors: HashMap<String, Tensor>,
+    llama_cache: Option<llama::Cache>,
+    /// Tokens whose state is in the caches, empty after a reset or a failed request
+    cached_tokens: Vec<u32>,
+    prefix_snapshot: Option<PrefixSnapshot>,
+    tokenizer: Tokenizer,
+    /// Token bytes for grammar masking, built on the first constrained request
+    token_trie: Option<TokenTrie>,
+    eos_token_ids: Vec<u32>,
+    max_context: usize,
+    device: Device,
+    dtype: DType,
+}
+
+impl CandleCausalLm {
+    /// Load a model directory on the given device
+    pub fn load(model_dir: &Path, device: &Device) -> Result<Self> {
+        let config_path = model_dir.join("config.json");
+        let config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&config_path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", config_path.display(), e))?)?;
+
+        let architecture = Architecture::detect(&config)
+            .ok_or_else(|| anyhow!("Unsupported model architecture in {} (model_type: {})",
+                config_path.display(),
+                config.get("model_type").and_then(|v| v.as_str()).unwrap_or("unknown")))?;
+
+        let weight_files = find_safetensors(model_dir)?;
+        let tokenizer_path = model_dir.join("tokenizer.json");
+        let tokenizer = Tokenizer::from_file(&tokenizer_path)
+            .map_err(|e| anyhow!("Failed to load tokenizer {}: {}", tokenizer_path.display(), e))?;
+
+        // Half-precision matmuls are slow on CPU, so weights are upcast to f32. The
+        // base tensors are kept so LoRA adapters can be merged and removed later
+        let dtype = DType::F32;
+        info!("🔧 Loading {:?} weights from {} safetensors file(s)", architecture, weight_files.len());
+        let mut base_tensors = HashMap::new();
+        for file in &weight_files {
+            for (name, tensor) in candle_core::safetensors::load(file, device)? {
+                base_tensors.insert(name, tensor.to_dtype(dtype)?);
+            }
+        }
+        let vb = VarBuilder::from_tensors(base_tensors.clone(), dtype, device);
+        let weights = build_weights(architecture, &config, vb)?;
+
+        let mut eos_token_ids = token_ids(config.get("eos_token_id"));
+        if let Ok(content) = std::fs::read_to_string(model_dir.join("generation_config.json")) {
+            if let Ok(generation_config) = serde_json::from_str::<serde_json::Value>(&content) {
+                for id in token_ids(generation_config.get("eos_token_id")) {
+                    if !eos_token_ids.contains(&id) {
+                        eos_token_ids.push(id);
+                    }
+                }
+            }
+        }
+
+        let max_context = config.get("max_position_embeddings")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(4096) as usize;
+
+        Ok(Self {
+            architecture,
+            config,
+            weights,
+            base_tensors,
+            llama_cache: None,
+            cached_tokens: Vec::new(),
+            prefix_snapshot: None,
+            tokenizer,
+            token_trie: None,
+            eos_token_ids,
+            max_context,
+            device: device.clone(),
+            dtype,
+        })
+    }
+
+    pub fn architecture(&self) -> Architecture {
+        self.architecture
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// Rebuild the model from the base weights with `adapters` merged in; none restores the base model
+    pub fn set_adapters(&mut self, adapters: &[ActiveAdapter]) -> Result<()> {
+        let tensors = self.merged_tensors(adapters)?;
+        let vb = VarBuilder::from_tensors(tensors, self.dtype, &self.device);
+        self.weights = build_weights(self.architecture, &self.config, vb)?;
+        // Cached attention state was computed with the old weights
+        self.prefix_snapshot = None;
+        self.reset()
+    }
+
+    /// The base weights plus `scale * alpha / r * B·A` for every layer a PEFT adapter targets
+    fn merged_tensors(&self, adapters: &[ActiveAdapter]) -> Result<HashMap<String, Tensor>> {
+        let mut tensors = self.base_tensors.clone();
+        for adapter in adapters {
+            if adapter.format != AdapterFormat::Safetensors {
+                return Err(anyhow!("{} is a GGUF adapter; safetensors models need a PEFT .safetensors adapter", adapter.name));
+            }
+            let lora = candle_core::safetensors::load(&adapter.path, &self.device)?;
+            let scaling = (lora::peft_scaling(&adapter.path) * adapter.scale) as f64;
+
+            let mut merged = 0;
+            for (name, a) in &lora {
+                let Some(module) = name.strip_suffix(".lora_A.weight") else { continue };
+                let b = lora.get(&format!("{}.lora_B.weight", module))
+                    .ok_or_else(|| anyhow!("Adapter {} has lora_A but no lora_B for {}", adapter.name, module))?;
+                let target = format!("{}.weight", module.strip_prefix("base_model.model.").unwrap_or(module));
+                let base = tensors.get(&target)
+                    .ok_or_else(|| anyhow!("Adapter {} targets {}, which the model does not have", adapter.name, target))?;
+                let delta = (b.to_dtype(self.dtype)?.matmul(&a.to_dtype(self.dtype)?)? * scaling)?;
+                let weight = (base + &delta)?;
+                tensors.insert(target, weight);
+                merged += 1;
+            }
+            if merged == 0 {
+                return Err(anyhow!("Adapter {} has no lora_A/lora_B weights", adapter.name));
+            }
+            debug!("🔍 Merged {} layers of adapter {} at scale {}", merged, adapter.name, adapter.scale);
+        }
+        Ok(tensors)
+    }
+
+    /// Drop all cached attention/convolution state before a fresh sequence
+    fn reset(&mut self) -> Result<()> {
+        match &mut self.weights {
+            Weights::Llama { config, .. } => {
+                self.llama_cache = Some(llama::Cache::new(true, self.dtype, config, &self.device)?);
+            },
+            Weights::Qwen2(model) => model.clear_kv_cache(),
+            Weights::Gemma(model) => model.clear_kv_cache(),
+            Weights::Gemma2(model) => model.clear_kv_cache(),
+            Weights::Gemma3(model) => model.clear_kv_cache(),
+            Weights::Lfm2(model) => model.clear_kv_cache(),
+        }
+        self.cached_tokens.clear();
+        Ok(())
+    }
+
+    /// Bring the caches to the longest reusable prefix of `tokens` and return its length
+    ///
+    /// The caches can only grow, so the state is kept if everything cached is a
+    /// prefix of the new prompt, and otherwise restored from the preamble snapshot
+    /// or reset. At least one token is always left to evaluate for fresh logits.
+    fn prepare_cache(&mut self, tokens: &[u32], reuse: bool) -> Result<usize> {
+        let reusable = |cached: &[u32]| !cached.is_empty() && cached.len() < tokens.len() && tokens.starts_with(cached);
+        if !reuse {
+            self.reset()?;
+            return Ok(0);
+        }
+        if reusable(&self.cached_tokens) {
+            return Ok(self.cached_tokens.len());
+        }
+        self.reset()?;
+        match &self.prefix_snapshot {
+            Some(snapshot) if reusable(&snapshot.tokens) => {
+                self.weights = snapshot.weights.clone();
+                self.llama_cache = snapshot.llama_cache.clone();
+                self.cached_tokens = snapshot.tokens.clone();
+                Ok(self.cached_tokens.len())
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Evaluate `tokens` after whatever is cached, recording them as cached
+    fn extend(&mut self, tokens: &[u32]) -> Result<Tensor> {
+        // Candle's Llama only masks within the new tokens, so on top of a cache they go in one at a time
+        let step = match self.weights {
+            Weights::Llama { .. } if !self.cached_tokens.is_empty() => 1,
+            _ => tokens.len().max(1),
+        };
+        let mut logits = None;
+        for chunk in tokens.chunks(step) {
+            logits = Some(self.forward(chunk, self.cached_tokens.len())?);
+            self.cached_tokens.extend_from_slice(chunk);
+        }
+        logits.ok_or_else(|| anyhow!("No prompt tokens to evaluate"))
+    }
+
+    /// Grammar state after `token`, or None if the grammar rejects it
+    fn grammar_step(&self, grammar: &Grammar, state: &GrammarState, token: u32) -> Option<GrammarState> {
+        let bytes = self.token_trie.as_ref()?.token_bytes(token);
+        if bytes.is_empty() {
+            return None;
+        }
+        state.accept_bytes(grammar, bytes)
+    }
+
+    /// Sample a token the grammar allows
+    ///
+    /// The unconstrained sample is tried first, since it usually fits; only when it
+    /// doesn't is every token checked and the rejected ones masked out.
+    fn sample_constrained(&self, logits: &[f32], sampler: &mut Sampler, grammar: &Grammar, state: &GrammarState) -> Result<u32> {
+        let allowed = |token: u32| if self.eos_token_ids.contains(&token) {
+            state.is_accepting()
+        } else {
+            self.grammar_step(grammar, state, token).is_some()
+        };
+
+        let token = sampler.sample(logits)?;
+        if allowed(token) {
+            return Ok(token);
+        }
+
+        let trie = self.token_trie.as_ref()
+            .ok_or_else(|| anyhow!("Token table for constrained generation is missing"))?;
+        let mut masked = vec![f32::NEG_INFINITY; logits.len()];
+        let mut any = false;
+        let accepting = state.is_accepting();
+        let candidates = trie.allowed_tokens(grammar, state).into_iter()
+            .chain(self.eos_token_ids.iter().copied().filter(|_| accepting));
+        for token in candidates {
+            if let (Some(slot), Some(&logit)) = (masked.get_mut(token as usize), logits.get(token as usize)) {
+                *slot = logit;
+                any = true;
+            }
+        }
+        if !any {
+            return Err(anyhow!("The output grammar allows no token the model can produce here"));
+        }
+        sampler.sample(&masked)
+    }
+
+    /// Evaluate `tokens` starting at `offset`; returns the logits of the last position
+    fn forward(&mut self, tokens: &[u32], offset: usize) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        let logits = match &mut self.weights {
+            Weights::Llama { model, .. } => {
+                let cache = self.llama_cache.as_mut()
+                    .ok_or_else(|| anyhow!("Llama cache not initialized"))?;
+                model.forward(&input, offset, cache)?
+            },
+            Weights::Qwen2(model) => model.forward(&input, offset)?,
+            Weights::Gemma(model) => model.forward(&input, offset)?,
+            Weights::Gemma2(model) => model.forward(&input, offset)?,
+            Weights::Gemma3(model) => model.forward(&input, offset)?,
+            Weights::Lfm2(model) => model.forward(&input, offset)?,
+        };
+        Ok(logits.flatten_all()?.to_dtype(DType::F32)?)
+    }
+
+    /// Generate a completion for `prompt`, streaming text deltas to `on_token`
+    ///
+    /// `add_special_tokens` should be false when the prompt already starts with BOS.
+    /// `preamble` is the start of the prompt shared by every conversation; its
+    /// state is snapshotted so new conversations can skip it too.
+    pub fn generate(&mut self, prompt: &str, preamble: Option<&str>, add_special_tokens: bool, params: &GenerationParams, on_token: &mut TokenCallback) -> Result<GenerationOutput> {
+        let result = self.generate_inner(prompt, preamble, add_special_tokens, params, on_token);
+        if result.is_err() {
+            // The caches may hold part of a failed evaluation
+            self.cached_tokens.clear();
+        }
+        result
+    }
+
+    fn generate_inner(&mut self, prompt: &str, preamble: Option<&str>, add_special_tokens: bool, params: &GenerationParams, on_token: &mut TokenCallback) -> Result<GenerationOutput> {
+        let encoding = self.tokenizer.encode(prompt, add_special_tokens)
+            .map_err(|e| anyhow!("Failed to tokenize prompt: {}", e))?;
+        let prompt_tokens = encoding.get_ids().to_vec();
+        if prompt_tokens.is_empty() {
+            return Err(anyhow!("Prompt produced no tokens"));
+        }
+        if prompt_tokens.len() >= self.max_context {
+            return Err(anyhow!("Prompt is {} tokens but the model only supports {} tokens of context",
+                prompt_tokens.len(), self.max_context));
+        }
+        let max_new_tokens = (params.max_tokens as usize).min(self.max_context - prompt_tokens.len());
+
+        debug!("🔍 Candle generation: prompt_tokens={}, max_new_tokens={}, params={:?}",
+               prompt_tokens.len(), max_new_tokens, params);
+
+        let started = Instant::now();
+        let cached = self.prepare_cache(&prompt_tokens, params.reuse_prompt_cache)?;
+
+        // Split off the preamble so its state can be snapshotted on the way
+        let preamble_len = match preamble {
+            Some(preamble) if params.reuse_prompt_cache => {
+                let preamble_tokens = self.tokenizer.encode(preamble, add_special_tokens)
+                    .map_err(|e| anyhow!("Failed to tokenize prompt preamble: {}", e))?;
+                let len = preamble_tokens.get_ids().len();
+                let fresh = self.prefix_snapshot.as_ref().is_none_or(|snapshot| snapshot.tokens != preamble_tokens.get_ids());
+                (fresh && len > cached && len < prompt_tokens.len() && prompt_tokens.starts_with(preamble_tokens.get_ids()))
+                    .then_some(len)
+            }
+            _ => None,
+        };
+        let mut logits = match preamble_len {
+            Some(len) => {
+                self.extend(&prompt_tokens[cached..len])?;
+                self.prefix_snapshot = Some(PrefixSnapshot {
+                    tokens: prompt_tokens[..len].to_vec(),
+                    weights: self.weights.clone(),
+                    llama_cache: self.llama_cache.clone(),
+                });
+                debug!("🔍 Snapshotted {} preamble tokens", len);
+                self.extend(&prompt_tokens[len..])?
+            }
+            None => self.extend(&prompt_tokens[cached..])?,
+        };
+        debug!("🔍 Prompt evaluated in {} ms ({} of {} tokens reused from the cache)",
+               started.elapsed().as_millis(), cached, prompt_tokens.len());
+
+        let grammar = params.constraint.as_ref().map(|constraint| constraint.compile()).transpose()?;
+        let mut grammar_state = grammar.as_ref().map(Grammar::start);
+        if grammar.is_some() && self.token_trie.is_none() {
+            self.token_trie = Some(TokenTrie::new(token_bytes(&self.tokenizer)));
+        }
+
+        let mut sampler = Sampler::new(params);
+        let mut output = OutputBuffer::new(&params.stop_sequences);
+        let mut generated: Vec<u32> = Vec::new();
+        let mut emitted = 0;
+        let prompt_len = prompt_tokens.len();
+        let mut finish_reason = FinishReason::Length;
+
+        for step in 0..max_new_tokens {
+            if step > 0 {
+                let last = generated[generated.len() - 1];
+                logits = self.extend(&[last])?;
+            }
+
+            let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+            let token = match (&grammar, &mut grammar_state) {
+                (Some(grammar), Some(state)) => {
+                    let token = self.sample_constrained(&logits, &mut sampler, grammar, state)?;
+                    if !self.eos_token_ids.contains(&token) {
+                        *state = self.grammar_step(grammar, state, token)
+                            .ok_or_else(|| anyhow!("Sampled a token the output grammar does not allow"))?;
+                    }
+                    token
+                }
+                _ => sampler.sample(&logits)?,
+            };
+            sampler.accept(token);
+            if self.eos_token_ids.contains(&token) {
+                finish_reason = FinishReason::Eos;
+                break;
+            }
+            generated.push(token);
+            // Nothing may follow a finished grammar match
+            let grammar_complete = grammar_state.as_ref().is_some_and(GrammarState::is_complete);
+
+            // Decode the whole answer so multi-token characters come out intact
+            let text = self.tokenizer.decode(&generated, false)
+                .map_err(|e| anyhow!("Failed to decode tokens: {}", e))?;
+            if text.len() > emitted && !text.ends_with('\u{FFFD}') && text.is_char_boundary(emitted) {
+                let stop = output.push(&text.as_bytes()[emitted..]);
+                emitted = text.len();
+                if stop {
+                    finish_reason = FinishReason::StopSequence;
+                    break;
+                }
+            }
+            if !output.emit(on_token) {
+                finish_reason = FinishReason::Cancelled;
+                break;
+            }
+            if grammar_complete {
+                finish_reason = FinishReason::Eos;
+                break;
+            }
+        }
+
+        debug!("🔍 Candle generation finished after {} tokens ({:?})", generated.len(), finish_reason);
+
+        Ok(GenerationOutput {
+            text: output.finish(on_token),
+            prompt_tokens: prompt_len,
+            cached_prompt_tokens: cached,
+            completion_tokens: generated.len(),
+            finish_reason,
+        })
+    }
+}
+
+/// The bytes each token id stands for; special tokens get none
+fn token_bytes(tokenizer: &Tokenizer) -> Vec<Vec<u8>> {
+    let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+    let byte_chars = byte_level_chars();
+    let special: Vec<u32> = tokenizer.get_added_tokens_decoder().into_iter()
+        .filter(|(_, token)| token.special)
+        .map(|(id, _)| id)
+        .collect();
+
+    (0..tokenizer.get_vocab_size(true) as u32)
+        .map(|id| {
+            let Some(piece) = tokenizer.id_to_token(id).filter(|_| !special.contains(&id)) else {
+                return Vec::new();
+            };
+            if byte_level {
+                // GPT-2 style vocabularies spell raw bytes with printable stand-in characters
+                let mut bytes = Vec::with_capacity(piece.len());
+                for c in piece.chars() {
+                    match byte_chars.get(&c) {
+                        Some(&byte) => bytes.push(byte),
+                        None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+                return bytes;
+            }
+            // SentencePiece: byte-fallback tokens look like <0x0A> and '▁' marks a space
+            if let Some(hex) = piece.strip_prefix("<0x").and_then(|rest| rest.strip_suffix('>')) {
+                if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                    return vec![byte];
+                }
+            }
+            piece.replace('\u{2581}', " ").into_bytes()
+        })
+        .collect()
+}
+
+/// GPT-2's byte-to-character table, inverted
+fn byte_level_chars() -> HashMap<char, u8> {
+    let printable = |byte: u8| matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    let mut shifted = 0;
+    (0..=255u8)
+        .map(|byte| {
+            let c = if printable(byte) {
+                byte as u32
+            } else {
+                shifted += 1;
+                255 + shifted
+            };
+            (char::from_u32(c).unwrap_or_default(), byte)
+        })
+        .collect()
+}
+
+/// Build the model graph for `architecture` from config.json and its weights
+fn build_weights(architecture: Architecture, config: &serde_json::Value, vb: VarBuilder) -> Result<Weights> {
+    Ok(match architecture {
+        Architecture::Llama => {
+            let llama_config: llama::LlamaConfig = serde_json::from_value(config.clone())?;
+            let config = llama_config.into_config(false);
+            Weights::Llama { model: llama::Llama::load(vb, &config)?, config }
+        },
+        Architecture::Qwen2 => {
+            let config: qwen2::Config = serde_json::from_value(config.clone())?;
+            Weights::Qwen2(qwen2::ModelForCausalLM::new(&config, vb)?)
+        },
+        Architecture::Gemma => {
+            let config: gemma::Config = serde_json::from_value(config.clone())?;
+            Weights::Gemma(gemma::Model::new(false, &config, vb)?)
+        },
+        Architecture::Gemma2 => {
+            let config: gemma2::Config = serde_json::from_value(config.clone())?;
+            Weights::Gemma2(gemma2::Model::new(false, &config, vb)?)
+        },
+        Architecture::Gemma3 => {
+            let config: gemma3::Config = serde_json::from_value(gemma3_config(config))?;
+            Weights::Gemma3(gemma3::Model::new(false, &config, vb)?)
+        },
+        Architecture::Lfm2 => {
+            let config: lfm2::Config = serde_json::from_value(config.clone())?;
+            Weights::Lfm2(lfm2::Model::new(&config, vb)?)
+        },
+    })
+}
+
+/// `eos_token_id` may be a single id or a list of ids
+fn token_ids(value: Option<&serde_json::Value>) -> Vec<u32> {
+    match value {
+        Some(serde_json::Value::Number(n)) => n.as_u64().map(|id| vec![id as u32]).unwrap_or_default(),
+        Some(serde_json::Value::Array(ids)) => ids.iter()
+            .filter_map(|v| v.as_u64())
+            .map(|id| id as u32)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Newer Gemma 3 configs describe the sliding window layout with `layer_types`
+/// instead of the `sliding_window_pattern` field candle expects
+fn gemma3_config(config: &serde_json::Value) -> serde_json::Value {
+    let mut config = config.get("text_config").cloned().unwrap_or_else(|| config.clone());
+
+    if config.get("sliding_window_pattern").is_none() {
+        let pattern = config.get("_sliding_window_pattern")
+            .and_then(|v| v.as_u64())
+            .or_else(|| {
+                config.get("layer_types")
+                    .and_then(|v| v.as_array())
+                    .and_then(|types| types.iter().position(|t| t.as_str() == Some("full_attention")))
+                    .map(|index| index as u64 + 1)
+            })
+            .unwrap_or(6);
+        config["sliding_window_pattern"] = serde_json::json!(pattern);
+    }
+    if config.get("rope_local_base_freq").is_none() {
+        config["rope_local_base_freq"] = serde_json::json!(10_000.0);
+    }
+
+    config
+}
+
+pub(super) fn find_safetensors(model_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(model_dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map(|ext| ext == "safetensors").unwrap_or(false))
+        .collect();
+    files.sort();
+
+    if files.is_empty() {
+        return Err(anyhow!("No .safetensors weights found in {}", model_dir.display()));
+    }
+    Ok(files)
+}
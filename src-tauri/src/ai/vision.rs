@@ -0,0 +1,163 @@
+/*!
+ * Image Inputs
+ *
+ * Images attached to chat messages for vision-language models, given as a
+ * file path or as encoded bytes (PNG, JPEG, WebP). Before an image reaches
+ * the model's multimodal projector it is decoded, scaled down so its long
+ * side fits `MAX_IMAGE_SIDE` and converted to 8-bit RGB; the projector does
+ * its own tiling and normalization from there.
+ */
+
+use std::path::PathBuf;
+use anyhow::{Result, anyhow};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+
+use super::model_manager::ConversationMessage;
+
+/// Larger attachments are refused before decoding
+pub const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Long side images are scaled down to; screenshots rarely need more to stay legible
+pub const MAX_IMAGE_SIDE: u32 = 1536;
+
+/// An image attached to a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ImageInput {
+    /// An image file on disk, e.g. a screenshot
+    Path { path: PathBuf },
+    /// Encoded image bytes, base64 in JSON
+    Bytes {
+        #[serde(with = "base64_data")]
+        data: Vec<u8>,
+    },
+}
+
+impl ImageInput {
+    /// Parse a `data:image/png;base64,...` URL as sent by OpenAI clients
+    pub fn from_data_url(url: &str) -> Result<Self> {
+        let (header, data) = url.strip_prefix("data:")
+            .and_then(|rest| rest.split_once(','))
+            .ok_or_else(|| anyhow!("Only data: URLs are supported for images"))?;
+        if !header.starts_with("image/") || !header.ends_with(";base64") {
+            return Err(anyhow!("Expected a base64 image data URL, got data:{}", header));
+        }
+        let data = STANDARD.decode(data)
+            .map_err(|e| anyhow!("Invalid base64 image data: {}", e))?;
+        Ok(Self::Bytes { data })
+    }
+
+    /// The encoded image
+    pub fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            Self::Path { path } => {
+                let size = std::fs::metadata(path)
+                    .map_err(|e| anyhow!("Failed to read image {}: {}", path.display(), e))?
+                    .len();
+                if size > MAX_IMAGE_BYTES {
+                    return Err(anyhow!("Image {} is {} MB; the limit is {} MB",
+                        path.display(), size / (1024 * 1024), MAX_IMAGE_BYTES / (1024 * 1024)));
+                }
+                std::fs::read(path).map_err(|e| anyhow!("Failed to read image {}: {}", path.display(), e))
+            }
+            Self::Bytes { data } => {
+                if data.len() as u64 > MAX_IMAGE_BYTES {
+                    return Err(anyhow!("Image is {} MB; the limit is {} MB",
+                        data.len() / (1024 * 1024), MAX_IMAGE_BYTES / (1024 * 1024)));
+                }
+                Ok(data.clone())
+            }
+        }
+    }
+
+    /// For log and error messages
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Path { path } => path.display().to_string(),
+            Self::Bytes { data } => format!("attached image ({} bytes)", data.len()),
+        }
+    }
+}
+
+/// Decoded pixels ready for a vision projector
+#[cfg(feature = "vision")]
+pub struct PreparedImage {
+    pub width: u32,
+    pub height: u32,
+    /// Row-major 8-bit RGB
+    pub rgb: Vec<u8>,
+}
+
+/// Decode an attachment, scaling it down to `MAX_IMAGE_SIDE` if needed
+#[cfg(feature = "vision")]
+pub fn prepare(input: &ImageInput) -> Result<PreparedImage> {
+    let bytes = input.read()?;
+    let decoded = image::load_from_memory(&bytes)
+        .map_err(|e| anyhow!("Failed to decode {}: {}", input.describe(), e))?;
+    let decoded = if decoded.width().max(decoded.height()) > MAX_IMAGE_SIDE {
+        // Keeps the aspect ratio
+        decoded.resize(MAX_IMAGE_SIDE, MAX_IMAGE_SIDE, image::imageops::FilterType::Triangle)
+    } else {
+        decoded
+    };
+    let rgb = decoded.to_rgb8();
+    Ok(PreparedImage {
+        width: rgb.width(),
+        height: rgb.height(),
+        rgb: rgb.into_raw(),
+    })
+}
+
+/// Every image attached to `messages`, in conversation order
+pub fn attached_images(messages: &[ConversationMessage]) -> Vec<ImageInput> {
+    messages.iter().flat_map(|message| message.images.iter().cloned()).collect()
+}
+
+/// `messages` with one `marker` per attached image in front of the text, ready for the chat template
+///
+/// The backend replaces the markers with the images' embeddings, in order.
+pub fn with_image_markers(messages: &[ConversationMessage], marker: &str) -> Vec<ConversationMessage> {
+    messages.iter()
+        .map(|message| {
+            let mut content = String::new();
+            for _ in &message.images {
+                content.push_str(marker);
+                content.push('\n');
+            }
+            content.push_str(&message.content);
+            ConversationMessage {
+                role: message.role.clone(),
+                content,
+                truncated: message.truncated,
+                images: Vec::new(),
+            }
+        })
+        .collect()
+}
+
+/// `messages` with their images dropped, for models that only read text
+pub fn without_images(messages: &[ConversationMessage]) -> Vec<ConversationMessage> {
+    messages.iter()
+        .map(|message| ConversationMessage {
+            role: message.role.clone(),
+            content: message.content.clone(),
+            truncated: message.truncated,
+            images: Vec::new(),
+        })
+        .collect()
+}
+
+mod base64_data {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
@@ -5,6 +5,7 @@
  */
 
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 use dirs::config_dir;
 
@@ -47,7 +48,20 @@ pub struct EditorConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AIConfig {
+    /// Primary models directory; imported models are placed here
     pub models_directory: PathBuf,
+    /// Additional directories scanned for models: plain model folders or a HuggingFace hub cache
+    #[serde(default)]
+    pub model_search_paths: Vec<PathBuf>,
+    /// Also list models from the HuggingFace hub cache (`$HF_HOME/hub`, default `~/.cache/huggingface/hub`)
+    #[serde(default = "default_scan_hf_cache")]
+    pub scan_hf_cache: bool,
+    /// Host for model downloads, e.g. an internal mirror; defaults to `HF_ENDPOINT` or huggingface.co
+    #[serde(default)]
+    pub download_base_url: Option<String>,
+    /// RAM that resident models may use together, in MB; defaults to 60% of physical memory
+    #[serde(default)]
+    pub model_memory_budget_mb: Option<u64>,
     pub preferred_models: Vec<String>,
     pub auto_load_model: bool,
     pub context_strategy: ContextStrategy,
@@ -57,10 +71,119 @@ pub struct AIConfig {
     pub max_tokens: u32,
     pub streaming_enabled: bool,
     pub code_completion_enabled: bool,
+    /// Inline completions still queued after this long are dropped instead of run
+    #[serde(default = "default_completion_deadline_ms")]
+    pub completion_deadline_ms: u64,
     pub chat_enabled: bool,
     pub documentation_generation: bool,
     pub test_generation: bool,
     pub refactoring_assistance: bool,
+    /// Chat template overrides by model id: a built-in name (chatml, llama3, gemma, lfm2) or Jinja source
+    #[serde(default)]
+    pub chat_templates: HashMap<String, String>,
+    #[serde(default)]
+    pub api_server: ApiServerConfig,
+    /// Models served by other machines, listed next to the local ones
+    #[serde(default)]
+    pub remote_models: Vec<RemoteModelConfig>,
+    /// Named sampling presets, e.g. "deterministic" or "creative"
+    #[serde(default = "default_sampling_presets")]
+    pub sampling_presets: HashMap<String, SamplingPreset>,
+    /// Preset name by task (chat, completion, analysis, commit_message); tasks
+    /// without an entry use the generation settings as they are
+    #[serde(default = "default_task_presets")]
+    pub task_presets: HashMap<String, String>,
+}
+
+/// Sampling settings to lay over the generation settings; unset fields keep theirs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplingPreset {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
+    pub max_tokens: Option<u32>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub min_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    pub mirostat: Option<u8>,
+    pub mirostat_tau: Option<f32>,
+    pub mirostat_eta: Option<f32>,
+    pub logit_bias: Option<BTreeMap<u32, f32>>,
+    pub seed: Option<u64>,
+}
+
+/// A model running behind an OpenAI-compatible or Ollama HTTP endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteModelConfig {
+    /// Id used in the model list; must not clash with a local model
+    pub id: String,
+    /// Server root, e.g. `http://workstation:11434` for Ollama or `http://workstation:8000/v1` for OpenAI-compatible servers
+    pub base_url: String,
+    #[serde(default)]
+    pub api: RemoteApi,
+    /// Model name on the server; defaults to `id`
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Sent as `Authorization: Bearer <key>`
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Seconds to wait for the response to start and between streamed chunks
+    #[serde(default = "default_remote_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Retries after connection errors, timeouts, 429 and 5xx responses, as long as no text has streamed yet
+    #[serde(default = "default_remote_max_retries")]
+    pub max_retries: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteApi {
+    /// `/chat/completions` and `/completions` with server-sent events
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// `/api/chat` and `/api/generate` with newline-delimited JSON
+    Ollama,
+}
+
+fn default_remote_timeout_secs() -> u64 {
+    120
+}
+
+fn default_remote_max_retries() -> u32 {
+    2
+}
+
+/// Opt-in OpenAI-compatible HTTP server for other local tools
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiServerConfig {
+    /// Start the server with the app
+    #[serde(default)]
+    pub enabled: bool,
+    /// Address to listen on; keep it on loopback unless the token is set
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+    /// Clients must send `Authorization: Bearer <token>` when set
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+fn default_bind_address() -> String {
+    "127.0.0.1:8089".to_string()
+}
+
+impl Default for ApiServerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: default_bind_address(),
+            api_key: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -194,10 +317,54 @@ impl Default for EditorConfig {
     }
 }
 
+fn default_scan_hf_cache() -> bool {
+    true
+}
+
+fn default_completion_deadline_ms() -> u64 {
+    1500
+}
+
+fn default_sampling_presets() -> HashMap<String, SamplingPreset> {
+    HashMap::from([
+        // Greedy with a fixed seed: the same prompt always gives the same answer
+        ("deterministic".to_string(), SamplingPreset {
+            temperature: Some(0.0),
+            seed: Some(0),
+            ..SamplingPreset::default()
+        }),
+        ("precise".to_string(), SamplingPreset {
+            temperature: Some(0.2),
+            top_p: Some(0.9),
+            repeat_penalty: Some(1.05),
+            ..SamplingPreset::default()
+        }),
+        ("creative".to_string(), SamplingPreset {
+            temperature: Some(0.9),
+            top_p: Some(0.95),
+            min_p: Some(0.05),
+            repeat_penalty: Some(1.1),
+            ..SamplingPreset::default()
+        }),
+    ])
+}
+
+fn default_task_presets() -> HashMap<String, String> {
+    HashMap::from([
+        ("commit_message".to_string(), "deterministic".to_string()),
+        ("analysis".to_string(), "deterministic".to_string()),
+        ("completion".to_string(), "precise".to_string()),
+    ])
+}
+
 impl Default for AIConfig {
     fn default() -> Self {
         Self {
             models_directory: PathBuf::from("./models"),
+            model_search_paths: Vec::new(),
+            scan_hf_cache: default_scan_hf_cache(),
+            download_base_url: None,
+            model_memory_budget_mb: None,
             preferred_models: vec!["LFM2-VL-1.6B".to_string()],
             auto_load_model: true,
             context_strategy: ContextStrategy::Smart,
@@ -207,10 +374,16 @@ impl Default for AIConfig {
             max_tokens: 1024,
             streaming_enabled: true,
             code_completion_enabled: true,
+            completion_deadline_ms: default_completion_deadline_ms(),
             chat_enabled: true,
             documentation_generation: true,
             test_generation: true,
             refactoring_assistance: true,
+            chat_templates: HashMap::new(),
+            api_server: ApiServerConfig::default(),
+            remote_models: Vec::new(),
+            sampling_presets: default_sampling_presets(),
+            task_presets: default_task_presets(),
         }
     }
 }
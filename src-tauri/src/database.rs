@@ -2,10 +2,11 @@
  * Database Module
  * 
  * Manages persistent storage for RAIN.CHAT v2 using SQLite.
- * Stores project metadata, chat history, settings, and user data.
+ * Stores project metadata, chat history, settings, the semantic code index and user data.
  */
 
 use sqlx::{SqlitePool, Row};
+use sqlx::sqlite::SqliteRow;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use tracing::info;
@@ -39,6 +40,36 @@ pub struct ChatRecord {
     pub context_type: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelCacheRecord {
+    pub model_path: String,
+    pub model_type: String,
+    pub size_mb: f64,
+    pub last_used: i64,
+    pub load_time_ms: i64,
+    pub capabilities: String, // JSON
+    pub manifest: String, // JSON
+    pub model_info: Option<String>, // JSON
+    pub integrity: String, // JSON
+    pub verified_at: Option<i64>,
+}
+
+/// One benchmark run of a model; `prompts` holds the per-prompt measurements
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelBenchmarkRecord {
+    pub model_id: String,
+    pub model_path: String,
+    pub run_at: i64,
+    pub app_version: String,
+    pub format: String,
+    pub load_time_ms: i64,
+    pub prompt_tokens_per_sec: f64,
+    pub generation_tokens_per_sec: f64,
+    pub time_to_first_token_ms: i64,
+    pub peak_rss_mb: i64,
+    pub prompts: String, // JSON
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileRecord {
     pub id: String,
@@ -50,6 +81,32 @@ pub struct FileRecord {
     pub content_hash: String,
 }
 
+/// A file of the semantic code index with the state of its chunks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedFileRecord {
+    pub id: String,
+    pub path: String,
+    pub size: i64,
+    pub last_modified: i64,
+    pub content_hash: String,
+    pub chunk_count: i64,
+    /// Chunks embedded with the model the index was asked about
+    pub embedded_count: i64,
+}
+
+/// A chunk of an indexed file; `embedding` holds little-endian f32s
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CodeChunkRecord {
+    pub file_id: String,
+    pub start_line: i64,
+    pub end_line: i64,
+    pub kind: String,
+    pub symbol: Option<String>,
+    pub content: String,
+    pub embedding: Option<Vec<u8>>,
+    pub embedding_model: Option<String>,
+}
+
 impl Database {
     pub fn new() -> Self {
         // Try multiple fallback locations for the database
@@ -61,6 +118,14 @@ impl Database {
         }
     }
 
+    /// A database at `db_path` instead of the default location
+    pub fn with_path(db_path: PathBuf) -> Self {
+        Self {
+            pool: None,
+            db_path,
+        }
+    }
+
     fn get_database_path() -> PathBuf {
         // For now, use current directory to avoid permission issues
         let current_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
@@ -170,6 +235,24 @@ impl Database {
         .execute(pool)
         .await?;
 
+        // Semantic code index: chunks of the files above with their embeddings
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS code_chunks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                file_id TEXT NOT NULL,
+                start_line INTEGER NOT NULL,
+                end_line INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                symbol TEXT,
+                content TEXT NOT NULL,
+                embedding BLOB,
+                embedding_model TEXT,
+                FOREIGN KEY(file_id) REFERENCES files(id) ON DELETE CASCADE
+            )"
+        )
+        .execute(pool)
+        .await?;
+
         // Settings table
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS settings (
@@ -189,7 +272,51 @@ impl Database {
                 size_mb REAL NOT NULL,
                 last_used INTEGER NOT NULL,
                 load_time_ms INTEGER NOT NULL,
-                capabilities TEXT NOT NULL
+                capabilities TEXT NOT NULL,
+                manifest TEXT NOT NULL DEFAULT '[]',
+                model_info TEXT,
+                integrity TEXT NOT NULL DEFAULT '{\"status\":\"unverified\"}',
+                verified_at INTEGER
+            )"
+        )
+        .execute(pool)
+        .await?;
+
+        // Databases created before manifests existed have the table without these columns
+        let model_cache_columns = [
+            ("manifest", "TEXT NOT NULL DEFAULT '[]'"),
+            ("model_info", "TEXT"),
+            ("integrity", "TEXT NOT NULL DEFAULT '{\"status\":\"unverified\"}'"),
+            ("verified_at", "INTEGER"),
+        ];
+        for (column, definition) in model_cache_columns {
+            let exists = sqlx::query("SELECT 1 FROM pragma_table_info('model_cache') WHERE name = ?1")
+                .bind(column)
+                .fetch_optional(pool)
+                .await?
+                .is_some();
+            if !exists {
+                sqlx::query(&format!("ALTER TABLE model_cache ADD COLUMN {} {}", column, definition))
+                    .execute(pool)
+                    .await?;
+            }
+        }
+
+        // Benchmark history, one row per run
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS model_benchmarks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                model_id TEXT NOT NULL,
+                model_path TEXT NOT NULL,
+                run_at INTEGER NOT NULL,
+                app_version TEXT NOT NULL,
+                format TEXT NOT NULL,
+                load_time_ms INTEGER NOT NULL,
+                prompt_tokens_per_sec REAL NOT NULL,
+                generation_tokens_per_sec REAL NOT NULL,
+                time_to_first_token_ms INTEGER NOT NULL,
+                peak_rss_mb INTEGER NOT NULL,
+                prompts TEXT NOT NULL DEFAULT '[]'
             )"
         )
         .execute(pool)
@@ -214,16 +341,42 @@ impl Database {
         .execute(pool)
         .await?;
 
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_files_project_path ON files(project_id, path)"
+        )
+        .execute(pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_code_chunks_file ON code_chunks(file_id)"
+        )
+        .execute(pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_model_benchmarks_model ON model_benchmarks(model_id, run_at DESC)"
+        )
+        .execute(pool)
+        .await?;
+
         Ok(())
     }
 
     // Project operations
+
+    /// Insert a project, or refresh the one at the same path while keeping its id and files
     pub async fn insert_project(&self, project: &ProjectRecord) -> Result<()> {
         if let Some(pool) = &self.pool {
             sqlx::query(
-                "INSERT OR REPLACE INTO projects 
+                "INSERT INTO projects 
                 (id, name, path, project_type, language, created_at, last_opened, settings) 
-                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT(path) DO UPDATE SET
+                    name = excluded.name,
+                    project_type = excluded.project_type,
+                    language = excluded.language,
+                    last_opened = excluded.last_opened,
+                    settings = excluded.settings"
             )
             .bind(&project.id)
             .bind(&project.name)
@@ -265,6 +418,190 @@ impl Database {
         Ok(Vec::new())
     }
 
+    /// Id of the project at `path`, inserting `project` if there is none
+    pub async fn get_or_insert_project(&self, project: &ProjectRecord) -> Result<String> {
+        if let Some(pool) = &self.pool {
+            sqlx::query(
+                "INSERT INTO projects 
+                (id, name, path, project_type, language, created_at, last_opened, settings) 
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT(path) DO NOTHING"
+            )
+            .bind(&project.id)
+            .bind(&project.name)
+            .bind(&project.path)
+            .bind(&project.project_type)
+            .bind(&project.language)
+            .bind(project.created_at)
+            .bind(project.last_opened)
+            .bind(&project.settings)
+            .execute(pool)
+            .await?;
+
+            let row = sqlx::query("SELECT id FROM projects WHERE path = ?1")
+                .bind(&project.path)
+                .fetch_one(pool)
+                .await?;
+            return Ok(row.get("id"));
+        }
+        Ok(project.id.clone())
+    }
+
+    pub async fn find_project_id(&self, path: &str) -> Result<Option<String>> {
+        if let Some(pool) = &self.pool {
+            let row = sqlx::query("SELECT id FROM projects WHERE path = ?1")
+                .bind(path)
+                .fetch_optional(pool)
+                .await?;
+            return Ok(row.map(|row| row.get("id")));
+        }
+        Ok(None)
+    }
+
+    // Code index operations
+
+    /// Indexed files of a project, counting the chunks embedded with `embedding_model`
+    pub async fn get_indexed_files(&self, project_id: &str, embedding_model: Option<&str>) -> Result<Vec<IndexedFileRecord>> {
+        if let Some(pool) = &self.pool {
+            let rows = sqlx::query(
+                "SELECT f.id, f.path, f.size, f.last_modified, f.content_hash,
+                        COUNT(c.id) AS chunk_count,
+                        COALESCE(SUM(c.embedding_model IS ?2), 0) AS embedded_count
+                 FROM files f LEFT JOIN code_chunks c ON c.file_id = f.id
+                 WHERE f.project_id = ?1
+                 GROUP BY f.id"
+            )
+            .bind(project_id)
+            .bind(embedding_model)
+            .fetch_all(pool)
+            .await?;
+
+            let files = rows.iter().map(|row| IndexedFileRecord {
+                id: row.get("id"),
+                path: row.get("path"),
+                size: row.get("size"),
+                last_modified: row.get("last_modified"),
+                content_hash: row.get("content_hash"),
+                chunk_count: row.get("chunk_count"),
+                embedded_count: row.get("embedded_count"),
+            }).collect();
+
+            return Ok(files);
+        }
+        Ok(Vec::new())
+    }
+
+    /// Store a file and replace its chunks in one transaction
+    pub async fn replace_file_chunks(&self, file: &FileRecord, chunks: &[CodeChunkRecord]) -> Result<()> {
+        if let Some(pool) = &self.pool {
+            let mut transaction = pool.begin().await?;
+            sqlx::query(
+                "INSERT INTO files (id, project_id, path, language, size, last_modified, content_hash)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT(id) DO UPDATE SET
+                    language = excluded.language,
+                    size = excluded.size,
+                    last_modified = excluded.last_modified,
+                    content_hash = excluded.content_hash"
+            )
+            .bind(&file.id)
+            .bind(&file.project_id)
+            .bind(&file.path)
+            .bind(&file.language)
+            .bind(file.size)
+            .bind(file.last_modified)
+            .bind(&file.content_hash)
+            .execute(&mut *transaction)
+            .await?;
+
+            sqlx::query("DELETE FROM code_chunks WHERE file_id = ?1")
+                .bind(&file.id)
+                .execute(&mut *transaction)
+                .await?;
+
+            for chunk in chunks {
+                sqlx::query(
+                    "INSERT INTO code_chunks
+                    (file_id, start_line, end_line, kind, symbol, content, embedding, embedding_model)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+                )
+                .bind(&file.id)
+                .bind(chunk.start_line)
+                .bind(chunk.end_line)
+                .bind(&chunk.kind)
+                .bind(&chunk.symbol)
+                .bind(&chunk.content)
+                .bind(&chunk.embedding)
+                .bind(&chunk.embedding_model)
+                .execute(&mut *transaction)
+                .await?;
+            }
+            transaction.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Record a new size and modification time for a file whose content is unchanged
+    pub async fn update_file_stat(&self, file_id: &str, size: i64, last_modified: i64) -> Result<()> {
+        if let Some(pool) = &self.pool {
+            sqlx::query("UPDATE files SET size = ?2, last_modified = ?3 WHERE id = ?1")
+                .bind(file_id)
+                .bind(size)
+                .bind(last_modified)
+                .execute(pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Remove files and their chunks from the index
+    pub async fn delete_files(&self, file_ids: &[String]) -> Result<()> {
+        if let Some(pool) = &self.pool {
+            let mut transaction = pool.begin().await?;
+            for file_id in file_ids {
+                sqlx::query("DELETE FROM code_chunks WHERE file_id = ?1")
+                    .bind(file_id)
+                    .execute(&mut *transaction)
+                    .await?;
+                sqlx::query("DELETE FROM files WHERE id = ?1")
+                    .bind(file_id)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+            transaction.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Every chunk of a project's indexed files
+    pub async fn get_code_chunks(&self, project_id: &str) -> Result<Vec<CodeChunkRecord>> {
+        if let Some(pool) = &self.pool {
+            let rows = sqlx::query(
+                "SELECT c.file_id, c.start_line, c.end_line, c.kind, c.symbol, c.content, c.embedding, c.embedding_model
+                 FROM code_chunks c JOIN files f ON f.id = c.file_id
+                 WHERE f.project_id = ?1
+                 ORDER BY f.path, c.start_line"
+            )
+            .bind(project_id)
+            .fetch_all(pool)
+            .await?;
+
+            let chunks = rows.iter().map(|row| CodeChunkRecord {
+                file_id: row.get("file_id"),
+                start_line: row.get("start_line"),
+                end_line: row.get("end_line"),
+                kind: row.get("kind"),
+                symbol: row.get("symbol"),
+                content: row.get("content"),
+                embedding: row.get("embedding"),
+                embedding_model: row.get("embedding_model"),
+            }).collect();
+
+            return Ok(chunks);
+        }
+        Ok(Vec::new())
+    }
+
     // Chat operations
     pub async fn insert_chat_message(&self, message: &ChatRecord) -> Result<()> {
         if let Some(pool) = &self.pool {
@@ -314,6 +651,178 @@ impl Database {
         Ok(Vec::new())
     }
 
+    // Model cache operations
+    pub async fn get_model_cache_entries(&self) -> Result<Vec<ModelCacheRecord>> {
+        if let Some(pool) = &self.pool {
+            let rows = sqlx::query(
+                "SELECT model_path, model_type, size_mb, last_used, load_time_ms, capabilities,
+                        manifest, model_info, integrity, verified_at
+                 FROM model_cache"
+            )
+            .fetch_all(pool)
+            .await?;
+
+            return Ok(rows.iter().map(Self::model_cache_record).collect());
+        }
+        Ok(Vec::new())
+    }
+
+    pub async fn get_model_cache_entry(&self, model_path: &str) -> Result<Option<ModelCacheRecord>> {
+        if let Some(pool) = &self.pool {
+            let row = sqlx::query(
+                "SELECT model_path, model_type, size_mb, last_used, load_time_ms, capabilities,
+                        manifest, model_info, integrity, verified_at
+                 FROM model_cache WHERE model_path = ?1"
+            )
+            .bind(model_path)
+            .fetch_optional(pool)
+            .await?;
+
+            return Ok(row.as_ref().map(Self::model_cache_record));
+        }
+        Ok(None)
+    }
+
+    fn model_cache_record(row: &SqliteRow) -> ModelCacheRecord {
+        ModelCacheRecord {
+            model_path: row.get("model_path"),
+            model_type: row.get("model_type"),
+            size_mb: row.get("size_mb"),
+            last_used: row.get("last_used"),
+            load_time_ms: row.get("load_time_ms"),
+            capabilities: row.get("capabilities"),
+            manifest: row.get("manifest"),
+            model_info: row.get("model_info"),
+            integrity: row.get("integrity"),
+            verified_at: row.get("verified_at"),
+        }
+    }
+
+    /// Insert or refresh a discovered model, keeping its load statistics
+    pub async fn upsert_model_cache(&self, record: &ModelCacheRecord) -> Result<()> {
+        if let Some(pool) = &self.pool {
+            sqlx::query(
+                "INSERT INTO model_cache
+                (model_path, model_type, size_mb, last_used, load_time_ms, capabilities, manifest, model_info, integrity, verified_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                ON CONFLICT(model_path) DO UPDATE SET
+                    model_type = excluded.model_type,
+                    size_mb = excluded.size_mb,
+                    capabilities = excluded.capabilities,
+                    manifest = excluded.manifest,
+                    model_info = excluded.model_info,
+                    integrity = excluded.integrity,
+                    verified_at = excluded.verified_at"
+            )
+            .bind(&record.model_path)
+            .bind(&record.model_type)
+            .bind(record.size_mb)
+            .bind(record.last_used)
+            .bind(record.load_time_ms)
+            .bind(&record.capabilities)
+            .bind(&record.manifest)
+            .bind(&record.model_info)
+            .bind(&record.integrity)
+            .bind(record.verified_at)
+            .execute(pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn update_model_integrity(&self, model_path: &str, manifest: &str, integrity: &str, verified_at: i64) -> Result<()> {
+        if let Some(pool) = &self.pool {
+            sqlx::query(
+                "UPDATE model_cache SET manifest = ?2, integrity = ?3, verified_at = ?4 WHERE model_path = ?1"
+            )
+            .bind(model_path)
+            .bind(manifest)
+            .bind(integrity)
+            .bind(verified_at)
+            .execute(pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn record_model_load(&self, model_path: &str, load_time_ms: i64) -> Result<()> {
+        if let Some(pool) = &self.pool {
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64;
+
+            sqlx::query(
+                "UPDATE model_cache SET load_time_ms = ?2, last_used = ?3 WHERE model_path = ?1"
+            )
+            .bind(model_path)
+            .bind(load_time_ms)
+            .bind(timestamp)
+            .execute(pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    // Benchmark operations
+    pub async fn insert_model_benchmark(&self, record: &ModelBenchmarkRecord) -> Result<()> {
+        if let Some(pool) = &self.pool {
+            sqlx::query(
+                "INSERT INTO model_benchmarks
+                (model_id, model_path, run_at, app_version, format, load_time_ms, prompt_tokens_per_sec,
+                 generation_tokens_per_sec, time_to_first_token_ms, peak_rss_mb, prompts)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"
+            )
+            .bind(&record.model_id)
+            .bind(&record.model_path)
+            .bind(record.run_at)
+            .bind(&record.app_version)
+            .bind(&record.format)
+            .bind(record.load_time_ms)
+            .bind(record.prompt_tokens_per_sec)
+            .bind(record.generation_tokens_per_sec)
+            .bind(record.time_to_first_token_ms)
+            .bind(record.peak_rss_mb)
+            .bind(&record.prompts)
+            .execute(pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// A model's benchmark runs, newest first
+    pub async fn get_model_benchmarks(&self, model_id: &str, limit: usize) -> Result<Vec<ModelBenchmarkRecord>> {
+        if let Some(pool) = &self.pool {
+            let rows = sqlx::query(
+                "SELECT model_id, model_path, run_at, app_version, format, load_time_ms, prompt_tokens_per_sec,
+                        generation_tokens_per_sec, time_to_first_token_ms, peak_rss_mb, prompts
+                 FROM model_benchmarks WHERE model_id = ?1
+                 ORDER BY run_at DESC, id DESC LIMIT ?2"
+            )
+            .bind(model_id)
+            .bind(limit as i64)
+            .fetch_all(pool)
+            .await?;
+
+            let records = rows.iter().map(|row| ModelBenchmarkRecord {
+                model_id: row.get("model_id"),
+                model_path: row.get("model_path"),
+                run_at: row.get("run_at"),
+                app_version: row.get("app_version"),
+                format: row.get("format"),
+                load_time_ms: row.get("load_time_ms"),
+                prompt_tokens_per_sec: row.get("prompt_tokens_per_sec"),
+                generation_tokens_per_sec: row.get("generation_tokens_per_sec"),
+                time_to_first_token_ms: row.get("time_to_first_token_ms"),
+                peak_rss_mb: row.get("peak_rss_mb"),
+                prompts: row.get("prompts"),
+            }).collect();
+
+            return Ok(records);
+        }
+        Ok(Vec::new())
+    }
+
     // Settings operations
     pub async fn set_setting(&self, key: &str, value: &str) -> Result<()> {
         if let Some(pool) = &self.pool {
@@ -10,7 +10,7 @@
     windows_subsystem = "windows"
 )]
 
-use tracing::info;
+use tracing::{info, warn};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
@@ -33,7 +33,11 @@ use core::{
 };
 
 use ai::{
+    api_server::ApiServer,
+    code_index::CodeIndex,
+    generation::GenerationRegistry,
     model_manager::ModelManager,
+    scheduler::InferenceScheduler,
     chat::ChatEngine,
     context::ContextManager,
     assistant::CodeAssistant,
@@ -55,24 +59,36 @@ pub struct AppState {
     pub git: Arc<RwLock<GitManager>>,
     pub lsp: Arc<RwLock<LanguageServerManager>>,
     pub ai_models: Arc<RwLock<ModelManager>>,
+    /// Runs generations one at a time by priority; use it instead of holding `ai_models` while generating
+    pub scheduler: InferenceScheduler,
+    pub api_server: Arc<RwLock<Option<ApiServer>>>,
+    pub generations: Arc<RwLock<GenerationRegistry>>,
     pub chat: Arc<RwLock<ChatEngine>>,
     pub context: Arc<RwLock<ContextManager>>,
+    /// Semantic code index of projects; the context manager reads from it
+    pub code_index: CodeIndex,
     pub assistant: Arc<RwLock<CodeAssistant>>,
     pub performance: Arc<RwLock<PerformanceMonitor>>,
 }
 
 impl Default for AppState {
     fn default() -> Self {
+        let ai_models = Arc::new(RwLock::new(ModelManager::new()));
+        let database = Arc::new(RwLock::new(Database::new()));
         Self {
             config: Arc::new(RwLock::new(AppConfig::default())),
-            database: Arc::new(RwLock::new(Database::new())),
+            code_index: CodeIndex::new(database.clone(), ai_models.clone()),
+            database,
             projects: Arc::new(RwLock::new(ProjectManager::new())),
             editor: Arc::new(RwLock::new(EditorEngine::new())),
             terminal: Arc::new(RwLock::new(TerminalManager::new())),
             debugger: Arc::new(RwLock::new(DebuggerEngine::new())),
             git: Arc::new(RwLock::new(GitManager::new())),
             lsp: Arc::new(RwLock::new(LanguageServerManager::new())),
-            ai_models: Arc::new(RwLock::new(ModelManager::new())),
+            scheduler: InferenceScheduler::new(ai_models.clone()),
+            ai_models,
+            api_server: Arc::new(RwLock::new(None)),
+            generations: Arc::new(RwLock::new(GenerationRegistry::new())),
             chat: Arc::new(RwLock::new(ChatEngine::new())),
             context: Arc::new(RwLock::new(ContextManager::new())),
             assistant: Arc::new(RwLock::new(CodeAssistant::new())),
@@ -98,6 +114,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut db = app_state.database.write().await;
         db.initialize().await?;
     }
+    app_state.ai_models.write().await.set_database(app_state.database.clone());
+
+    // Budget chat history and context with the default model's tokenizer
+    let token_counter = app_state.ai_models.read().await.token_counter();
+    app_state.chat.write().await.set_token_counter(token_counter.clone());
+    {
+        let mut context = app_state.context.write().await;
+        context.set_token_counter(token_counter);
+        context.set_code_index(app_state.code_index.clone());
+    }
+
+    // Restore saved settings and hand the model directories and chat template overrides to the model manager
+    {
+        let mut config = app_state.config.write().await;
+        match AppConfig::load() {
+            Ok(saved) => *config = saved,
+            Err(e) => warn!("⚠️ Failed to load settings, using defaults: {}", e),
+        }
+        let mut ai_models = app_state.ai_models.write().await;
+        ai_models.set_chat_template_overrides(config.ai.chat_templates.clone());
+        ai_models.configure_directories(&config.ai.models_directory, config.ai.model_search_paths.clone(), config.ai.scan_hf_cache);
+        ai_models.set_memory_budget_mb(config.ai.model_memory_budget_mb);
+        ai_models.set_preferred_models(config.ai.preferred_models.clone());
+        ai_models.set_remote_models(config.ai.remote_models.clone());
+        ai_models.set_sampling_presets(config.ai.sampling_presets.clone(), config.ai.task_presets.clone());
+        drop(ai_models);
+
+        if config.ai.api_server.enabled {
+            match ApiServer::start(app_state.ai_models.clone(), app_state.scheduler.clone(), &config.ai.api_server) {
+                Ok(server) => *app_state.api_server.write().await = Some(server),
+                Err(e) => warn!("⚠️ Failed to start the API server: {}", e),
+            }
+        }
+    }
 
     // Build and run Tauri application
     tauri::Builder::default()
@@ -141,6 +191,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             ui::ai::load_best_model,
             ui::ai::load_model_by_name,
             ui::ai::generate_response,
+            ui::ai::cancel_generation,
+            ui::ai::set_chat_template,
+            ui::ai::apply_lora_adapter,
+            ui::ai::remove_lora_adapter,
+            ui::ai::verify_model,
+            ui::ai::import_model,
+            ui::ai::download_model,
             ui::ai::get_model_info,
             ui::ai::clear_conversation,
             ui::ai::reset_context,
@@ -150,6 +207,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             ui::ai::load_embedding_model,
             ui::ai::encode_text,
             ui::ai::compute_similarity,
+            ui::ai::index_project,
+            ui::ai::cancel_project_index,
+            ui::ai::get_project_index_status,
+            ui::ai::search_project_index,
+            ui::ai::start_api_server,
+            ui::ai::stop_api_server,
+            ui::ai::get_api_server_status,
+            ui::ai::get_inline_completion,
+            ui::ai::get_scheduler_metrics,
+            ui::ai::benchmark_model,
+            ui::ai::get_benchmark_history,
             
             // Terminal operations
             ui::terminal::create_terminal,
@@ -152,6 +152,13 @@ impl PerformanceMonitor {
         *last_update = Instant::now();
     }
 
+    /// Memory that can be handed to new allocations without swapping, in bytes
+    pub fn available_memory(&self) -> Result<u64, String> {
+        let mut system = self.system.lock().map_err(|e| format!("Failed to lock system: {}", e))?;
+        system.refresh_memory();
+        Ok(system.available_memory())
+    }
+
     pub fn get_system_info(&self) -> Result<SystemInfo, String> {
         let system = self.system.lock().map_err(|e| format!("Failed to lock system: {}", e))?;
         
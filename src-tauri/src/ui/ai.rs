@@ -4,16 +4,45 @@
  * Tauri command handlers for AI operations.
  */
 
-use tauri::State;
+use tauri::{AppHandle, Emitter, State};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tracing::{info, warn};
+use uuid::Uuid;
 
 use crate::ai::chat::{MessageRole, MessageMetadata};
 use crate::ai::context::{ContextRequest, ContextResponse};
+use crate::ai::generation::{CancelToken, FinishReason, GenerationOutput};
+use crate::ai::integrity::{IntegrityError, IntegrityStatus};
+use crate::ai::lora::{ActiveAdapter, AdapterInfo};
+use crate::ai::api_server::{ApiServer, ApiServerStatus};
+use crate::ai::assistant::ModelAnalysis;
+use crate::ai::benchmark::BenchmarkResult;
+use crate::ai::code_index::{self, CodeSearchResult, IndexProgress};
+use crate::ai::download::{self, ModelDownloader, RemoteFile};
+use crate::ai::embeddings;
+use crate::ai::model_import::{self, ImportMode, ImportPlan};
+use crate::ai::model_manager::ModelMetadata;
+use crate::ai::model_selection::ModelSelection;
+use crate::ai::scheduler::{Priority, SchedulerError, SchedulerMetrics};
+use crate::ai::vision::ImageInput;
+use crate::config::{ApiServerConfig, SamplingPreset};
 use crate::AppState;
 
+/// Event carrying one streamed text delta
+pub const GENERATION_TOKEN_EVENT: &str = "ai-generation-token";
+/// Event emitted once a generation has finished
+pub const GENERATION_COMPLETE_EVENT: &str = "ai-generation-complete";
+/// Event carrying `ImportProgress` while a model is imported
+pub const MODEL_IMPORT_PROGRESS_EVENT: &str = "ai-model-import-progress";
+/// Event carrying `DownloadProgress` while a model is downloaded
+pub const MODEL_DOWNLOAD_PROGRESS_EVENT: &str = "ai-model-download-progress";
+/// Event carrying `IndexProgress` while a project is indexed and once indexing has ended
+pub const CODE_INDEX_PROGRESS_EVENT: &str = "ai-code-index-progress";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ModelInfo {
     pub id: String,
@@ -23,7 +52,10 @@ pub struct ModelInfo {
     pub path: String,
     pub size_mb: u64,
     pub loaded: bool,
-    pub capabilities: Vec<String>,
+    pub capabilities: BTreeSet<String>,
+    pub metadata: Option<ModelMetadata>,
+    pub integrity: IntegrityStatus,
+    pub adapters: Vec<AdapterInfo>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,6 +76,14 @@ pub struct ChatRequest {
     pub context_type: String,
     pub include_file_context: bool,
     pub include_project_context: bool,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Model to answer with; the default model when absent
+    #[serde(default)]
+    pub model_id: Option<String>,
+    /// Images for vision models, e.g. screenshots
+    #[serde(default)]
+    pub images: Vec<ImageInput>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -53,6 +93,29 @@ pub struct ChatResponse {
     pub metadata: MessageMetadata,
 }
 
+/// Payload of `ai-generation-token`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationToken {
+    pub request_id: String,
+    pub index: usize,
+    pub delta: String,
+}
+
+/// Payload of `ai-generation-complete`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationSummary {
+    pub request_id: String,
+    pub model_id: Option<String>,
+    pub prompt_tokens: usize,
+    /// Prompt tokens reused from the model's KV cache rather than evaluated
+    pub cached_prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub finish_reason: FinishReason,
+    pub time_to_first_token_ms: Option<u64>,
+    pub total_time_ms: u64,
+    pub tokens_per_second: f32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CodeSuggestion {
     pub id: String,
@@ -108,17 +171,135 @@ pub async fn load_model(
 ) -> Result<(), String> {
     let mut model_manager = state.ai_models.write().await;
     model_manager.load_model_by_id(&model_id).await
-        .map_err(|e| format!("Failed to load model: {}", e))?;
+        .map_err(|e| load_error("Failed to load model", e))?;
     Ok(())
 }
 
+/// Integrity failures get their own message so the UI can tell them apart from backend errors
+fn load_error(context: &str, e: anyhow::Error) -> String {
+    match e.downcast_ref::<IntegrityError>() {
+        Some(integrity) => format!("Model integrity check failed: {}", integrity),
+        None => format!("{}: {}", context, e),
+    }
+}
+
+/// Bring a model file, model folder or HuggingFace cache entry into the models directory
+///
+/// Returns the id of the imported model.
+#[tauri::command]
+pub async fn import_model(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    source: String,
+    mode: Option<ImportMode>,
+    name: Option<String>,
+) -> Result<String, String> {
+    let models_dir = state.ai_models.read().await.models_dir().to_path_buf();
+    let mode = mode.unwrap_or_default();
+    info!("📦 Importing {} into {} ({:?})", source, models_dir.display(), mode);
+
+    let imported = tokio::task::spawn_blocking(move || -> Result<String> {
+        let plan = ImportPlan::new(&PathBuf::from(&source), name.as_deref())?;
+        std::fs::create_dir_all(&models_dir)?;
+        model_import::execute(&plan, &models_dir, mode, &mut |progress| {
+            if let Err(e) = app.emit(MODEL_IMPORT_PROGRESS_EVENT, progress) {
+                warn!("⚠️ Failed to emit import progress: {}", e);
+            }
+        })?;
+        Ok(plan.name)
+    }).await
+        .map_err(|e| format!("Failed to import model: {}", e))?
+        .map_err(|e| format!("Failed to import model: {}", e))?;
+
+    state.ai_models.write().await.discover_models().await
+        .map_err(|e| format!("Failed to discover models: {}", e))?;
+    info!("✅ Imported model {}", imported);
+    Ok(imported)
+}
+
+/// Download a model repository into the models directory (or `models/embedding`)
+///
+/// Interrupted downloads resume where they stopped when the command is run again.
+/// Returns the name of the model directory.
+#[tauri::command]
+pub async fn download_model(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    repo_id: String,
+    revision: Option<String>,
+    files: Option<Vec<String>>,
+    name: Option<String>,
+    embedding: Option<bool>,
+) -> Result<String, String> {
+    let base_url = state.config.read().await.ai.download_base_url.clone();
+    let models_dir = state.ai_models.read().await.models_dir().to_path_buf();
+    let revision = revision.unwrap_or_else(|| "main".to_string());
+    let name = name.unwrap_or_else(|| repo_id.rsplit('/').next().unwrap_or(&repo_id).to_string());
+    download::safe_directory_name(&name).map_err(|e| format!("Failed to download model: {}", e))?;
+    let target = if embedding.unwrap_or(false) {
+        models_dir.join("embedding").join(&name)
+    } else {
+        models_dir.join(&name)
+    };
+
+    let downloader = ModelDownloader::new(base_url.as_deref())
+        .map_err(|e| format!("Failed to download model: {}", e))?;
+    info!("📦 Downloading {}@{} from {} into {}", repo_id, revision, downloader.base_url(), target.display());
+
+    // The listing carries the checksums; plain mirrors without the API still work when files are named
+    let remote_files = match (downloader.list_files(&repo_id, &revision).await, files) {
+        (Ok(listed), Some(wanted)) => wanted.iter()
+            .map(|path| listed.iter().find(|file| file.path == *path).cloned()
+                .unwrap_or(RemoteFile { path: path.clone(), size: None, sha256: None }))
+            .collect(),
+        (Ok(listed), None) => listed,
+        (Err(e), Some(wanted)) => {
+            warn!("⚠️ Could not list {}, downloading without published checksums: {}", repo_id, e);
+            wanted.into_iter().map(|path| RemoteFile { path, size: None, sha256: None }).collect()
+        }
+        (Err(e), None) => return Err(format!("Failed to download model: {}", e)),
+    };
+
+    let mut on_progress = |progress: &download::DownloadProgress| {
+        if let Err(e) = app.emit(MODEL_DOWNLOAD_PROGRESS_EVENT, progress) {
+            warn!("⚠️ Failed to emit download progress: {}", e);
+        }
+    };
+    let hashes = downloader.download(&repo_id, &revision, &remote_files, &target, &mut on_progress).await
+        .map_err(|e| format!("Failed to download model: {}", e))?;
+    download::write_model_info(&target, &repo_id, &revision, &hashes).await
+        .map_err(|e| format!("Failed to write model info: {}", e))?;
+
+    state.ai_models.write().await.discover_models().await
+        .map_err(|e| format!("Failed to discover models: {}", e))?;
+    info!("✅ Downloaded {} ({} files)", repo_id, hashes.len());
+    Ok(name)
+}
+
+/// Hash every file of a model again, ignoring cached hashes
+#[tauri::command]
+pub async fn verify_model(
+    state: State<'_, AppState>,
+    model_id: String,
+) -> Result<IntegrityStatus, String> {
+    // Build the job under the read lock, then hash without holding it
+    let job = state.ai_models.read().await
+        .verification_job(&model_id, true)
+        .map_err(|e| format!("Failed to verify model: {}", e))?;
+    job.run().await
+        .map_err(|e| format!("Failed to verify model: {}", e))
+}
+
 #[tauri::command]
 pub async fn unload_model(
     state: State<'_, AppState>,
+    model_id: Option<String>,
 ) -> Result<(), String> {
     let mut model_manager = state.ai_models.write().await;
-    model_manager.unload_current_model()
-        .map_err(|e| format!("Failed to unload model: {}", e))?;
+    match model_id {
+        Some(model_id) => model_manager.unload_model(&model_id),
+        None => model_manager.unload_current_model(),
+    }.map_err(|e| format!("Failed to unload model: {}", e))?;
     Ok(())
 }
 
@@ -138,6 +319,9 @@ pub async fn get_available_models(
         size_mb: model.size_mb,
         loaded: model.loaded,
         capabilities: model.capabilities.clone(),
+        metadata: model.metadata.clone(),
+        integrity: model_manager.integrity_status(&model.id),
+        adapters: model.adapters.clone(),
     }).collect();
     
     Ok(model_infos)
@@ -145,14 +329,12 @@ pub async fn get_available_models(
 
 #[tauri::command]
 pub async fn chat_with_ai(
+    app: AppHandle,
     state: State<'_, AppState>,
     request: ChatRequest,
 ) -> Result<ChatResponse, String> {
-    let mut chat_engine = state.chat.write().await;
-    let mut context_manager = state.context.write().await;
-    
     // Add user message to chat
-    let _user_message_id = chat_engine.add_message(
+    let _user_message_id = state.chat.write().await.add_message(
         &request.session_id,
         MessageRole::User,
         request.message.clone(),
@@ -167,17 +349,22 @@ pub async fn chat_with_ai(
     
     // Get context if requested
     let context = if request.include_file_context || request.include_project_context {
+        let project_root = if request.include_project_context {
+            state.projects.read().await.current_project.as_ref().map(|project| project.path.clone())
+        } else {
+            None
+        };
         let context_request = ContextRequest {
             query: request.message.clone(),
             current_file: None, // Would be set based on current editor state
-            project_root: None, // Would be set based on current project
+            project_root,
             strategy: "smart".to_string(),
             max_tokens: 2048,
             include_selection: false,
             selection_content: None,
         };
         
-        context_manager.get_context(context_request)
+        state.context.write().await.get_context(context_request).await
             .map_err(|e| format!("Failed to get context: {}", e))?
     } else {
         ContextResponse {
@@ -188,33 +375,36 @@ pub async fn chat_with_ai(
         }
     };
     
-    // Generate AI response (placeholder)
-    let ai_response = format!("AI response to: {}", request.message);
-    
+    // Generate AI response with the loaded model
+    let request_id = request.request_id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
+    let turn = ChatTurn {
+        message: request.message.clone(),
+        images: request.images.clone(),
+        context: context.to_system_prompt(),
+    };
+    let (output, summary) = stream_response(&app, &state, &request_id, request.model_id.as_deref(), turn).await?;
+
+    let mut chat_engine = state.chat.write().await;
+    let metadata = MessageMetadata {
+        model_used: summary.model_id.clone(),
+        tokens_used: Some(output.completion_tokens as u32),
+        generation_time_ms: Some(summary.total_time_ms),
+        context_files: context.context_items.iter().map(|item| item.id.clone()).collect(),
+        code_blocks: chat_engine.extract_code_blocks(&output.text),
+    };
+
     // Add AI response to chat
     let ai_message_id = chat_engine.add_message(
         &request.session_id,
         MessageRole::Assistant,
-        ai_response.clone(),
-        MessageMetadata {
-            model_used: Some("mock-model".to_string()),
-            tokens_used: Some(100),
-            generation_time_ms: Some(500),
-            context_files: context.context_items.iter().map(|item| item.id.clone()).collect(),
-            code_blocks: vec![],
-        },
+        output.text.clone(),
+        metadata.clone(),
     ).map_err(|e| format!("Failed to add AI message: {}", e))?;
     
     Ok(ChatResponse {
         message_id: ai_message_id,
-        content: ai_response,
-        metadata: MessageMetadata {
-            model_used: Some("mock-model".to_string()),
-            tokens_used: Some(100),
-            generation_time_ms: Some(500),
-            context_files: context.context_items.iter().map(|item| item.id.clone()).collect(),
-            code_blocks: vec![],
-        },
+        content: output.text,
+        metadata,
     })
 }
 
@@ -266,11 +456,29 @@ pub async fn analyze_code(
     file_path: String,
     content: String,
 ) -> Result<CodeAnalysis, String> {
-    let assistant = state.assistant.read().await;
     let path = PathBuf::from(&file_path);
-    
-    let analysis = assistant.analyze_code(&path, &content).await
-        .map_err(|e| format!("Failed to analyze code: {}", e))?;
+
+    // Ask the loaded model for schema-constrained issues in the background class, falling back to the heuristics
+    let (messages, schema) = {
+        let assistant = state.assistant.read().await;
+        (assistant.analysis_messages(&path, &content), assistant.analysis_schema())
+    };
+    let model_analysis = state.scheduler.submit(Priority::Background, None, move |model_manager| Box::pin(async move {
+        if model_manager.get_current_model().is_none() {
+            return Ok(None);
+        }
+        model_manager.generate_structured::<ModelAnalysis>(None, "analysis", &messages, schema).await.map(Some)
+    })).await
+        .unwrap_or_else(|e| {
+            warn!("⚠️ Model analysis of {} failed, using heuristics: {}", file_path, e);
+            None
+        });
+
+    let assistant = state.assistant.read().await;
+    let analysis = match model_analysis {
+        Some(model_analysis) => assistant.analyze_code_with_model(&path, &content, model_analysis).await,
+        None => assistant.analyze_code(&path, &content).await,
+    }.map_err(|e| format!("Failed to analyze code: {}", e))?;
     
     let issues: Vec<CodeIssue> = analysis.issues.into_iter().map(|issue| CodeIssue {
         id: issue.id,
@@ -342,6 +550,9 @@ pub async fn discover_models(
         size_mb: model.size_mb,
         loaded: model.loaded,
         capabilities: model.capabilities.clone(),
+        metadata: model.metadata.clone(),
+        integrity: model_manager.integrity_status(&model.id),
+        adapters: model.adapters.clone(),
     }).collect();
     
     Ok(model_infos)
@@ -366,6 +577,9 @@ pub async fn discover_embedding_models(
         size_mb: model.size_mb,
         loaded: model.loaded,
         capabilities: model.capabilities.clone(),
+        metadata: model.metadata.clone(),
+        integrity: IntegrityStatus::default(),
+        adapters: model.adapters.clone(),
     }).collect();
     
     Ok(model_infos)
@@ -374,9 +588,17 @@ pub async fn discover_embedding_models(
 #[tauri::command]
 pub async fn load_best_model(
     state: State<'_, AppState>,
-) -> Result<bool, String> {
+    task: Option<String>,
+) -> Result<ModelSelection, String> {
+    let task = task.unwrap_or_else(|| "chat".to_string());
+    let free_memory = state.performance.read().await.available_memory()
+        .unwrap_or_else(|e| {
+            warn!("⚠️ Could not read free memory, ranking without it: {}", e);
+            u64::MAX
+        });
+
     let mut model_manager = state.ai_models.write().await;
-    model_manager.load_best_model().await
+    model_manager.load_best_model(&task, free_memory).await
         .map_err(|e| format!("Failed to load best model: {}", e))
 }
 
@@ -387,17 +609,288 @@ pub async fn load_model_by_name(
 ) -> Result<bool, String> {
     let mut model_manager = state.ai_models.write().await;
     model_manager.load_model_by_name(&model_name).await
-        .map_err(|e| format!("Failed to load model by name: {}", e))
+        .map_err(|e| load_error("Failed to load model by name", e))
+}
+
+#[tauri::command]
+pub async fn set_chat_template(
+    state: State<'_, AppState>,
+    model_id: String,
+    template: Option<String>,
+) -> Result<(), String> {
+    let template = template.filter(|t| !t.trim().is_empty());
+
+    state.ai_models.write().await
+        .set_chat_template_override(&model_id, template.clone())
+        .map_err(|e| format!("Failed to set chat template: {}", e))?;
+
+    let mut config = state.config.write().await;
+    match template {
+        Some(template) => config.ai.chat_templates.insert(model_id.clone(), template),
+        None => config.ai.chat_templates.remove(&model_id),
+    };
+    config.save().await
+        .map_err(|e| format!("Failed to save settings: {}", e))?;
+
+    info!("🔧 Chat template override for {} updated", model_id);
+    Ok(())
+}
+
+/// Apply a LoRA adapter from the model's `adapters` directory, or change its scale
+///
+/// Returns the adapters now applied to the model.
+#[tauri::command]
+pub async fn apply_lora_adapter(
+    state: State<'_, AppState>,
+    adapter: String,
+    scale: Option<f32>,
+    model_id: Option<String>,
+) -> Result<Vec<ActiveAdapter>, String> {
+    state.ai_models.write().await
+        .apply_adapter(model_id.as_deref(), &adapter, scale.unwrap_or(1.0)).await
+        .map_err(|e| format!("Failed to apply adapter: {}", e))
+}
+
+#[tauri::command]
+pub async fn remove_lora_adapter(
+    state: State<'_, AppState>,
+    adapter: String,
+    model_id: Option<String>,
+) -> Result<Vec<ActiveAdapter>, String> {
+    state.ai_models.write().await
+        .remove_adapter(model_id.as_deref(), &adapter).await
+        .map_err(|e| format!("Failed to remove adapter: {}", e))
 }
 
 #[tauri::command]
 pub async fn generate_response(
+    app: AppHandle,
     state: State<'_, AppState>,
     message: String,
+    request_id: Option<String>,
+    model_id: Option<String>,
+    images: Option<Vec<ImageInput>>,
 ) -> Result<String, String> {
-    let mut model_manager = state.ai_models.write().await;
-    model_manager.generate_response(&message).await
-        .map_err(|e| format!("Failed to generate response: {}", e))
+    let request_id = request_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+    let turn = ChatTurn { message, images: images.unwrap_or_default(), context: None };
+    let (output, _) = stream_response(&app, &state, &request_id, model_id.as_deref(), turn).await?;
+    Ok(output.text)
+}
+
+#[tauri::command]
+pub async fn cancel_generation(
+    state: State<'_, AppState>,
+    request_id: String,
+) -> Result<bool, String> {
+    // Only touches the registry, so it never waits on the model lock held by the generation
+    let cancelled = state.generations.read().await.cancel(&request_id);
+    if cancelled {
+        info!("🔄 Cancelling generation {}", request_id);
+    }
+    Ok(cancelled)
+}
+
+/// Text before the cursor to continue inline
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InlineCompletionRequest {
+    pub file_path: String,
+    pub prefix: String,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InlineCompletion {
+    pub text: String,
+    pub model_id: String,
+}
+
+/// Characters of text before the cursor given to the model
+const INLINE_COMPLETION_CONTEXT_CHARS: usize = 4096;
+/// Default length of an inline completion
+const INLINE_COMPLETION_MAX_TOKENS: u32 = 64;
+
+/// Continue the code before the cursor with the default model, ahead of queued chat and analysis
+///
+/// Returns None when completions are disabled, no model is loaded or the request went
+/// stale in the queue (the editor will have asked again by then).
+#[tauri::command]
+pub async fn get_inline_completion(
+    state: State<'_, AppState>,
+    request: InlineCompletionRequest,
+) -> Result<Option<InlineCompletion>, String> {
+    let (enabled, deadline) = {
+        let config = state.config.read().await;
+        (config.ai.code_completion_enabled, Duration::from_millis(config.ai.completion_deadline_ms))
+    };
+    if !enabled || request.prefix.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let start = request.prefix.char_indices()
+        .rev()
+        .nth(INLINE_COMPLETION_CONTEXT_CHARS - 1)
+        .map_or(0, |(index, _)| index);
+    let prompt = request.prefix[start..].to_string();
+    let max_tokens = request.max_tokens.unwrap_or(INLINE_COMPLETION_MAX_TOKENS);
+
+    let result = state.scheduler.submit(Priority::Completion, Some(deadline), move |model_manager| Box::pin(async move {
+        if model_manager.get_current_model().is_none() {
+            return Ok(None);
+        }
+        let params = crate::ai::GenerationParams {
+            max_tokens,
+            // A blank line ends the statement or block being completed
+            stop_sequences: vec!["\n\n".to_string()],
+            constraint: None,
+            ..model_manager.params_for_task("completion")
+        };
+        let (model_id, output) = model_manager.complete_text(None, &prompt, &params).await?;
+        Ok(Some(InlineCompletion { text: output.text, model_id }))
+    })).await;
+
+    match result {
+        Ok(completion) => Ok(completion),
+        Err(e) if e.downcast_ref::<SchedulerError>().is_some_and(|e| matches!(e, SchedulerError::Expired { .. })) => Ok(None),
+        Err(e) => Err(load_error(&format!("Failed to complete {}", request.file_path), e)),
+    }
+}
+
+/// Benchmark runs returned when the caller doesn't ask for a number
+const BENCHMARK_HISTORY_LIMIT: usize = 50;
+
+/// Benchmark a model on this machine and store the result
+///
+/// Runs in the background class, so chat and completions go first.
+#[tauri::command]
+pub async fn benchmark_model(
+    state: State<'_, AppState>,
+    model_id: String,
+) -> Result<BenchmarkResult, String> {
+    state.scheduler.submit(Priority::Background, None, move |model_manager| Box::pin(async move {
+        model_manager.benchmark_model(&model_id).await
+    })).await
+        .map_err(|e| format!("Failed to benchmark model: {}", e))
+}
+
+/// Earlier benchmark runs of a model, newest first
+#[tauri::command]
+pub async fn get_benchmark_history(
+    state: State<'_, AppState>,
+    model_id: String,
+    limit: Option<usize>,
+) -> Result<Vec<BenchmarkResult>, String> {
+    state.ai_models.read().await
+        .benchmark_history(&model_id, limit.unwrap_or(BENCHMARK_HISTORY_LIMIT)).await
+        .map_err(|e| format!("Failed to load benchmark history: {}", e))
+}
+
+/// Queue depth, wait times and outcomes per request class
+#[tauri::command]
+pub async fn get_scheduler_metrics(
+    state: State<'_, AppState>,
+) -> Result<SchedulerMetrics, String> {
+    Ok(state.scheduler.metrics())
+}
+
+/// One user message for the chat session
+struct ChatTurn {
+    message: String,
+    images: Vec<ImageInput>,
+    /// Retrieved project context, sent to the model as a system message
+    context: Option<String>,
+}
+
+/// Run `model_id` (or the default model) on `turn`, emitting token deltas and a final summary for `request_id`
+async fn stream_response(
+    app: &AppHandle,
+    state: &State<'_, AppState>,
+    request_id: &str,
+    model_id: Option<&str>,
+    turn: ChatTurn,
+) -> Result<(GenerationOutput, GenerationSummary), String> {
+    // Register before waiting for the model so queued requests can be cancelled too
+    let cancel_token = state.generations.write().await.register(request_id);
+    let result = run_generation(app, state, request_id, model_id, turn, &cancel_token).await;
+    state.generations.write().await.finish(request_id);
+    result
+}
+
+async fn run_generation(
+    app: &AppHandle,
+    state: &State<'_, AppState>,
+    request_id: &str,
+    requested_model: Option<&str>,
+    turn: ChatTurn,
+    cancel_token: &CancelToken,
+) -> Result<(GenerationOutput, GenerationSummary), String> {
+    let streaming_enabled = state.config.read().await.ai.streaming_enabled;
+    let started = Instant::now();
+    let token_app = app.clone();
+    let job_request_id = request_id.to_string();
+    let requested_model = requested_model.map(str::to_string);
+    let cancel_token = cancel_token.clone();
+
+    let (model_id, output, first_token) = state.scheduler.submit(Priority::Chat, None, move |model_manager| Box::pin(async move {
+        let model_id = requested_model.clone()
+            .or_else(|| model_manager.get_current_model().map(|model| model.id.clone()));
+        if cancel_token.is_cancelled() {
+            info!("🔄 Generation {} cancelled before it started", job_request_id);
+            return Ok((model_id, model_manager.record_cancelled_turn(&message, images), None));
+        }
+
+        let mut first_token: Option<u64> = None;
+        let mut index = 0;
+        let mut on_token = |delta: &str| {
+            if delta.is_empty() {
+                return !cancel_token.is_cancelled();
+            }
+            first_token.get_or_insert_with(|| started.elapsed().as_millis() as u64);
+            if streaming_enabled {
+                let token = GenerationToken {
+                    request_id: job_request_id.clone(),
+                    index,
+                    delta: delta.to_string(),
+                };
+                if let Err(e) = token_app.emit(GENERATION_TOKEN_EVENT, token) {
+                    warn!("Failed to emit generation token: {}", e);
+                }
+            }
+            index += 1;
+            !cancel_token.is_cancelled()
+        };
+
+        let output = model_manager.generate_response_stream(requested_model.as_deref(), &turn.message, turn.images, turn.context.as_deref(), &mut on_token).await?;
+        Ok((model_id, output, first_token))
+    })).await
+        .map_err(|e| load_error("Failed to generate response", e))?;
+
+    let total_time_ms = started.elapsed().as_millis() as u64;
+    let summary = GenerationSummary {
+        request_id: request_id.to_string(),
+        model_id,
+        prompt_tokens: output.prompt_tokens,
+        cached_prompt_tokens: output.cached_prompt_tokens,
+        completion_tokens: output.completion_tokens,
+        finish_reason: output.finish_reason,
+        time_to_first_token_ms: first_token,
+        total_time_ms,
+        tokens_per_second: if total_time_ms > 0 {
+            output.completion_tokens as f32 * 1000.0 / total_time_ms as f32
+        } else {
+            0.0
+        },
+    };
+
+    info!("✅ Generation {} finished: {} prompt ({} cached) + {} completion tokens in {} ms, first token after {:?} ms ({:?})",
+          request_id, summary.prompt_tokens, summary.cached_prompt_tokens, summary.completion_tokens, total_time_ms,
+          summary.time_to_first_token_ms, summary.finish_reason);
+
+    if let Err(e) = app.emit(GENERATION_COMPLETE_EVENT, summary.clone()) {
+        warn!("Failed to emit generation summary: {}", e);
+    }
+
+    Ok((output, summary))
 }
 
 #[tauri::command]
@@ -433,6 +926,32 @@ pub struct GenerationSettings {
     pub top_k: u32,
     pub max_tokens: u32,
     pub stop_sequences: Vec<String>,
+    /// Keep the KV cache between turns; defaults to on
+    #[serde(default)]
+    pub reuse_prompt_cache: Option<bool>,
+    /// Extended sampling controls; absent ones take their defaults
+    #[serde(default)]
+    pub repeat_penalty: Option<f32>,
+    #[serde(default)]
+    pub repeat_last_n: Option<usize>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub min_p: Option<f32>,
+    #[serde(default)]
+    pub typical_p: Option<f32>,
+    #[serde(default)]
+    pub mirostat: Option<u8>,
+    #[serde(default)]
+    pub mirostat_tau: Option<f32>,
+    #[serde(default)]
+    pub mirostat_eta: Option<f32>,
+    #[serde(default)]
+    pub logit_bias: Option<BTreeMap<u32, f32>>,
+    #[serde(default)]
+    pub seed: Option<u64>,
 }
 
 #[tauri::command]
@@ -447,7 +966,23 @@ pub async fn update_generation_settings(
         top_k: settings.top_k,
         max_tokens: settings.max_tokens,
         stop_sequences: settings.stop_sequences,
-    };
+        reuse_prompt_cache: settings.reuse_prompt_cache.unwrap_or(true),
+        constraint: None,
+        ..Default::default()
+    }.with_preset(&SamplingPreset {
+        repeat_penalty: settings.repeat_penalty,
+        repeat_last_n: settings.repeat_last_n,
+        frequency_penalty: settings.frequency_penalty,
+        presence_penalty: settings.presence_penalty,
+        min_p: settings.min_p,
+        typical_p: settings.typical_p,
+        mirostat: settings.mirostat,
+        mirostat_tau: settings.mirostat_tau,
+        mirostat_eta: settings.mirostat_eta,
+        logit_bias: settings.logit_bias,
+        seed: settings.seed,
+        ..SamplingPreset::default()
+    });
     model_manager.update_generation_settings(params);
     Ok(())
 }
@@ -457,6 +992,9 @@ pub async fn update_generation_settings(
 pub struct EmbeddingRequest {
     pub text: String,
     pub task_type: String,
+    /// Matryoshka truncation to 512, 256 or 128; the full dimension when absent
+    #[serde(default)]
+    pub dimension: Option<usize>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -470,6 +1008,8 @@ pub struct EmbeddingResponse {
 pub struct SimilarityRequest {
     pub query: String,
     pub documents: Vec<String>,
+    #[serde(default)]
+    pub dimension: Option<usize>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -483,9 +1023,8 @@ pub async fn load_embedding_model(
     state: State<'_, AppState>,
     model_name: String,
 ) -> Result<(), String> {
-    // TODO: Implement actual embedding model loading
-    println!("📥 Loading embedding model: {}", model_name);
-    info!("📥 Loading embedding model: {}", model_name);
+    embeddings::resident_model(&state.ai_models, Some(&model_name)).await
+        .map_err(|e| format!("Failed to load embedding model: {}", e))?;
     Ok(())
 }
 
@@ -494,17 +1033,21 @@ pub async fn encode_text(
     state: State<'_, AppState>,
     request: EmbeddingRequest,
 ) -> Result<EmbeddingResponse, String> {
-    // TODO: Implement actual embedding generation
-    // For now, return a mock embedding based on the text
-    let mock_embeddings = generate_mock_embedding(&request.text, &request.task_type);
-    
-    info!("🔤 Encoded text with task type '{}': {} chars", 
-          request.task_type, request.text.len());
-    
+    let model = embeddings::resident_model(&state.ai_models, None).await
+        .map_err(|e| format!("Failed to load embedding model: {}", e))?;
+    let started = Instant::now();
+    let embedding = embeddings::encode(model.clone(), vec![request.text.clone()], request.task_type.clone(), request.dimension).await
+        .map_err(|e| format!("Failed to encode text: {}", e))?
+        .pop()
+        .unwrap_or_default();
+
+    info!("🔤 Encoded text with task type '{}': {} chars in {} ms",
+          request.task_type, request.text.len(), started.elapsed().as_millis());
+
     Ok(EmbeddingResponse {
-        embeddings: mock_embeddings,
-        dimension: 768,
-        model_name: "google/embeddinggemma-300m".to_string(),
+        dimension: embedding.len(),
+        embeddings: embedding,
+        model_name: model.model_id().to_string(),
     })
 }
 
@@ -513,13 +1056,21 @@ pub async fn compute_similarity(
     state: State<'_, AppState>,
     request: SimilarityRequest,
 ) -> Result<SimilarityResponse, String> {
-    // TODO: Implement actual similarity computation
-    // For now, generate mock similarities
-    let similarities = generate_mock_similarities(&request.query, &request.documents);
-    
+    let model = embeddings::resident_model(&state.ai_models, None).await
+        .map_err(|e| format!("Failed to load embedding model: {}", e))?;
+    let query = embeddings::encode(model.clone(), vec![request.query], "query".to_string(), request.dimension).await
+        .map_err(|e| format!("Failed to encode query: {}", e))?
+        .pop()
+        .unwrap_or_default();
+    let documents = embeddings::encode(model, request.documents.clone(), "document".to_string(), request.dimension).await
+        .map_err(|e| format!("Failed to encode documents: {}", e))?;
+    let similarities: Vec<f32> = documents.iter()
+        .map(|document| embeddings::cosine_similarity(&query, document))
+        .collect();
+
     // Rank documents by similarity
     let mut ranked: Vec<(usize, f32)> = similarities.iter().enumerate().map(|(i, &sim)| (i, sim)).collect();
-    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
     
     info!("🔍 Computed similarities for {} documents", request.documents.len());
     
@@ -529,42 +1080,119 @@ pub async fn compute_similarity(
     })
 }
 
-// Helper function to generate mock embeddings
-fn generate_mock_embedding(text: &str, task_type: &str) -> Vec<f32> {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-    
-    // Create a deterministic "embedding" based on text content
-    let mut hasher = DefaultHasher::new();
-    text.hash(&mut hasher);
-    task_type.hash(&mut hasher);
-    let hash = hasher.finish();
-    
-    // Generate 768-dimensional vector based on hash
-    let mut embedding = Vec::with_capacity(768);
-    for i in 0..768 {
-        let seed = hash.wrapping_add(i as u64);
-        let value = ((seed % 10000) as f32 / 10000.0 - 0.5) * 2.0; // Normalize to [-1, 1]
-        embedding.push(value);
-    }
-    
-    // Normalize the vector
-    let norm: f32 = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
-    if norm > 0.0 {
-        for val in &mut embedding {
-            *val /= norm;
+/// Build or update the semantic code index of a project in the background
+///
+/// Progress arrives as `ai-code-index-progress` events; only files that changed
+/// since the last run are chunked and embedded again.
+#[tauri::command]
+pub async fn index_project(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    project_path: String,
+) -> Result<(), String> {
+    state.code_index.start(PathBuf::from(project_path), move |progress| {
+        if let Err(e) = app.emit(CODE_INDEX_PROGRESS_EVENT, progress) {
+            warn!("⚠️ Failed to emit index progress: {}", e);
         }
+    }).map_err(|e| format!("Failed to index project: {}", e))
+}
+
+#[tauri::command]
+pub async fn cancel_project_index(
+    state: State<'_, AppState>,
+    project_path: String,
+) -> Result<bool, String> {
+    let cancelled = state.code_index.cancel(&PathBuf::from(&project_path));
+    if cancelled {
+        info!("🔄 Cancelling indexing of {}", project_path);
     }
-    
-    embedding
-}
-
-// Helper function to generate mock similarities
-fn generate_mock_similarities(query: &str, documents: &[String]) -> Vec<f32> {
-    documents.iter().enumerate().map(|(i, doc)| {
-        // Simple similarity based on text length and position
-        let length_sim = 1.0 - (query.len() as f32 - doc.len() as f32).abs() / (query.len() as f32 + doc.len() as f32);
-        let position_sim = 1.0 - (i as f32 * 0.1);
-        (length_sim + position_sim) / 2.0
-    }).collect()
-}
\ No newline at end of file
+    Ok(cancelled)
+}
+
+/// Progress of the running or last indexing job of a project
+#[tauri::command]
+pub async fn get_project_index_status(
+    state: State<'_, AppState>,
+    project_path: String,
+) -> Result<Option<IndexProgress>, String> {
+    Ok(state.code_index.progress(&PathBuf::from(project_path)))
+}
+
+/// The indexed chunks closest to `query`, with their paths and line ranges
+#[tauri::command]
+pub async fn search_project_index(
+    state: State<'_, AppState>,
+    project_path: String,
+    query: String,
+    top_k: Option<usize>,
+) -> Result<Vec<CodeSearchResult>, String> {
+    let started = Instant::now();
+    let results = state.code_index.search(&PathBuf::from(&project_path), &query, top_k.unwrap_or(code_index::DEFAULT_TOP_K)).await
+        .map_err(|e| format!("Failed to search project: {}", e))?;
+    info!("🔍 Searched {} in {} ms: {} results", project_path, started.elapsed().as_millis(), results.len());
+    Ok(results)
+}
+
+/// Start the OpenAI-compatible API server, restarting it if it is already running
+///
+/// `bind_address` and `api_key` override the saved settings and are persisted,
+/// along with `enabled`, so the server comes back on the next launch.
+#[tauri::command]
+pub async fn start_api_server(
+    state: State<'_, AppState>,
+    bind_address: Option<String>,
+    api_key: Option<String>,
+) -> Result<ApiServerStatus, String> {
+    let mut config = state.config.write().await;
+    if let Some(bind_address) = bind_address {
+        config.ai.api_server.bind_address = bind_address;
+    }
+    if let Some(api_key) = api_key {
+        config.ai.api_server.api_key = Some(api_key).filter(|key| !key.is_empty());
+    }
+
+    let mut api_server = state.api_server.write().await;
+    if let Some(running) = api_server.take() {
+        running.stop();
+    }
+    let server = ApiServer::start(state.ai_models.clone(), state.scheduler.clone(), &config.ai.api_server)
+        .map_err(|e| format!("Failed to start API server: {}", e))?;
+    *api_server = Some(server);
+
+    config.ai.api_server.enabled = true;
+    config.save().await
+        .map_err(|e| format!("Failed to save settings: {}", e))?;
+
+    Ok(api_server_status(api_server.as_ref(), &config.ai.api_server))
+}
+
+#[tauri::command]
+pub async fn stop_api_server(
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    if let Some(server) = state.api_server.write().await.take() {
+        server.stop();
+    }
+    let mut config = state.config.write().await;
+    config.ai.api_server.enabled = false;
+    config.save().await
+        .map_err(|e| format!("Failed to save settings: {}", e))?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_api_server_status(
+    state: State<'_, AppState>,
+) -> Result<ApiServerStatus, String> {
+    let config = state.config.read().await;
+    let api_server = state.api_server.read().await;
+    Ok(api_server_status(api_server.as_ref(), &config.ai.api_server))
+}
+
+fn api_server_status(server: Option<&ApiServer>, config: &ApiServerConfig) -> ApiServerStatus {
+    ApiServerStatus {
+        running: server.is_some(),
+        address: server.map(|server| format!("http://{}/v1", server.address())),
+        requires_api_key: config.api_key.is_some(),
+    }
+}
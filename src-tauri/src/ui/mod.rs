@@ -23,9 +23,10 @@ pub use editor::{
 pub use ai::{
     load_model, unload_model, get_available_models, chat_with_ai, get_code_suggestions,
     analyze_code, discover_models, discover_embedding_models, load_best_model,
-    load_model_by_name, generate_response, get_model_info, clear_conversation,
+    load_model_by_name, generate_response, cancel_generation, set_chat_template, verify_model, import_model, download_model, get_model_info, clear_conversation,
     reset_context, update_generation_settings, load_embedding_model, encode_text,
-    compute_similarity
+    compute_similarity, start_api_server, stop_api_server, get_api_server_status,
+    get_inline_completion, get_scheduler_metrics
 };
 pub use terminal::{
     create_terminal, execute_command, get_terminal_output
@@ -7,8 +7,10 @@
 use tauri::State;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 // use crate::config::AppConfig; // Unused for now
+use crate::config::{RemoteModelConfig, SamplingPreset};
 use crate::AppState;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -51,6 +53,17 @@ pub struct EditorSettingsInfo {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AISettingsInfo {
     pub models_directory: String,
+    /// Optional so older settings payloads leave the stored values alone
+    #[serde(default)]
+    pub model_search_paths: Option<Vec<String>>,
+    #[serde(default)]
+    pub scan_hf_cache: Option<bool>,
+    #[serde(default)]
+    pub remote_models: Option<Vec<RemoteModelConfig>>,
+    #[serde(default)]
+    pub sampling_presets: Option<HashMap<String, SamplingPreset>>,
+    #[serde(default)]
+    pub task_presets: Option<HashMap<String, String>>,
     pub preferred_models: Vec<String>,
     pub auto_load_model: bool,
     pub context_strategy: String,
@@ -137,6 +150,11 @@ pub async fn get_settings(
         },
         ai: AISettingsInfo {
             models_directory: config.ai.models_directory.to_string_lossy().to_string(),
+            model_search_paths: Some(config.ai.model_search_paths.iter().map(|p| p.to_string_lossy().to_string()).collect()),
+            scan_hf_cache: Some(config.ai.scan_hf_cache),
+            remote_models: Some(config.ai.remote_models.clone()),
+            sampling_presets: Some(config.ai.sampling_presets.clone()),
+            task_presets: Some(config.ai.task_presets.clone()),
             preferred_models: config.ai.preferred_models.clone(),
             auto_load_model: config.ai.auto_load_model,
             context_strategy: format!("{:?}", config.ai.context_strategy),
@@ -217,7 +235,28 @@ pub async fn update_settings(
     
     // Update AI settings
     config.ai.models_directory = std::path::PathBuf::from(settings.ai.models_directory);
+    if let Some(search_paths) = settings.ai.model_search_paths {
+        config.ai.model_search_paths = search_paths.into_iter().map(std::path::PathBuf::from).collect();
+    }
+    if let Some(scan_hf_cache) = settings.ai.scan_hf_cache {
+        config.ai.scan_hf_cache = scan_hf_cache;
+    }
+    state.ai_models.write().await
+        .configure_directories(&config.ai.models_directory, config.ai.model_search_paths.clone(), config.ai.scan_hf_cache);
+    if let Some(remote_models) = settings.ai.remote_models {
+        config.ai.remote_models = remote_models;
+        state.ai_models.write().await.set_remote_models(config.ai.remote_models.clone());
+    }
+    if let Some(sampling_presets) = settings.ai.sampling_presets {
+        config.ai.sampling_presets = sampling_presets;
+    }
+    if let Some(task_presets) = settings.ai.task_presets {
+        config.ai.task_presets = task_presets;
+    }
+    state.ai_models.write().await
+        .set_sampling_presets(config.ai.sampling_presets.clone(), config.ai.task_presets.clone());
     config.ai.preferred_models = settings.ai.preferred_models;
+    state.ai_models.write().await.set_preferred_models(config.ai.preferred_models.clone());
     config.ai.auto_load_model = settings.ai.auto_load_model;
     config.ai.max_context_length = settings.ai.max_context_length;
     config.ai.temperature = settings.ai.temperature;
@@ -13,6 +13,7 @@ import {
   SmartToy,
   Clear,
   Settings,
+  Stop,
 } from '@mui/icons-material';
 import { useAIStore } from '../stores/aiStore';
 
@@ -31,6 +32,7 @@ const AIChatPanel: React.FC<AIChatPanelProps> = ({ onOpenModelPicker }) => {
   const {
     currentModel,
     generateResponse,
+    cancelGeneration,
     clearConversation,
   } = useAIStore();
 
@@ -61,17 +63,23 @@ const AIChatPanel: React.FC<AIChatPanelProps> = ({ onOpenModelPicker }) => {
     setInputText('');
     setIsGenerating(true);
 
-    try {
-      const response = await generateResponse(userMessage.content);
-      
-      const assistantMessage: Message = {
-        id: (Date.now() + 1).toString(),
-        role: 'assistant',
-        content: response,
-        timestamp: new Date(),
-      };
+    const assistantId = (Date.now() + 1).toString();
+    const updateAssistant = (update: (content: string) => string) => {
+      setMessages(prev => {
+        const existing = prev.find(m => m.id === assistantId);
+        if (!existing) {
+          return [...prev, { id: assistantId, role: 'assistant', content: update(''), timestamp: new Date() }];
+        }
+        return prev.map(m => (m.id === assistantId ? { ...m, content: update(m.content) } : m));
+      });
+    };
 
-      setMessages(prev => [...prev, assistantMessage]);
+    try {
+      // Show tokens as they arrive, then settle on the final text
+      const response = await generateResponse(userMessage.content, (delta) => {
+        updateAssistant(content => content + delta);
+      });
+      updateAssistant(() => response);
     } catch (error) {
       let errorContent = 'Failed to generate response';
       
@@ -87,13 +95,7 @@ const AIChatPanel: React.FC<AIChatPanelProps> = ({ onOpenModelPicker }) => {
         }
       }
       
-      const errorMessage: Message = {
-        id: (Date.now() + 1).toString(),
-        role: 'assistant',
-        content: errorContent,
-        timestamp: new Date(),
-      };
-      setMessages(prev => [...prev, errorMessage]);
+      updateAssistant(() => errorContent);
     } finally {
       setIsGenerating(false);
     }
@@ -385,8 +387,9 @@ const AIChatPanel: React.FC<AIChatPanelProps> = ({ onOpenModelPicker }) => {
           }}
         />
         <IconButton
-          onClick={handleSendMessage}
-          disabled={!inputText.trim() || isGenerating}
+          onClick={isGenerating ? cancelGeneration : handleSendMessage}
+          disabled={!inputText.trim() && !isGenerating}
+          title={isGenerating ? 'Stop generating' : 'Send'}
           sx={{
             bgcolor: '#3b82f6',
             color: 'white',
@@ -399,7 +402,7 @@ const AIChatPanel: React.FC<AIChatPanelProps> = ({ onOpenModelPicker }) => {
             },
           }}
         >
-          <Send fontSize="small" />
+          {isGenerating ? <Stop fontSize="small" /> : <Send fontSize="small" />}
         </IconButton>
       </Box>
     </Box>
@@ -19,7 +19,7 @@ import {
   Refresh,
   PlayArrow,
 } from '@mui/icons-material';
-import { useAIStore } from '../stores/aiStore';
+import { useAIStore, ModelMetadata, ModelSelection } from '../stores/aiStore';
 
 const AIModelPicker: React.FC = () => {
   const {
@@ -32,6 +32,7 @@ const AIModelPicker: React.FC = () => {
   } = useAIStore();
 
   const [selectedModel, setSelectedModel] = useState<string>('');
+  const [failedSelection, setFailedSelection] = useState<ModelSelection | null>(null);
 
   useEffect(() => {
     // Auto-discover models on component mount
@@ -62,7 +63,8 @@ const AIModelPicker: React.FC = () => {
   };
 
   const handleLoadBest = async () => {
-    await loadBestModel();
+    const selection = await loadBestModel();
+    setFailedSelection(selection && !selection.selected ? selection : null);
   };
 
   const handleRefresh = async () => {
@@ -76,6 +78,23 @@ const AIModelPicker: React.FC = () => {
     return `${(sizeMb / 1024).toFixed(1)} GB`;
   };
 
+  const formatParameters = (count: number) => {
+    if (count >= 1e9) {
+      return `${(count / 1e9).toFixed(1)}B`;
+    }
+    return `${Math.round(count / 1e6)}M`;
+  };
+
+  const describeMetadata = (metadata: ModelMetadata | null) => {
+    if (!metadata) return [];
+    return [
+      metadata.architecture,
+      metadata.parameter_count ? `${formatParameters(metadata.parameter_count)} params` : null,
+      metadata.quantization,
+      metadata.context_length ? `${metadata.context_length.toLocaleString()} ctx` : null,
+    ].filter((part): part is string => Boolean(part));
+  };
+
   const getFormatIcon = (format: string) => {
     switch (format.toLowerCase()) {
       case 'gguf':
@@ -84,6 +103,8 @@ const AIModelPicker: React.FC = () => {
         return '⚡'; // Lightning for ONNX
       case 'huggingface':
         return '🤗'; // Hugging Face emoji
+      case 'remote':
+        return '🌐'; // Globe for models on another machine
       default:
         return '🤖'; // Robot for others
     }
@@ -97,6 +118,8 @@ const AIModelPicker: React.FC = () => {
         return '#FF9800'; // Orange
       case 'huggingface':
         return '#2196F3'; // Blue
+      case 'remote':
+        return '#9C27B0'; // Purple
       default:
         return '#9E9E9E'; // Gray
     }
@@ -167,9 +190,23 @@ const AIModelPicker: React.FC = () => {
                       {model.name}
                     </Typography>
                     <Typography variant="caption" sx={{ color: 'text.secondary' }}>
-                      {model.format.toUpperCase()} • {formatSize(model.size_mb)}
+                      {(model.format === 'Remote'
+                        ? [model.format.toUpperCase(), model.description]
+                        : [model.format.toUpperCase(), formatSize(model.size_mb), ...describeMetadata(model.metadata)]
+                      ).join(' • ')}
                     </Typography>
                   </Box>
+                  {(model.integrity.status === 'partial' || model.integrity.status === 'corrupt') && (
+                    <Tooltip title={`${model.integrity.file} ${model.integrity.reason}`}>
+                      <Chip
+                        label={model.integrity.status === 'partial' ? 'Incomplete' : 'Corrupted'}
+                        size="small"
+                        color="warning"
+                        variant="outlined"
+                        sx={{ ml: 1 }}
+                      />
+                    </Tooltip>
+                  )}
                   {model.loaded && (
                     <Chip
                       label="Loaded"
@@ -252,6 +289,15 @@ const AIModelPicker: React.FC = () => {
                 fontWeight: 500,
               }}
             />
+            {describeMetadata(currentModel.metadata).map((detail) => (
+              <Chip
+                key={detail}
+                label={detail}
+                size="small"
+                variant="outlined"
+                sx={{ borderColor: 'rgba(255, 255, 255, 0.3)' }}
+              />
+            ))}
             {currentModel.capabilities.map((capability) => (
               <Chip
                 key={capability}
@@ -274,6 +320,18 @@ const AIModelPicker: React.FC = () => {
         </Box>
       )}
 
+      {/* Why Load Best found nothing it could load */}
+      {failedSelection && failedSelection.candidates.length > 0 && (
+        <Alert severity="warning" onClose={() => setFailedSelection(null)} sx={{ mb: 2 }}>
+          No model could be loaded ({failedSelection.free_memory_mb.toLocaleString()} MB free):
+          {failedSelection.candidates.map((candidate) => (
+            <Typography key={candidate.model_id} variant="caption" sx={{ display: 'block' }}>
+              {candidate.name}: {candidate.skipped ?? candidate.reasons[candidate.reasons.length - 1]}
+            </Typography>
+          ))}
+        </Alert>
+      )}
+
       {/* No Models Alert */}
       {availableModels.length === 0 && !isModelLoading && (
         <Alert 
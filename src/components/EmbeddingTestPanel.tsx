@@ -88,8 +88,8 @@ const EmbeddingTestPanel: React.FC = () => {
 
       {/* Model Status */}
       <Alert severity="info" sx={{ mb: 2 }}>
-        This is a test panel for EmbeddingGemma-300m integration. 
-        The actual model download and loading will be implemented in the next phase.
+        Test panel for EmbeddingGemma-300m. Place the model files in models/embedding/embeddinggemma-300m;
+        the model is loaded on first use if it isn't loaded here.
       </Alert>
 
       {/* Load Model Button */}
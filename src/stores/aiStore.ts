@@ -2,6 +2,23 @@ import { create } from 'zustand';
 import { devtools } from 'zustand/middleware';
 import { immer } from 'zustand/middleware/immer';
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+
+export interface ModelMetadata {
+  architecture: string | null;
+  context_length: number | null;
+  embedding_length: number | null;
+  layer_count: number | null;
+  quantization: string | null;
+  parameter_count: number | null;
+  tokenizer: string | null;
+  vocab_size: number | null;
+  has_chat_template: boolean;
+}
+
+export type IntegrityStatus =
+  | { status: 'unverified' | 'verifying' | 'verified' }
+  | { status: 'partial' | 'corrupt'; file: string; reason: string };
 
 interface AIModel {
   id: string;
@@ -12,6 +29,8 @@ interface AIModel {
   size_mb: number;
   loaded: boolean;
   capabilities: string[];
+  metadata: ModelMetadata | null;
+  integrity: IntegrityStatus;
 }
 
 interface ChatMessage {
@@ -21,6 +40,62 @@ interface ChatMessage {
   timestamp: Date;
 }
 
+interface GenerationToken {
+  request_id: string;
+  index: number;
+  delta: string;
+}
+
+export type ImportMode = 'symlink' | 'hardlink' | 'copy';
+
+export interface ImportProgress {
+  name: string;
+  file: string;
+  files_done: number;
+  files_total: number;
+  bytes_done: number;
+  bytes_total: number;
+}
+
+export interface DownloadProgress {
+  repo_id: string;
+  file: string;
+  files_done: number;
+  files_total: number;
+  bytes_done: number;
+  bytes_total: number | null;
+  resumed_from: number;
+}
+
+export interface DownloadOptions {
+  revision?: string;
+  files?: string[];
+  name?: string;
+  embedding?: boolean;
+}
+
+export interface CandidateRanking {
+  model_id: string;
+  name: string;
+  rank: number | null;
+  estimated_ram_mb: number;
+  skipped: string | null;
+  reasons: string[];
+}
+
+export interface ModelSelection {
+  task: string;
+  free_memory_mb: number;
+  selected: string | null;
+  candidates: CandidateRanking[];
+}
+
+export interface ApiServerStatus {
+  running: boolean;
+  address: string | null;
+  requires_api_key: boolean;
+}
+
 interface ChatSession {
   id: string;
   name: string;
@@ -34,6 +109,7 @@ interface AIState {
   currentModel: AIModel | null;
   isModelLoading: boolean;
   isDiscoveringModels: boolean;
+  activeRequestId: string | null;
   
   // Chat
   chatSessions: ChatSession[];
@@ -49,20 +125,37 @@ interface AIState {
   addMessageToSession: (sessionId: string, message: ChatMessage) => void;
   discoverModels: () => Promise<void>;
   loadModel: (modelName: string) => Promise<boolean>;
-  loadBestModel: () => Promise<boolean>;
-  generateResponse: (message: string) => Promise<string>;
+  loadBestModel: (task?: string) => Promise<ModelSelection | null>;
+  generateResponse: (message: string, onToken?: (delta: string) => void, modelId?: string) => Promise<string>;
+  cancelGeneration: () => Promise<boolean>;
+  setChatTemplate: (modelId: string, template: string | null) => Promise<void>;
+  importModel: (
+    source: string,
+    mode?: ImportMode,
+    name?: string,
+    onProgress?: (progress: ImportProgress) => void,
+  ) => Promise<string>;
+  downloadModel: (
+    repoId: string,
+    options?: DownloadOptions,
+    onProgress?: (progress: DownloadProgress) => void,
+  ) => Promise<string>;
   getModelInfo: () => Promise<any>;
+  startApiServer: (bindAddress?: string, apiKey?: string) => Promise<ApiServerStatus>;
+  stopApiServer: () => Promise<void>;
+  getApiServerStatus: () => Promise<ApiServerStatus | null>;
   clearConversation: () => Promise<void>;
 }
 
 export const useAIStore = create<AIState>()(
   devtools(
-    immer((set) => ({
+    immer((set, get) => ({
       // Initial state
       availableModels: [],
       currentModel: null,
       isModelLoading: false,
       isDiscoveringModels: false,
+      activeRequestId: null,
       chatSessions: [],
       currentChatSession: null,
 
@@ -143,16 +236,16 @@ export const useAIStore = create<AIState>()(
         }
       },
 
-      loadBestModel: async () => {
+      loadBestModel: async (task?: string) => {
         set((state) => {
           state.isModelLoading = true;
         });
         try {
-          const success: boolean = await invoke('load_best_model');
+          const selection: ModelSelection = await invoke('load_best_model', { task: task ?? null });
           
-          if (success) {
+          if (selection.selected) {
             const models: AIModel[] = await invoke('discover_models');
-            const loadedModel = models.find(m => m.loaded);
+            const loadedModel = models.find(m => m.id === selection.selected);
             
             set((state) => {
               state.currentModel = loadedModel || null;
@@ -164,22 +257,111 @@ export const useAIStore = create<AIState>()(
               state.isModelLoading = false;
             });
           }
-          return success;
+          return selection;
         } catch (error) {
           console.error('Failed to load best model:', error);
           set((state) => {
             state.isModelLoading = false;
           });
-          return false;
+          return null;
         }
       },
 
-      generateResponse: async (message: string) => {
+      generateResponse: async (message: string, onToken?: (delta: string) => void, modelId?: string) => {
+        const requestId = crypto.randomUUID();
+        set((state) => {
+          state.activeRequestId = requestId;
+        });
+        const unlisten = onToken
+          ? await listen<GenerationToken>('ai-generation-token', (event) => {
+              if (event.payload.request_id === requestId) {
+                onToken(event.payload.delta);
+              }
+            })
+          : undefined;
+
         try {
-          return await invoke('generate_response', { message });
+          return await invoke<string>('generate_response', { message, requestId, modelId: modelId ?? null });
         } catch (error) {
           console.error('Failed to generate response:', error);
           throw error;
+        } finally {
+          unlisten?.();
+          set((state) => {
+            if (state.activeRequestId === requestId) {
+              state.activeRequestId = null;
+            }
+          });
+        }
+      },
+
+      cancelGeneration: async () => {
+        const requestId = get().activeRequestId;
+        if (!requestId) return false;
+        try {
+          return await invoke<boolean>('cancel_generation', { requestId });
+        } catch (error) {
+          console.error('Failed to cancel generation:', error);
+          return false;
+        }
+      },
+
+      setChatTemplate: async (modelId: string, template: string | null) => {
+        try {
+          await invoke('set_chat_template', { modelId, template });
+        } catch (error) {
+          console.error('Failed to set chat template:', error);
+          throw error;
+        }
+      },
+
+      importModel: async (source, mode = 'symlink', name, onProgress) => {
+        const unlisten = onProgress
+          ? await listen<ImportProgress>('ai-model-import-progress', (event) => onProgress(event.payload))
+          : undefined;
+
+        try {
+          const modelId = await invoke<string>('import_model', { source, mode, name: name ?? null });
+          const models: AIModel[] = await invoke('get_available_models');
+          set((state) => {
+            state.availableModels = models;
+          });
+          return modelId;
+        } catch (error) {
+          console.error('Failed to import model:', error);
+          throw error;
+        } finally {
+          unlisten?.();
+        }
+      },
+
+      downloadModel: async (repoId, options = {}, onProgress) => {
+        const unlisten = onProgress
+          ? await listen<DownloadProgress>('ai-model-download-progress', (event) => {
+              if (event.payload.repo_id === repoId) {
+                onProgress(event.payload);
+              }
+            })
+          : undefined;
+
+        try {
+          const name = await invoke<string>('download_model', {
+            repoId,
+            revision: options.revision ?? null,
+            files: options.files ?? null,
+            name: options.name ?? null,
+            embedding: options.embedding ?? null,
+          });
+          const models: AIModel[] = await invoke('get_available_models');
+          set((state) => {
+            state.availableModels = models;
+          });
+          return name;
+        } catch (error) {
+          console.error('Failed to download model:', error);
+          throw error;
+        } finally {
+          unlisten?.();
         }
       },
 
@@ -192,6 +374,35 @@ export const useAIStore = create<AIState>()(
         }
       },
 
+      startApiServer: async (bindAddress, apiKey) => {
+        try {
+          return await invoke<ApiServerStatus>('start_api_server', {
+            bindAddress: bindAddress ?? null,
+            apiKey: apiKey ?? null,
+          });
+        } catch (error) {
+          console.error('Failed to start API server:', error);
+          throw error;
+        }
+      },
+
+      stopApiServer: async () => {
+        try {
+          await invoke('stop_api_server');
+        } catch (error) {
+          console.error('Failed to stop API server:', error);
+        }
+      },
+
+      getApiServerStatus: async () => {
+        try {
+          return await invoke<ApiServerStatus>('get_api_server_status');
+        } catch (error) {
+          console.error('Failed to get API server status:', error);
+          return null;
+        }
+      },
+
       clearConversation: async () => {
         try {
           await invoke('clear_conversation');